@@ -0,0 +1,182 @@
+//! Spotting backups that are much larger or smaller than usual, like a backup of a disk that wasn't mounted, which
+//! succeeds but backs up nothing
+
+use slog::debug;
+
+use crate::backup::BackupSummary;
+use crate::format::format_bytes;
+use crate::restic::Restic;
+use crate::state::StateStore;
+
+/// How many standard deviations from the recent average a backup can be, unless the profile's `anomaly.max_deviation`
+/// says otherwise
+const DEFAULT_MAX_DEVIATION: f64 = 3.0;
+
+/// How many of the latest successful backups to compare against
+const HISTORY_SIZE: usize = 10;
+
+/// Fewest earlier backups to compare against, so that a profile's first few backups aren't judged on too little history
+const MIN_HISTORY_SIZE: usize = 3;
+
+/// Smallest standard deviation to assume, as a fraction of the average. Otherwise, if every recent backup had the same
+/// number of files, a single new file would be unusual.
+const MIN_RELATIVE_SPREAD: f64 = 0.1;
+
+/// Smallest standard deviation to assume for added data, so that a profile that usually adds a few kilobytes isn't
+/// flagged for adding a few megabytes
+const MIN_BYTES_SPREAD: f64 = 100.0 * 1024.0 * 1024.0;
+
+/// Extends the Restic wrapper with anomaly detection
+impl<'a> Restic<'a> {
+    /// Compares a finished backup to the profile's recent successful backups in the run history, and describes what's
+    /// unusual about it, if anything. A backup is unusual if its file count is far above or below the recent average, or
+    /// if it added far more data than usual. Adding much less data than usual isn't unusual, since it only means that less
+    /// changed.
+    pub fn backup_anomaly(&self, summary: &BackupSummary) -> Option<String> {
+        let options = &self.profile().anomaly;
+        if options.disabled {
+            return None;
+        }
+        let store = match StateStore::open(self.config()) {
+            Ok(store) => store,
+            Err(err) => {
+                debug!(self.logger(), "Not checking backup size: {:#}", err);
+                return None;
+            }
+        };
+        let max_deviation = options.max_deviation.unwrap_or(DEFAULT_MAX_DEVIATION);
+        let runs: Vec<_> = store
+            .runs(self.profile_name(), "backup")
+            .rev()
+            .filter(|run| run.success)
+            .take(HISTORY_SIZE)
+            .collect();
+
+        let mut problems = Vec::new();
+        let files: Vec<f64> = runs
+            .iter()
+            .filter_map(|run| run.files)
+            .map(|files| files as f64)
+            .collect();
+        if let Some(average) = outlier(
+            &files,
+            summary.total_files_processed as f64,
+            max_deviation,
+            1.0,
+            false,
+        ) {
+            problems.push(format!(
+                "it backed up {} files, but recent backups averaged {:.0}",
+                summary.total_files_processed, average
+            ));
+        }
+        let bytes_added: Vec<f64> = runs
+            .iter()
+            .filter_map(|run| run.bytes_added)
+            .map(|bytes| bytes as f64)
+            .collect();
+        if let Some(average) = outlier(
+            &bytes_added,
+            summary.data_added as f64,
+            max_deviation,
+            MIN_BYTES_SPREAD,
+            true,
+        ) {
+            problems.push(format!(
+                "it added {}, but recent backups averaged {}",
+                format_bytes(summary.data_added),
+                format_bytes(average as u64)
+            ));
+        }
+
+        if problems.is_empty() {
+            None
+        } else {
+            Some(problems.join(", and "))
+        }
+    }
+}
+
+/// If `value` is more than `max_deviation` standard deviations away from the average of `history`, returns the average.
+/// The standard deviation is at least `min_spread`, or a fraction of the average if that's larger. With `increases_only`,
+/// values below the average are never outliers.
+fn outlier(
+    history: &[f64],
+    value: f64,
+    max_deviation: f64,
+    min_spread: f64,
+    increases_only: bool,
+) -> Option<f64> {
+    if history.len() < MIN_HISTORY_SIZE {
+        return None;
+    }
+    let count = history.len() as f64;
+    let average = history.iter().sum::<f64>() / count;
+    let variance = history.iter().map(|x| (x - average).powi(2)).sum::<f64>() / count;
+    let spread = variance
+        .sqrt()
+        .max(average * MIN_RELATIVE_SPREAD)
+        .max(min_spread);
+
+    let deviation = (value - average) / spread;
+    if deviation > max_deviation || (!increases_only && -deviation > max_deviation) {
+        Some(average)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Utc;
+
+    use super::outlier;
+    use crate::backup::BackupSummary;
+    use crate::state::{RunRecord, StateStore};
+    use crate::test::TestFixture;
+
+    #[test]
+    fn test_outlier() {
+        let history = [10_000.0, 10_200.0, 9_900.0, 10_050.0];
+        assert_eq!(outlier(&history, 10_100.0, 3.0, 1.0, false), None);
+        assert!(outlier(&history, 0.0, 3.0, 1.0, false).is_some());
+        assert_eq!(outlier(&history, 0.0, 3.0, 1.0, true), None);
+        assert!(outlier(&history, 30_000.0, 3.0, 1.0, true).is_some());
+        assert!(outlier(&history, 14_000.0, 3.0, 1.0, false).is_some());
+        // A larger tolerance lets more through
+        assert_eq!(outlier(&history, 14_000.0, 5.0, 1.0, false), None);
+        // Too little history to judge
+        assert_eq!(outlier(&history[..2], 0.0, 3.0, 1.0, false), None);
+        // Identical history still tolerates small changes
+        assert_eq!(outlier(&[500.0; 5], 520.0, 3.0, 1.0, false), None);
+    }
+
+    #[test]
+    fn test_backup_anomaly() {
+        let mut fixture = TestFixture::new();
+        let restic = fixture.restic();
+        let mut store = StateStore::open(restic.config()).unwrap();
+        for files in [1200, 1180, 1210, 1195] {
+            let mut run = RunRecord::finished("test", "backup", Utc::now(), Some(0), None);
+            run.files = Some(files);
+            run.bytes_added = Some(1024 * 1024);
+            store.record(run).unwrap();
+        }
+
+        let mut summary = BackupSummary {
+            total_files_processed: 1190,
+            data_added: 2 * 1024 * 1024,
+            ..BackupSummary::default()
+        };
+        assert_eq!(restic.backup_anomaly(&summary), None);
+
+        // A backup of an empty mount point
+        summary.total_files_processed = 0;
+        let anomaly = restic.backup_anomaly(&summary).unwrap();
+        assert!(anomaly.contains("backed up 0 files"), "{}", anomaly);
+
+        drop(restic);
+        fixture.profile_mut().anomaly.disabled = true;
+        assert_eq!(fixture.restic().backup_anomaly(&summary), None);
+    }
+}
@@ -0,0 +1,134 @@
+//! Environment variables stored in AWS Secrets Manager or Systems Manager Parameter Store. These are read with the `aws`
+//! CLI, so that its default credential chain (environment variables, shared configuration, or an instance or task role)
+//! applies without Rustic needing any long-lived keys of its own.
+
+use std::collections::HashMap;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+
+use anyhow::{bail, Context, Result};
+use lazy_static::lazy_static;
+
+use crate::json;
+
+lazy_static! {
+    /// Secrets read so far, so that profiles sharing a secret only read it once per run
+    static ref SECRETS: Mutex<HashMap<String, HashMap<String, String>>> = Mutex::new(HashMap::new());
+}
+
+/// Reads the environment variables stored in the secret or parameter named by `arn`. Its value must be a JSON object
+/// whose values are all strings.
+pub fn secret_environment(arn: &str) -> Result<HashMap<String, String>> {
+    let mut secrets = SECRETS.lock().unwrap();
+    if let Some(environment) = secrets.get(arn) {
+        return Ok(environment.clone());
+    }
+
+    let output = Command::new("aws")
+        .args(secret_args(arn)?)
+        .stdin(Stdio::null())
+        .output()
+        .context("Could not run aws")?;
+    if !output.status.success() {
+        bail!(
+            "Could not read AWS secret {}: {}",
+            arn,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let environment = parse_environment(&String::from_utf8_lossy(&output.stdout))
+        .with_context(|| format!("Could not parse AWS secret {}", arn))?;
+    secrets.insert(arn.to_string(), environment.clone());
+    Ok(environment)
+}
+
+/// Arguments to the `aws` CLI that print the value of the secret or parameter named by `arn`. The region comes from the
+/// ARN, since the secret may not be in the default region.
+pub fn secret_args(arn: &str) -> Result<Vec<String>> {
+    let parts: Vec<&str> = arn.splitn(6, ':').collect();
+    if parts.len() != 6 || parts[0] != "arn" || parts[3].is_empty() {
+        bail!("`{}` is not an AWS ARN", arn);
+    }
+    let (service, region, resource) = (parts[2], parts[3], parts[5]);
+
+    let mut args: Vec<String> = match service {
+        "secretsmanager" if resource.starts_with("secret:") => vec![
+            "secretsmanager".into(),
+            "get-secret-value".into(),
+            "--secret-id".into(),
+            arn.into(),
+            "--query".into(),
+            "SecretString".into(),
+        ],
+        "ssm" if resource.starts_with("parameter/") => vec![
+            "ssm".into(),
+            "get-parameter".into(),
+            "--name".into(),
+            arn.into(),
+            "--with-decryption".into(),
+            "--query".into(),
+            "Parameter.Value".into(),
+        ],
+        _ => bail!(
+            "`{}` is not the ARN of a Secrets Manager secret or Systems Manager parameter",
+            arn
+        ),
+    };
+    args.extend_from_slice(&[
+        "--region".into(),
+        region.into(),
+        "--output".into(),
+        "text".into(),
+    ]);
+    Ok(args)
+}
+
+/// Parses a secret's value as a JSON object of environment variables
+fn parse_environment(value: &str) -> Result<HashMap<String, String>> {
+    json::from_str(value.trim()).context("Expected a JSON object of environment variables")
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_environment, secret_args};
+
+    #[test]
+    fn test_secret_args() {
+        assert_eq!(
+            secret_args("arn:aws:secretsmanager:us-east-2:123456789012:secret:restic/home-AbCdEf")
+                .unwrap(),
+            vec![
+                "secretsmanager",
+                "get-secret-value",
+                "--secret-id",
+                "arn:aws:secretsmanager:us-east-2:123456789012:secret:restic/home-AbCdEf",
+                "--query",
+                "SecretString",
+                "--region",
+                "us-east-2",
+                "--output",
+                "text"
+            ]
+        );
+        assert_eq!(
+            secret_args("arn:aws:ssm:eu-west-1:123456789012:parameter/restic/home").unwrap()[..5],
+            [
+                "ssm",
+                "get-parameter",
+                "--name",
+                "arn:aws:ssm:eu-west-1:123456789012:parameter/restic/home",
+                "--with-decryption"
+            ]
+        );
+        assert!(secret_args("restic/home").is_err());
+        assert!(secret_args("arn:aws:s3:::my-bucket").is_err());
+
+        let environment = parse_environment(
+            "{\"AWS_ACCESS_KEY_ID\": \"AKIA\", \"AWS_SECRET_ACCESS_KEY\": \"shh\"}\n",
+        )
+        .unwrap();
+        assert_eq!(environment["AWS_ACCESS_KEY_ID"], "AKIA");
+        assert_eq!(environment["AWS_SECRET_ACCESS_KEY"], "shh");
+        assert!(parse_environment("AWS_ACCESS_KEY_ID=AKIA").is_err());
+    }
+}
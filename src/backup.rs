@@ -1,30 +1,217 @@
 //! Implementation for Restic backups.
 
 use std::collections::HashMap;
+use std::fs;
 use std::io::prelude::*;
-use std::time::Instant;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, bail, Context, Result};
-use slog::{debug, error, info};
+use chrono::{DateTime, Local, Utc};
+use serde::{Deserialize, Serialize};
+use slog::{debug, error, info, warn, Logger};
 use tempfile::NamedTempFile;
 
-use crate::config::Fileset;
-use crate::restic::Restic;
+use crate::conditions;
+use crate::config::{Configuration, Fileset};
+use crate::databases;
+use crate::error::RusticError;
+use crate::events::{Event, HookOutcome, Outcome, Progress};
+use crate::format::{format_bytes, format_duration};
+use crate::hooks::run_hook;
+use crate::json;
+use crate::lock::RepositoryLock;
+use crate::metrics::Metric;
+use crate::redact;
+use crate::restic::{RepositoryState, Restic, StdinCommand};
+use crate::state::StateStore;
+use crate::version::ResticVersion;
 
-/// Writes all patterns specified by a fileset and any filesets it inherits from to some stream, such as an include or exclude file.
-fn write_fileset<W: Write>(
-    out: &mut W,
+/// Statistics about a completed backup, as reported by Restic's `summary` message.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct BackupSummary {
+    /// Number of files that were not in the parent snapshot
+    pub files_new: u64,
+
+    /// Number of files that changed since the parent snapshot
+    pub files_changed: u64,
+
+    /// Number of files that did not change since the parent snapshot
+    pub files_unmodified: u64,
+
+    /// Number of directories that were not in the parent snapshot
+    pub dirs_new: u64,
+
+    /// Number of directories that changed since the parent snapshot
+    pub dirs_changed: u64,
+
+    /// Number of directories that did not change since the parent snapshot
+    pub dirs_unmodified: u64,
+
+    /// Bytes of new data added to the repository (before compression)
+    pub data_added: u64,
+
+    /// Total number of files in the backup
+    pub total_files_processed: u64,
+
+    /// Total size of all files in the backup
+    pub total_bytes_processed: u64,
+
+    /// Time Restic spent on the backup, in seconds
+    pub total_duration: f64,
+
+    /// ID of the new snapshot. If the profile backs up command output, which goes into separate snapshots, this lists the ID
+    /// of each snapshot, separated by commas.
+    pub snapshot_id: String,
+}
+
+impl BackupSummary {
+    /// Combines the summaries of several backups into one, adding up their statistics
+    fn combine(summaries: Vec<BackupSummary>) -> BackupSummary {
+        let mut summaries = summaries.into_iter();
+        let mut combined = summaries.next().expect("no backup summaries to combine");
+        for summary in summaries {
+            combined.files_new += summary.files_new;
+            combined.files_changed += summary.files_changed;
+            combined.files_unmodified += summary.files_unmodified;
+            combined.dirs_new += summary.dirs_new;
+            combined.dirs_changed += summary.dirs_changed;
+            combined.dirs_unmodified += summary.dirs_unmodified;
+            combined.data_added += summary.data_added;
+            combined.total_files_processed += summary.total_files_processed;
+            combined.total_bytes_processed += summary.total_bytes_processed;
+            combined.total_duration += summary.total_duration;
+            combined.snapshot_id.push(',');
+            combined.snapshot_id.push_str(&summary.snapshot_id);
+        }
+        combined
+    }
+
+    /// How long Restic spent on the backup
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs_f64(self.total_duration)
+    }
+
+    /// Prometheus metrics describing the backup
+    pub fn metrics(&self) -> Vec<Metric<'static>> {
+        vec![
+            (
+                "files_new",
+                "Number of new files in the last backup",
+                self.files_new as f64,
+            ),
+            (
+                "files_changed",
+                "Number of changed files in the last backup",
+                self.files_changed as f64,
+            ),
+            (
+                "files_unmodified",
+                "Number of unmodified files in the last backup",
+                self.files_unmodified as f64,
+            ),
+            (
+                "bytes_added",
+                "Bytes added to the repository by the last backup",
+                self.data_added as f64,
+            ),
+            (
+                "files_processed",
+                "Total number of files in the last backup",
+                self.total_files_processed as f64,
+            ),
+            (
+                "bytes_processed",
+                "Total size of files in the last backup",
+                self.total_bytes_processed as f64,
+            ),
+        ]
+    }
+
+    /// Logs the summary's statistics
+    pub fn log(&self, logger: &Logger) {
+        info!(logger, "Created snapshot {}", self.snapshot_id;
+            "files_new" => self.files_new,
+            "files_changed" => self.files_changed,
+            "files_unmodified" => self.files_unmodified,
+            "dirs_new" => self.dirs_new,
+            "dirs_changed" => self.dirs_changed,
+            "dirs_unmodified" => self.dirs_unmodified,
+            "data_added" => self.data_added,
+            "total_files_processed" => self.total_files_processed,
+            "total_bytes_processed" => self.total_bytes_processed,
+            "duration" => ?self.duration(),
+        );
+    }
+}
+
+/// Patterns from a fileset and the filesets it inherits from, grouped by how Restic interprets them
+#[derive(Debug, Default, PartialEq)]
+struct FilesetPatterns {
+    /// Glob patterns
+    globs: Vec<String>,
+
+    /// Glob patterns that match regardless of case
+    insensitive: Vec<String>,
+
+    /// Literal paths, from filesets with `verbatim` set
+    verbatim: Vec<String>,
+}
+
+impl FilesetPatterns {
+    /// Adds the patterns from `other`, transforming each one with `anchor`
+    fn extend_anchored<F: Fn(&str) -> String>(&mut self, other: FilesetPatterns, anchor: F) {
+        self.globs
+            .extend(other.globs.iter().map(|pattern| anchor(pattern)));
+        self.insensitive
+            .extend(other.insensitive.iter().map(|pattern| anchor(pattern)));
+        self.verbatim
+            .extend(other.verbatim.iter().map(|pattern| anchor(pattern)));
+    }
+}
+
+/// Collects all patterns specified by a fileset and any filesets it inherits from.
+fn fileset_patterns(
     fileset: &Fileset,
     named_filesets: &HashMap<String, Fileset>,
+    patterns: &mut FilesetPatterns,
 ) -> Result<()> {
-    for pattern in fileset.patterns.iter() {
-        writeln!(out, "{}", pattern).context("Could not write fileset")?;
+    collect_patterns(fileset, named_filesets, &mut Vec::new(), patterns)
+}
+
+/// Recursive implementation of [`fileset_patterns`]. `chain` holds the names of the filesets currently being expanded, so
+/// that inheritance cycles are reported instead of recursing forever.
+fn collect_patterns<'f>(
+    fileset: &'f Fileset,
+    named_filesets: &'f HashMap<String, Fileset>,
+    chain: &mut Vec<&'f str>,
+    patterns: &mut FilesetPatterns,
+) -> Result<()> {
+    if fileset.verbatim {
+        patterns.verbatim.extend(fileset.patterns.iter().cloned());
+    } else {
+        patterns.globs.extend(fileset.patterns.iter().cloned());
     }
+    patterns
+        .insensitive
+        .extend(fileset.insensitive_patterns.iter().cloned());
 
     for inherited in fileset.inherits.iter() {
+        if let Some(start) = chain.iter().position(|name| name == inherited) {
+            bail!(
+                "Fileset inheritance cycle: {} -> {}",
+                chain[start..].join(" -> "),
+                inherited
+            );
+        }
+
         match named_filesets.get(inherited) {
             Some(fs) => {
-                write_fileset(out, fs, named_filesets).context("Could not write fileset")?
+                chain.push(inherited);
+                collect_patterns(fs, named_filesets, chain, patterns)?;
+                chain.pop();
             }
             None => bail!("Fileset {} does not exist", inherited),
         }
@@ -33,71 +220,763 @@ fn write_fileset<W: Write>(
     Ok(())
 }
 
+/// A command whose output is backed up into its own snapshot
+struct StdinSource {
+    /// Command to run
+    command: StdinCommand,
+
+    /// File name to store the output as, if not Restic's default of `stdin`
+    filename: Option<String>,
+
+    /// Tags for the snapshot
+    tags: Vec<String>,
+}
+
+/// Writes patterns to a temporary file to pass to Restic, or returns `None` if there aren't any. `kind` describes the
+/// patterns for logs and errors.
+fn pattern_file(logger: &Logger, kind: &str, patterns: &[String]) -> Result<Option<NamedTempFile>> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut file = NamedTempFile::new()
+        .with_context(|| format!("Could not create temporary {} file", kind))?;
+    debug!(logger, "Creating {} file", kind; "path" => %file.path().display());
+    write_patterns(file.as_file_mut(), patterns)
+        .with_context(|| format!("Could not generate {} file", kind))?;
+    Ok(Some(file))
+}
+
+/// Writes patterns to a stream, such as an include or exclude file.
+fn write_patterns<W: Write>(out: &mut W, patterns: &[String]) -> Result<()> {
+    for pattern in patterns.iter() {
+        writeln!(out, "{}", pattern).context("Could not write fileset")?;
+    }
+    Ok(())
+}
+
+/// Resolves an include pattern from a source against the source's base directory.
+fn anchor_include(base_directory: &Path, pattern: &str) -> String {
+    if Path::new(pattern).is_absolute() {
+        pattern.to_string()
+    } else {
+        base_directory.join(pattern).display().to_string()
+    }
+}
+
+/// The part of a glob pattern before the first path component with a wildcard in it
+fn glob_root(pattern: &str) -> PathBuf {
+    Path::new(pattern)
+        .components()
+        .take_while(|component| {
+            !component
+                .as_os_str()
+                .to_string_lossy()
+                .contains(['*', '?', '['])
+        })
+        .collect()
+}
+
+/// Restricts an exclude pattern from a source to files under the source's base directory. Restic matches relative exclude
+/// patterns anywhere in the backup, so they're converted to absolute patterns matching at any depth below the base directory.
+fn anchor_exclude(base_directory: &Path, pattern: &str) -> String {
+    if Path::new(pattern).is_absolute() {
+        pattern.to_string()
+    } else {
+        base_directory
+            .join("**")
+            .join(pattern)
+            .display()
+            .to_string()
+    }
+}
+
+/// Restic's exit code for a backup that created a snapshot, but couldn't read some files
+const PARTIAL_BACKUP_EXIT_CODE: i32 = 3;
+
+/// How often `wait_for_repository` checks whether the repository is reachable
+const REPOSITORY_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// First Restic release with the `--files-from-verbatim` flag
+const FILES_FROM_VERBATIM_VERSION: ResticVersion = ResticVersion::new(0, 12, 0);
+
+/// Returns why `profile` isn't due for a backup yet, or `None` if it is: it's not due if it was backed up successfully
+/// within its `frequency`, according to the run history. This only reads the configuration and the run history, so a
+/// profile that isn't due is skipped without resolving its password or running Restic.
+pub fn not_due_reason(
+    config: &Configuration,
+    profile: &str,
+    logger: &Logger,
+) -> Result<Option<String>> {
+    let frequency = match config
+        .profiles
+        .get(profile)
+        .map(|settings| settings.frequency)
+    {
+        Some(Some(frequency)) => frequency,
+        Some(None) => {
+            warn!(
+                logger,
+                "Backing up, since the profile has no `frequency` to tell whether a backup is due"
+            );
+            return Ok(None);
+        }
+        // Opening the profile reports that it doesn't exist
+        None => return Ok(None),
+    };
+    let store = StateStore::open(config)?;
+    if let Some(last) = store.last_success(profile, "backup") {
+        let finished_at: DateTime<Local> = last.finished_at.into();
+        if !frequency.is_due(Some(finished_at), Local::now()) {
+            return Ok(Some(format!(
+                "Not due yet, since the last backup finished at {} and the profile is backed up {}",
+                finished_at.format("%Y-%m-%d %H:%M"),
+                frequency
+            )));
+        }
+    }
+    Ok(None)
+}
+
 // This uses an impl block in a separate file so it has access to all the repo info but keeps backup-specific Restic details
 // nicely contained
 
 /// Extends the Restic wrapper with backup commands.
 impl<'a> Restic<'a> {
-    /// Attempts to initialize the Restic repository. Note that this *does not* check if the repository has already been initialized.
-    pub fn init(&self) -> Result<()> {
-        let mut cmd = self.new_command();
-        cmd.arg("init");
-        debug!(self.logger(), "Initializing Restic repository"; "command" => ?cmd);
+    /// Returns why the backup should be skipped, or `None` if it should run. The backup is skipped if the machine is on
+    /// battery power or a metered network and the profile doesn't allow that, or if a path it backs up from is missing and
+    /// the profile sets `preconditions.skip_missing_sources`. Whether it's due is checked separately, by
+    /// [`not_due_reason`].
+    pub fn backup_skip_reason(&self) -> Result<Option<String>> {
+        if self.profile().require_ac_power {
+            match conditions::on_ac_power() {
+                Some(true) => (),
+                Some(false) => {
+                    return Ok(Some(
+                        "Running on battery power, and the profile sets `require_ac_power`"
+                            .to_string(),
+                    ))
+                }
+                None => warn!(
+                    self.logger(),
+                    "Could not tell whether the machine is on AC power, backing up anyway"
+                ),
+            }
+        }
+        if self.profile().skip_on_metered {
+            match conditions::on_metered_network() {
+                Some(false) => (),
+                Some(true) => {
+                    return Ok(Some(
+                        "Connected to a metered network, and the profile sets `skip_on_metered`"
+                            .to_string(),
+                    ))
+                }
+                None => warn!(
+                    self.logger(),
+                    "Could not tell whether the network is metered, backing up anyway"
+                ),
+            }
+        }
 
-        let status = cmd
-            .status()
-            .with_context(|| format!("Could not run {:?}", cmd))?;
+        if self.profile().preconditions.skip_missing_sources {
+            if let Some(problem) = self.missing_source()? {
+                return Ok(Some(format!(
+                    "{}, and the profile sets `preconditions.skip_missing_sources`",
+                    problem
+                )));
+            }
+        }
 
-        if status.success() {
-            debug!(self.logger(), "Restic repository initialized");
-            Ok(())
-        } else {
-            bail!("Initializing the Restic repository failed with {}", status);
+        Ok(None)
+    }
+
+    /// Waits for the repository like [`wait_for_repository`](Restic::wait_for_repository). If it still can't be reached,
+    /// the skipped backup is reported as a failed one, so that the run history, metrics, health checks, and notifications
+    /// show that the profile isn't being backed up, and this fails with [`RusticError::RepositoryUnreachable`].
+    pub fn require_repository(&self) -> Result<()> {
+        let started_at = Utc::now();
+        let start = Instant::now();
+        let reason = match self.wait_for_repository()? {
+            Some(reason) => reason,
+            None => return Ok(()),
+        };
+
+        self.emit(&Event::Started {
+            operation: "backup",
+        });
+        self.emit(&Event::Failed(&Outcome {
+            operation: "backup",
+            started_at,
+            duration: Instant::now() - start,
+            exit_code: None,
+            summary: None,
+            forget: None,
+            anomaly: None,
+            error: Some(reason.clone()),
+        }));
+        Err(RusticError::RepositoryUnreachable(reason).into())
+    }
+
+    /// Waits up to the profile's `preconditions.wait_for_repository` for Restic to reach the repository. Returns why the
+    /// repository is unreachable if it still can't be reached, or `None` if it can (or the profile doesn't wait for it).
+    pub fn wait_for_repository(&self) -> Result<Option<String>> {
+        let timeout = match self.profile().preconditions.wait_for_repository {
+            Some(timeout) if self.profile().failover_repositories.is_empty() => timeout,
+            _ => return Ok(None),
+        };
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let reason = match self.repository_state()? {
+                RepositoryState::Unreachable(reason) => reason,
+                _ => return Ok(None),
+            };
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(Some(format!(
+                    "Could not reach repository {} within {}: {}",
+                    redact::url(self.repository()),
+                    format_duration(timeout),
+                    reason
+                )));
+            }
+            let delay = REPOSITORY_POLL_INTERVAL.min(deadline - now);
+            info!(self.logger(), "Could not reach repository, checking again in {:?}", delay; "reason" => reason);
+            thread::sleep(delay);
         }
     }
 
-    /// Runs a backup. If the repository does not exist and `auto_init` is set in the profile, it will be initialized first.
-    pub fn backup(&self) -> Result<()> {
-        if !self.repository_exists()? {
-            if self.profile().auto_init {
-                self.init()?;
-            } else {
-                bail!("Repository not initialized");
+    /// The profile's exclude patterns, as they're passed to `restic backup`: glob patterns, and glob patterns that match
+    /// regardless of case
+    pub fn exclude_patterns(&self) -> Result<(Vec<String>, Vec<String>)> {
+        let (_, excludes) = self.backup_patterns()?;
+        Ok((excludes.globs, excludes.insensitive))
+    }
+
+    /// Files and directories the profile backs up from. Glob patterns start from the directory before their first wildcard.
+    pub fn include_roots(&self) -> Result<Vec<PathBuf>> {
+        let (includes, _) = self.backup_patterns()?;
+        let base_directory = &self.profile().base_directory;
+        let mut roots: Vec<PathBuf> = includes
+            .globs
+            .iter()
+            .map(|pattern| glob_root(pattern))
+            .chain(includes.verbatim.iter().map(PathBuf::from))
+            .map(|path| base_directory.join(path))
+            .collect();
+        roots.sort();
+        roots.dedup();
+        Ok(roots)
+    }
+
+    /// Checks that each path the profile backs up from exists, and is a mount point if the profile's
+    /// `preconditions.require_mountpoint` is set. Returns what's wrong, if anything. Otherwise, backing up a directory whose
+    /// disk isn't mounted would succeed with a snapshot of an empty directory.
+    pub fn missing_source(&self) -> Result<Option<String>> {
+        let require_mountpoint = self.profile().preconditions.require_mountpoint;
+        for root in self.include_roots()? {
+            if !root.exists() {
+                return Ok(Some(format!("{} does not exist", root.display())));
+            }
+            if require_mountpoint {
+                match conditions::is_mountpoint(&root) {
+                    Some(true) => (),
+                    Some(false) => {
+                        return Ok(Some(format!("{} is not a mount point", root.display())))
+                    }
+                    None => warn!(
+                        self.logger(),
+                        "Could not tell whether {} is a mount point, backing up anyway",
+                        root.display()
+                    ),
+                }
             }
         }
+        Ok(None)
+    }
 
-        let mut include_file =
-            NamedTempFile::new().context("Could not create temporary includes file")?;
-        debug!(self.logger(), "Creating includes file"; "path" => %include_file.path().display());
-        write_fileset(
-            include_file.as_file_mut(),
-            &self.profile().include,
-            &self.config().filesets,
-        )
-        .context("Could not generate includes file")?;
-
-        let mut exclude_file =
-            NamedTempFile::new().context("Could not create temporary excludes file")?;
-        debug!(self.logger(), "Creating excludes file"; "path" => %exclude_file.path().display());
-        write_fileset(
-            exclude_file.as_file_mut(),
-            &self.profile().exclude,
-            &self.config().filesets,
-        )
-        .context("Could not generate excludes file")?;
+    /// Checks a finished backup against the profile's `sanity` minimums, returning what it's missing, if anything
+    fn sanity_problem(&self, summary: &BackupSummary) -> Option<String> {
+        // Dry runs don't report a summary to check
+        if self.is_dry_run() {
+            return None;
+        }
+        let sanity = &self.profile().sanity;
+        if let Some(min_files) = sanity.min_files {
+            if summary.total_files_processed < min_files {
+                return Some(format!(
+                    "Snapshot {} only has {} files, fewer than the profile's `sanity.min_files` of {}",
+                    summary.snapshot_id, summary.total_files_processed, min_files
+                ));
+            }
+        }
+        if let Some(min_total_size) = sanity.min_total_size {
+            if summary.total_bytes_processed < min_total_size {
+                return Some(format!(
+                    "Snapshot {} only has {} of files, less than the profile's `sanity.min_total_size` of {}",
+                    summary.snapshot_id,
+                    format_bytes(summary.total_bytes_processed),
+                    format_bytes(min_total_size)
+                ));
+            }
+        }
+        None
+    }
 
+    /// Forgets the snapshots of a backup that failed its sanity checks, so they don't count toward the retention policy.
+    /// The caller holds the repository lock. Returns whether they were forgotten, logging why not if they weren't.
+    fn forget_rejected_snapshot(&self, snapshot_id: &str) -> bool {
+        if let Err(err) = self.require_full_access("forget") {
+            warn!(
+                self.logger(),
+                "Keeping rejected snapshot {}: {:#}", snapshot_id, err
+            );
+            return false;
+        }
         let mut cmd = self.new_command();
-        cmd.arg("backup")
-            // Keeping these owned and using .path() instead of .into_temp_path() makes sure the files get deleted
-            .arg("--files-from")
-            .arg(include_file.path())
-            .arg("--exclude-file")
-            .arg(exclude_file.path());
+        cmd.arg("forget").args(snapshot_id.split(','));
+        info!(self.logger(), "Forgetting rejected snapshot {}", snapshot_id; "command" => %redact::command_line(&cmd));
+        match self.execute(&mut cmd, None) {
+            Ok(execution) if execution.status.success() => true,
+            Ok(execution) => {
+                error!(self.logger(), "Could not forget rejected snapshot {}", snapshot_id;
+                    "status" => %execution.status, "command" => %redact::command_line(&cmd));
+                false
+            }
+            Err(err) => {
+                error!(
+                    self.logger(),
+                    "Could not forget rejected snapshot {}: {:#}", snapshot_id, err
+                );
+                false
+            }
+        }
+    }
+
+    /// Files and directories to watch for changes to what the profile backs up. Glob patterns are watched from the directory
+    /// before their first wildcard. Paths that don't exist are left out, and so are paths inside others in the list.
+    pub fn watched_paths(&self) -> Result<Vec<PathBuf>> {
+        let paths: Vec<PathBuf> = self
+            .include_roots()?
+            .into_iter()
+            .filter(|path| {
+                let exists = path.exists();
+                if !exists {
+                    warn!(
+                        self.logger(),
+                        "Not watching {}, since it doesn't exist",
+                        path.display()
+                    );
+                }
+                exists
+            })
+            .collect();
+        let mut watched: Vec<PathBuf> = Vec::new();
+        for path in paths {
+            if !watched.iter().any(|parent| path.starts_with(parent)) {
+                watched.push(path);
+            }
+        }
+        Ok(watched)
+    }
+
+    /// Runs a backup, along with any configured hooks. If the repository does not exist and `auto_init` is set in the profile, it will
+    /// be initialized first. If `copy_after_backup` is set, the new snapshot is then copied to the `copy_to` repository.
+    ///
+    /// The repository is locked against other Rustic processes for the whole backup, including hooks. Maintenance on the
+    /// repository waits for the backup, even while it's waiting for the lock itself.
+    pub fn backup(&self) -> Result<BackupSummary> {
+        let hooks = &self.profile().hooks;
+        let started_at = Utc::now();
+        let start = Instant::now();
+        self.emit(&Event::Started {
+            operation: "backup",
+        });
+
+        let mut exit_status = None;
+        let mut partial_summary = None;
+        let result = self.queue_backup().and_then(|_queued| {
+            let _lock = self.lock_repository()?;
+            self.run_pre_backup_hooks()?;
+            if let Some(problem) = self.missing_source()? {
+                bail!("Not backing up, since {}", problem);
+            }
+            let containers = self.quiesce_containers()?;
+            let result = self.run_backup();
+            drop(containers);
+            let (status, summary) = result?;
+            exit_status = Some(status);
+            let summary = match summary {
+                Some(summary) if status.code() == Some(PARTIAL_BACKUP_EXIT_CODE) => {
+                    if self.profile().fail_on_partial {
+                        let snapshot_id = summary.snapshot_id.clone();
+                        partial_summary = Some(summary);
+                        return Err(anyhow!(self.failed("backup", status)).context(format!(
+                            "Some files could not be read, so snapshot {} is incomplete",
+                            snapshot_id
+                        )));
+                    }
+                    warn!(
+                        self.logger(),
+                        "Some files could not be read, so snapshot {} is incomplete",
+                        summary.snapshot_id
+                    );
+                    summary
+                }
+                _ if !status.success() => bail!(self.failed("backup", status)),
+                Some(summary) => summary,
+                // Nothing was actually backed up
+                None if self.is_dry_run() => BackupSummary::default(),
+                None => bail!("Restic did not report a backup summary"),
+            };
+
+            if let Some(problem) = self.sanity_problem(&summary) {
+                let forgotten = self.profile().sanity.forget_rejected
+                    && self.forget_rejected_snapshot(&summary.snapshot_id);
+                if !forgotten {
+                    partial_summary = Some(summary);
+                }
+                bail!(problem);
+            }
+
+            if self.profile().copy_after_backup {
+                self.copy()
+                    .context("Backup succeeded, but copying snapshots failed")?;
+            }
+
+            Ok(summary)
+        });
+        let duration = Instant::now() - start;
+
+        let anomaly = match result {
+            Ok(ref summary) if !self.is_dry_run() => self.backup_anomaly(summary),
+            _ => None,
+        };
+        if let Some(ref anomaly) = anomaly {
+            warn!(self.logger(), "Backup looks unusual: {}", anomaly);
+        }
+
+        let exit_code = exit_status.and_then(|status| status.code());
+        let outcome = Outcome {
+            operation: "backup",
+            started_at,
+            duration,
+            exit_code,
+            // A partial backup still created a snapshot, even if it counts as a failure
+            summary: result.as_ref().ok().or(partial_summary.as_ref()),
+            forget: None,
+            anomaly,
+            error: result.as_ref().err().map(|err| format!("{:#}", err)),
+        };
+        self.emit(&match result {
+            Ok(_) => Event::Finished(&outcome),
+            Err(_) => Event::Failed(&outcome),
+        });
+
+        let mut env = vec![
+            ("RUSTIC_PROFILE", self.profile_name().to_string()),
+            ("RUSTIC_REPOSITORY", self.repository().to_string()),
+            ("RUSTIC_DURATION", duration.as_secs_f64().to_string()),
+            (
+                "RUSTIC_EXIT_STATUS",
+                exit_code.map(|code| code.to_string()).unwrap_or_default(),
+            ),
+        ];
+        let (outcome_stage, outcome_hooks) = match result {
+            Ok(ref summary) => {
+                env.push(("RUSTIC_RESULT", "success".to_string()));
+                env.push(("RUSTIC_SNAPSHOT_ID", summary.snapshot_id.clone()));
+                env.push(("RUSTIC_BYTES_ADDED", summary.data_added.to_string()));
+                ("on_success", &hooks.on_success)
+            }
+            Err(ref err) => {
+                env.push(("RUSTIC_RESULT", "failure".to_string()));
+                env.push(("RUSTIC_ERROR", format!("{:#}", err)));
+                ("on_failure", &hooks.on_failure)
+            }
+        };
+
+        // Failing post-backup hooks are reported, but don't change the result of the backup itself
+        let post_hooks = outcome_hooks
+            .iter()
+            .map(|hook| (outcome_stage, hook))
+            .chain(hooks.post_backup.iter().map(|hook| ("post_backup", hook)));
+        for (stage, hook) in post_hooks {
+            if self.is_dry_run() {
+                println!("Would run hook: {}", hook);
+                continue;
+            }
+            if let Err(err) = self.run_backup_hook(stage, hook, env.iter().cloned()) {
+                warn!(self.logger(), "Post-backup hook failed: {:#}", err);
+            }
+        }
+
+        result
+    }
+
+    fn run_pre_backup_hooks(&self) -> Result<()> {
+        for hook in self.profile().hooks.pre_backup.iter() {
+            if self.is_dry_run() {
+                println!("Would run hook: {}", hook);
+                continue;
+            }
+            self.run_backup_hook(
+                "pre_backup",
+                hook,
+                vec![("RUSTIC_PROFILE", self.profile_name().to_string())],
+            )
+            .context("Pre-backup hook failed, aborting backup")?;
+        }
+
+        Ok(())
+    }
+
+    /// Runs one of the profile's backup hooks, and reports how it went to the sinks
+    fn run_backup_hook<I>(&self, stage: &str, hook: &str, env: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (&'static str, String)>,
+    {
+        let start = Instant::now();
+        let result = run_hook(self.logger(), hook, &self.profile().base_directory, env);
+        self.emit(&Event::Hook(&HookOutcome {
+            operation: "backup",
+            stage,
+            command: hook,
+            duration: Instant::now() - start,
+            error: result.as_ref().err().map(|err| format!("{:#}", err)),
+        }));
+        result
+    }
+
+    /// Picks the repository to back up to: the profile's `repository`, or if Restic can't reach it, the first of its
+    /// `failover_repositories` that it can. The repository is created if it doesn't exist yet and `auto_init` is set.
+    ///
+    /// The caller has already locked `repository`. Failing over locks the failover repository as well, and returns that lock.
+    fn choose_repository(&self) -> Result<Option<RepositoryLock>> {
+        let mut lock = None;
+        loop {
+            match self.repository_state()? {
+                RepositoryState::Exists => return Ok(lock),
+                RepositoryState::Missing if self.profile().auto_init => {
+                    self.init()?;
+                    return Ok(lock);
+                }
+                RepositoryState::Missing => bail!(RusticError::RepositoryNotFound {
+                    repository: self.repository().to_string()
+                }),
+                RepositoryState::Unreachable(reason) => {
+                    let unreachable = self.repository().to_string();
+                    match self.fail_over() {
+                        Some(next) => {
+                            warn!(self.logger(), "Could not reach repository {}, backing up to {} instead", redact::url(&unreachable), redact::url(next); "reason" => reason);
+                            // Drop the lock on the previous failover repository, if any, before taking the next one
+                            drop(lock.take());
+                            lock = Some(self.lock_repository()?);
+                        }
+                        None => bail!(
+                            "Could not reach repository {}: {}",
+                            redact::url(&unreachable),
+                            reason
+                        ),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Computes the include and exclude patterns for a backup, combining the profile's top-level filesets with those of each
+    /// of its file sources.
+    fn backup_patterns(&self) -> Result<(FilesetPatterns, FilesetPatterns)> {
+        let profile = self.profile();
+        let filesets = &self.config().filesets;
+
+        let mut includes = FilesetPatterns::default();
+        fileset_patterns(&profile.include, filesets, &mut includes)?;
+        let mut excludes = FilesetPatterns::default();
+        fileset_patterns(&profile.exclude, filesets, &mut excludes)?;
+
+        for source in profile.sources.iter() {
+            let base_directory = profile.base_directory.join(&source.base_directory);
+
+            let mut source_includes = FilesetPatterns::default();
+            fileset_patterns(&source.include, filesets, &mut source_includes)?;
+            if source.stdin_command.is_some() {
+                if source_includes != FilesetPatterns::default() {
+                    bail!("A source with `stdin_command` can't also include files");
+                }
+                continue;
+            }
+            includes.extend_anchored(source_includes, |pattern| {
+                anchor_include(&base_directory, pattern)
+            });
+
+            let mut source_excludes = FilesetPatterns::default();
+            fileset_patterns(&source.exclude, filesets, &mut source_excludes)?;
+            excludes.extend_anchored(source_excludes, |pattern| {
+                anchor_exclude(&base_directory, pattern)
+            });
+        }
+
+        if !includes.insensitive.is_empty() {
+            bail!("`insensitive_patterns` can only be used to exclude files");
+        }
+        if !excludes.verbatim.is_empty() {
+            bail!("`verbatim` filesets can only be used to include files");
+        }
+
+        Ok((includes, excludes))
+    }
+
+    /// Commands whose output the profile backs up, from its own `stdin_command`, those of its sources, and its databases
+    fn stdin_sources(&self) -> Result<Vec<StdinSource>> {
+        let profile = self.profile();
+        let mut stdin_sources = Vec::new();
+        if let Some(ref command) = profile.stdin_command {
+            stdin_sources.push(StdinSource {
+                command: StdinCommand::shell(command, &profile.base_directory),
+                filename: profile.stdin_filename.clone(),
+                tags: profile.tags.clone(),
+            });
+        }
+        for source in profile.sources.iter() {
+            if let Some(ref command) = source.stdin_command {
+                stdin_sources.push(StdinSource {
+                    command: StdinCommand::shell(
+                        command,
+                        &profile.base_directory.join(&source.base_directory),
+                    ),
+                    filename: source.stdin_filename.clone(),
+                    tags: profile
+                        .tags
+                        .iter()
+                        .chain(source.tags.iter())
+                        .cloned()
+                        .collect(),
+                });
+            }
+        }
+        for (kind, database) in databases::all(&profile.databases) {
+            stdin_sources.push(StdinSource {
+                command: databases::dump_command(kind, database, &profile.base_directory)?,
+                filename: Some(databases::dump_filename(kind, database)),
+                tags: profile
+                    .tags
+                    .iter()
+                    .cloned()
+                    .chain(std::iter::once(kind.name().to_string()))
+                    .collect(),
+            });
+        }
+        Ok(stdin_sources)
+    }
+
+    /// Runs `restic backup` for the profile's files, and then once for each command whose output it backs up. Returns the
+    /// exit status of the last run and a summary combining all of them, stopping at the first run that fails.
+    fn run_backup(&self) -> Result<(ExitStatus, Option<BackupSummary>)> {
+        // Held until the backup finishes, if it fails over to another repository
+        let _failover_lock = self.choose_repository()?;
+
+        let (includes, excludes) = self
+            .backup_patterns()
+            .context("Could not determine files to back up")?;
+        let stdin_sources = self
+            .stdin_sources()
+            .context("Could not prepare commands to back up")?;
+        let has_files = !includes.globs.is_empty() || !includes.verbatim.is_empty();
+        if self.profile().stdin_command.is_some() && has_files {
+            bail!("A profile with `stdin_command` can't also include files. Add them as a source instead.");
+        }
+        if !has_files && stdin_sources.is_empty() {
+            bail!("Profile does not include any files to back up");
+        }
+
+        let mut runs = Vec::new();
+        if has_files {
+            runs.push(self.run_file_backup(&includes, &excludes)?);
+        }
+        for stdin_source in stdin_sources.iter() {
+            if let Some((status, _)) = runs.last() {
+                if !status.success() && status.code() != Some(PARTIAL_BACKUP_EXIT_CODE) {
+                    break;
+                }
+            }
+            runs.push(self.run_stdin_backup(stdin_source)?);
+        }
+
+        // A partial backup is reported even if later runs succeeded, but any other failure ends the backup
+        let status = runs
+            .iter()
+            .map(|(status, _)| *status)
+            .find(|status| !status.success())
+            .unwrap_or(runs[runs.len() - 1].0);
+        let summary = runs
+            .into_iter()
+            .map(|(_, summary)| summary)
+            .collect::<Option<Vec<_>>>()
+            .map(BackupSummary::combine);
+        Ok((status, summary))
+    }
+
+    /// Backs up the files matching `includes` and `excludes`
+    fn run_file_backup(
+        &self,
+        includes: &FilesetPatterns,
+        excludes: &FilesetPatterns,
+    ) -> Result<(ExitStatus, Option<BackupSummary>)> {
+        if !includes.verbatim.is_empty() {
+            self.require_version(FILES_FROM_VERBATIM_VERSION, "`verbatim` filesets")?;
+        }
+
+        // The pattern files are kept open until Restic finishes, and deleted when they're dropped
+        let include_file = pattern_file(self.logger(), "includes", &includes.globs)?;
+        let verbatim_file = pattern_file(self.logger(), "verbatim includes", &includes.verbatim)?;
+        let exclude_file = pattern_file(self.logger(), "excludes", &excludes.globs)?;
+        let insensitive_exclude_file = pattern_file(
+            self.logger(),
+            "case-insensitive excludes",
+            &excludes.insensitive,
+        )?;
+
+        let tags = self.profile().tags.iter().chain(
+            self.profile()
+                .sources
+                .iter()
+                .filter(|source| source.stdin_command.is_none())
+                .flat_map(|source| source.tags.iter()),
+        );
+        let mut cmd = self.backup_command(tags);
+        for (flag, file) in [
+            ("--files-from", &include_file),
+            ("--files-from-verbatim", &verbatim_file),
+            ("--exclude-file", &exclude_file),
+            ("--iexclude-file", &insensitive_exclude_file),
+        ]
+        .iter()
+        {
+            if let Some(file) = file {
+                cmd.arg(flag).arg(file.path());
+                // The file is deleted after the backup, so show the patterns in it
+                if self.is_dry_run() {
+                    println!("Patterns for {} {}:", flag, file.path().display());
+                    for pattern in fs::read_to_string(file.path())?.lines() {
+                        println!("    {}", pattern);
+                    }
+                }
+            }
+        }
 
         if self.profile().exclude_caches {
             cmd.arg("--exclude-caches");
         }
 
+        for filename in self.profile().exclude_if_present.iter() {
+            cmd.arg("--exclude-if-present").arg(filename);
+        }
+
+        if let Some(ref size) = self.profile().exclude_larger_than {
+            cmd.arg("--exclude-larger-than").arg(size);
+        }
+
         if self.profile().one_file_system {
             cmd.arg("--one-file-system");
         }
@@ -106,19 +985,412 @@ impl<'a> Restic<'a> {
             cmd.arg("--ignore-inode");
         }
 
-        info!(self.logger(), "Beginning backup"; "command" => ?cmd);
+        cmd.arg("--json");
+        self.run_backup_command(&mut cmd, None)
+    }
+
+    /// Backs up the output of a command
+    fn run_stdin_backup(
+        &self,
+        source: &StdinSource,
+    ) -> Result<(ExitStatus, Option<BackupSummary>)> {
+        let mut cmd = self.backup_command(source.tags.iter());
+        cmd.arg("--stdin");
+        if let Some(ref filename) = source.filename {
+            cmd.arg("--stdin-filename").arg(filename);
+        }
+        cmd.arg("--json");
+
+        self.run_backup_command(&mut cmd, Some(&source.command))
+    }
+
+    /// Starts a `restic backup` command with the given tags and the profile's host
+    fn backup_command<'t, I: Iterator<Item = &'t String>>(&self, tags: I) -> Command {
+        let mut cmd = self.new_command();
+        cmd.arg("backup");
+
+        let mut tags: Vec<&String> = tags.collect();
+        tags.sort();
+        tags.dedup();
+        for tag in tags {
+            cmd.arg("--tag").arg(tag);
+        }
+
+        if let Some(ref host) = self.profile().host {
+            cmd.arg("--host").arg(host);
+        }
+        cmd
+    }
+
+    /// Runs a `restic backup` command, returning its exit status and the backup summary it reported
+    fn run_backup_command(
+        &self,
+        cmd: &mut Command,
+        stdin: Option<&StdinCommand>,
+    ) -> Result<(ExitStatus, Option<BackupSummary>)> {
+        info!(self.logger(), "Beginning backup"; "command" => %redact::command_line(cmd));
         let start = Instant::now();
-        let status = cmd
-            .status()
-            .with_context(|| format!("Could not run {:?}", cmd))?;
+        let mut summary = None;
+        let mut on_stdout = |line: &str| {
+            if let Some(parsed) = self.parse_backup_message(line) {
+                summary = Some(parsed);
+            }
+        };
+        // Restic reports files it couldn't back up as JSON on stderr
+        let execution = self.execute_with_stderr(cmd, stdin, &mut on_stdout, log_backup_error);
+        let status = match execution {
+            Ok(execution) => execution.status,
+            // If the command whose output was being backed up failed, Restic still saved whatever it got
+            Err(err) => {
+                return Err(match summary {
+                    Some(summary) => {
+                        err.context(format!("Snapshot {} is incomplete", summary.snapshot_id))
+                    }
+                    None => err,
+                })
+            }
+        };
         let duration = Instant::now() - start;
 
         if status.success() {
-            info!(self.logger(), "Backup finished successfully in {:?}", duration; "command" => ?cmd);
-            Ok(())
+            info!(self.logger(), "Backup finished successfully in {:?}", duration; "command" => %redact::command_line(cmd));
+        } else if status.code() == Some(PARTIAL_BACKUP_EXIT_CODE) {
+            warn!(self.logger(), "Backup finished in {:?}, but some files could not be read", duration; "command" => %redact::command_line(cmd));
         } else {
-            error!(self.logger(), "Backup failed"; "status" => %status, "command" => ?cmd);
-            Err(anyhow!("Restic backup failed with {}", status))
+            error!(self.logger(), "Backup failed"; "status" => %status, "command" => %redact::command_line(cmd));
+        }
+        Ok((status, summary))
+    }
+
+    /// Handles a line of JSON output from `restic backup`, returning the backup summary if that's what the line contains.
+    fn parse_backup_message(&self, line: &str) -> Option<BackupSummary> {
+        let message = match json::parse(line) {
+            Ok(message) => message,
+            Err(_) => {
+                // Restic occasionally prints plain-text messages even in JSON mode
+                debug!(self.logger(), "Restic: {}", line);
+                return None;
+            }
+        };
+
+        match message.get("message_type").and_then(|t| t.as_str()) {
+            Some("status") => {
+                match message.try_into::<Progress>() {
+                    Ok(progress) => self.emit(&Event::Progress {
+                        operation: "backup",
+                        progress: &progress,
+                    }),
+                    Err(err) => {
+                        debug!(self.logger(), "Could not parse backup status: {}", err; "status" => line)
+                    }
+                }
+                None
+            }
+            Some("error") => {
+                log_backup_error(self.logger(), line);
+                None
+            }
+            Some("summary") => match message.try_into::<BackupSummary>() {
+                Ok(summary) => Some(summary),
+                Err(err) => {
+                    warn!(self.logger(), "Could not parse backup summary: {}", err; "summary" => line);
+                    None
+                }
+            },
+            _ => None,
         }
     }
 }
+
+/// Logs an error message from `restic backup --json`, like one for a file that couldn't be read, returning whether `line`
+/// was one
+fn log_backup_error(logger: &Logger, line: &str) -> bool {
+    let message = match json::parse(line) {
+        Ok(message) => message,
+        Err(_) => return false,
+    };
+    if message.get("message_type").and_then(|t| t.as_str()) != Some("error") {
+        return false;
+    }
+
+    let error = message
+        .get("error")
+        .and_then(|error| error.get("message"))
+        .and_then(|message| message.as_str())
+        .unwrap_or(line);
+    match message.get("item").and_then(|item| item.as_str()) {
+        Some(item) if !item.is_empty() => warn!(logger, "Could not back up {}: {}", item, error),
+        _ => warn!(logger, "Restic error: {}", error),
+    }
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::Path;
+    use std::time::Duration;
+
+    use chrono::Utc;
+    use slog::{o, Discard, Logger};
+
+    use super::{
+        anchor_exclude, anchor_include, fileset_patterns, glob_root, not_due_reason,
+        FilesetPatterns,
+    };
+    use crate::cadence::Cadence;
+    use crate::config::Fileset;
+    use crate::error;
+    use crate::runner::{MockResponse, MockRunner};
+    use crate::state::{RunRecord, StateStore};
+    use crate::test::{CapturedLog, TestFixture};
+
+    #[test]
+    fn test_anchor_patterns() {
+        let base = Path::new("/srv/data");
+        assert_eq!(anchor_include(base, "photos"), "/srv/data/photos");
+        assert_eq!(anchor_include(base, "/etc"), "/etc");
+        assert_eq!(anchor_exclude(base, "*.tmp"), "/srv/data/**/*.tmp");
+        assert_eq!(anchor_exclude(base, "/var/log"), "/var/log");
+        assert_eq!(
+            glob_root("/home/me/projects/*/src"),
+            Path::new("/home/me/projects")
+        );
+        assert_eq!(glob_root("Documents"), Path::new("Documents"));
+        assert_eq!(glob_root("**/*.jpg"), Path::new(""));
+    }
+
+    #[test]
+    fn test_fileset_cycle() {
+        let fileset = |inherits: &[&str], pattern: &str| Fileset {
+            inherits: inherits.iter().map(|name| name.to_string()).collect(),
+            patterns: vec![pattern.to_string()],
+            ..Fileset::default()
+        };
+        let mut named = HashMap::new();
+        named.insert("a".to_string(), fileset(&["b", "c"], "a"));
+        named.insert("b".to_string(), fileset(&["c"], "b"));
+        named.insert("c".to_string(), fileset(&[], "c"));
+
+        // Inheriting the same fileset along two paths is fine
+        let mut patterns = FilesetPatterns::default();
+        fileset_patterns(&fileset(&["a"], "root"), &named, &mut patterns).unwrap();
+        assert_eq!(patterns.globs, vec!["root", "a", "b", "c", "c"]);
+
+        named.insert("c".to_string(), fileset(&["a"], "c"));
+        let err = fileset_patterns(
+            &fileset(&["a"], "root"),
+            &named,
+            &mut FilesetPatterns::default(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Fileset inheritance cycle: a -> b -> c -> a"
+        );
+    }
+
+    #[test]
+    fn test_fileset_pattern_kinds() {
+        let mut named = HashMap::new();
+        named.insert(
+            "media".to_string(),
+            Fileset {
+                insensitive_patterns: vec!["*.mp4".to_string()],
+                ..Fileset::default()
+            },
+        );
+        let fileset = Fileset {
+            inherits: vec!["media".to_string()],
+            patterns: vec!["/srv/[weird]*name".to_string()],
+            verbatim: true,
+            ..Fileset::default()
+        };
+
+        let mut patterns = FilesetPatterns::default();
+        fileset_patterns(&fileset, &named, &mut patterns).unwrap();
+        assert_eq!(
+            patterns,
+            FilesetPatterns {
+                globs: vec![],
+                insensitive: vec!["*.mp4".to_string()],
+                verbatim: vec!["/srv/[weird]*name".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_not_due_reason() {
+        let mut fixture = TestFixture::new();
+        fixture.profile_mut().frequency = Some(Cadence::Every(Duration::from_secs(60 * 60)));
+        // Whether the profile is due is decided before its password is resolved
+        fixture.profile_mut().password = None;
+        fixture.profile_mut().password_command = Some("exit 1".to_string());
+        let logger = Logger::root(Discard, o!());
+        let config = &*fixture.config_mut();
+
+        assert_eq!(not_due_reason(config, "test", &logger).unwrap(), None);
+        let mut store = StateStore::open(config).unwrap();
+        store
+            .record(RunRecord::finished(
+                "test",
+                "backup",
+                Utc::now(),
+                Some(0),
+                None,
+            ))
+            .unwrap();
+        assert!(not_due_reason(config, "test", &logger).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_missing_source() {
+        let mut fixture = TestFixture::new();
+        fixture.profile_mut().include.patterns = vec!["photos".to_string()];
+        let photos = fixture.content_root().join("photos");
+        let (restic, _runner) = fixture.mock_restic();
+
+        let err = restic.backup().unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("photos does not exist"),
+            "{:#}",
+            err
+        );
+        assert_eq!(restic.backup_skip_reason().unwrap(), None);
+
+        drop(restic);
+        fixture.profile_mut().preconditions.skip_missing_sources = true;
+        assert!(fixture.restic().backup_skip_reason().unwrap().is_some());
+
+        fs::create_dir(&photos).unwrap();
+        assert_eq!(fixture.restic().missing_source().unwrap(), None);
+        fixture.profile_mut().preconditions.require_mountpoint = true;
+        let problem = fixture.restic().missing_source().unwrap().unwrap();
+        assert!(
+            problem.ends_with("photos is not a mount point"),
+            "{}",
+            problem
+        );
+    }
+
+    #[test]
+    fn test_sanity() {
+        let mut fixture = TestFixture::new();
+        fixture.profile_mut().include.patterns = vec![".".to_string()];
+        fixture.profile_mut().sanity.min_files = Some(10);
+        fixture.profile_mut().sanity.forget_rejected = true;
+        let (restic, runner) = fixture.mock_restic();
+
+        // Checking that the repository exists
+        runner.respond(MockResponse::success(""));
+        runner.respond(MockResponse::success(
+            r#"{"message_type":"summary","files_new":0,"files_changed":0,"files_unmodified":0,"dirs_new":1,"dirs_changed":0,"dirs_unmodified":0,"data_blobs":0,"tree_blobs":1,"data_added":300,"total_files_processed":0,"total_bytes_processed":0,"total_duration":0.1,"snapshot_id":"0123456789abcdef"}"#,
+        ));
+        let err = restic.backup().unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("fewer than the profile's `sanity.min_files`"),
+            "{:#}",
+            err
+        );
+        let invocations = runner.invocations();
+        let forget = invocations.last().unwrap();
+        assert!(forget.has_arg("forget"));
+        assert!(forget.has_arg("0123456789abcdef"));
+    }
+
+    #[test]
+    fn test_partial_backup() {
+        let mut fixture = TestFixture::new();
+        fixture.profile_mut().include.patterns = vec![".".to_string()];
+        let summary = r#"{"message_type":"summary","files_new":2,"files_changed":0,"files_unmodified":0,"dirs_new":1,"dirs_changed":0,"dirs_unmodified":0,"data_blobs":2,"tree_blobs":1,"data_added":1024,"total_files_processed":2,"total_bytes_processed":2048,"total_duration":1.5,"snapshot_id":"0123456789abcdef"}"#;
+        let error = r#"{"message_type":"error","error":{"message":"open /home/ben/secret: permission denied"},"during":"archival","item":"/home/ben/secret"}"#;
+        let partial = || MockResponse {
+            stdout: summary.to_string(),
+            ..MockResponse::failure(3, error)
+        };
+
+        // By default, a partial backup still succeeds, since it created a snapshot. The files Restic couldn't read are
+        // reported on stderr.
+        let captured = CapturedLog::default();
+        let runner = MockRunner::new();
+        let mut restic = fixture.restic_with_logger(&captured.logger());
+        restic.set_runner(Box::new(runner.clone()));
+        runner.respond(MockResponse::success(""));
+        runner.respond(partial());
+        let summary = restic.backup().unwrap();
+        assert_eq!(summary.snapshot_id, "0123456789abcdef");
+        let logs = captured.contents();
+        assert!(
+            logs.contains(
+                "Could not back up /home/ben/secret: open /home/ben/secret: permission denied"
+            ),
+            "{}",
+            logs
+        );
+        assert!(!logs.contains("message_type"), "{}", logs);
+        drop(restic);
+
+        fixture.profile_mut().fail_on_partial = true;
+        let (restic, runner) = fixture.mock_restic();
+        runner.respond(MockResponse::success(""));
+        runner.respond(partial());
+        let err = restic.backup().unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("snapshot 0123456789abcdef is incomplete"),
+            "{:#}",
+            err
+        );
+
+        // The run history still has the snapshot that the failed backup created
+        let store = StateStore::open(restic.config()).unwrap();
+        let runs: Vec<&RunRecord> = store.runs("test", "backup").collect();
+        assert_eq!(runs.len(), 2);
+        assert!(runs[0].success);
+        assert!(!runs[1].success);
+        assert_eq!(runs[1].exit_code, Some(3));
+        assert_eq!(runs[1].snapshot_id.as_deref(), Some("0123456789abcdef"));
+    }
+
+    #[test]
+    fn test_wait_for_repository() {
+        let mut fixture = TestFixture::new();
+        fixture.profile_mut().preconditions.wait_for_repository = Some(Duration::from_secs(0));
+        let (restic, runner) = fixture.mock_restic();
+
+        runner.respond(MockResponse::failure(
+            1,
+            "Fatal: unable to open config file: dial tcp 192.168.1.5:8000: connect: no route to host",
+        ));
+        let reason = restic.wait_for_repository().unwrap().unwrap();
+        assert!(reason.ends_with(": no route to host"), "{}", reason);
+        assert_eq!(restic.wait_for_repository().unwrap(), None);
+        assert_eq!(runner.invocations().len(), 2);
+    }
+
+    #[test]
+    fn test_require_repository() {
+        let mut fixture = TestFixture::new();
+        fixture.profile_mut().preconditions.wait_for_repository = Some(Duration::from_secs(0));
+        let (restic, runner) = fixture.mock_restic();
+
+        runner.respond(MockResponse::failure(
+            1,
+            "Fatal: unable to open config file: dial tcp 192.168.1.5:8000: connect: no route to host",
+        ));
+        let err = restic.require_repository().unwrap_err();
+        assert_eq!(error::exit_code(&err), 8);
+
+        // The skipped backup shows up in the run history as a failure
+        let store = StateStore::open(restic.config()).unwrap();
+        let run = store.runs_for_profile("test").last().unwrap();
+        assert_eq!(run.operation, "backup");
+        assert!(run
+            .error
+            .as_deref()
+            .unwrap()
+            .ends_with(": no route to host"));
+
+        restic.require_repository().unwrap();
+    }
+}
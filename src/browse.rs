@@ -0,0 +1,193 @@
+//! Interactive snapshot browser, for finding and restoring files without typing out snapshot IDs and paths by hand
+
+use std::collections::BTreeSet;
+use std::io::{self, prelude::*};
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use chrono::Local;
+
+use crate::format::format_bytes;
+use crate::ls::Node;
+use crate::restic::Restic;
+use crate::snapshots::{Snapshot, SnapshotFilter};
+
+const HELP: &str = "Commands: <n> open directory or mark file, m <n> mark or unmark, .. go up, r restore marked files, b back to snapshots, q quit";
+
+/// What to do after leaving a snapshot
+enum Next {
+    Back,
+    Quit,
+}
+
+/// Extends the Restic wrapper with the snapshot browser
+impl<'a> Restic<'a> {
+    /// Lets the user pick a snapshot, walk its directories, mark files, and restore them, by answering prompts on the terminal.
+    pub fn browse(&self) -> Result<()> {
+        let snapshots = self.list_snapshots(&SnapshotFilter::default(), &[])?;
+        if snapshots.is_empty() {
+            println!("No snapshots in repository");
+            return Ok(());
+        }
+
+        loop {
+            println!();
+            for (i, snapshot) in snapshots.iter().enumerate() {
+                println!(
+                    "{:>3}) {}  {}  {}  {}",
+                    i + 1,
+                    snapshot.short_id(),
+                    snapshot
+                        .time
+                        .with_timezone(&Local)
+                        .format("%Y-%m-%d %H:%M:%S"),
+                    snapshot.hostname,
+                    snapshot.paths.join(", ")
+                );
+            }
+
+            let answer = match prompt("Snapshot to browse (number, or q to quit)")? {
+                Some(answer) => answer,
+                None => return Ok(()),
+            };
+            if answer == "q" {
+                return Ok(());
+            }
+            match answer
+                .parse::<usize>()
+                .ok()
+                .and_then(|n| snapshots.get(n.wrapping_sub(1)))
+            {
+                Some(snapshot) => {
+                    if let Next::Quit = self.browse_snapshot(snapshot)? {
+                        return Ok(());
+                    }
+                }
+                None => println!("No snapshot `{}`", answer),
+            }
+        }
+    }
+
+    fn browse_snapshot(&self, snapshot: &Snapshot) -> Result<Next> {
+        let mut directory = "/".to_string();
+        let mut marked = BTreeSet::new();
+        let mut nodes = self.list_directory(&snapshot.id, &directory)?;
+
+        loop {
+            println!("\n{}:{}", snapshot.short_id(), directory);
+            for (i, node) in nodes.iter().enumerate() {
+                println!(
+                    "{:>4} {} {}{}  {}  {}",
+                    i + 1,
+                    if marked.contains(&node.path) {
+                        "*"
+                    } else {
+                        " "
+                    },
+                    node.name,
+                    if node.is_dir() { "/" } else { "" },
+                    node.size.map(format_bytes).unwrap_or_default(),
+                    node.mtime
+                        .map(|mtime| mtime
+                            .with_timezone(&Local)
+                            .format("%Y-%m-%d %H:%M")
+                            .to_string())
+                        .unwrap_or_default()
+                );
+            }
+            println!("{} marked. {}", marked.len(), HELP);
+
+            let answer = match prompt(">")? {
+                Some(answer) => answer,
+                None => return Ok(Next::Quit),
+            };
+            let mut words = answer.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("q"), None) => return Ok(Next::Quit),
+                (Some("b"), None) => return Ok(Next::Back),
+                (Some(".."), None) => {
+                    directory = parent(&directory);
+                    nodes = self.list_directory(&snapshot.id, &directory)?;
+                }
+                (Some("r"), None) => self.restore_marked(snapshot, &marked)?,
+                (Some("m"), Some(n)) => match select(&nodes, n) {
+                    Some(node) => toggle(&mut marked, node),
+                    None => println!("No entry `{}`", n),
+                },
+                (Some(n), None) => match select(&nodes, n) {
+                    Some(node) if node.is_dir() => {
+                        directory = node.path.clone();
+                        nodes = self.list_directory(&snapshot.id, &directory)?;
+                    }
+                    Some(node) => toggle(&mut marked, node),
+                    None => println!("Unknown command `{}`", answer),
+                },
+                _ => println!("Unknown command `{}`", answer),
+            }
+        }
+    }
+
+    fn restore_marked(&self, snapshot: &Snapshot, marked: &BTreeSet<String>) -> Result<()> {
+        if marked.is_empty() {
+            println!("Nothing is marked. Mark files or directories with `m <n>` first.");
+            return Ok(());
+        }
+
+        let default_target = format!("restore-{}", snapshot.short_id());
+        let target = match prompt(&format!(
+            "Restore {} paths to [{}]",
+            marked.len(),
+            default_target
+        ))? {
+            Some(target) if !target.is_empty() => PathBuf::from(target),
+            Some(_) => PathBuf::from(default_target),
+            None => return Ok(()),
+        };
+        let target = std::env::current_dir()
+            .context("Could not determine current directory")?
+            .join(target);
+
+        let include: Vec<String> = marked.iter().cloned().collect();
+        match self.restore(&snapshot.id, &target, &include) {
+            Ok(()) => println!("Restored {} paths to {}", include.len(), target.display()),
+            // Keep browsing, so the user can pick a different target
+            Err(err) => println!("Restore failed: {:#}", err),
+        }
+        Ok(())
+    }
+}
+
+fn select<'n>(nodes: &'n [Node], n: &str) -> Option<&'n Node> {
+    n.parse::<usize>()
+        .ok()
+        .and_then(|n| nodes.get(n.wrapping_sub(1)))
+}
+
+fn toggle(marked: &mut BTreeSet<String>, node: &Node) {
+    if !marked.remove(&node.path) {
+        marked.insert(node.path.clone());
+    }
+}
+
+fn parent(directory: &str) -> String {
+    match directory.trim_end_matches('/').rfind('/') {
+        Some(0) | None => "/".to_string(),
+        Some(i) => directory[..i].to_string(),
+    }
+}
+
+/// Prints a prompt and reads a line from stdin. Returns `None` at end of input.
+fn prompt(message: &str) -> Result<Option<String>> {
+    print!("{} ", message);
+    io::stdout().flush()?;
+
+    let mut line = String::new();
+    let read = io::stdin()
+        .read_line(&mut line)
+        .context("Could not read from terminal")?;
+    if read == 0 {
+        println!();
+        return Ok(None);
+    }
+    Ok(Some(line.trim().to_string()))
+}
@@ -0,0 +1,60 @@
+//! Management of Restic's local metadata cache
+
+use std::process::Command;
+
+use anyhow::{anyhow, bail, Result};
+use slog::{info, Logger};
+
+use crate::config::Configuration;
+use crate::install;
+use crate::redact;
+
+/// Removes cache directories for repositories that haven't been used in `max_age_days` days (Restic's default is 30). The
+/// cache is shared by all profiles, so this doesn't need a profile's repository or credentials. In a dry run, the Restic
+/// command is printed instead of run.
+pub fn cleanup(
+    logger: &Logger,
+    config: &Configuration,
+    max_age_days: Option<u32>,
+    dry_run: bool,
+) -> Result<()> {
+    let mut cmd = Command::new(install::resolve_restic_command(&config.restic_command)?);
+    cmd.arg("cache").arg("--cleanup");
+    if let Some(days) = max_age_days {
+        cmd.arg("--max-age").arg(days.to_string());
+    }
+    if let Some(ref cache_directory) = config.cache_directory {
+        cmd.arg("--cache-dir").arg(cache_directory);
+    }
+
+    if dry_run {
+        println!("Would run: {}", redact::command_line(&cmd));
+        return Ok(());
+    }
+    info!(logger, "Cleaning up cache"; "command" => %redact::command_line(&cmd));
+    let status = cmd
+        .status()
+        .map_err(|err| anyhow!("Could not run {}: {}", redact::command_line(&cmd), err))?;
+    if !status.success() {
+        bail!("Restic cache cleanup failed with {}", status);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use slog::{o, Discard, Logger};
+
+    use super::cleanup;
+    use crate::test::TestFixture;
+
+    #[test]
+    fn test_cleanup_dry_run() {
+        let mut fixture = TestFixture::new();
+        // Restic can't run in tests, so this only succeeds if nothing is run
+        fixture.config_mut().restic_command = "/nonexistent/restic".to_string();
+        let logger = Logger::root(Discard, o!());
+        cleanup(&logger, fixture.config_mut(), Some(7), true).unwrap();
+        assert!(cleanup(&logger, fixture.config_mut(), Some(7), false).is_err());
+    }
+}
@@ -0,0 +1,119 @@
+//! How often recurring work like pruning or checking should run, like `weekly` or `3d`
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Datelike, Local};
+use serde::Deserialize;
+
+use crate::duration::parse_duration;
+use crate::format::format_duration;
+
+/// How often something should run. The named cadences follow the calendar, so a daily operation is due again on the next
+/// day no matter what time it last ran, and a scheduled run a few seconds earlier than yesterday's doesn't skip a day.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Cadence {
+    /// Once per calendar day
+    Daily,
+
+    /// Once per ISO week, which starts on Monday
+    Weekly,
+
+    /// Once per calendar month
+    Monthly,
+
+    /// Once this much time has passed since the last run
+    Every(Duration),
+}
+
+impl Cadence {
+    /// Whether something that last ran at `last` (or never, if it's `None`) is due again at `now`
+    pub fn is_due(&self, last: Option<DateTime<Local>>, now: DateTime<Local>) -> bool {
+        let last = match last {
+            Some(last) => last,
+            None => return true,
+        };
+        match self {
+            Cadence::Daily => last.date() < now.date(),
+            Cadence::Weekly => last.iso_week() != now.iso_week() && last < now,
+            Cadence::Monthly => (last.year(), last.month()) < (now.year(), now.month()),
+            Cadence::Every(interval) => match (now - last).to_std() {
+                Ok(elapsed) => elapsed >= *interval,
+                Err(_) => false,
+            },
+        }
+    }
+}
+
+impl FromStr for Cadence {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Cadence> {
+        match s.trim() {
+            "daily" => Ok(Cadence::Daily),
+            "weekly" => Ok(Cadence::Weekly),
+            "monthly" => Ok(Cadence::Monthly),
+            other => parse_duration(other).map(Cadence::Every).map_err(|_| {
+                anyhow!(
+                    "Invalid cadence `{}`, expected `daily`, `weekly`, `monthly`, or a duration like `12h`",
+                    s
+                )
+            }),
+        }
+    }
+}
+
+impl TryFrom<String> for Cadence {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Cadence> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Cadence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Cadence::Daily => write!(f, "daily"),
+            Cadence::Weekly => write!(f, "weekly"),
+            Cadence::Monthly => write!(f, "monthly"),
+            Cadence::Every(interval) => write!(f, "every {}", format_duration(*interval)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use chrono::{Local, TimeZone};
+
+    use super::Cadence;
+
+    #[test]
+    fn test_is_due() {
+        let at = |day, hour, minute| Local.ymd(2020, 8, day).and_hms(hour, minute, 0);
+        // Saturday the 1st, just after 3am
+        let last = at(1, 3, 1);
+
+        assert!(!Cadence::Daily.is_due(Some(last), at(1, 23, 0)));
+        assert!(Cadence::Daily.is_due(Some(last), at(2, 3, 0)));
+        assert!(!Cadence::Weekly.is_due(Some(last), at(2, 3, 0)));
+        assert!(Cadence::Weekly.is_due(Some(last), at(3, 0, 0)));
+        assert!(!Cadence::Monthly.is_due(Some(last), at(31, 0, 0)));
+        assert!(Cadence::Monthly.is_due(Some(last), Local.ymd(2020, 9, 1).and_hms(0, 0, 0)));
+
+        let cadence: Cadence = "12h".parse().unwrap();
+        assert_eq!(cadence, Cadence::Every(Duration::from_secs(12 * 60 * 60)));
+        assert!(!cadence.is_due(Some(last), at(1, 15, 0)));
+        assert!(cadence.is_due(Some(last), at(1, 15, 1)));
+        assert!(cadence.is_due(None, last));
+
+        assert_eq!("weekly".parse::<Cadence>().unwrap(), Cadence::Weekly);
+        assert!("fortnightly".parse::<Cadence>().is_err());
+    }
+}
@@ -0,0 +1,124 @@
+//! Repository integrity checks
+
+use std::time::Instant;
+
+use anyhow::{bail, Result};
+use slog::{error, info};
+
+use crate::redact;
+use crate::restic::Restic;
+use crate::state::StateStore;
+
+/// Extends the Restic wrapper with integrity checking
+impl<'a> Restic<'a> {
+    /// Checks the repository for errors, reading back data according to the profile's check options.
+    pub fn check(&self) -> Result<()> {
+        let options = &self.profile().check;
+
+        let mut cmd = self.new_command();
+        cmd.arg("check");
+
+        let subset = match options.read_data_subset {
+            Some(ref subset) if options.rotate_subset => Some(self.rotated_subset(subset)?),
+            ref subset => subset.clone(),
+        };
+        if let Some(ref subset) = subset {
+            if options.read_data {
+                bail!("Cannot set both `check.read_data` and `check.read_data_subset`");
+            }
+            cmd.arg(format!("--read-data-subset={}", subset));
+        } else if options.read_data {
+            cmd.arg("--read-data");
+        }
+
+        info!(self.logger(), "Checking repository"; "command" => %redact::command_line(&cmd));
+        let start = Instant::now();
+        let status = self.run_reported("check", &mut cmd)?;
+        let duration = Instant::now() - start;
+
+        if status.success() {
+            info!(self.logger(), "Checked repository in {:?}", duration; "command" => %redact::command_line(&cmd));
+            // Dry runs don't record anything, like they don't record run history
+            if let (true, false, Some(subset)) = (options.rotate_subset, self.is_dry_run(), subset)
+            {
+                self.record_check_subset(&subset);
+            }
+            Ok(())
+        } else {
+            error!(self.logger(), "Repository check failed"; "status" => %status, "command" => %redact::command_line(&cmd));
+            Err(self.failed("check", status).into())
+        }
+    }
+
+    /// Picks the subset of pack data to read when rotating through `subset`, a fraction like `1/10`: the one after the last
+    /// subset that was checked successfully, or `subset` itself if there isn't one (or it was for a different fraction)
+    fn rotated_subset(&self, subset: &str) -> Result<String> {
+        let (first, total) = match parse_fraction(subset) {
+            Some(fraction) => fraction,
+            None => bail!(
+                "`check.rotate_subset` requires `check.read_data_subset` to be a fraction like `1/10`, not `{}`",
+                subset
+            ),
+        };
+        let store = StateStore::open(self.config())?;
+        let next = match store
+            .last_check_subset(self.profile_name())
+            .and_then(parse_fraction)
+        {
+            Some((last, last_total)) if last_total == total => last % total + 1,
+            _ => first,
+        };
+        Ok(format!("{}/{}", next, total))
+    }
+}
+
+/// Parses a `read_data_subset` fraction like `3/10` into its numerator and denominator
+pub fn parse_fraction(subset: &str) -> Option<(u32, u32)> {
+    let mut parts = subset.splitn(2, '/');
+    let n: u32 = parts.next()?.trim().parse().ok()?;
+    let total: u32 = parts.next()?.trim().parse().ok()?;
+    if n >= 1 && n <= total {
+        Some((n, total))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_fraction;
+    use crate::runner::MockResponse;
+    use crate::test::TestFixture;
+
+    #[test]
+    fn test_rotate_subset() {
+        let mut fixture = TestFixture::new();
+        fixture.profile_mut().check.read_data_subset = Some("2/3".to_string());
+        fixture.profile_mut().check.rotate_subset = true;
+        let (restic, runner) = fixture.mock_restic();
+
+        restic.check().unwrap();
+        restic.check().unwrap();
+        // A failed check is tried again next time
+        runner.respond(MockResponse::failure(1, "Fatal: pack data is damaged"));
+        assert!(restic.check().is_err());
+        restic.check().unwrap();
+        restic.check().unwrap();
+
+        let subsets: Vec<String> = runner
+            .invocations()
+            .iter()
+            .filter_map(|invocation| {
+                invocation
+                    .args
+                    .iter()
+                    .find_map(|arg| arg.strip_prefix("--read-data-subset="))
+                    .map(String::from)
+            })
+            .collect();
+        assert_eq!(subsets, ["2/3", "3/3", "1/3", "1/3", "2/3"]);
+
+        assert_eq!(parse_fraction("5%"), None);
+        assert_eq!(parse_fraction("11/10"), None);
+    }
+}
@@ -0,0 +1,248 @@
+//! Shell completion scripts. The scripts clap generates only know Rustic's commands and flags, so each one is extended to
+//! complete profile names too, by running `rustic complete-profiles` against the configuration file being used.
+
+use anyhow::{bail, Result};
+use structopt::clap::{App, AppSettings, Shell};
+
+use crate::config::Configuration;
+
+/// Name the completion scripts are registered for
+const BIN_NAME: &str = "rustic";
+
+/// Shells that completion scripts can be generated for
+pub const SHELLS: &[&str] = &["bash", "zsh", "fish", "powershell"];
+
+/// Generates the completion script for `shell`
+pub fn generate(app: &mut App, shell: Shell) -> Result<String> {
+    // clap would otherwise complete hidden commands, like `complete-profiles` itself
+    app.p
+        .subcommands
+        .retain(|subcommand| !subcommand.p.is_set(AppSettings::Hidden));
+    let mut commands = Vec::new();
+    profile_commands(app, &mut Vec::new(), &mut commands);
+
+    let mut script = Vec::new();
+    app.gen_completions_to(BIN_NAME, shell, &mut script);
+    let script = String::from_utf8(script)?;
+    Ok(match shell {
+        Shell::Bash => bash(&script),
+        Shell::Zsh => zsh(&script),
+        Shell::Fish => fish(&script, &commands),
+        Shell::PowerShell => powershell(&script, &commands),
+        Shell::Elvish => bail!("Completions for elvish are not supported"),
+    })
+}
+
+/// Profile names and `@<group>` references, for completing profile arguments
+pub fn profile_names(config: &Configuration) -> Vec<String> {
+    let mut profiles: Vec<String> = config.profiles.keys().cloned().collect();
+    profiles.sort();
+    let mut groups: Vec<String> = config
+        .groups
+        .keys()
+        .map(|group| format!("@{}", group))
+        .collect();
+    groups.sort();
+    profiles.extend(groups);
+    profiles
+}
+
+/// Collects the paths of every subcommand with a `profile` or `profiles` argument, like `["key", "list"]`
+fn profile_commands(app: &App, path: &mut Vec<String>, commands: &mut Vec<Vec<String>>) {
+    for subcommand in app.p.subcommands.iter() {
+        path.push(subcommand.p.meta.name.clone());
+        if subcommand
+            .p
+            .positionals
+            .values()
+            .any(|arg| arg.b.name == "profile" || arg.b.name == "profiles")
+        {
+            commands.push(path.clone());
+        }
+        profile_commands(subcommand, path, commands);
+        path.pop();
+    }
+}
+
+/// Whether a line of a zsh script is the `_arguments` spec for a profile argument, like `':profile -- Profile to check:_files'`
+fn is_profile_spec(line: &str) -> bool {
+    let spec = line.trim_start();
+    let name = spec
+        .strip_prefix('\'')
+        .map(|spec| spec.trim_start_matches(&['*', ':'][..]));
+    matches!(name, Some(name) if name.starts_with("profile -- ") || name.starts_with("profiles -- "))
+}
+
+fn bash(script: &str) -> String {
+    let mut completions = String::from(
+        r#"_rustic_profiles() {
+    local i config=()
+    for (( i = 1; i < COMP_CWORD - 1; i++ )); do
+        case "${COMP_WORDS[i]}" in
+            -c|--config) config=(--config "${COMP_WORDS[i+1]}") ;;
+        esac
+    done
+    rustic "${config[@]}" complete-profiles 2>/dev/null
+}
+
+"#,
+    );
+    for line in script.lines() {
+        if line.trim_start().starts_with("opts=") {
+            completions.push_str(
+                &line
+                    .replace("<profiles>...", "$(_rustic_profiles)")
+                    .replace("<profile>", "$(_rustic_profiles)"),
+            );
+        } else {
+            completions.push_str(line);
+        }
+        completions.push('\n');
+    }
+    completions
+}
+
+fn zsh(script: &str) -> String {
+    let mut completions = String::new();
+    for line in script.lines() {
+        if line == "_rustic \"$@\"" {
+            // Subcommands only see their own words, so the global `--config` flag is found in the whole command line
+            completions.push_str(
+                r#"_rustic_profiles() {
+    local -a args config profiles
+    args=(${(z)BUFFER})
+    local i=${args[(I)(-c|--config)]}
+    (( i )) && config=(--config ${(Q)args[i+1]})
+    profiles=(${(f)"$(rustic $config complete-profiles 2>/dev/null)"})
+    compadd -a profiles
+}
+
+"#,
+            );
+        }
+        if is_profile_spec(line) {
+            completions.push_str(&line.replace(":_files'", ":_rustic_profiles'"));
+        } else {
+            completions.push_str(line);
+        }
+        completions.push('\n');
+    }
+    completions
+}
+
+fn fish(script: &str, commands: &[Vec<String>]) -> String {
+    let mut completions = String::from(
+        r#"function __rustic_profiles
+    set -l tokens (commandline -opc)
+    set -l config
+    for i in (seq (math (count $tokens) - 1))
+        if contains -- $tokens[$i] -c --config
+            set config --config $tokens[(math $i + 1)]
+        end
+    end
+    rustic $config complete-profiles 2>/dev/null
+end
+
+"#,
+    );
+    completions.push_str(script);
+    for command in commands {
+        completions.push_str(&format!(
+            "complete -c {} -n \"__fish_seen_subcommand_from {}\" -f -a \"(__rustic_profiles)\"\n",
+            BIN_NAME,
+            command.last().unwrap()
+        ));
+    }
+    completions
+}
+
+fn powershell(script: &str, commands: &[Vec<String>]) -> String {
+    let cases: Vec<String> = commands
+        .iter()
+        .map(|command| format!("'{};{}' {{", BIN_NAME, command.join(";")))
+        .collect();
+    let mut completions = String::new();
+    let mut in_profile_case = false;
+    for line in script.lines() {
+        let trimmed = line.trim();
+        if cases.iter().any(|case| case == trimmed) {
+            in_profile_case = true;
+        } else if in_profile_case && trimmed == "break" {
+            completions.push_str(
+                "            rustic @config complete-profiles 2>$null | ForEach-Object {\n                \
+                 [CompletionResult]::new($_, $_, [CompletionResultType]::ParameterValue, $_)\n            }\n",
+            );
+            in_profile_case = false;
+        }
+        completions.push_str(line);
+        completions.push('\n');
+        if trimmed == "}) -join ';'" {
+            completions.push_str(
+                r#"
+    $config = @()
+    for ($i = 1; $i -lt $commandElements.Count - 1; $i++) {
+        if ('-c', '--config' -contains $commandElements[$i].ToString()) {
+            $config = @('--config', $commandElements[$i + 1].ToString())
+        }
+    }
+"#,
+            );
+        }
+    }
+    completions
+}
+
+#[cfg(test)]
+mod test {
+    use structopt::clap::{App, AppSettings, Arg, Shell, SubCommand};
+
+    use super::generate;
+
+    #[test]
+    fn test_generate() {
+        let app = || {
+            App::new("rustic")
+                .arg(
+                    Arg::with_name("config")
+                        .short("c")
+                        .long("config")
+                        .takes_value(true),
+                )
+                .subcommand(
+                    SubCommand::with_name("backup")
+                        .arg(Arg::with_name("profiles").help("Profiles").multiple(true)),
+                )
+                .subcommand(SubCommand::with_name("profiles"))
+                .subcommand(SubCommand::with_name("complete-profiles").setting(AppSettings::Hidden))
+                .subcommand(SubCommand::with_name("key").subcommand(
+                    SubCommand::with_name("list").arg(Arg::with_name("profile").help("Profile")),
+                ))
+        };
+
+        let bash = generate(&mut app(), Shell::Bash).unwrap();
+        assert!(bash.contains("_rustic_profiles() {"));
+        assert!(bash.contains("--help --version  $(_rustic_profiles) \""));
+        assert!(!bash.contains("<profile"));
+        assert!(!bash.contains("complete-profiles)"));
+
+        let zsh = generate(&mut app(), Shell::Zsh).unwrap();
+        assert!(zsh.contains("'::profiles -- Profiles:_rustic_profiles'"));
+        assert!(zsh.contains("'::profile -- Profile:_rustic_profiles'"));
+        assert!(zsh.trim_end().ends_with("_rustic \"$@\""));
+
+        let fish = generate(&mut app(), Shell::Fish).unwrap();
+        assert!(fish.contains(
+            "complete -c rustic -n \"__fish_seen_subcommand_from backup\" -f -a \"(__rustic_profiles)\""
+        ));
+        assert!(fish.contains("__fish_seen_subcommand_from list\" -f -a \"(__rustic_profiles)\""));
+        assert!(
+            !fish.contains("__fish_seen_subcommand_from profiles\" -f -a \"(__rustic_profiles)\"")
+        );
+
+        let powershell = generate(&mut app(), Shell::PowerShell).unwrap();
+        assert_eq!(powershell.matches("complete-profiles").count(), 2);
+        assert!(powershell.contains("$config = @()"));
+
+        assert!(generate(&mut app(), Shell::Elvish).is_err());
+    }
+}
@@ -0,0 +1,270 @@
+//! Checking whether the machine is in a state to back up, for profiles that shouldn't run on battery power or over a
+//! metered connection, or that back up a disk that might not be mounted. Each check returns `None` when the state can't
+//! be determined, so callers can decide whether to go ahead anyway.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::platform;
+
+/// Whether the machine is running on AC power rather than a battery
+pub fn on_ac_power() -> Option<bool> {
+    if cfg!(target_os = "linux") {
+        power_supply_ac_power(Path::new("/sys/class/power_supply"))
+    } else if cfg!(target_os = "macos") {
+        // pmset reads the power source from IOKit, like `Now drawing from 'AC Power'`
+        let output = command_output("pmset", &["-g", "batt"])?;
+        if output.contains("'AC Power'") {
+            Some(true)
+        } else if output.contains("'Battery Power'") {
+            Some(false)
+        } else {
+            None
+        }
+    } else if cfg!(windows) {
+        let output = powershell(
+            "Add-Type -AssemblyName System.Windows.Forms; \
+             [System.Windows.Forms.SystemInformation]::PowerStatus.PowerLineStatus",
+        )?;
+        match output.trim() {
+            "Online" => Some(true),
+            "Offline" => Some(false),
+            _ => None,
+        }
+    } else {
+        None
+    }
+}
+
+/// Whether the active network connection is metered, like a phone hotspot or a capped mobile plan
+pub fn on_metered_network() -> Option<bool> {
+    if cfg!(target_os = "linux") {
+        // NetworkManager's overall metered state, which follows the primary connection
+        let output = command_output(
+            "busctl",
+            &[
+                "--system",
+                "get-property",
+                "org.freedesktop.NetworkManager",
+                "/org/freedesktop/NetworkManager",
+                "org.freedesktop.NetworkManager",
+                "Metered",
+            ],
+        )?;
+        network_manager_metered(&output)
+    } else if cfg!(windows) {
+        let output = powershell(
+            "[Windows.Networking.Connectivity.NetworkInformation, Windows.Networking.Connectivity, ContentType = WindowsRuntime] | Out-Null; \
+             [Windows.Networking.Connectivity.NetworkInformation]::GetInternetConnectionProfile().GetConnectionCost().NetworkCostType",
+        )?;
+        match output.trim() {
+            "Unrestricted" => Some(false),
+            "Fixed" | "Variable" => Some(true),
+            _ => None,
+        }
+    } else {
+        // macOS only exposes Low Data Mode to apps through the Network framework
+        None
+    }
+}
+
+/// Whether `path` is a mount point, like the directory an external disk is mounted on
+pub fn is_mountpoint(path: &Path) -> Option<bool> {
+    let path = fs::canonicalize(path).ok()?;
+    if cfg!(target_os = "linux") {
+        let mountinfo = fs::read_to_string("/proc/self/mountinfo").ok()?;
+        let found = mountinfo_mountpoints(&mountinfo).any(|mountpoint| mountpoint == path);
+        Some(found)
+    } else {
+        device_boundary(&path)
+    }
+}
+
+/// Mount points listed in Linux's `/proc/self/mountinfo`. The mount point is the fifth field, with spaces and other special
+/// characters escaped as octal, like `\040`.
+fn mountinfo_mountpoints(mountinfo: &str) -> impl Iterator<Item = PathBuf> + '_ {
+    mountinfo
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .map(|field| {
+            let mut bytes = Vec::with_capacity(field.len());
+            let mut rest = field.as_bytes();
+            while let Some((&byte, tail)) = rest.split_first() {
+                let octal = tail
+                    .get(..3)
+                    .and_then(|digits| std::str::from_utf8(digits).ok())
+                    .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+                match octal {
+                    Some(escaped) if byte == b'\\' => {
+                        bytes.push(escaped);
+                        rest = &tail[3..];
+                    }
+                    _ => {
+                        bytes.push(byte);
+                        rest = tail;
+                    }
+                }
+            }
+            PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+        })
+}
+
+/// Whether `path` is on a different device than its parent directory, which means something is mounted on it. Bind mounts
+/// don't change the device, so they aren't detected.
+#[cfg(unix)]
+fn device_boundary(path: &Path) -> Option<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = fs::metadata(path).ok()?;
+    let parent = fs::metadata(path.join("..")).ok()?;
+    // The root directory is its own parent
+    Some(metadata.dev() != parent.dev() || metadata.ino() == parent.ino())
+}
+
+#[cfg(not(unix))]
+fn device_boundary(_path: &Path) -> Option<bool> {
+    None
+}
+
+/// Reads the power state from Linux's power supply class in sysfs. Any online mains or USB supply means AC power. A
+/// machine without any supplies, like most desktops, is assumed to be on AC power too.
+fn power_supply_ac_power(directory: &Path) -> Option<bool> {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(_) => return None,
+    };
+    let read = |path: &Path, name: &str| {
+        fs::read_to_string(path.join(name))
+            .map(|value| value.trim().to_string())
+            .unwrap_or_default()
+    };
+
+    let mut external_supplies = false;
+    let mut discharging = false;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        match read(&path, "type").as_str() {
+            "Mains" | "USB" => {
+                external_supplies = true;
+                if read(&path, "online") == "1" {
+                    return Some(true);
+                }
+            }
+            "Battery" if read(&path, "scope") != "Device" => {
+                discharging |= read(&path, "status") == "Discharging";
+            }
+            _ => (),
+        }
+    }
+    if external_supplies || discharging {
+        Some(false)
+    } else {
+        Some(true)
+    }
+}
+
+/// Parses NetworkManager's `Metered` property as printed by `busctl`, like `u 4`. The values are NetworkManager's
+/// `NMMetered` enum: unknown, yes, no, guessed yes, and guessed no.
+fn network_manager_metered(output: &str) -> Option<bool> {
+    match output.trim().strip_prefix("u ")? {
+        "1" | "3" => Some(true),
+        "2" | "4" => Some(false),
+        _ => None,
+    }
+}
+
+/// Runs a PowerShell command, returning what it printed
+fn powershell(script: &str) -> Option<String> {
+    command_output(
+        "powershell",
+        &["-NoProfile", "-NonInteractive", "-Command", script],
+    )
+}
+
+/// Runs `program`, returning what it printed if it succeeded
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    if !platform::program_exists(program) {
+        return None;
+    }
+    let output = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use tempfile::TempDir;
+
+    use super::{
+        is_mountpoint, mountinfo_mountpoints, network_manager_metered, power_supply_ac_power,
+    };
+
+    #[test]
+    fn test_power_supply_ac_power() {
+        let root = TempDir::new().unwrap();
+        let supply = |name: &str, values: &[(&str, &str)]| {
+            let path = root.path().join(name);
+            fs::create_dir_all(&path).unwrap();
+            for (file, value) in values {
+                fs::write(path.join(file), format!("{}\n", value)).unwrap();
+            }
+        };
+
+        // A desktop without any power supplies
+        assert_eq!(power_supply_ac_power(root.path()), Some(true));
+
+        supply("BAT0", &[("type", "Battery"), ("status", "Discharging")]);
+        // A wireless mouse's battery doesn't count
+        supply(
+            "hidpp_battery_0",
+            &[("type", "Battery"), ("scope", "Device"), ("status", "Full")],
+        );
+        assert_eq!(power_supply_ac_power(root.path()), Some(false));
+
+        supply("AC", &[("type", "Mains"), ("online", "0")]);
+        assert_eq!(power_supply_ac_power(root.path()), Some(false));
+        supply("AC", &[("online", "1")]);
+        assert_eq!(power_supply_ac_power(root.path()), Some(true));
+
+        assert_eq!(power_supply_ac_power(&root.path().join("missing")), None);
+    }
+
+    #[test]
+    fn test_network_manager_metered() {
+        assert_eq!(network_manager_metered("u 1\n"), Some(true));
+        assert_eq!(network_manager_metered("u 3\n"), Some(true));
+        assert_eq!(network_manager_metered("u 4\n"), Some(false));
+        assert_eq!(network_manager_metered("u 0\n"), None);
+        assert_eq!(network_manager_metered(""), None);
+    }
+
+    #[test]
+    fn test_mountpoints() {
+        let mountinfo = "22 1 8:2 / / rw,relatime shared:1 - ext4 /dev/sda2 rw\n\
+                         35 22 8:17 / /media/backup\\040disk rw,nosuid shared:20 - exfat /dev/sdb1 rw\n";
+        let mountpoints: Vec<_> = mountinfo_mountpoints(mountinfo).collect();
+        assert_eq!(
+            mountpoints,
+            vec![PathBuf::from("/"), PathBuf::from("/media/backup disk")]
+        );
+
+        if cfg!(unix) {
+            assert_eq!(is_mountpoint(Path::new("/")), Some(true));
+            let root = TempDir::new().unwrap();
+            fs::create_dir(root.path().join("photos")).unwrap();
+            assert_eq!(is_mountpoint(&root.path().join("photos")), Some(false));
+        }
+        assert_eq!(is_mountpoint(Path::new("/does/not/exist")), None);
+    }
+}
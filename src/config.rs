@@ -1,93 +1,1646 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
+//! Rustic configuration files, and the profiles, filesets, and other settings in them
 
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{Local, Weekday};
+use lazy_static::lazy_static;
+use regex::Regex;
 use serde::Deserialize;
+use slog::Level;
+use toml::value::{Table, Value};
+
+use crate::cadence::Cadence;
+use crate::duration;
+use crate::error::RusticError;
+use crate::platform;
+use crate::size;
+use crate::template;
+use crate::verify::SampleSize;
+use crate::version::ResticVersion;
+use crate::window::TimeWindow;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Configuration {
+    /// Backup profiles
+    pub profiles: HashMap<String, Profile>,
+
+    /// Named filesets to reuse in profiles
+    #[serde(default)]
+    pub filesets: HashMap<String, Fileset>,
+
+    /// Named lists of profiles, so that a subset of profiles can be run together by passing `@<group>` in place of a
+    /// profile name
+    #[serde(default)]
+    pub groups: HashMap<String, Vec<String>>,
+
+    /// Location of the `restic` binary. Defaults to `restic`
+    #[serde(default = "default_restic_command")]
+    pub restic_command: String,
+
+    /// Root cache directory for repository metadata. Defaults to a system-specific cache location.
+    /// Within this directory, restic creates a sub-directory for each repository.
+    #[serde(default)]
+    pub cache_directory: Option<PathBuf>,
+
+    /// Directory to write Prometheus metrics to, for node_exporter's textfile collector. If unset, no metrics are written.
+    #[serde(default)]
+    pub metrics_directory: Option<PathBuf>,
+
+    /// Where to send notifications when backups finish
+    #[serde(default)]
+    pub notifications: Notifications,
+
+    /// Log levels for Restic's output
+    #[serde(default)]
+    pub restic_output: ResticOutput,
+
+    /// Where to send logs
+    #[serde(default)]
+    pub logging: Logging,
+
+    /// File to record the history of backups and other operations in. Defaults to `rustic/state.json` in the user's local data
+    /// directory.
+    #[serde(default)]
+    pub state_file: Option<PathBuf>,
+
+    /// How many profiles `rustic backup` may back up at once. Profiles using the same repository are always backed up one
+    /// after another. Defaults to 1, which backs up every profile in turn.
+    #[serde(default = "default_max_parallel")]
+    pub max_parallel: usize,
+
+    /// File to write a JSON report of each finished operation to, for orchestration tools to collect. `{profile}` and
+    /// `{operation}` in the path are replaced with the profile name and the operation, such as `backup` or `forget`. If
+    /// unset, no reports are written. Overridden by `--report`.
+    #[serde(default)]
+    pub report_file: Option<String>,
+}
+
+impl Configuration {
+    /// Loads the configuration from `path`. If `path` is a directory, every `.toml` file in it is loaded as a fragment of
+    /// the configuration, in order by file name. Any file can also pull in more fragments with a top-level `include` key,
+    /// which is a list of paths or file name patterns (like `conf.d/*.toml`) relative to that file.
+    ///
+    /// Fragments are combined in the order they're loaded, with these rules:
+    /// * A profile, fileset, or group can only be defined in one fragment
+    /// * `[defaults]` tables are merged key by key, with later fragments taking precedence
+    /// * For any other top-level setting, the last fragment to set it wins
+    pub fn load(path: &Path) -> Result<Configuration> {
+        let mut loader = Loader::default();
+        if path.is_dir() {
+            for file in matching_files(path, "*.toml")? {
+                loader.load_file(&file)?;
+            }
+        } else {
+            loader.load_file(path)?;
+        }
+        Configuration::from_document(Value::Table(loader.document))
+    }
+
+    /// Expands profile names given on the command line, replacing each `@<group>` with the profiles in that group. A
+    /// profile named more than once, directly or through groups, is only included the first time.
+    pub fn expand_profiles(&self, names: &[String]) -> Result<Vec<String>> {
+        let mut profiles: Vec<String> = Vec::new();
+        for name in names {
+            let members = match name.strip_prefix('@') {
+                Some(group) => self.group(group)?,
+                None => std::slice::from_ref(name),
+            };
+            for member in members {
+                if !profiles.contains(member) {
+                    profiles.push(member.clone());
+                }
+            }
+        }
+        Ok(profiles)
+    }
+
+    /// Resolves a profile name given where only a single profile is accepted. A `@<group>` is accepted as long as it only
+    /// contains one profile.
+    pub fn resolve_profile_name(&self, name: &str) -> Result<String> {
+        let group = match name.strip_prefix('@') {
+            Some(group) => group,
+            None => return Ok(name.to_string()),
+        };
+        match self.group(group)? {
+            [profile] => Ok(profile.clone()),
+            members => bail!(
+                "Group `{}` has {} profiles, but only one profile can be used here",
+                group,
+                members.len()
+            ),
+        }
+    }
+
+    fn group(&self, name: &str) -> Result<&[String]> {
+        match self.groups.get(name) {
+            Some(members) => Ok(members),
+            None => bail!(RusticError::GroupNotFound(name.to_string())),
+        }
+    }
+
+    /// Parses a single configuration file. `include` keys are not supported.
+    #[cfg(test)]
+    pub fn parse(config_str: &str) -> Result<Configuration> {
+        Configuration::from_document(toml::from_str(config_str)?)
+    }
+
+    /// Deserializes a configuration. Before the profiles are deserialized, settings from the `[defaults]` table and from
+    /// profiles named by `inherits` are merged into each profile.
+    ///
+    /// Settings are applied in order: first `[defaults]`, then each inherited profile (starting from the one furthest up the
+    /// chain), and finally the profile's own settings. Tables (like `environment`, `retention`, or `hooks`) are merged key by
+    /// key, while any other value, including arrays, replaces the inherited one.
+    fn from_document(mut document: Value) -> Result<Configuration> {
+        resolve_profiles(&mut document)?;
+        match document.clone().try_into() {
+            Ok(config) => Ok(config),
+            Err(err) => Err(explain_error(&document, err.to_string())),
+        }
+    }
+}
+
+/// Locations to look for a configuration file in when none is given, in order of preference
+fn default_config_paths() -> Vec<PathBuf> {
+    let mut directories = Vec::new();
+    if let Some(xdg_config_home) = env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        directories.push(PathBuf::from(xdg_config_home));
+    }
+    if let Some(home) = dirs::home_dir() {
+        directories.push(home.join(".config"));
+    }
+    // On macOS and Windows, this is different from ~/.config
+    if let Some(config_dir) = dirs::config_dir() {
+        directories.push(config_dir);
+    }
+    #[cfg(unix)]
+    directories.push(PathBuf::from("/etc"));
+    #[cfg(windows)]
+    {
+        if let Some(program_data) = env::var_os("PROGRAMDATA") {
+            directories.push(PathBuf::from(program_data));
+        }
+    }
+
+    let mut paths: Vec<PathBuf> = Vec::new();
+    for directory in directories {
+        let path = directory.join("rustic").join("rustic.toml");
+        if !paths.contains(&path) {
+            paths.push(path);
+        }
+    }
+    paths
+}
+
+/// Finds the configuration file to use when none was given on the command line
+pub fn find_default_config() -> Result<PathBuf> {
+    let paths = default_config_paths();
+    match paths.iter().find(|path| path.is_file()) {
+        Some(path) => Ok(path.clone()),
+        None => bail!(
+            "No configuration file found. Pass `--config` or create one of: {}",
+            paths
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+/// Combines configuration fragments from multiple files
+#[derive(Default)]
+struct Loader {
+    document: Table,
+
+    /// Files loaded so far, so that each file is only loaded once
+    loaded: HashSet<PathBuf>,
+
+    /// The file each profile, fileset, and group was defined in, keyed by `profiles.<name>`, `filesets.<name>`, or
+    /// `groups.<name>`
+    origins: HashMap<String, PathBuf>,
+}
+
+impl Loader {
+    fn load_file(&mut self, path: &Path) -> Result<()> {
+        let canonical = path
+            .canonicalize()
+            .with_context(|| format!("Could not read configuration file {}", path.display()))?;
+        if !self.loaded.insert(canonical) {
+            return Ok(());
+        }
+
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Could not read configuration file {}", path.display()))?;
+        let mut fragment: Table = toml::from_str(&contents)
+            .with_context(|| format!("Could not parse configuration file {}", path.display()))?;
+
+        let includes: Vec<String> = match fragment.remove("include") {
+            Some(includes) => includes.try_into().with_context(|| {
+                format!("`include` in {} must be a list of paths", path.display())
+            })?,
+            None => Vec::new(),
+        };
+
+        self.merge_fragment(path, fragment)?;
+
+        let directory = path.parent().unwrap_or_else(|| Path::new("."));
+        for include in includes {
+            let pattern = directory.join(include);
+            let file_pattern = pattern
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or("");
+            if file_pattern.contains('*') || file_pattern.contains('?') {
+                let parent = pattern.parent().unwrap_or(directory);
+                for file in matching_files(parent, file_pattern)? {
+                    self.load_file(&file)?;
+                }
+            } else {
+                self.load_file(&pattern)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn merge_fragment(&mut self, path: &Path, fragment: Table) -> Result<()> {
+        for (key, value) in fragment {
+            match (key.as_str(), value) {
+                (section @ "profiles", Value::Table(entries))
+                | (section @ "filesets", Value::Table(entries))
+                | (section @ "groups", Value::Table(entries)) => {
+                    let combined = self
+                        .document
+                        .entry(section.to_string())
+                        .or_insert_with(|| Value::Table(Table::new()));
+                    let combined = match combined.as_table_mut() {
+                        Some(combined) => combined,
+                        None => bail!("`{}` in {} must be a table", section, path.display()),
+                    };
+                    for (name, entry) in entries {
+                        let origin_key = format!("{}.{}", section, name);
+                        if let Some(origin) = self.origins.get(&origin_key) {
+                            bail!(
+                                "`{}` is defined in both {} and {}",
+                                origin_key,
+                                origin.display(),
+                                path.display()
+                            );
+                        }
+                        self.origins.insert(origin_key, path.to_path_buf());
+                        combined.insert(name, entry);
+                    }
+                }
+                ("defaults", defaults) => match self.document.get_mut("defaults") {
+                    Some(existing) => merge(existing, defaults),
+                    None => {
+                        self.document.insert(key, defaults);
+                    }
+                },
+                (_, value) => {
+                    self.document.insert(key, value);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Lists the files in `directory` whose names match `pattern`, sorted by name. In the pattern, `*` matches any number of
+/// characters and `?` matches any single character.
+fn matching_files(directory: &Path, pattern: &str) -> Result<Vec<PathBuf>> {
+    let entries = fs::read_dir(directory).with_context(|| {
+        format!(
+            "Could not read configuration directory {}",
+            directory.display()
+        )
+    })?;
+
+    let mut files = Vec::new();
+    for entry in entries {
+        let entry = entry.with_context(|| {
+            format!(
+                "Could not read configuration directory {}",
+                directory.display()
+            )
+        })?;
+        let name = entry.file_name();
+        let matches = name
+            .to_str()
+            .is_some_and(|name| wildcard_match(pattern, name));
+        if matches && entry.path().is_file() {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+
+    Ok(files)
+}
+
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    match pattern.chars().next() {
+        None => name.is_empty(),
+        Some('*') => {
+            let rest = &pattern[1..];
+            name.char_indices()
+                .map(|(i, _)| i)
+                .chain(std::iter::once(name.len()))
+                .any(|i| wildcard_match(rest, &name[i..]))
+        }
+        Some(p) => match name.chars().next() {
+            Some(c) if p == '?' || p == c => {
+                wildcard_match(&pattern[p.len_utf8()..], &name[c.len_utf8()..])
+            }
+            _ => false,
+        },
+    }
+}
+
+lazy_static! {
+    static ref UNKNOWN_FIELD: Regex =
+        Regex::new(r"unknown field `([^`]*)`, (?:expected (.*)|there are no fields)").unwrap();
+    static ref FIELD_NAME: Regex = Regex::new(r"`([^`]*)`").unwrap();
+}
+
+/// Makes errors about unknown configuration keys more helpful, by saying where the key is and suggesting the closest valid key
+fn explain_error(document: &Value, message: String) -> anyhow::Error {
+    let captures = match UNKNOWN_FIELD.captures(&message) {
+        Some(captures) => captures,
+        None => return anyhow!(message),
+    };
+    let key = &captures[1];
+
+    let mut locations = Vec::new();
+    find_key(document, key, "", &mut locations);
+    let mut explanation = match locations.as_slice() {
+        [location] => format!("Unknown configuration key `{}`", location),
+        _ => format!("Unknown configuration key `{}`", key),
+    };
+
+    let expected = captures.get(2).map_or("", |m| m.as_str());
+    let suggestion = FIELD_NAME
+        .captures_iter(expected)
+        .map(|field| field[1].to_string())
+        .map(|field| (edit_distance(key, &field), field))
+        .min();
+    match suggestion {
+        Some((distance, field)) if distance <= 2.max(key.len() / 3) => {
+            write!(explanation, " (did you mean `{}`?)", field).unwrap()
+        }
+        _ if !expected.is_empty() => write!(explanation, ", expected {}", expected).unwrap(),
+        _ => {}
+    }
+
+    anyhow!(explanation)
+}
+
+/// Finds the dotted paths of every table entry named `key`
+fn find_key(value: &Value, key: &str, prefix: &str, locations: &mut Vec<String>) {
+    let children: Vec<(String, &Value)> = match value {
+        Value::Table(table) => table
+            .iter()
+            .map(|(k, v)| (format!("{}{}", prefix, k), v))
+            .collect(),
+        Value::Array(array) => array
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (format!("{}[{}]", prefix.trim_end_matches('.'), i), v))
+            .collect(),
+        _ => return,
+    };
+
+    for (path, child) in children {
+        if path.rsplit('.').next() == Some(key) {
+            locations.push(path.clone());
+        }
+        find_key(child, key, &format!("{}.", path), locations);
+    }
+}
+
+/// Levenshtein distance between two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == *cb { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Applies `[defaults]` and `inherits` to every profile in a configuration document
+fn resolve_profiles(document: &mut Value) -> Result<()> {
+    let document = match document.as_table_mut() {
+        Some(document) => document,
+        None => bail!("Configuration must be a table"),
+    };
+
+    let defaults = match document.remove("defaults") {
+        Some(Value::Table(defaults)) => defaults,
+        Some(_) => bail!("`defaults` must be a table"),
+        None => Table::new(),
+    };
+    if defaults.contains_key("inherits") {
+        bail!("`inherits` can't be set in `[defaults]`, only in a profile");
+    }
+
+    let profiles = match document.get("profiles") {
+        Some(Value::Table(profiles)) => profiles,
+        _ => return Ok(()),
+    };
+
+    let mut resolved = Table::new();
+    for name in profiles.keys() {
+        let mut profile = Value::Table(defaults.clone());
+        merge(
+            &mut profile,
+            resolve_profile(profiles, name, &mut Vec::new())?,
+        );
+        backend_repository(name, &mut profile)?;
+        expand_templates(name, &mut profile)?;
+        resolved.insert(name.clone(), profile);
+    }
+    document.insert("profiles".to_string(), Value::Table(resolved));
+
+    Ok(())
+}
+
+/// Merges a profile with the profiles it inherits from. `chain` holds the profiles that (transitively) inherit from this one,
+/// to detect cycles.
+fn resolve_profile(profiles: &Table, name: &str, chain: &mut Vec<String>) -> Result<Value> {
+    if chain.iter().any(|n| n == name) {
+        bail!(
+            "Profile inheritance cycle: {} -> {}",
+            chain.join(" -> "),
+            name
+        );
+    }
+
+    let mut profile = match (profiles.get(name), chain.last()) {
+        (Some(profile), _) => profile.clone(),
+        (None, Some(child)) => bail!(
+            "Profile `{}` inherits from `{}`, which does not exist",
+            child,
+            name
+        ),
+        (None, None) => bail!("Profile `{}` does not exist", name),
+    };
+
+    let parent = match profile.as_table_mut().and_then(|p| p.remove("inherits")) {
+        Some(Value::String(parent)) => parent,
+        Some(_) => bail!("`inherits` in profile `{}` must be a profile name", name),
+        None => return Ok(profile),
+    };
+
+    chain.push(name.to_string());
+    let mut base = resolve_profile(profiles, &parent, chain)?;
+    chain.pop();
+    merge(&mut base, profile);
+    Ok(base)
+}
+
+/// Fills in `repository` for profiles that describe their backend in a table instead, like `rclone`, `rest`, or
+/// `s3.bucket`
+fn backend_repository(name: &str, profile: &mut Value) -> Result<()> {
+    let profile = match profile.as_table_mut() {
+        Some(profile) => profile,
+        None => return Ok(()),
+    };
+    let setting = |table: &str, key: &str| match profile.get(table).and_then(|t| t.get(key)) {
+        Some(Value::String(value)) => Some(value.clone()),
+        _ => None,
+    };
+
+    let mut repositories = Vec::new();
+    if let Some(remote) = setting("rclone", "remote") {
+        repositories.push(("rclone", format!("rclone:{}", remote)));
+    }
+    if let Some(url) = setting("rest", "url") {
+        repositories.push(("rest", format!("rest:{}", url)));
+    }
+    if let Some(bucket) = setting("s3", "bucket") {
+        let endpoint = setting("s3", "endpoint").unwrap_or_else(|| DEFAULT_S3_ENDPOINT.to_string());
+        repositories.push((
+            "s3.bucket",
+            format!("s3:{}/{}", endpoint.trim_end_matches('/'), bucket),
+        ));
+    }
+    if let Some(bucket) = setting("b2", "bucket") {
+        repositories.push(("b2.bucket", format!("b2:{}", bucket)));
+    }
+
+    let (key, repository) = match &repositories[..] {
+        [] => return Ok(()),
+        [repository] => repository.clone(),
+        [(first, _), (second, _), ..] => bail!(
+            "Profile `{}` cannot set both `{}` and `{}`",
+            name,
+            first,
+            second
+        ),
+    };
+    if profile.contains_key("repository") {
+        bail!(
+            "Profile `{}` sets `{}`, so it can't also set `repository`",
+            name,
+            key
+        );
+    }
+    profile.insert("repository".to_string(), Value::String(repository));
+    Ok(())
+}
+
+/// Expands template variables in the profile settings that support them: `repository`, `base_directory`, and
+/// `copy_to.repository`
+fn expand_templates(name: &str, profile: &mut Value) -> Result<()> {
+    let lookup = |variable: &str| template_variable(name, variable);
+
+    let profile = match profile.as_table_mut() {
+        Some(profile) => profile,
+        None => return Ok(()),
+    };
+    for key in ["repository", "base_directory"].iter() {
+        if let Some(Value::String(value)) = profile.get_mut(*key) {
+            *value = template::expand(value, lookup)
+                .with_context(|| format!("Invalid `{}` in profile `{}`", key, name))?;
+        }
+    }
+    split_repository_list(name, profile, lookup)?;
+    if let Some(Value::String(value)) = profile
+        .get_mut("copy_to")
+        .and_then(|copy_to| copy_to.get_mut("repository"))
+    {
+        *value = template::expand(value, lookup)
+            .with_context(|| format!("Invalid `copy_to.repository` in profile `{}`", name))?;
+    }
+
+    Ok(())
+}
+
+/// Splits a `repository` given as a list into the primary repository and its `failover_repositories`, expanding templates
+/// in each of them
+fn split_repository_list<F>(name: &str, profile: &mut Table, lookup: F) -> Result<()>
+where
+    F: Fn(&str) -> Result<String>,
+{
+    let repositories = match profile.get("repository") {
+        Some(Value::Array(repositories)) => repositories.clone(),
+        _ => return Ok(()),
+    };
+    if profile.contains_key("failover_repositories") {
+        bail!(
+            "Profile `{}` lists several repositories in `repository`, so it can't also set `failover_repositories`",
+            name
+        );
+    }
+
+    let mut expanded = Vec::with_capacity(repositories.len());
+    for repository in repositories {
+        let repository = match repository {
+            Value::String(repository) => repository,
+            _ => bail!(
+                "`repository` in profile `{}` must be a string or a list of strings",
+                name
+            ),
+        };
+        expanded.push(Value::String(
+            template::expand(&repository, &lookup)
+                .with_context(|| format!("Invalid `repository` in profile `{}`", name))?,
+        ));
+    }
+    if expanded.is_empty() {
+        bail!("`repository` in profile `{}` must not be empty", name);
+    }
+
+    let primary = expanded.remove(0);
+    profile.insert("repository".to_string(), primary);
+    profile.insert("failover_repositories".to_string(), Value::Array(expanded));
+    Ok(())
+}
+
+/// Looks up the value of a template variable for a profile
+fn template_variable(profile: &str, variable: &str) -> Result<String> {
+    match variable {
+        "hostname" => platform::hostname().ok_or_else(|| anyhow!("Could not determine hostname")),
+        "user" => platform::username().ok_or_else(|| anyhow!("Could not determine user name")),
+        "date" => Ok(Local::now().format("%Y-%m-%d").to_string()),
+        "profile" => Ok(profile.to_string()),
+        _ => bail!(
+            "Unknown template variable `{}`, expected one of `hostname`, `user`, `date`, or `profile`",
+            variable
+        ),
+    }
+}
+
+/// Merges `overlay` into `base`, recursively merging tables and replacing all other values
+fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Table(base), Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+fn default_restic_command() -> String {
+    "restic".into()
+}
+
+fn default_max_parallel() -> usize {
+    1
+}
+
+/// Logging settings
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Logging {
+    /// Where to send logs. Defaults to the terminal.
+    pub target: LogTarget,
+}
+
+/// Where to send logs
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogTarget {
+    /// Print logs to stderr
+    #[default]
+    Terminal,
+    /// Send logs to the systemd journal, with key-value pairs as journal fields
+    Journald,
+    /// Send logs to the local syslog daemon
+    Syslog,
+}
+
+/// How to log Restic's output. Each line Restic prints is logged at the level for the stream it was printed to.
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResticOutput {
+    /// Level to log Restic's standard output at. Defaults to `info`.
+    pub stdout: LogLevel,
+
+    /// Level to log Restic's standard error at. Defaults to `warning`.
+    pub stderr: LogLevel,
+}
+
+impl Default for ResticOutput {
+    fn default() -> Self {
+        ResticOutput {
+            stdout: LogLevel::Info,
+            stderr: LogLevel::Warning,
+        }
+    }
+}
+
+/// Level of a log message
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogLevel {
+    Error,
+    Warning,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    /// The equivalent slog level
+    pub fn level(self) -> Level {
+        match self {
+            LogLevel::Error => Level::Error,
+            LogLevel::Warning => Level::Warning,
+            LogLevel::Info => Level::Info,
+            LogLevel::Debug => Level::Debug,
+            LogLevel::Trace => Level::Trace,
+        }
+    }
+}
+
+/// Services to notify when a backup finishes
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Notifications {
+    /// Also notify when forgetting snapshots, with how many were removed and kept
+    pub forget: bool,
+
+    /// Send an HTTP request to a webhook, such as a Slack, Discord, Mattermost, or ntfy endpoint
+    pub webhook: Option<Webhook>,
+
+    /// Send a push notification with [Pushover](https://pushover.net)
+    pub pushover: Option<Pushover>,
+
+    /// Send a message from a [Telegram bot](https://core.telegram.org/bots)
+    pub telegram: Option<Telegram>,
+}
+
+/// An HTTP endpoint to notify when a backup finishes. String values in `url`, `headers`, and `payload` can use these
+/// placeholders:
+/// * `{profile}`: the name of the profile that was backed up
+/// * `{operation}`: `backup`, or `forget` if `notifications.forget` is set
+/// * `{status}`: `success` or `failure`
+/// * `{hostname}`: the name of this machine
+/// * `{snapshot_id}`: the ID of the new snapshot, if the backup succeeded
+/// * `{bytes_added}`: how many bytes the backup added to the repository, if it succeeded
+/// * `{duration}`: how long the backup took, in seconds
+/// * `{snapshots_removed}`, `{snapshots_kept}`: how many snapshots a successful forget removed and kept
+/// * `{anomaly}`: what's unusual about a successful backup's size compared to recent backups, if anything
+/// * `{error}`: the error message, if the backup failed
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Webhook {
+    /// URL to send the notification to
+    pub url: String,
+
+    /// HTTP method to use. Defaults to `POST`.
+    #[serde(default = "default_webhook_method")]
+    pub method: String,
+
+    /// Extra HTTP headers to send, such as `Authorization`
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+
+    /// Request body, written as a TOML table and sent as JSON. Defaults to an object with the profile, operation, status,
+    /// snapshot ID and bytes added (or snapshots removed and kept, for forgets), duration, and error.
+    #[serde(default)]
+    pub payload: Option<Value>,
+
+    /// Only send notifications for failed backups, and for backups that look unusual
+    #[serde(default)]
+    pub failures_only: bool,
+}
+
+/// Pushover application and user to notify. The message can use the same placeholders as [`Webhook`].
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Pushover {
+    /// API token of the Pushover application to send from
+    pub token: String,
+
+    /// User or group key to send to
+    pub user: String,
+
+    /// Message priority, from -2 (lowest) to 1 (high). Defaults to Pushover's default of 0.
+    #[serde(default)]
+    pub priority: Option<i8>,
+
+    /// Message to send about backups. Defaults to a short description of the backup's outcome.
+    #[serde(default)]
+    pub message: Option<String>,
+
+    /// Message to send about forgets, if `notifications.forget` is set. `message` only applies to backups, so this
+    /// defaults to a short description of the forget's outcome.
+    #[serde(default)]
+    pub forget_message: Option<String>,
+
+    /// Only send notifications for failed backups, and for backups that look unusual
+    #[serde(default)]
+    pub failures_only: bool,
+}
+
+/// Telegram bot and chat to notify. The message can use the same placeholders as [`Webhook`].
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Telegram {
+    /// Token of the bot to send from, as given by BotFather
+    pub bot_token: String,
+
+    /// ID of the chat to send to
+    pub chat_id: String,
+
+    /// Message to send about backups. Defaults to a short description of the backup's outcome.
+    #[serde(default)]
+    pub message: Option<String>,
+
+    /// Message to send about forgets, if `notifications.forget` is set. `message` only applies to backups, so this
+    /// defaults to a short description of the forget's outcome.
+    #[serde(default)]
+    pub forget_message: Option<String>,
+
+    /// Only send notifications for failed backups, and for backups that look unusual
+    #[serde(default)]
+    pub failures_only: bool,
+}
+
+fn default_webhook_method() -> String {
+    "POST".into()
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// Repository URL. In the configuration file, this can also be a list of repositories in order of preference, in which
+    /// case the first is stored here and the rest in `failover_repositories`.
+    pub repository: String,
+
+    /// Repositories to back up to instead, in order, if Restic can't reach `repository`. They use the same password and
+    /// environment as `repository`.
+    #[serde(default)]
+    pub failover_repositories: Vec<String>,
+
+    /// Store the repository with rclone. This sets `repository` to the rclone remote, so the two can't both be set.
+    #[serde(default)]
+    pub rclone: Option<Rclone>,
+
+    /// Connection settings for a repository on a rest-server. This sets `repository` to the server URL, so the two can't
+    /// both be set.
+    #[serde(default)]
+    pub rest: Option<Rest>,
+
+    /// Credentials for an S3 repository, and optionally its bucket
+    #[serde(default)]
+    pub s3: Option<S3>,
+
+    /// Credentials for a Backblaze B2 repository, and optionally its bucket
+    #[serde(default)]
+    pub b2: Option<B2>,
+
+    /// Location of the `restic` binary for this profile, if it's different from the top-level `restic_command`
+    #[serde(default)]
+    pub restic_command: Option<String>,
+
+    /// Oldest Restic version this profile works with. If set, Rustic checks the version before running Restic.
+    #[serde(default)]
+    pub min_restic_version: Option<ResticVersion>,
+
+    /// If true, automatically initialize the repository if it does not exist.
+    #[serde(default)]
+    pub auto_init: bool,
+
+    /// Options for creating the repository, with `rustic init` or `auto_init`
+    #[serde(default)]
+    pub init: InitOptions,
+
+    /// If true and an operation fails because the repository is locked by a Restic process on this machine that no longer
+    /// exists, remove the stale lock and try again.
+    #[serde(default)]
+    pub auto_unlock: bool,
+
+    /// How many times to retry an operation that fails because of a transient error, like a dropped network connection, a
+    /// backend that's temporarily unavailable, or a repository that's locked by another Restic process
+    #[serde(default)]
+    pub retries: u32,
+
+    /// How long to wait before the first retry, like `30s` or `2m`. Each later retry waits twice as long as the one before,
+    /// plus a random amount of jitter. Defaults to 10 seconds.
+    #[serde(default, deserialize_with = "duration::deserialize_option")]
+    pub retry_delay: Option<Duration>,
+
+    /// Directory to run backups from. Included and excluded files, and the password and environment files (if specified), will be
+    /// resolved relative to this directory.
+    pub base_directory: PathBuf,
+
+    /// Repository password. Prefer `password_file` or `password_command` instead, unless there are strict permissions on the
+    /// configuration file.
+    #[serde(default)]
+    pub password: Option<String>,
+
+    /// File containing the repository password. At most one of `password`, `password_file`, `password_command`,
+    /// `password_keyring`, `password_op_item`, `password_bitwarden_item`, or `password_prompt` may be specified.
+    #[serde(default)]
+    pub password_file: Option<String>,
+
+    /// Command to run to get the repository password. At most one of `password`, `password_file`, `password_command`,
+    /// `password_keyring`, `password_op_item`, `password_bitwarden_item`, or `password_prompt` may be specified.
+    #[serde(default)]
+    pub password_command: Option<String>,
+
+    /// Entry in the operating system's keyring holding the repository password. Store the password with
+    /// `rustic secret set <profile>`.
+    #[serde(default)]
+    pub password_keyring: Option<KeyringEntry>,
+
+    /// 1Password secret reference for the repository password, like `op://Private/restic/password`, read with the `op` CLI
+    #[serde(default)]
+    pub password_op_item: Option<String>,
+
+    /// Name or ID of the Bitwarden item holding the repository password, read with the `bw` CLI
+    #[serde(default)]
+    pub password_bitwarden_item: Option<String>,
+
+    /// If true, prompt for the repository password on the terminal. Without any password source, Rustic also prompts when
+    /// it's run in a terminal. Each password is only asked for once per run.
+    #[serde(default)]
+    pub password_prompt: bool,
+
+    /// If true, remember a password entered at the prompt in the keyring until logging out, so that later runs don't ask
+    /// for it again. This uses the Secret Service's session collection, so it's only supported on Linux and other Unix-like
+    /// systems with a Secret Service like GNOME Keyring.
+    #[serde(default)]
+    pub remember_prompted_password: bool,
+
+    /// Environment variables to pass to Restic. Can be used to set repository backend credentials (ex. Backblaze B2 API keys). These will be
+    /// merged with variables in `environment_file`, if both are given.
+    #[serde(default)]
+    pub environment: HashMap<String, String>,
+
+    /// TOML file containing a table of environment variables to pass to Restic. Can be used to set repository backend credentials. Variables in
+    /// this file will be merged with `environment`, if both are given. Files named like `*.sops.toml` are decrypted with
+    /// `sops`, and files named like `*.age` with `age`.
+    #[serde(default)]
+    pub environment_file: Option<PathBuf>,
+
+    /// Format of `environment_file`: `toml`, or `dotenv` for `KEY=value` lines. Defaults to `dotenv` for files named like
+    /// `.env` or `b2.env`, and `toml` otherwise.
+    #[serde(default)]
+    pub environment_file_format: Option<EnvironmentFileFormat>,
+
+    /// age identity file to decrypt an `environment_file` ending in `.age` with. Defaults to the age key file SOPS uses,
+    /// `~/.config/sops/age/keys.txt` on Linux.
+    #[serde(default)]
+    pub age_identity_file: Option<PathBuf>,
+
+    /// ARN of an AWS Secrets Manager secret or Systems Manager parameter holding a JSON object of environment variables to
+    /// pass to Restic, such as S3 credentials. It's read at runtime with the `aws` CLI, using its default credential chain.
+    /// These variables take precedence over `environment` and `environment_file`.
+    #[serde(default)]
+    pub environment_from_aws_secret: Option<String>,
+
+    /// Tags to add to snapshots created by this profile. If set, `rustic forget` only considers snapshots with all of these tags,
+    /// so that profiles sharing a repository don't forget each other's snapshots.
+    #[serde(default)]
+    pub tags: Vec<String>,
+
+    /// Host name to record in snapshots instead of this machine's hostname, for machines whose hostname changes. Also
+    /// restricts `rustic forget` to snapshots from this host, unless the retention policy sets its own `host`.
+    #[serde(default)]
+    pub host: Option<String>,
+
+    /// Files to back up
+    #[serde(default)]
+    pub include: Fileset,
+
+    /// Files to exclude from the backup. These exclusions apply to all sources.
+    #[serde(default)]
+    pub exclude: Fileset,
+
+    /// Command whose output to back up instead of files, such as `pg_dump mydb`. It's run in the base directory, and its output
+    /// is piped into `restic backup --stdin`. A profile with `stdin_command` can't also `include` files, but file sources are
+    /// still backed up, into a separate snapshot.
+    #[serde(default)]
+    pub stdin_command: Option<String>,
+
+    /// File name to store the output of `stdin_command` as. Defaults to `stdin`.
+    #[serde(default)]
+    pub stdin_filename: Option<String>,
+
+    /// Additional sets of files to back up, each relative to its own base directory. These are all backed up into the same
+    /// snapshot, along with the files in `include`. Sources with a `stdin_command` are each backed up into a snapshot of
+    /// their own.
+    #[serde(default)]
+    pub sources: Vec<Source>,
+
+    /// Databases to dump and back up, each into a snapshot of its own
+    #[serde(default)]
+    pub databases: Databases,
+
+    /// Containers to pause or stop while backing up, so that their data doesn't change mid-backup
+    #[serde(default)]
+    pub containers: Containers,
+
+    /// Whether or not to exclude cache directories marked with a `CACHEDIR.TAG` file. See the
+    /// [Cache Directory Tagging Standard](http://bford.info/cachedir/spec.html) for more.
+    #[serde(default)]
+    pub exclude_caches: bool,
+
+    /// Exclude directories containing a file with one of these names, like `.nobackup`. Restic also accepts
+    /// `name:header`, which only excludes the directory if the file starts with `header`.
+    #[serde(default)]
+    pub exclude_if_present: Vec<String>,
+
+    /// Exclude files larger than this size, like `1G` or `500M`
+    #[serde(default)]
+    pub exclude_larger_than: Option<String>,
+
+    /// Do not cross filesystem boundaries when backing up
+    #[serde(default)]
+    pub one_file_system: bool,
+
+    /// Ignore inode number changes when checking for modified files
+    #[serde(default)]
+    pub ignore_inode: bool,
+
+    /// Treat a backup as failed if Restic couldn't read some files. Restic still creates a snapshot of everything else, so by
+    /// default this only logs a warning.
+    #[serde(default)]
+    pub fail_on_partial: bool,
+
+    /// How Restic compresses data it adds to the repository. Requires Restic 0.14 or later and a version 2 repository.
+    #[serde(default)]
+    pub compression: Option<Compression>,
+
+    /// Target size for pack files in the repository, in MiB. Larger packs mean fewer files and uploads for large
+    /// repositories. Requires Restic 0.15 or later.
+    #[serde(default)]
+    pub pack_size: Option<u32>,
+
+    /// Keep the system from going to sleep while Restic is running. This uses `systemd-inhibit` on Linux and `caffeinate` on macOS.
+    #[serde(default)]
+    pub prevent_sleep: bool,
+
+    /// Run Restic with this niceness (from -20 for the highest priority to 19 for the lowest). Only supported on Unix-like systems.
+    #[serde(default)]
+    pub niceness: Option<i32>,
+
+    /// Run Restic with this I/O scheduling class. Only supported on Linux.
+    #[serde(default)]
+    pub io_class: Option<IoClass>,
+
+    /// Maximum rate for Restic to upload at, in KiB/s
+    #[serde(default)]
+    pub limit_upload: Option<u32>,
+
+    /// Maximum rate for Restic to download at, in KiB/s
+    #[serde(default)]
+    pub limit_download: Option<u32>,
+
+    /// Bandwidth limits for particular times of day, checked when Restic starts. The first window containing the current
+    /// time replaces `limit_upload` and `limit_download`.
+    #[serde(default)]
+    pub bandwidth_schedule: Vec<BandwidthWindow>,
+
+    /// Policy for how long to keep backup snapshots
+    #[serde(default)]
+    pub retention: RetentionPolicy,
+
+    /// Options for verifying repository integrity
+    #[serde(default)]
+    pub check: CheckOptions,
+
+    /// Options for test restores with `rustic verify`
+    #[serde(default)]
+    pub verify: VerifyOptions,
+
+    /// Options for warning about backups that are much larger or smaller than usual
+    #[serde(default)]
+    pub anomaly: AnomalyOptions,
+
+    /// Least data a backup must contain to succeed
+    #[serde(default)]
+    pub sanity: SanityOptions,
+
+    /// Options for removing unreferenced data, with `rustic prune` or `rustic forget --prune`
+    #[serde(default)]
+    pub prune: PruneOptions,
+
+    /// How often `rustic maintain` forgets, prunes, and checks
+    #[serde(default)]
+    pub maintenance: MaintenanceSchedule,
+
+    /// Commands to run before and after backups
+    #[serde(default)]
+    pub hooks: Hooks,
+
+    /// Secondary repository to copy snapshots to, such as an off-site mirror of a local repository
+    #[serde(default)]
+    pub copy_to: Option<CopyTarget>,
+
+    /// If true, copy snapshots to the `copy_to` repository after every successful backup
+    #[serde(default)]
+    pub copy_after_backup: bool,
+
+    /// If true, the profile's credentials can only add to the repository (for example, an append-only rest-server or a
+    /// bucket without delete permission), so Rustic refuses to forget, prune, or remove keys with it
+    #[serde(default)]
+    pub append_only: bool,
+
+    /// Profile with full access to the same repository, for forgetting and pruning snapshots an append-only profile backs up
+    #[serde(default)]
+    pub maintenance_profile: Option<String>,
+
+    /// External monitoring of backups
+    #[serde(default)]
+    pub monitoring: Monitoring,
+
+    /// How long each operation may run before Restic is stopped and the operation fails
+    #[serde(default)]
+    pub timeouts: Timeouts,
+
+    /// When to run scheduled backups, as a systemd [calendar event](https://www.freedesktop.org/software/systemd/man/systemd.time.html#Calendar%20Events)
+    /// like `daily` or `*-*-* 03:00:00`. Used by `rustic install-units`.
+    #[serde(default)]
+    pub schedule: Option<String>,
+
+    /// Upper bound on a random delay before each scheduled backup, like `15m`. This spreads out backups that would
+    /// otherwise start at the same moment, like those missed while the machine was asleep and caught up on resume.
+    #[serde(default, deserialize_with = "duration::deserialize_option")]
+    pub schedule_jitter: Option<Duration>,
+
+    /// How old the last successful backup can be before `rustic status` reports the profile as stale, like `36h` or `7d`
+    #[serde(default, deserialize_with = "duration::deserialize_option")]
+    pub max_age: Option<Duration>,
+
+    /// How often the profile needs backing up, like `24h` or `daily`. `rustic backup --if-due` skips the backup if the last
+    /// successful one is more recent than this.
+    #[serde(default)]
+    pub frequency: Option<Cadence>,
+
+    /// If true, skip backups while running on battery power
+    #[serde(default)]
+    pub require_ac_power: bool,
+
+    /// If true, skip backups while connected to a metered network, like a phone hotspot
+    #[serde(default)]
+    pub skip_on_metered: bool,
+
+    /// Checks to make before starting a backup
+    #[serde(default)]
+    pub preconditions: Preconditions,
+}
+
+/// Options for `restic init`
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct InitOptions {
+    /// Repository format version to create, such as `1`, `2`, or `latest`. Version 2 (Restic 0.14 and later) supports
+    /// compression.
+    pub repository_version: Option<String>,
+
+    /// Name of another profile whose repository to copy chunker parameters from. Repositories with the same chunker
+    /// parameters deduplicate data the same way, which keeps `restic copy` between them efficient.
+    pub copy_chunker_params_from: Option<String>,
+}
+
+/// Settings for reporting backup status to monitoring services
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Monitoring {
+    /// [Healthchecks.io](https://healthchecks.io)-compatible ping URL. Rustic pings `<url>/start` when a backup starts, `<url>`
+    /// when it succeeds, and `<url>/fail` (with the error message as the request body) when it fails. Requires `curl`.
+    pub healthcheck_url: Option<String>,
+}
+
+/// Limits on how long Restic may run for each operation, like `6h` or `30m`. Operations without a timeout can run forever.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    /// Timeout for `restic backup`
+    #[serde(deserialize_with = "duration::deserialize_option")]
+    pub backup: Option<Duration>,
+
+    /// Timeout for `restic forget`, including pruning if `prune` is set in the retention policy
+    #[serde(deserialize_with = "duration::deserialize_option")]
+    pub forget: Option<Duration>,
+
+    /// Timeout for `restic prune`
+    #[serde(deserialize_with = "duration::deserialize_option")]
+    pub prune: Option<Duration>,
 
+    /// Timeout for `restic check`
+    #[serde(deserialize_with = "duration::deserialize_option")]
+    pub check: Option<Duration>,
+
+    /// Timeout for `restic copy`
+    #[serde(deserialize_with = "duration::deserialize_option")]
+    pub copy: Option<Duration>,
+
+    /// Timeout for `restic restore`
+    #[serde(deserialize_with = "duration::deserialize_option")]
+    pub restore: Option<Duration>,
+}
+
+impl Timeouts {
+    /// Timeout for a Restic subcommand, if it has one
+    pub fn for_operation(&self, operation: &str) -> Option<Duration> {
+        match operation {
+            "backup" => self.backup,
+            "forget" => self.forget,
+            "prune" => self.prune,
+            "check" => self.check,
+            "copy" => self.copy,
+            "restore" => self.restore,
+            _ => None,
+        }
+    }
+}
+
+/// A repository that snapshots are copied to with `restic copy`. For deduplication to work across both repositories, the target
+/// repository should be initialized with the same chunker parameters as the profile's repository (see `restic init
+/// --copy-chunker-params`).
 #[derive(Deserialize)]
-pub struct Configuration {
-    /// Backup profiles
-    pub profiles: HashMap<String, Profile>,
+#[serde(deny_unknown_fields)]
+pub struct CopyTarget {
+    /// Repository URL
+    pub repository: String,
 
-    /// Named filesets to reuse in profiles
+    /// Repository password. At most one of `password`, `password_file`, `password_command`, `password_keyring`,
+    /// `password_op_item`, `password_bitwarden_item`, or `password_prompt` may be specified.
     #[serde(default)]
-    pub filesets: HashMap<String, Fileset>,
+    pub password: Option<String>,
 
-    /// Location of the `restic` binary. Defaults to `restic`
-    #[serde(default = "default_restic_command")]
-    pub restic_command: String,
+    /// File containing the repository password, relative to the profile's base directory
+    #[serde(default)]
+    pub password_file: Option<String>,
 
-    /// Root cache directory for repository metadata. Defaults to a system-specific cache location.
-    /// Within this directory, restic creates a sub-directory for each repository.
+    /// Command to run to get the repository password
+    #[serde(default)]
+    pub password_command: Option<String>,
+
+    /// Entry in the operating system's keyring holding the repository password
+    #[serde(default)]
+    pub password_keyring: Option<KeyringEntry>,
+
+    /// 1Password secret reference for the repository password
+    #[serde(default)]
+    pub password_op_item: Option<String>,
+
+    /// Name or ID of the Bitwarden item holding the repository password
+    #[serde(default)]
+    pub password_bitwarden_item: Option<String>,
+
+    /// If true, prompt for the repository password on the terminal
+    #[serde(default)]
+    pub password_prompt: bool,
+
+    /// If true, remember a password entered at the prompt in the keyring until logging out
+    #[serde(default)]
+    pub remember_prompted_password: bool,
+
+    /// Additional environment variables for accessing the repository, such as backend credentials. These are merged with the
+    /// profile's environment variables.
     #[serde(default)]
-    pub cache_directory: Option<PathBuf>
+    pub environment: HashMap<String, String>,
 }
 
-fn default_restic_command() -> String {
-    "restic".into()
+/// Settings for storing a repository with rclone, so that Restic can use any backend rclone supports
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Rclone {
+    /// rclone remote and path to store the repository at, like `gdrive:backups/restic`
+    pub remote: String,
+
+    /// rclone configuration file, relative to the profile's base directory. Defaults to rclone's own default.
+    #[serde(default)]
+    pub config: Option<PathBuf>,
+
+    /// rclone executable to run. Defaults to `rclone` on the `PATH`.
+    #[serde(default)]
+    pub program: Option<String>,
+
+    /// Bandwidth limit, in the format of rclone's `--bwlimit` flag, like `4M` or `08:00,512k 19:00,off`
+    #[serde(default)]
+    pub bwlimit: Option<String>,
+
+    /// Additional arguments for rclone, like `["--drive-chunk-size", "64M"]`
+    #[serde(default)]
+    pub args: Vec<String>,
 }
 
-#[derive(Deserialize)]
-pub struct Profile {
-    /// Repository URL
-    pub repository: String,
+/// S3 endpoint used if an `s3` table sets `bucket` but not `endpoint`
+const DEFAULT_S3_ENDPOINT: &str = "s3.amazonaws.com";
 
-    /// If true, automatically initialize the repository if it does not exist.
+/// Credentials for an S3 (or S3-compatible) repository, passed to Restic as `AWS_*` environment variables
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct S3 {
+    /// Access key ID
+    pub access_key_id: String,
+
+    /// Secret access key. Exactly one of `secret_access_key` and `secret_access_key_file` must be set.
     #[serde(default)]
-    pub auto_init: bool,
+    pub secret_access_key: Option<String>,
 
-    /// Directory to run backups from. Included and excluded files, and the password and environment files (if specified), will be
-    /// resolved relative to this directory.
-    pub base_directory: PathBuf,
+    /// File containing the secret access key, relative to the profile's base directory
+    #[serde(default)]
+    pub secret_access_key_file: Option<PathBuf>,
 
-    /// Repository password. Prefer `password_file` or `password_command` instead, unless there are strict permissions on the
-    /// configuration file.
+    /// Region the bucket is in
+    #[serde(default)]
+    pub region: Option<String>,
+
+    /// Bucket, and optionally a path within it, like `my-bucket/laptop`. If set, this determines the profile's
+    /// repository, so `repository` can't also be set.
+    #[serde(default)]
+    pub bucket: Option<String>,
+
+    /// Server to connect to when using `bucket`, for S3-compatible services like MinIO. Defaults to AWS.
+    #[serde(default)]
+    pub endpoint: Option<String>,
+}
+
+/// Credentials for a Backblaze B2 repository, passed to Restic as `B2_*` environment variables
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct B2 {
+    /// Account ID or application key ID
+    pub account_id: String,
+
+    /// Account key or application key. Exactly one of `account_key` and `account_key_file` must be set.
+    #[serde(default)]
+    pub account_key: Option<String>,
+
+    /// File containing the account key, relative to the profile's base directory
+    #[serde(default)]
+    pub account_key_file: Option<PathBuf>,
+
+    /// Bucket, and optionally a path within it, like `my-bucket:laptop`. If set, this determines the profile's repository,
+    /// so `repository` can't also be set.
+    #[serde(default)]
+    pub bucket: Option<String>,
+}
+
+/// A repository on a rest-server, with the connection settings Restic needs for it
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Rest {
+    /// URL of the repository on the server, like `https://nas.lan:8000/laptop`. This determines the profile's repository,
+    /// so `repository` can't also be set.
+    pub url: String,
+
+    /// User name to log in to the server with
+    #[serde(default)]
+    pub username: Option<String>,
+
+    /// Password to log in to the server with. At most one of `password`, `password_file`, and `password_keyring` may be
+    /// set, and only along with `username`.
     #[serde(default)]
     pub password: Option<String>,
 
-    /// File containing the repository password. Exactly one of `password`, `password_file`, or `password_command` must be specified.
+    /// File containing the password to log in to the server with, relative to the profile's base directory
     #[serde(default)]
-    pub password_file: Option<String>,
+    pub password_file: Option<PathBuf>,
 
-    /// Command to run to get the repository password. Exactly one of `password`, `password_file`, or `password_command` must be specified.
+    /// Entry in the operating system's keyring holding the password to log in to the server with
     #[serde(default)]
-    pub password_command: Option<String>,
+    pub password_keyring: Option<KeyringEntry>,
 
-    /// Environment variables to pass to Restic. Can be used to set repository backend credentials (ex. Backblaze B2 API keys). These will be
-    /// merged with variables in `environment_file`, if both are given.
+    /// CA certificate to verify the server with, for servers using a self-signed certificate
     #[serde(default)]
-    pub environment: HashMap<String, String>,
+    pub ca_cert: Option<PathBuf>,
 
-    /// TOML file containing a table of environment variables to pass to Restic. Can be used to set repository backend credentials. Variables in
-    /// this file will be merged with `environment`, if both are given.
+    /// PEM file with a client certificate and its private key, for servers that require TLS client authentication
     #[serde(default)]
-    pub environment_file: Option<PathBuf>,
+    pub client_cert: Option<PathBuf>,
+}
+
+/// An entry in the operating system's keyring: the Secret Service on Linux and other Unix-like systems (looked up with
+/// `secret-tool`), or the login Keychain on macOS (looked up with `security`)
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct KeyringEntry {
+    /// Service the password is stored under. Defaults to `rustic`.
+    #[serde(default = "default_keyring_service")]
+    pub service: String,
+
+    /// Account the password is stored under, which tells apart the passwords for different repositories
+    pub account: String,
+}
+
+fn default_keyring_service() -> String {
+    "rustic".into()
+}
+
+/// Where a repository's password comes from, borrowed from a profile or `copy_to` target. At most one source should be
+/// set, and Rustic prompts for the password if none are.
+#[derive(Clone, Copy)]
+pub struct PasswordSettings<'c> {
+    /// Repository the password is for, to prompt with
+    pub repository: &'c str,
+    pub password: Option<&'c str>,
+    pub password_file: Option<&'c str>,
+    pub password_command: Option<&'c str>,
+    pub password_keyring: Option<&'c KeyringEntry>,
+    pub password_op_item: Option<&'c str>,
+    pub password_bitwarden_item: Option<&'c str>,
+    pub password_prompt: bool,
+    pub remember_prompted_password: bool,
+}
+
+impl Profile {
+    /// Where the profile's repository password comes from
+    pub fn password_settings(&self) -> PasswordSettings<'_> {
+        PasswordSettings {
+            repository: &self.repository,
+            password: self.password.as_deref(),
+            password_file: self.password_file.as_deref(),
+            password_command: self.password_command.as_deref(),
+            password_keyring: self.password_keyring.as_ref(),
+            password_op_item: self.password_op_item.as_deref(),
+            password_bitwarden_item: self.password_bitwarden_item.as_deref(),
+            password_prompt: self.password_prompt,
+            remember_prompted_password: self.remember_prompted_password,
+        }
+    }
+}
+
+impl CopyTarget {
+    /// Where the copy target's repository password comes from
+    pub fn password_settings(&self) -> PasswordSettings<'_> {
+        PasswordSettings {
+            repository: &self.repository,
+            password: self.password.as_deref(),
+            password_file: self.password_file.as_deref(),
+            password_command: self.password_command.as_deref(),
+            password_keyring: self.password_keyring.as_ref(),
+            password_op_item: self.password_op_item.as_deref(),
+            password_bitwarden_item: self.password_bitwarden_item.as_deref(),
+            password_prompt: self.password_prompt,
+            remember_prompted_password: self.remember_prompted_password,
+        }
+    }
+}
+
+/// Databases to back up by piping their dumps into Restic
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Databases {
+    /// PostgreSQL databases, dumped with `pg_dump`
+    pub postgresql: Vec<Database>,
+
+    /// MySQL or MariaDB databases, dumped with `mysqldump`
+    pub mysql: Vec<Database>,
+}
+
+/// Containers to quiesce during backups. They're paused or stopped after the pre-backup hooks run, and resumed as soon as
+/// Restic finishes, before copying snapshots or running any other hooks. Containers are resumed even if the backup fails,
+/// or if Rustic is interrupted.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Containers {
+    /// Container runtime to manage the containers with, such as `docker` or `podman`. Defaults to `docker`.
+    pub runtime: Option<String>,
+
+    /// Containers to pause, which freezes their processes without shutting them down
+    pub pause: Vec<String>,
+
+    /// Containers to stop, and start again after the backup. Stopping is slower than pausing, but lets applications shut
+    /// down cleanly and flush their data to disk first. Containers are stopped in the order listed and started in reverse.
+    pub stop: Vec<String>,
+
+    /// Longest the containers may stay paused or stopped, like `30m`. If the backup takes longer, they're resumed anyway so
+    /// the services they run aren't down indefinitely, and the backup carries on. Defaults to 1 hour.
+    #[serde(deserialize_with = "duration::deserialize_option")]
+    pub max_downtime: Option<Duration>,
+}
+
+impl Containers {
+    /// Whether there are any containers to pause or stop
+    pub fn is_empty(&self) -> bool {
+        self.pause.is_empty() && self.stop.is_empty()
+    }
+}
+
+/// A database to dump and how to connect to it. Connection settings that aren't given are left to the dump tool, which
+/// usually connects to a server on this machine as the current user.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Database {
+    /// Name of the database
+    pub name: String,
+
+    /// Host name of the database server, or for PostgreSQL, a directory containing its socket
+    #[serde(default)]
+    pub host: Option<String>,
+
+    /// Port the database server listens on
+    #[serde(default)]
+    pub port: Option<u16>,
+
+    /// User to connect as
+    #[serde(default)]
+    pub user: Option<String>,
+
+    /// File containing the password to connect with. If relative, this is resolved against the profile's base directory.
+    #[serde(default)]
+    pub password_file: Option<PathBuf>,
+
+    /// Extra arguments for the dump tool, like `["--exclude-table=audit_log"]` for `pg_dump` or
+    /// `["--ignore-table=gitea.audit_log"]` for `mysqldump`
+    #[serde(default)]
+    pub dump_options: Vec<String>,
+}
+
+/// A set of files to back up, relative to some base directory
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Source {
+    /// Directory that include and exclude patterns are resolved against. If relative, this is resolved against the profile's
+    /// base directory.
+    #[serde(default)]
+    pub base_directory: PathBuf,
+
+    /// Command whose output to back up instead of files, such as `pg_dump mydb`. It's run in the source's base directory, and
+    /// backed up into its own snapshot with the profile's and source's tags.
+    #[serde(default)]
+    pub stdin_command: Option<String>,
+
+    /// File name to store the output of `stdin_command` as. Defaults to `stdin`.
+    #[serde(default)]
+    pub stdin_filename: Option<String>,
 
     /// Files to back up
+    #[serde(default)]
     pub include: Fileset,
 
-    /// Files to exclude from the backup
+    /// Files to exclude. Relative patterns only exclude files under this source's base directory.
     #[serde(default)]
     pub exclude: Fileset,
 
-    /// Whether or not to exclude cache directories marked with a `CACHEDIR.TAG` file. See the
-    /// [Cache Directory Tagging Standard](http://bford.info/cachedir/spec.html) for more.
+    /// Additional tags to add to the snapshot
     #[serde(default)]
-    pub exclude_caches: bool,
+    pub tags: Vec<String>,
+}
 
-    /// Do not cross filesystem boundaries when backing up
+/// Bandwidth limits that apply during part of the day
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct BandwidthWindow {
+    /// Times of day the limits apply, like `09:00-18:00`
+    pub hours: TimeWindow,
+
+    /// Days of the week the window applies on, like `["mon", "tue"]`. Defaults to every day.
     #[serde(default)]
-    pub one_file_system: bool,
+    pub days: Vec<Weekday>,
 
-    /// Ignore inode number changes when checking for modified files
+    /// Maximum upload rate during the window, in KiB/s. If unset, uploads aren't limited during the window.
     #[serde(default)]
-    pub ignore_inode: bool,
+    pub limit_upload: Option<u32>,
 
-    /// Policy for how long to keep backup snapshots
+    /// Maximum download rate during the window, in KiB/s. If unset, downloads aren't limited during the window.
     #[serde(default)]
-    pub retention: RetentionPolicy,
+    pub limit_download: Option<u32>,
+}
+
+/// Compression levels for data Restic writes to the repository
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Compression {
+    /// Fast compression that still saves most of the space. This is Restic's default.
+    Auto,
+    /// Slower, stronger compression
+    Max,
+    /// Don't compress new data
+    Off,
+}
+
+impl Compression {
+    /// Value for Restic's `--compression` flag
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Compression::Auto => "auto",
+            Compression::Max => "max",
+            Compression::Off => "off",
+        }
+    }
+}
+
+/// Formats an environment file can be written in
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum EnvironmentFileFormat {
+    /// A TOML table of variables
+    Toml,
+    /// `KEY=value` lines, as used by Docker Compose and many other tools
+    Dotenv,
+}
+
+impl EnvironmentFileFormat {
+    /// Guesses the format of an environment file from its name, ignoring any `.age` suffix from encryption
+    pub fn detect(path: &Path) -> EnvironmentFileFormat {
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy(),
+            None => return EnvironmentFileFormat::Toml,
+        };
+        let name = name.strip_suffix(".age").unwrap_or(&name);
+        if name.ends_with(".env") || name.starts_with(".env.") {
+            EnvironmentFileFormat::Dotenv
+        } else {
+            EnvironmentFileFormat::Toml
+        }
+    }
+}
+
+/// I/O scheduling classes, as used by `ionice`
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum IoClass {
+    /// Only perform I/O when no other process needs the disk
+    Idle,
+    /// The default scheduling class
+    BestEffort,
+    /// Always get first access to the disk. This can starve other processes, and usually requires root.
+    Realtime,
 }
 
 #[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
 /// Specification of a set of files (to include or exclude)
 pub struct Fileset {
     /// Names of other filesets to inherit from. Patterns from inherited filesets (including from filesets they inherit from)
@@ -101,13 +1654,23 @@ pub struct Fileset {
     /// [excluding](https://restic.readthedocs.io/en/latest/040_backup.html#excluding-files) files for details on how these are interpreted.
     #[serde(default)]
     pub patterns: Vec<String>,
+
+    /// Patterns that match regardless of case, so that `*.mp4` also excludes `VIDEO.MP4`. Only supported for excluding
+    /// files.
+    #[serde(default)]
+    pub insensitive_patterns: Vec<String>,
+
+    /// Treat `patterns` as literal paths instead of globs, so that paths containing characters like `*` or `[` are backed up
+    /// exactly as written. Only supported for including files, and requires Restic 0.12 or later.
+    #[serde(default)]
+    pub verbatim: bool,
 }
 
 /// Describes how to keep/forget snapshots.
 ///
 /// See the [Restic documentation](https://restic.readthedocs.io/en/latest/060_forget.html#removing-snapshots-according-to-a-policy).
 #[derive(Deserialize, Default)]
-#[serde(default)]
+#[serde(default, deny_unknown_fields)]
 pub struct RetentionPolicy {
     /// Keep the `n` most recent snapshots
     pub keep_last: Option<usize>,
@@ -133,7 +1696,45 @@ pub struct RetentionPolicy {
 
     /// Keep all snapshots with any of these tag lists. For example, if this is set to `[["tag1", "tag2"], ["tag3"]]`, Restic will keep snapshots
     /// that either have both `tag1` and `tag2` or have `tag3`.
-    pub keep_tags: Vec<Vec<String>>, // TODO: restrict to tags + host
+    pub keep_tags: Vec<Vec<String>>,
+
+    /// Tag that `rustic pin` adds to snapshots. Snapshots with this tag are always kept, whatever the rest of the policy
+    /// says. Defaults to `pinned`.
+    pub pin_tag: Option<String>,
+
+    /// Only apply the policy to snapshots taken on this host. Defaults to the profile's `host`.
+    pub host: Option<String>,
+
+    /// Only apply the policy to snapshots with all of these tags. Defaults to the profile's `tags`.
+    pub tags: Vec<String>,
+
+    /// How to group snapshots before applying the policy. The policy is applied to each group separately, so for example
+    /// `keep_last = 5` keeps the last 5 snapshots of every host and set of paths. Defaults to Restic's default, grouping by
+    /// host and paths.
+    pub group_by: Vec<GroupBy>,
+}
+
+/// Snapshot attributes that `restic forget` can group snapshots by
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum GroupBy {
+    /// Group snapshots taken on the same host
+    Host,
+    /// Group snapshots of the same set of paths
+    Paths,
+    /// Group snapshots with the same set of tags
+    Tags,
+}
+
+impl GroupBy {
+    /// Name of the grouping, as passed to Restic's `--group-by`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            GroupBy::Host => "host",
+            GroupBy::Paths => "paths",
+            GroupBy::Tags => "tags",
+        }
+    }
 }
 
 impl RetentionPolicy {
@@ -149,3 +1750,522 @@ impl RetentionPolicy {
             && self.keep_tags.is_empty()
     }
 }
+
+/// Options for `restic check`.
+///
+/// See the [Restic documentation](https://restic.readthedocs.io/en/latest/045_working_with_repos.html#checking-integrity-and-consistency).
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct CheckOptions {
+    /// Read all data blobs in the repository, rather than only checking metadata. This can take a long time and download a lot of data
+    /// for remote repositories.
+    pub read_data: bool,
+
+    /// Only read a subset of the repository's data blobs. This is either a fraction like `1/5` (check the first fifth of pack files) or
+    /// a percentage like `5%` (check a random 5% of pack files). Cannot be combined with `read_data`.
+    pub read_data_subset: Option<String>,
+
+    /// Check the next part of the repository's data each time, so that with `read_data_subset = "1/10"`, ten successful checks
+    /// read all of it. The position is saved in the state file. Requires `read_data_subset` to be a fraction.
+    pub rotate_subset: bool,
+}
+
+/// Options for `rustic verify`, which restores a random sample of files from the latest snapshot and checks them
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct VerifyOptions {
+    /// How many files to restore, either a number like `20` or a percentage of the snapshot's files like `5%`. Defaults
+    /// to 20 files.
+    pub sample: Option<SampleSize>,
+
+    /// Directory to restore the sample into, for when the system's temporary directory is too small for it. Each
+    /// verification restores into a new directory inside it, which is removed afterwards. Defaults to the system's temporary
+    /// directory.
+    pub restore_directory: Option<PathBuf>,
+}
+
+/// Options for spotting unusual backups, by comparing each backup's file count and added data to the last few backups
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnomalyOptions {
+    /// Don't compare backups to earlier ones
+    pub disabled: bool,
+
+    /// How many standard deviations from the recent average a backup can be before it's unusual. Defaults to 3.
+    pub max_deviation: Option<f64>,
+}
+
+/// Least data a backup must contain, so that a backup of an empty or missing source fails instead of creating a snapshot
+/// that the retention policy then counts in place of a real one
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SanityOptions {
+    /// Fail backups with fewer files than this
+    pub min_files: Option<u64>,
+
+    /// Fail backups whose files add up to less than this, like `10G`
+    #[serde(deserialize_with = "size::deserialize_option")]
+    pub min_total_size: Option<u64>,
+
+    /// Forget the snapshot of a backup that fails these checks. Append-only profiles can't forget snapshots, so they keep it.
+    pub forget_rejected: bool,
+}
+
+/// Options for `restic prune`, which are also used when forgetting snapshots with `--prune`.
+///
+/// See the [Restic documentation](https://restic.readthedocs.io/en/latest/060_forget.html#customize-pruning).
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct PruneOptions {
+    /// How much unused space to tolerate in the repository, either as a size like `5G`, a percentage of the repository size
+    /// like `10%`, or `unlimited`. Tolerating more unused space means less data has to be repacked. Restic defaults to `5%`.
+    pub max_unused: Option<String>,
+
+    /// Maximum amount of data to repack in one prune, like `2G`. Useful for limiting how much is downloaded and uploaded
+    /// over slow connections.
+    pub max_repack_size: Option<String>,
+
+    /// Only repack pack files that are cached locally, meaning tree and metadata packs. Avoids downloading data packs from
+    /// remote repositories, at the cost of leaving more unused space behind.
+    pub repack_cacheable_only: bool,
+
+    /// Only report what `restic prune` would remove, without changing the repository. With `forget --prune`, snapshots are
+    /// still forgotten, and the dry-run prune runs on its own afterwards.
+    pub dry_run: bool,
+}
+
+/// How often `rustic maintain` runs each maintenance operation. Each is either `daily`, `weekly`, or `monthly` (following
+/// the calendar, so a daily operation is due again on the next day), or a duration like `12h` since it last succeeded.
+/// Operations without a cadence are never run by `rustic maintain`.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct MaintenanceSchedule {
+    /// How often to forget snapshots according to the retention policy
+    pub forget: Option<Cadence>,
+
+    /// How often to prune unreferenced data
+    pub prune: Option<Cadence>,
+
+    /// How often to check the repository for errors
+    pub check: Option<Cadence>,
+}
+
+impl MaintenanceSchedule {
+    /// Each operation and its cadence, in the order `rustic maintain` runs them
+    pub fn operations(&self) -> Vec<(&'static str, Option<Cadence>)> {
+        vec![
+            ("forget", self.forget),
+            ("prune", self.prune),
+            ("check", self.check),
+        ]
+    }
+}
+
+/// Checks to make before starting a backup, so that it isn't started only to fail partway through
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Preconditions {
+    /// How long to wait for the repository to become reachable, like `5m`. If it's still unreachable after that, the backup
+    /// is skipped and reported as unreachable rather than failed. Use `0s` to check once without waiting. Profiles with
+    /// `failover_repositories` don't wait, since the backup fails over instead.
+    #[serde(deserialize_with = "duration::deserialize_option")]
+    pub wait_for_repository: Option<Duration>,
+
+    /// Require each path the profile backs up from to be a mount point, like the directory an external disk is mounted on.
+    /// Without this, a backup only fails if one of those paths doesn't exist.
+    pub require_mountpoint: bool,
+
+    /// Skip the backup, instead of failing it, if a path the profile backs up from is missing or not mounted. This is
+    /// checked before the profile's hooks run, so it doesn't suit paths that a `pre_backup` hook mounts.
+    pub skip_missing_sources: bool,
+}
+
+/// Shell commands to run around a backup. Each command is run with the profile's base directory as its working directory.
+///
+/// Post-backup hooks (`post_backup`, `on_success`, and `on_failure`) are passed these environment variables:
+/// * `RUSTIC_PROFILE`: the name of the profile being backed up
+/// * `RUSTIC_REPOSITORY`: the repository that was backed up to, which may be one of `failover_repositories`
+/// * `RUSTIC_RESULT`: either `success` or `failure`
+/// * `RUSTIC_EXIT_STATUS`: Restic's exit code, or empty if Restic didn't run or was killed by a signal
+/// * `RUSTIC_DURATION`: how long the backup took, in seconds
+/// * `RUSTIC_SNAPSHOT_ID`: the ID of the new snapshot, if the backup succeeded
+/// * `RUSTIC_BYTES_ADDED`: how much new data was added to the repository, if the backup succeeded
+/// * `RUSTIC_ERROR`: the error message, if the backup failed
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Hooks {
+    /// Commands to run before starting the backup. If any of them fail, the backup is aborted.
+    pub pre_backup: Vec<String>,
+
+    /// Commands to run after every backup, whether or not it succeeded
+    pub post_backup: Vec<String>,
+
+    /// Commands to run after a backup fails
+    pub on_failure: Vec<String>,
+
+    /// Commands to run after a backup succeeds
+    pub on_success: Vec<String>,
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn test_load_fragments() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("conf.d")).unwrap();
+        fs::write(
+            dir.path().join("rustic.toml"),
+            r#"
+            include = ["conf.d/*.toml"]
+            restic_command = "/usr/bin/restic"
+            [defaults]
+            password = "hunter2"
+            base_directory = "/"
+            "#,
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("conf.d/10-home.toml"),
+            r#"
+            restic_command = "/usr/local/bin/restic"
+            [defaults]
+            base_directory = "/home"
+            [profiles.home]
+            repository = "local:/backups/home"
+            "#,
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("conf.d/20-system.toml"),
+            r#"
+            [profiles.system]
+            repository = "local:/backups/system"
+            [filesets.logs]
+            patterns = ["/var/log"]
+            "#,
+        )
+        .unwrap();
+        fs::write(dir.path().join("conf.d/README"), "not configuration").unwrap();
+
+        let config = Configuration::load(&dir.path().join("rustic.toml")).unwrap();
+        assert_eq!(config.restic_command, "/usr/local/bin/restic");
+        assert_eq!(config.profiles.len(), 2);
+        assert_eq!(config.profiles["home"].password.as_deref(), Some("hunter2"));
+        assert_eq!(config.profiles["system"].base_directory, Path::new("/home"));
+        assert!(config.filesets.contains_key("logs"));
+
+        // Loading the directory directly works too
+        let config = Configuration::load(&dir.path().join("conf.d")).unwrap();
+        assert_eq!(config.profiles.len(), 2);
+
+        fs::write(
+            dir.path().join("conf.d/30-duplicate.toml"),
+            r#"
+            [profiles.home]
+            repository = "local:/elsewhere"
+            "#,
+        )
+        .unwrap();
+        let err = Configuration::load(&dir.path().join("rustic.toml"))
+            .err()
+            .unwrap();
+        assert!(
+            err.to_string()
+                .starts_with("`profiles.home` is defined in both"),
+            "Unexpected error: {}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("*.toml", "home.toml"));
+        assert!(wildcard_match("??-*.toml", "10-home.toml"));
+        assert!(!wildcard_match("*.toml", "home.toml.bak"));
+        assert!(!wildcard_match("?.toml", ".toml"));
+    }
+
+    #[test]
+    fn test_defaults_and_inheritance() {
+        let config = Configuration::parse(
+            r#"
+            [defaults]
+            repository = "local:/backups/{profile}"
+            password_file = "/etc/rustic/password"
+            exclude_caches = true
+            environment = { B2_ACCOUNT_ID = "id", B2_ACCOUNT_KEY = "key" }
+            retention = { keep_daily = 7, keep_weekly = 4 }
+
+            [profiles.base]
+            base_directory = "/"
+            tags = ["base"]
+
+            [profiles.home]
+            inherits = "base"
+            repository = "b2:bucket:home"
+            base_directory = "/home"
+            tags = ["home"]
+            environment = { B2_ACCOUNT_KEY = "other" }
+            retention = { keep_daily = 14 }
+
+            [profiles.system]
+            inherits = "home"
+            base_directory = "/etc"
+            exclude_caches = false
+            "#,
+        )
+        .unwrap();
+
+        let base = &config.profiles["base"];
+        assert_eq!(base.repository, "local:/backups/base");
+        assert_eq!(base.password_file.as_deref(), Some("/etc/rustic/password"));
+        assert!(base.exclude_caches);
+        assert_eq!(base.retention.keep_daily, Some(7));
+
+        let home = &config.profiles["home"];
+        assert_eq!(home.repository, "b2:bucket:home");
+        assert_eq!(home.base_directory, Path::new("/home"));
+        assert_eq!(home.tags, vec!["home".to_string()]);
+        assert_eq!(home.environment["B2_ACCOUNT_ID"], "id");
+        assert_eq!(home.environment["B2_ACCOUNT_KEY"], "other");
+        assert_eq!(home.retention.keep_daily, Some(14));
+        assert_eq!(home.retention.keep_weekly, Some(4));
+
+        let system = &config.profiles["system"];
+        assert_eq!(system.repository, "b2:bucket:home");
+        assert_eq!(system.base_directory, Path::new("/etc"));
+        assert!(!system.exclude_caches);
+        assert_eq!(system.retention.keep_daily, Some(14));
+    }
+
+    #[test]
+    fn test_inheritance_errors() {
+        let cycle = Configuration::parse(
+            r#"
+            [profiles.a]
+            inherits = "b"
+            [profiles.b]
+            inherits = "a"
+            "#,
+        )
+        .err()
+        .unwrap();
+        assert!(
+            cycle.to_string().contains("cycle: a -> b -> a"),
+            "Unexpected error: {}",
+            cycle
+        );
+
+        let missing = Configuration::parse(
+            r#"
+            [profiles.a]
+            inherits = "nope"
+            "#,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            missing.to_string(),
+            "Profile `a` inherits from `nope`, which does not exist"
+        );
+
+        let defaults = Configuration::parse(
+            r#"
+            [defaults]
+            inherits = "a"
+            [profiles.a]
+            repository = "local:/backups"
+            "#,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            defaults.to_string(),
+            "`inherits` can't be set in `[defaults]`, only in a profile"
+        );
+    }
+
+    #[test]
+    fn test_unknown_keys() {
+        let typo = Configuration::parse(
+            r#"
+            [profiles.home]
+            repository = "local:/backups"
+            base_directory = "/home"
+            password = "hunter2"
+            retention = { keep_dailly = 7 }
+            "#,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            typo.to_string(),
+            "Unknown configuration key `profiles.home.retention.keep_dailly` (did you mean `keep_daily`?)"
+        );
+
+        let unrelated = Configuration::parse(
+            r#"
+            profiles = {}
+            color = "blue"
+            "#,
+        )
+        .err()
+        .unwrap();
+        assert!(
+            unrelated
+                .to_string()
+                .starts_with("Unknown configuration key `color`, expected one of `profiles`"),
+            "Unexpected error: {}",
+            unrelated
+        );
+    }
+
+    #[test]
+    fn test_profile_groups() {
+        let config = Configuration::parse(
+            r#"
+            [defaults]
+            repository = "local:/backups/{profile}"
+            password = "hunter2"
+            base_directory = "/"
+
+            [profiles.home]
+            [profiles.system]
+            [profiles.photos]
+
+            [groups]
+            nightly = ["home", "system"]
+            media = ["photos"]
+            "#,
+        )
+        .unwrap();
+
+        let names = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            config
+                .expand_profiles(&names(&["@nightly", "photos", "home"]))
+                .unwrap(),
+            names(&["home", "system", "photos"])
+        );
+        assert_eq!(config.resolve_profile_name("@media").unwrap(), "photos");
+        assert_eq!(config.resolve_profile_name("home").unwrap(), "home");
+        assert_eq!(
+            config
+                .resolve_profile_name("@nightly")
+                .unwrap_err()
+                .to_string(),
+            "Group `nightly` has 2 profiles, but only one profile can be used here"
+        );
+        assert_eq!(
+            config
+                .expand_profiles(&names(&["@weekly"]))
+                .unwrap_err()
+                .to_string(),
+            "Group `weekly` does not exist"
+        );
+    }
+
+    #[test]
+    fn test_repository_list() {
+        let config = Configuration::parse(
+            r#"
+            [profiles.laptop]
+            repository = ["rest:http://nas.lan:8000/{profile}", "s3:s3.amazonaws.com/bucket/{profile}"]
+            base_directory = "/home"
+            password = "hunter2"
+            "#,
+        )
+        .unwrap();
+        let laptop = &config.profiles["laptop"];
+        assert_eq!(laptop.repository, "rest:http://nas.lan:8000/laptop");
+        assert_eq!(
+            laptop.failover_repositories,
+            vec!["s3:s3.amazonaws.com/bucket/laptop"]
+        );
+    }
+
+    #[test]
+    fn test_rclone() {
+        let config = Configuration::parse(
+            r#"
+            [profiles.laptop]
+            base_directory = "/home"
+            password = "hunter2"
+            rclone = { remote = "gdrive:backups/{profile}", bwlimit = "08:00,512k 19:00,off" }
+            "#,
+        )
+        .unwrap();
+        let laptop = &config.profiles["laptop"];
+        assert_eq!(laptop.repository, "rclone:gdrive:backups/laptop");
+        assert!(Configuration::parse(
+            r#"
+            [profiles.laptop]
+            repository = "local:/backups"
+            base_directory = "/home"
+            password = "hunter2"
+            rclone = { remote = "gdrive:backups" }
+            "#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_backend_buckets() {
+        let config = Configuration::parse(
+            r#"
+            [profiles.minio]
+            base_directory = "/home"
+            password = "hunter2"
+            s3 = { access_key_id = "minio", secret_access_key = "secret", bucket = "backups/{profile}", endpoint = "https://minio.lan:9000/" }
+
+            [profiles.aws]
+            base_directory = "/home"
+            password = "hunter2"
+            s3 = { access_key_id = "AKIA", secret_access_key_file = "aws-secret", bucket = "backups" }
+
+            [profiles.b2]
+            base_directory = "/home"
+            password = "hunter2"
+            b2 = { account_id = "0012ab", account_key = "K001", bucket = "backups:laptop" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.profiles["minio"].repository,
+            "s3:https://minio.lan:9000/backups/minio"
+        );
+        assert_eq!(
+            config.profiles["aws"].repository,
+            "s3:s3.amazonaws.com/backups"
+        );
+        assert_eq!(config.profiles["b2"].repository, "b2:backups:laptop");
+    }
+
+    #[test]
+    fn test_rest() {
+        let config = Configuration::parse(
+            r#"
+            [profiles.laptop]
+            base_directory = "/home"
+            password = "hunter2"
+            rest = { url = "https://nas.lan:8000/{profile}", username = "laptop", password_file = "rest-password", ca_cert = "nas.pem" }
+            "#,
+        )
+        .unwrap();
+        let laptop = &config.profiles["laptop"];
+        assert_eq!(laptop.repository, "rest:https://nas.lan:8000/laptop");
+        assert_eq!(
+            laptop.rest.as_ref().unwrap().username.as_deref(),
+            Some("laptop")
+        );
+    }
+}
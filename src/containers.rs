@@ -0,0 +1,260 @@
+//! Pausing or stopping containers while backing up, so their data is backed up in a consistent state
+
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use slog::{error, info, warn, Logger};
+
+use crate::format::format_duration;
+#[cfg(unix)]
+use crate::platform::signals;
+use crate::restic::Restic;
+
+/// Container runtime used if a profile doesn't set one
+const DEFAULT_RUNTIME: &str = "docker";
+
+/// How long containers may stay down if a profile doesn't set `max_downtime`
+const DEFAULT_MAX_DOWNTIME: Duration = Duration::from_secs(60 * 60);
+
+/// How a container was quiesced, and so how to resume it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Pause,
+    Stop,
+}
+
+impl Action {
+    /// Runtime subcommand that quiesces a container
+    fn command(self) -> &'static str {
+        match self {
+            Action::Pause => "pause",
+            Action::Stop => "stop",
+        }
+    }
+
+    /// Runtime subcommand that undoes `command`
+    fn undo_command(self) -> &'static str {
+        match self {
+            Action::Pause => "unpause",
+            Action::Stop => "start",
+        }
+    }
+}
+
+/// Containers that have been paused or stopped, in the order they were quiesced
+struct Quiesced {
+    logger: Logger,
+    runtime: String,
+    containers: Vec<(Action, String)>,
+}
+
+impl Quiesced {
+    /// Pauses or stops a container, remembering it so it's resumed later
+    fn quiesce(&mut self, action: Action, container: &str) -> Result<()> {
+        info!(
+            self.logger,
+            "Running {} {} {}",
+            self.runtime,
+            action.command(),
+            container
+        );
+        run_runtime(&self.runtime, action.command(), container)?;
+        self.containers.push((action, container.to_string()));
+        Ok(())
+    }
+
+    /// Resumes every quiesced container in reverse order. Failures are logged rather than returned, so that one container
+    /// failing to come back doesn't leave the rest down.
+    fn resume(&mut self) {
+        while let Some((action, container)) = self.containers.pop() {
+            info!(
+                self.logger,
+                "Running {} {} {}",
+                self.runtime,
+                action.undo_command(),
+                container
+            );
+            if let Err(err) = run_runtime(&self.runtime, action.undo_command(), &container) {
+                error!(
+                    self.logger,
+                    "Could not resume container {}: {:#}", container, err
+                );
+            }
+        }
+    }
+}
+
+/// Guard for containers quiesced during a backup. Dropping it resumes the containers, so they come back up however the
+/// backup ends. A watchdog thread also resumes them once `max_downtime` passes, if the backup is still running.
+pub struct QuiescedContainers {
+    finished: Option<Sender<()>>,
+    watchdog: Option<JoinHandle<()>>,
+}
+
+impl Drop for QuiescedContainers {
+    fn drop(&mut self) {
+        // Dropping the sender wakes up the watchdog, which resumes the containers unless it already has
+        self.finished.take();
+        if let Some(watchdog) = self.watchdog.take() {
+            let _ = watchdog.join();
+        }
+        release_signals();
+    }
+}
+
+/// Extends the Restic wrapper with quiescing containers
+impl<'a> Restic<'a> {
+    /// Pauses and stops the profile's containers, returning a guard that resumes them when dropped. Returns `None` if the
+    /// profile has no containers to quiesce. If any container can't be quiesced, the ones that already were are resumed
+    /// before returning the error.
+    pub fn quiesce_containers(&self) -> Result<Option<QuiescedContainers>> {
+        let config = &self.profile().containers;
+        if config.is_empty() {
+            return Ok(None);
+        }
+        let runtime = config.runtime.as_deref().unwrap_or(DEFAULT_RUNTIME);
+        if self.is_dry_run() {
+            for container in config.stop.iter() {
+                println!("Would run: {} stop {}", runtime, container);
+            }
+            for container in config.pause.iter() {
+                println!("Would run: {} pause {}", runtime, container);
+            }
+            return Ok(None);
+        }
+
+        // Hold off on termination signals until the containers are resumed. Restic is in the same process group, so an
+        // interrupt still stops the backup, after which the guard is dropped as usual.
+        #[cfg(unix)]
+        signals::install();
+
+        let mut quiesced = Quiesced {
+            logger: self.logger().clone(),
+            runtime: runtime.to_string(),
+            containers: Vec::new(),
+        };
+        let actions = config
+            .stop
+            .iter()
+            .map(|container| (Action::Stop, container))
+            .chain(
+                config
+                    .pause
+                    .iter()
+                    .map(|container| (Action::Pause, container)),
+            );
+        for (action, container) in actions {
+            if let Err(err) = quiesced.quiesce(action, container) {
+                quiesced.resume();
+                release_signals();
+                return Err(err.context("Could not quiesce containers, aborting backup"));
+            }
+        }
+
+        let max_downtime = config.max_downtime.unwrap_or(DEFAULT_MAX_DOWNTIME);
+        let (finished, wait_for_finish) = mpsc::channel::<()>();
+        let watchdog = thread::spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) = wait_for_finish.recv_timeout(max_downtime) {
+                warn!(
+                    quiesced.logger,
+                    "Containers have been down for {}, resuming them before the backup finishes. The backup may not be consistent.",
+                    format_duration(max_downtime)
+                );
+            }
+            quiesced.resume();
+        });
+
+        Ok(Some(QuiescedContainers {
+            finished: Some(finished),
+            watchdog: Some(watchdog),
+        }))
+    }
+}
+
+/// Stops holding back termination signals. Now that the containers are back up, a signal received while they were down
+/// makes Rustic exit the way it would have.
+#[cfg(unix)]
+fn release_signals() {
+    // If other backups running in parallel still have containers down, the last of them handles the signal instead
+    if signals::restore() {
+        if let Some(signal) = signals::take() {
+            signals::raise(signal);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn release_signals() {}
+
+/// Runs a container runtime subcommand on a single container
+fn run_runtime(runtime: &str, subcommand: &str, container: &str) -> Result<()> {
+    let mut cmd = Command::new(runtime);
+    cmd.arg(subcommand).arg(container).stdin(Stdio::null());
+    let output = cmd
+        .output()
+        .with_context(|| format!("Could not run {}", runtime))?;
+    if !output.status.success() {
+        bail!(
+            "`{} {} {}` failed with {}: {}",
+            runtime,
+            subcommand,
+            container,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    use crate::config::Containers;
+    use crate::test::TestFixture;
+
+    #[test]
+    fn test_quiesce_containers() {
+        let mut fixture = TestFixture::new();
+        let runtime = fixture.content_root().join("runtime");
+        let log = fixture.content_root().join("runtime.log");
+        fs::write(
+            &runtime,
+            format!(
+                "#!/bin/sh\necho \"$@\" >> '{}'\n[ \"$2\" != broken ]\n",
+                log.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&runtime, fs::Permissions::from_mode(0o755)).unwrap();
+
+        fixture.profile_mut().containers = Containers {
+            runtime: Some(runtime.to_string_lossy().into_owned()),
+            pause: vec!["web".to_string()],
+            stop: vec!["db".to_string()],
+            max_downtime: None,
+        };
+        let containers = fixture.restic().quiesce_containers().unwrap();
+        assert!(containers.is_some());
+        assert_eq!(fs::read_to_string(&log).unwrap(), "stop db\npause web\n");
+        drop(containers);
+        assert_eq!(
+            fs::read_to_string(&log).unwrap(),
+            "stop db\npause web\nunpause web\nstart db\n"
+        );
+
+        // Containers that were already paused are resumed if a later one can't be
+        fs::remove_file(&log).unwrap();
+        fixture.profile_mut().containers.stop = vec![];
+        fixture.profile_mut().containers.pause = vec!["web".to_string(), "broken".to_string()];
+        assert!(fixture.restic().quiesce_containers().is_err());
+        assert_eq!(
+            fs::read_to_string(&log).unwrap(),
+            "pause web\npause broken\nunpause web\n"
+        );
+    }
+}
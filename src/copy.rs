@@ -0,0 +1,67 @@
+//! Copying snapshots to a secondary repository
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::time::Instant;
+
+use anyhow::{bail, Context, Result};
+use itertools::join;
+use slog::{error, info};
+
+use crate::redact;
+use crate::restic::{add_password_source, Restic};
+
+/// Extends the Restic wrapper with support for copying snapshots between repositories
+impl<'a> Restic<'a> {
+    /// Copies snapshots from this profile's repository to its `copy_to` repository. Snapshots that were already copied are
+    /// skipped. If the profile has tags, only snapshots with those tags are copied.
+    pub fn copy(&self) -> Result<()> {
+        let target = match self.profile().copy_to {
+            Some(ref target) => target,
+            None => bail!("Profile does not have a `copy_to` repository"),
+        };
+
+        // The copy target is the main repository from Restic's perspective, and this profile's repository is the source
+        let mut args: Vec<OsString> = vec!["--repo".into(), target.repository.clone().into()];
+        let mut env: HashMap<OsString, OsString> = self.env().clone();
+        env.remove(&OsString::from("RESTIC_PASSWORD"));
+        for (var, value) in target.environment.iter() {
+            env.insert(var.into(), value.into());
+        }
+        add_password_source(target.password_settings(), false, &mut args, &mut env)
+            .context("Invalid `copy_to` password")?;
+
+        args.push(self.verbosity_flag().into());
+        args.push("copy".into());
+        args.push("--from-repo".into());
+        args.push(self.repository().into());
+        add_password_source(
+            self.profile().password_settings(),
+            true,
+            &mut args,
+            &mut env,
+        )?;
+
+        let tags = &self.profile().tags;
+        if !tags.is_empty() {
+            args.push("--tag".into());
+            args.push(join(tags, ",").into());
+        }
+
+        let mut cmd = self.bare_command();
+        cmd.args(&args).envs(&env);
+
+        info!(self.logger(), "Copying snapshots"; "destination" => %redact::url(&target.repository), "command" => %redact::command_line(&cmd));
+        let start = Instant::now();
+        let status = self.execute(&mut cmd, None)?.status;
+        let duration = Instant::now() - start;
+
+        if status.success() {
+            info!(self.logger(), "Copied snapshots in {:?}", duration; "command" => %redact::command_line(&cmd));
+            Ok(())
+        } else {
+            error!(self.logger(), "Copying snapshots failed"; "status" => %status, "command" => %redact::command_line(&cmd));
+            Err(self.failed("copy", status).into())
+        }
+    }
+}
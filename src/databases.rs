@@ -0,0 +1,170 @@
+//! Dumping PostgreSQL and MySQL databases, so that their dumps can be piped into Restic
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{Context, Result};
+
+use crate::config::{Database, Databases};
+use crate::restic::StdinCommand;
+
+/// Database servers Rustic knows how to dump
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DatabaseKind {
+    Postgresql,
+    Mysql,
+}
+
+impl DatabaseKind {
+    /// Name of the kind of database, which is also used to tag its snapshots
+    pub fn name(self) -> &'static str {
+        match self {
+            DatabaseKind::Postgresql => "postgresql",
+            DatabaseKind::Mysql => "mysql",
+        }
+    }
+
+    /// Tool that dumps this kind of database
+    fn dump_tool(self) -> &'static str {
+        match self {
+            DatabaseKind::Postgresql => "pg_dump",
+            DatabaseKind::Mysql => "mysqldump",
+        }
+    }
+
+    /// Environment variable the dump tool reads its password from
+    fn password_variable(self) -> &'static str {
+        match self {
+            DatabaseKind::Postgresql => "PGPASSWORD",
+            DatabaseKind::Mysql => "MYSQL_PWD",
+        }
+    }
+}
+
+/// All of a profile's databases, along with what kind each one is
+pub fn all(databases: &Databases) -> impl Iterator<Item = (DatabaseKind, &Database)> {
+    databases
+        .postgresql
+        .iter()
+        .map(|database| (DatabaseKind::Postgresql, database))
+        .chain(
+            databases
+                .mysql
+                .iter()
+                .map(|database| (DatabaseKind::Mysql, database)),
+        )
+}
+
+/// File name a database's dump is stored as in its snapshot, like `postgresql-gitea.sql`
+pub fn dump_filename(kind: DatabaseKind, database: &Database) -> String {
+    format!("{}-{}.sql", kind.name(), database.name)
+}
+
+/// Command that writes a plain SQL dump of `database` to stdout. Plain SQL is larger than the compressed formats the dump
+/// tools also offer, but deduplicates well from one backup to the next.
+pub fn dump_command(
+    kind: DatabaseKind,
+    database: &Database,
+    base_directory: &Path,
+) -> Result<StdinCommand> {
+    let mut cmd = Command::new(kind.dump_tool());
+    cmd.current_dir(base_directory);
+    match kind {
+        // Never prompt for a password, since there's nobody to answer
+        DatabaseKind::Postgresql => cmd.arg("--no-password"),
+        // Dump InnoDB tables from a consistent snapshot without locking them
+        DatabaseKind::Mysql => cmd.arg("--single-transaction"),
+    };
+    if let Some(ref host) = database.host {
+        cmd.arg(format!("--host={}", host));
+    }
+    if let Some(port) = database.port {
+        cmd.arg(format!("--port={}", port));
+    }
+    if let Some(ref user) = database.user {
+        match kind {
+            DatabaseKind::Postgresql => cmd.arg(format!("--username={}", user)),
+            DatabaseKind::Mysql => cmd.arg(format!("--user={}", user)),
+        };
+    }
+    cmd.args(&database.dump_options);
+    match kind {
+        DatabaseKind::Postgresql => cmd.arg(format!("--dbname={}", database.name)),
+        DatabaseKind::Mysql => cmd.arg(&database.name),
+    };
+
+    // Passwords go through the environment, so they don't show up in the process list
+    if let Some(ref password_file) = database.password_file {
+        let password_file = base_directory.join(password_file);
+        let password = fs::read_to_string(&password_file).with_context(|| {
+            format!(
+                "Could not read password file {} for database {}",
+                password_file.display(),
+                database.name
+            )
+        })?;
+        cmd.env(
+            kind.password_variable(),
+            password.trim_end_matches(&['\r', '\n'][..]),
+        );
+    }
+
+    Ok(StdinCommand::new(
+        cmd,
+        format!("{} of database `{}`", kind.dump_tool(), database.name),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn test_dump_command() {
+        let mut database = Database {
+            name: "gitea".to_string(),
+            host: Some("db.internal".to_string()),
+            port: None,
+            user: Some("backup".to_string()),
+            password_file: None,
+            dump_options: vec!["--exclude-table=audit_log".to_string()],
+        };
+        let args = |kind, database: &Database| {
+            let stdin = dump_command(kind, database, Path::new("/")).unwrap();
+            stdin
+                .command()
+                .get_args()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            args(DatabaseKind::Postgresql, &database),
+            vec![
+                "--no-password",
+                "--host=db.internal",
+                "--username=backup",
+                "--exclude-table=audit_log",
+                "--dbname=gitea"
+            ]
+        );
+
+        database.dump_options = vec!["--ignore-table=gitea.audit_log".to_string()];
+        assert_eq!(
+            args(DatabaseKind::Mysql, &database),
+            vec![
+                "--single-transaction",
+                "--host=db.internal",
+                "--user=backup",
+                "--ignore-table=gitea.audit_log",
+                "gitea"
+            ]
+        );
+        assert_eq!(
+            dump_filename(DatabaseKind::Mysql, &database),
+            "mysql-gitea.sql"
+        );
+    }
+}
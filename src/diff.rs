@@ -0,0 +1,174 @@
+//! Comparing snapshots
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use slog::{debug, warn};
+
+use crate::format::format_bytes;
+use crate::json;
+use crate::output::{self, OutputFormat};
+use crate::redact;
+use crate::restic::Restic;
+use crate::snapshots::SnapshotFilter;
+
+/// A changed path, from a `change` message in `restic diff --json` output
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Change {
+    /// Path that changed
+    pub path: String,
+
+    /// How the path changed: `+` (added), `-` (removed), `M` (content modified), `T` (type changed), or `U` (metadata
+    /// updated)
+    pub modifier: String,
+}
+
+/// Totals for added or removed items in a diff
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DiffCounts {
+    /// Number of regular files
+    pub files: u64,
+
+    /// Number of directories
+    pub dirs: u64,
+
+    /// Number of other entries, like symlinks or devices
+    pub others: u64,
+
+    /// Total size of the data
+    pub bytes: u64,
+}
+
+/// Summary of a diff, from the `statistics` message in `restic diff --json` output
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DiffStatistics {
+    /// Number of files whose contents changed
+    pub changed_files: u64,
+
+    /// What was only in the second snapshot
+    pub added: DiffCounts,
+
+    /// What was only in the first snapshot
+    pub removed: DiffCounts,
+}
+
+/// Differences between two snapshots
+#[derive(Debug, Serialize)]
+pub struct Diff {
+    /// ID of the snapshot compared from
+    pub from: String,
+
+    /// ID of the snapshot compared to
+    pub to: String,
+
+    /// Every changed path
+    pub changes: Vec<Change>,
+
+    /// Totals for the whole diff
+    pub statistics: DiffStatistics,
+}
+
+/// Extends the Restic wrapper with snapshot comparison
+impl<'a> Restic<'a> {
+    /// Compares two snapshots. If `to` is not given, `from` is compared to the latest snapshot, and if neither is given, the
+    /// two latest snapshots are compared. Only snapshots with the profile's tags are considered when picking the latest ones.
+    pub fn diff(&self, from: Option<String>, to: Option<String>) -> Result<Diff> {
+        let (from, to) = match (from, to) {
+            (Some(from), Some(to)) => (from, to),
+            (from, None) => {
+                let mut filter = SnapshotFilter::default();
+                if !self.profile().tags.is_empty() {
+                    filter.tags.push(self.profile().tags.join(","));
+                }
+                let snapshots = self.list_snapshots(&filter, &[])?;
+                let mut latest = snapshots.iter().rev().map(|s| s.id.clone());
+                match from {
+                    Some(from) => match latest.next() {
+                        Some(to) => (from, to),
+                        None => bail!("Repository has no snapshots"),
+                    },
+                    None => match (latest.next(), latest.next()) {
+                        (Some(to), Some(from)) => (from, to),
+                        _ => bail!("Need at least two snapshots to compare"),
+                    },
+                }
+            }
+            (None, Some(_)) => unreachable!("Second snapshot given without the first"),
+        };
+
+        let mut cmd = self.new_command();
+        cmd.arg("diff").arg("--json").arg(&from).arg(&to);
+        debug!(self.logger(), "Comparing snapshots"; "command" => %redact::command_line(&cmd));
+        let output = self
+            .execute_output(&mut cmd)
+            .context("Comparing snapshots failed")?;
+
+        let mut diff = Diff {
+            from,
+            to,
+            changes: Vec::new(),
+            statistics: DiffStatistics::default(),
+        };
+        for line in output.lines() {
+            let message = match json::parse(line) {
+                Ok(message) => message,
+                Err(_) => continue,
+            };
+            let parsed = match message.get("message_type").and_then(|t| t.as_str()) {
+                Some("change") => message.try_into().map(|change| diff.changes.push(change)),
+                Some("statistics") => message
+                    .try_into()
+                    .map(|statistics| diff.statistics = statistics),
+                _ => Ok(()),
+            };
+            if let Err(err) = parsed {
+                warn!(self.logger(), "Could not parse `restic diff` output: {}", err; "line" => line);
+            }
+        }
+
+        Ok(diff)
+    }
+}
+
+/// Prints a diff. With `summary_only`, the list of changed paths is left out of table output.
+pub fn print_diff(diff: &Diff, output: OutputFormat, summary_only: bool) -> Result<()> {
+    if output == OutputFormat::Json {
+        return output::print_json(diff);
+    }
+
+    println!(
+        "Comparing snapshot {} to {}:",
+        short(&diff.from),
+        short(&diff.to)
+    );
+    if !summary_only {
+        for change in diff.changes.iter() {
+            println!("{:<4} {}", change.modifier, change.path);
+        }
+        println!();
+    }
+
+    let stats = &diff.statistics;
+    println!(
+        "Added:    {} files, {} directories, {} others, {}",
+        stats.added.files,
+        stats.added.dirs,
+        stats.added.others,
+        format_bytes(stats.added.bytes)
+    );
+    println!(
+        "Removed:  {} files, {} directories, {} others, {}",
+        stats.removed.files,
+        stats.removed.dirs,
+        stats.removed.others,
+        format_bytes(stats.removed.bytes)
+    );
+    println!("Modified: {} files", stats.changed_files);
+
+    Ok(())
+}
+
+fn short(id: &str) -> &str {
+    &id[..id.len().min(8)]
+}
@@ -0,0 +1,326 @@
+//! Diagnosing a misbehaving backup machine: whether the configuration loads, Restic runs, the cache is writable, and each
+//! profile's password and repository can be reached
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use slog::Logger;
+
+use crate::config::{self, Configuration, Profile};
+use crate::hooks;
+use crate::install;
+use crate::keyring;
+use crate::prompt;
+use crate::redact;
+use crate::restic::{password_sources, RepositoryState, Restic};
+use crate::validate::{self, Severity};
+use crate::version::ResticVersion;
+
+/// Result of a single check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl Outcome {
+    /// Label for the outcome in reports
+    pub fn label(self) -> &'static str {
+        match self {
+            Outcome::Pass => "PASS",
+            Outcome::Warn => "WARN",
+            Outcome::Fail => "FAIL",
+        }
+    }
+}
+
+/// Outcome of a single check, with details explaining it
+#[derive(Debug, Serialize)]
+pub struct Diagnosis {
+    /// What was checked, like `Profile home repository`
+    pub check: String,
+    pub outcome: Outcome,
+    pub detail: String,
+}
+
+impl Diagnosis {
+    fn new(check: impl Into<String>, result: Result<String>) -> Diagnosis {
+        match result {
+            Ok(detail) => Diagnosis {
+                check: check.into(),
+                outcome: Outcome::Pass,
+                detail,
+            },
+            Err(err) => Diagnosis {
+                check: check.into(),
+                outcome: Outcome::Fail,
+                detail: format!("{:#}", err),
+            },
+        }
+    }
+
+    fn warning(check: impl Into<String>, detail: impl Into<String>) -> Diagnosis {
+        Diagnosis {
+            check: check.into(),
+            outcome: Outcome::Warn,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// Runs every check, in order. `config_file` is the configuration file to load, or `None` for the default one.
+/// `profiles` limits the profile checks to certain profiles or groups, or checks every profile if it's empty.
+pub fn diagnose(
+    logger: &Logger,
+    config_file: Option<&Path>,
+    profiles: &[String],
+    verbosity: u8,
+) -> Vec<Diagnosis> {
+    let mut report = Vec::new();
+
+    let loaded = match config_file {
+        Some(path) => Ok(path.to_path_buf()),
+        None => config::find_default_config(),
+    }
+    .and_then(|path| Configuration::load(&path).map(|config| (path, config)));
+    let config = match loaded {
+        Ok((path, config)) => {
+            report.push(Diagnosis::new(
+                "Configuration",
+                Ok(format!("Loaded {}", path.display())),
+            ));
+            config
+        }
+        Err(err) => {
+            report.push(Diagnosis::new("Configuration", Err(err)));
+            // Still check the Restic on the PATH, since that's what a fixed configuration will most likely use
+            report.push(Diagnosis::new("Restic", check_restic("restic")));
+            return report;
+        }
+    };
+
+    report.push(check_validation(&config));
+
+    let mut restic_commands = vec![&config.restic_command];
+    for profile in config.profiles.values() {
+        if let Some(ref command) = profile.restic_command {
+            if !restic_commands.contains(&command) {
+                restic_commands.push(command);
+            }
+        }
+    }
+    for command in restic_commands {
+        report.push(Diagnosis::new(
+            format!("Restic ({})", command),
+            check_restic(command),
+        ));
+    }
+
+    report.push(Diagnosis::new(
+        "Cache directory",
+        check_cache_directory(&config),
+    ));
+
+    let names = if profiles.is_empty() {
+        let mut names: Vec<String> = config.profiles.keys().cloned().collect();
+        names.sort();
+        names
+    } else {
+        match config.expand_profiles(profiles) {
+            Ok(names) => names,
+            Err(err) => {
+                report.push(Diagnosis::new("Profiles", Err(err)));
+                return report;
+            }
+        }
+    };
+    for name in names {
+        let profile = &config.profiles[&name];
+        let prompts = prompts_for_password(profile);
+        report.push(if prompts {
+            Diagnosis::warning(
+                format!("Profile {} password", name),
+                "The password is prompted for, so unattended backups will fail",
+            )
+        } else {
+            Diagnosis::new(
+                format!("Profile {} password", name),
+                check_password(profile),
+            )
+        });
+        report.push(if prompts && !prompt::can_prompt() {
+            Diagnosis::warning(
+                format!("Profile {} repository", name),
+                "Not checked, since there's no terminal to prompt for the password on",
+            )
+        } else {
+            Diagnosis::new(
+                format!("Profile {} repository", name),
+                Restic::for_profile(&config, logger, name.clone(), verbosity)
+                    .and_then(|restic| check_repository(&restic)),
+            )
+        });
+    }
+
+    report
+}
+
+/// Validates the configuration, failing on errors and warning about warnings
+fn check_validation(config: &Configuration) -> Diagnosis {
+    let problems = validate::validate(config);
+    let errors: Vec<&str> = problems
+        .iter()
+        .filter(|problem| problem.severity == Severity::Error)
+        .map(|problem| problem.message.as_str())
+        .collect();
+    let (outcome, detail) = if !errors.is_empty() {
+        (
+            Outcome::Fail,
+            format!(
+                "{} error{}, including: {}. Run `rustic config check` for all of them.",
+                errors.len(),
+                if errors.len() == 1 { "" } else { "s" },
+                errors[0]
+            ),
+        )
+    } else if !problems.is_empty() {
+        (
+            Outcome::Warn,
+            format!(
+                "{} warning{}. Run `rustic config check` to see them.",
+                problems.len(),
+                if problems.len() == 1 { "" } else { "s" }
+            ),
+        )
+    } else {
+        (Outcome::Pass, "No problems found".to_string())
+    };
+    Diagnosis {
+        check: "Configuration is valid".to_string(),
+        outcome,
+        detail,
+    }
+}
+
+/// Checks that a Restic command runs, and reports its version
+fn check_restic(command: &str) -> Result<String> {
+    let path = install::resolve_restic_command(command)?;
+    let output = Command::new(&path)
+        .arg("version")
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| format!("Could not run {}", path.to_string_lossy()))?;
+    if !output.status.success() {
+        bail!(
+            "`{} version` failed with {}",
+            path.to_string_lossy(),
+            output.status
+        );
+    }
+    let version = ResticVersion::from_version_output(&String::from_utf8_lossy(&output.stdout))?;
+    Ok(format!("Restic {} at {}", version, path.to_string_lossy()))
+}
+
+/// Checks that Rustic can write to Restic's cache directory, creating it if needed
+fn check_cache_directory(config: &Configuration) -> Result<String> {
+    let directory: PathBuf = match config.cache_directory {
+        Some(ref directory) => directory.into(),
+        None => match dirs::cache_dir() {
+            Some(cache_dir) => cache_dir.join("restic"),
+            None => {
+                bail!("Could not determine the default cache directory. Set `cache_directory`.")
+            }
+        },
+    };
+    fs::create_dir_all(&directory)
+        .with_context(|| format!("Could not create {}", directory.display()))?;
+    tempfile::tempfile_in(&directory)
+        .with_context(|| format!("Could not write to {}", directory.display()))?;
+    Ok(format!("{} is writable", directory.display()))
+}
+
+/// Checks that the password sources Restic reads itself produce a password. Sources Rustic reads, like password
+/// managers, are checked when setting up the profile for the repository check.
+fn check_password(profile: &Profile) -> Result<String> {
+    let password = if let Some(ref password_file) = profile.password_file {
+        let path = profile.base_directory.join(password_file);
+        fs::read_to_string(&path)
+            .with_context(|| format!("Could not read password file {}", path.display()))?
+    } else if let Some(ref password_command) = profile.password_command {
+        let output = hooks::shell_command(password_command)
+            .current_dir(&profile.base_directory)
+            .stdin(Stdio::null())
+            .output()
+            .with_context(|| format!("Could not run `{}`", password_command))?;
+        if !output.status.success() {
+            bail!("`{}` failed with {}", password_command, output.status);
+        }
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    } else if let Some(ref entry) = profile.password_keyring {
+        keyring::lookup(entry)?
+    } else {
+        return Ok("Password is set".to_string());
+    };
+
+    if password.trim_end_matches(&['\r', '\n'][..]).is_empty() {
+        bail!("The password is empty");
+    }
+    Ok("Password is set".to_string())
+}
+
+/// Whether Rustic prompts for the profile's password, because it's configured to or there's no other source
+fn prompts_for_password(profile: &Profile) -> bool {
+    let settings = profile.password_settings();
+    settings.password_prompt || password_sources(settings).is_empty()
+}
+
+/// Checks that the repository can be reached and exists
+fn check_repository(restic: &Restic) -> Result<String> {
+    match restic.repository_state()? {
+        RepositoryState::Exists => Ok(format!("{} exists", redact::url(restic.repository()))),
+        RepositoryState::Missing => bail!(
+            "{} does not exist. Run `rustic init {}` to create it.",
+            redact::url(restic.repository()),
+            restic.profile_name()
+        ),
+        RepositoryState::Unreachable(reason) => {
+            bail!(
+                "Could not reach {}: {}",
+                redact::url(restic.repository()),
+                reason
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::check_password;
+    use crate::test::TestFixture;
+
+    #[test]
+    fn test_check_password() {
+        let mut fixture = TestFixture::new();
+        fs::write(fixture.content_root().join("password"), "hunter2\n").unwrap();
+        fs::write(fixture.content_root().join("empty"), "\n").unwrap();
+
+        let mut check = |password_file: &str| {
+            fixture.profile_mut().password = None;
+            fixture.profile_mut().password_file = Some(password_file.to_string());
+            check_password(fixture.profile())
+        };
+        assert!(check("password").is_ok());
+        assert_eq!(
+            check("empty").unwrap_err().to_string(),
+            "The password is empty"
+        );
+        assert!(check("missing").is_err());
+    }
+}
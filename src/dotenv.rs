@@ -0,0 +1,120 @@
+//! Reading `.env` files, the `KEY=value` format most tools already keep backend credentials in
+
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+
+/// Parses the variables in a `.env` file. Blank lines and lines starting with `#` are skipped, and a leading `export` is
+/// ignored. Values may be unquoted (with trailing `# comments` removed), single-quoted (taken literally), or
+/// double-quoted (where `\n`, `\"`, and `\\` are unescaped). Variable references like `${HOME}` are not expanded.
+pub fn parse(contents: &str) -> Result<HashMap<String, String>> {
+    let mut vars = HashMap::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line).trim_start();
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => bail!("Line {}: expected KEY=value", number + 1),
+        };
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            bail!(
+                "Line {}: `{}` is not a valid variable name",
+                number + 1,
+                key
+            );
+        }
+        let value =
+            parse_value(value).map_err(|err| err.context(format!("Line {}", number + 1)))?;
+        vars.insert(key.to_string(), value);
+    }
+    Ok(vars)
+}
+
+/// Parses the part of a line after the `=`
+fn parse_value(value: &str) -> Result<String> {
+    if let Some(rest) = value.strip_prefix('\'') {
+        match rest.find('\'') {
+            Some(end) => Ok(rest[..end].to_string()),
+            None => bail!("Unterminated single quote"),
+        }
+    } else if let Some(rest) = value.strip_prefix('"') {
+        let mut parsed = String::new();
+        let mut chars = rest.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => return Ok(parsed),
+                '\\' => match chars.next() {
+                    Some('n') => parsed.push('\n'),
+                    Some(escaped @ ('"' | '\\' | '$')) => parsed.push(escaped),
+                    Some(other) => {
+                        parsed.push('\\');
+                        parsed.push(other);
+                    }
+                    None => break,
+                },
+                c => parsed.push(c),
+            }
+        }
+        bail!("Unterminated double quote")
+    } else {
+        let value = match value.find(" #") {
+            Some(comment) => &value[..comment],
+            None => value,
+        };
+        Ok(value.trim_end().to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::parse;
+    use crate::config::EnvironmentFileFormat;
+
+    #[test]
+    fn test_parse() {
+        let vars = parse(
+            r#"
+# Backblaze credentials
+B2_ACCOUNT_ID=0012ab
+export B2_ACCOUNT_KEY = "K001\"xyz\"\\n" # application key
+AWS_SECRET_ACCESS_KEY='a#b\c'
+RESTIC_COMPRESSION=max # or auto
+EMPTY=
+"#,
+        )
+        .unwrap();
+        assert_eq!(vars.len(), 5);
+        assert_eq!(vars["B2_ACCOUNT_ID"], "0012ab");
+        assert_eq!(vars["B2_ACCOUNT_KEY"], "K001\"xyz\"\\n");
+        assert_eq!(vars["AWS_SECRET_ACCESS_KEY"], "a#b\\c");
+        assert_eq!(vars["RESTIC_COMPRESSION"], "max");
+        assert_eq!(vars["EMPTY"], "");
+
+        assert_eq!(parse("MULTI=\"a\\nb\"").unwrap()["MULTI"], "a\nb");
+        assert!(parse("NOT A VARIABLE").is_err());
+        assert!(parse("BAD KEY=1").is_err());
+        assert!(parse("OPEN=\"unterminated").is_err());
+
+        for (name, format) in [
+            (".env", EnvironmentFileFormat::Dotenv),
+            ("b2.env", EnvironmentFileFormat::Dotenv),
+            (".env.production", EnvironmentFileFormat::Dotenv),
+            ("b2.sops.env", EnvironmentFileFormat::Dotenv),
+            ("b2.env.age", EnvironmentFileFormat::Dotenv),
+            ("b2.toml", EnvironmentFileFormat::Toml),
+            ("environment", EnvironmentFileFormat::Toml),
+        ] {
+            assert_eq!(
+                EnvironmentFileFormat::detect(Path::new(name)),
+                format,
+                "{}",
+                name
+            );
+        }
+    }
+}
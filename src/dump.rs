@@ -0,0 +1,95 @@
+//! Extracting single files from snapshots
+
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use slog::{debug, info};
+use tempfile::NamedTempFile;
+
+use crate::redact;
+use crate::restic::Restic;
+
+/// Extends the Restic wrapper with `restic dump`
+impl<'a> Restic<'a> {
+    /// Writes the contents of `path` in a snapshot to `out`, or to stdout if no output file is given. Directories are dumped
+    /// as a tar archive.
+    ///
+    /// The output file is only replaced once Restic has succeeded, so a failed dump never leaves a truncated file behind.
+    pub fn dump(&self, snapshot: &str, path: &str, out: Option<&Path>) -> Result<()> {
+        let mut cmd = self.new_command();
+        cmd.arg("dump").arg(snapshot).arg(path);
+
+        let out = match out {
+            Some(out) => out,
+            None => {
+                debug!(self.logger(), "Dumping file to stdout"; "command" => %redact::command_line(&cmd));
+                let status = self.execute_passthrough(&mut cmd)?.status;
+                if !status.success() {
+                    bail!(self.failed("dump", status));
+                }
+                return Ok(());
+            }
+        };
+
+        let directory = match out.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let file = NamedTempFile::new_in(directory)
+            .with_context(|| format!("Could not create file in {}", directory.display()))?;
+
+        info!(self.logger(), "Dumping {} from snapshot {}", path, snapshot; "out" => %out.display(), "command" => %redact::command_line(&cmd));
+        let status = self.execute_to_file(&mut cmd, file.path())?.status;
+        if !status.success() {
+            bail!(self.failed("dump", status));
+        }
+        file.persist(out)
+            .with_context(|| format!("Could not write {}", out.display()))?;
+        info!(self.logger(), "Wrote {} to {}", path, out.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::time::Duration;
+
+    use crate::test::TestFixture;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_dump_retry() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Fails with a transient error partway through the first dump, and succeeds the second time
+        let mut fixture = TestFixture::new();
+        let content_root = fixture.content_root().to_path_buf();
+        let script = content_root.join("restic.sh");
+        let attempts = content_root.join("attempts");
+        fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\n\
+                 if [ -e {attempts} ]; then echo complete; exit 0; fi\n\
+                 touch {attempts}\n\
+                 echo partial\n\
+                 echo 'connection reset by peer' >&2\n\
+                 exit 1\n",
+                attempts = attempts.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        fixture.profile_mut().restic_command = Some(script.display().to_string());
+        fixture.profile_mut().retries = 1;
+        fixture.profile_mut().retry_delay = Some(Duration::from_millis(1));
+
+        let out = content_root.join("notes.txt");
+        fixture
+            .restic()
+            .dump("latest", "/home/notes.txt", Some(&out))
+            .unwrap();
+        assert_eq!(fs::read_to_string(&out).unwrap(), "complete\n");
+    }
+}
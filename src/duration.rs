@@ -0,0 +1,80 @@
+//! Parsing human-friendly durations like `36h` or `1h30m` from the configuration and command line
+
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Deserializer};
+
+/// Parses a duration made up of one or more `<number><unit>` pairs, where the unit is one of `s`, `m`, `h`, `d`, or `w`.
+/// A bare number is interpreted as seconds.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    if s.is_empty() {
+        bail!("Duration is empty");
+    }
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(Duration::from_secs(secs));
+    }
+
+    let mut total = 0u64;
+    let mut number = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+
+        let multiplier = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => bail!("Invalid duration `{}`: unknown unit `{}`", s, c),
+        };
+        let n: u64 = number
+            .parse()
+            .map_err(|_| anyhow!("Invalid duration `{}`: expected a number before `{}`", s, c))?;
+        total += n * multiplier;
+        number.clear();
+    }
+
+    if !number.is_empty() {
+        bail!("Invalid duration `{}`: missing unit after {}", s, number);
+    }
+
+    Ok(Duration::from_secs(total))
+}
+
+/// Deserializes an optional duration using [`parse_duration`], for use with `#[serde(deserialize_with = "...")]`.
+pub fn deserialize_option<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<Duration>, D::Error> {
+    let value: Option<String> = Option::deserialize(deserializer)?;
+    value
+        .map(|s| parse_duration(&s).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(
+            parse_duration("36h").unwrap(),
+            Duration::from_secs(36 * 3600)
+        );
+        assert_eq!(parse_duration("1h30m").unwrap(), Duration::from_secs(5400));
+        assert_eq!(
+            parse_duration("2w1d").unwrap(),
+            Duration::from_secs(15 * 86400)
+        );
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("5x").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("1h30").is_err());
+    }
+}
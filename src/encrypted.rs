@@ -0,0 +1,145 @@
+//! Environment files encrypted with SOPS or age, so that backend credentials can be kept in a dotfiles repository. Files
+//! are decrypted with the `sops` or `age` command-line tools, and the plaintext is only ever held in memory.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context, Result};
+
+/// How an environment file is encrypted, based on its name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encryption {
+    /// Encrypted with SOPS, named like `credentials.sops.toml` or `credentials.sops.env`. SOPS finds the key itself, from
+    /// its own configuration.
+    Sops,
+
+    /// Encrypted with age, named like `credentials.toml.age`
+    Age,
+}
+
+impl Encryption {
+    /// How the file at `path` is encrypted, or `None` if it's plain text
+    pub fn of(path: &Path) -> Option<Encryption> {
+        let name = path.file_name()?.to_string_lossy();
+        if name.ends_with(".sops.toml") || name.ends_with(".sops.env") {
+            Some(Encryption::Sops)
+        } else if name.ends_with(".age") {
+            Some(Encryption::Age)
+        } else {
+            None
+        }
+    }
+}
+
+/// Reads an environment file, decrypting it first if it's encrypted. `age_identity_file` is the age identity to decrypt
+/// `.age` files with, defaulting to the key file SOPS uses for age.
+pub fn read_environment_file(path: &Path, age_identity_file: Option<&Path>) -> Result<String> {
+    let encryption = match Encryption::of(path) {
+        Some(encryption) => encryption,
+        None => {
+            return fs::read_to_string(path)
+                .with_context(|| format!("Could not read environment file {}", path.display()))
+        }
+    };
+
+    let mut cmd = decrypt_command(encryption, path, age_identity_file)?;
+    let program = cmd.get_program().to_string_lossy().into_owned();
+    let output = cmd
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| format!("Could not run {}", program))?;
+    if !output.status.success() {
+        bail!(
+            "Could not decrypt environment file {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    String::from_utf8(output.stdout).with_context(|| {
+        format!(
+            "Decrypted environment file {} is not valid UTF-8",
+            path.display()
+        )
+    })
+}
+
+/// Command that writes the decrypted contents of `path` to stdout
+fn decrypt_command(
+    encryption: Encryption,
+    path: &Path,
+    age_identity_file: Option<&Path>,
+) -> Result<Command> {
+    let cmd = match encryption {
+        Encryption::Sops => {
+            let mut cmd = Command::new("sops");
+            cmd.arg("--decrypt").arg(path);
+            cmd
+        }
+        Encryption::Age => {
+            let identity_file = match age_identity_file {
+                Some(identity_file) => identity_file.to_path_buf(),
+                None => default_age_identity_file().context(
+                    "Could not find an age identity file. Set `age_identity_file` in the profile.",
+                )?,
+            };
+            let mut cmd = Command::new("age");
+            cmd.arg("--decrypt")
+                .arg("--identity")
+                .arg(identity_file)
+                .arg(path);
+            cmd
+        }
+    };
+    Ok(cmd)
+}
+
+/// The age key file SOPS reads by default, so keys set up for SOPS work for plain age files too
+fn default_age_identity_file() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("sops").join("age").join("keys.txt"))
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn test_decrypt_command() {
+        assert_eq!(
+            Encryption::of(Path::new("b2.sops.toml")),
+            Some(Encryption::Sops)
+        );
+        assert_eq!(
+            Encryption::of(Path::new("/etc/rustic/b2.toml.age")),
+            Some(Encryption::Age)
+        );
+        assert_eq!(Encryption::of(Path::new("b2.toml")), None);
+
+        let args = |encryption| {
+            decrypt_command(
+                encryption,
+                Path::new("/etc/rustic/b2.toml.age"),
+                Some(Path::new("/etc/rustic/key.txt")),
+            )
+            .unwrap()
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            args(Encryption::Age),
+            vec![
+                "--decrypt",
+                "--identity",
+                "/etc/rustic/key.txt",
+                "/etc/rustic/b2.toml.age"
+            ]
+        );
+        assert_eq!(
+            args(Encryption::Sops),
+            vec!["--decrypt", "/etc/rustic/b2.toml.age"]
+        );
+    }
+}
@@ -0,0 +1,173 @@
+//! Errors that callers may need to tell apart, like a typo in the configuration versus an unreachable repository.
+//!
+//! Rustic's functions return [`anyhow::Error`]s, which carry context about what was being done when something went wrong.
+//! When the cause is one of the categories here, a [`RusticError`] is at the root of the error chain, and
+//! [`RusticError::find`] digs it out.
+//!
+//! Each category has its own exit code, so that scripts and monitoring can react to failures without parsing logs:
+//!
+//! | Code | Meaning |
+//! | ---- | ------- |
+//! | 0 | Success |
+//! | 1 | Backup finished, but some files couldn't be read and the profile sets `fail_on_partial` |
+//! | 2 | Configuration error, including a profile or group that doesn't exist |
+//! | 3 | The repository doesn't exist |
+//! | 4 | The repository is locked by another process |
+//! | 5 | Restic failed |
+//! | 6 | Restic timed out |
+//! | 7 | Any other error |
+//! | 8 | The repository couldn't be reached within the profile's `preconditions.wait_for_repository` |
+
+use std::error::Error;
+use std::fmt;
+use std::process::ExitStatus;
+use std::time::Duration;
+
+use crate::redact;
+
+/// Exit code for errors that don't fall into any category
+pub const EXIT_OTHER_ERROR: i32 = 7;
+
+/// Exit code for Rustic to exit with when it fails because of `err`
+pub fn exit_code(err: &anyhow::Error) -> i32 {
+    RusticError::find(err).map_or(EXIT_OTHER_ERROR, RusticError::exit_code)
+}
+
+/// A categorized Rustic failure
+#[derive(Debug)]
+pub enum RusticError {
+    /// The configuration file couldn't be found, read, or parsed
+    Config(anyhow::Error),
+
+    /// A profile that was asked for doesn't exist
+    ProfileNotFound(String),
+
+    /// A group of profiles that was asked for doesn't exist
+    GroupNotFound(String),
+
+    /// Restic exited unsuccessfully
+    ResticFailed {
+        /// Restic subcommand that failed, such as `backup`
+        operation: String,
+
+        /// Restic's exit code, or `None` if it was killed by a signal
+        exit_code: Option<i32>,
+    },
+
+    /// The repository doesn't exist yet
+    RepositoryNotFound {
+        /// Repository that doesn't exist
+        repository: String,
+    },
+
+    /// The repository is locked by another Rustic or Restic process
+    RepositoryLocked {
+        /// Repository that was locked
+        repository: String,
+    },
+
+    /// Restic ran past the timeout for its operation and was stopped
+    Timeout(Duration),
+
+    /// The repository couldn't be reached in time, for this reason
+    RepositoryUnreachable(String),
+}
+
+impl RusticError {
+    /// Finds the categorized error at the root of `err`, if there is one
+    pub fn find(err: &anyhow::Error) -> Option<&RusticError> {
+        err.chain().find_map(|cause| cause.downcast_ref())
+    }
+
+    /// Creates an error for a failed Restic operation
+    pub fn restic_failed(operation: &str, status: ExitStatus) -> RusticError {
+        RusticError::ResticFailed {
+            operation: operation.to_string(),
+            exit_code: status.code(),
+        }
+    }
+
+    /// Exit code for Rustic to exit with when it fails because of this error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            // Restic still creates a snapshot when it can't read some files
+            RusticError::ResticFailed {
+                operation,
+                exit_code: Some(3),
+            } if operation == "backup" => 1,
+            RusticError::Config(_)
+            | RusticError::ProfileNotFound(_)
+            | RusticError::GroupNotFound(_) => 2,
+            RusticError::RepositoryNotFound { .. } => 3,
+            RusticError::RepositoryLocked { .. } => 4,
+            RusticError::ResticFailed { .. } => 5,
+            RusticError::Timeout(_) => 6,
+            RusticError::RepositoryUnreachable(_) => 8,
+        }
+    }
+}
+
+impl fmt::Display for RusticError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            // Configuration errors already describe what's wrong
+            RusticError::Config(err) => fmt::Display::fmt(err, f),
+            RusticError::ProfileNotFound(profile) => {
+                write!(f, "Profile `{}` does not exist", profile)
+            }
+            RusticError::GroupNotFound(group) => write!(f, "Group `{}` does not exist", group),
+            RusticError::ResticFailed {
+                operation,
+                exit_code: Some(exit_code),
+            } => write!(
+                f,
+                "Restic {} failed with exit code {}",
+                operation, exit_code
+            ),
+            RusticError::ResticFailed {
+                operation,
+                exit_code: None,
+            } => write!(f, "Restic {} was killed by a signal", operation),
+            RusticError::RepositoryNotFound { repository } => {
+                write!(f, "Repository {} does not exist", redact::url(repository))
+            }
+            RusticError::RepositoryLocked { repository } => {
+                write!(
+                    f,
+                    "Repository {} is locked by another process",
+                    redact::url(repository)
+                )
+            }
+            RusticError::Timeout(timeout) => write!(f, "Restic timed out after {:?}", timeout),
+            RusticError::RepositoryUnreachable(reason) => f.write_str(reason),
+        }
+    }
+}
+
+impl Error for RusticError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RusticError::Config(err) => err.source(),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Context;
+
+    use super::*;
+
+    #[test]
+    fn test_find() {
+        let err = Err::<(), _>(RusticError::ProfileNotFound("home".to_string()))
+            .context("Could not back up")
+            .unwrap_err();
+        assert!(matches!(
+            RusticError::find(&err),
+            Some(RusticError::ProfileNotFound(profile)) if profile == "home"
+        ));
+        assert!(RusticError::find(&anyhow::anyhow!("Something else")).is_none());
+    }
+}
@@ -0,0 +1,321 @@
+//! Events emitted by backups, forgets, prunes, and checks, and the sinks that report them.
+//!
+//! Operations only emit events. Everything that reports on them, like the run history, metrics, and notifications, is a
+//! [`Sink`] that subscribes to those events, so new kinds of reporting don't need to touch the operations themselves.
+
+use std::cell::RefCell;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use slog::{debug, trace};
+
+use crate::backup::BackupSummary;
+use crate::forget::ForgetReport;
+use crate::monitoring::Healthcheck;
+use crate::notify::Notification;
+use crate::redact;
+use crate::report::{HookReport, Report};
+use crate::restic::Restic;
+use crate::state::RunRecord;
+
+/// Something that happened during an operation on a profile
+pub enum Event<'e> {
+    /// An operation started
+    Started {
+        /// Operation that started, such as `backup` or `prune`
+        operation: &'e str,
+    },
+
+    /// An operation made progress. Only backups report progress, and only if a sink [wants it](Sink::wants_progress).
+    Progress {
+        /// Operation that made progress
+        operation: &'e str,
+
+        /// How far along the operation is
+        progress: &'e Progress,
+    },
+
+    /// A hook command finished, whether it succeeded or not
+    Hook(&'e HookOutcome<'e>),
+
+    /// An operation succeeded
+    Finished(&'e Outcome<'e>),
+
+    /// An operation failed
+    Failed(&'e Outcome<'e>),
+}
+
+/// Progress of a running backup, from a `status` message in `restic backup --json` output
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Progress {
+    /// How much of the backup is done, from 0 to 1
+    pub percent_done: f64,
+
+    /// Seconds since the backup started
+    pub seconds_elapsed: u64,
+
+    /// Estimated seconds until the backup finishes, once Restic has an estimate
+    pub seconds_remaining: Option<u64>,
+
+    /// Number of files to back up, once Restic has finished scanning
+    pub total_files: u64,
+
+    /// Number of files backed up so far
+    pub files_done: u64,
+
+    /// Total size of the files to back up, once Restic has finished scanning
+    pub total_bytes: u64,
+
+    /// Bytes backed up so far
+    pub bytes_done: u64,
+
+    /// Files Restic is reading right now
+    pub current_files: Vec<String>,
+}
+
+/// Result of a finished operation
+pub struct Outcome<'e> {
+    /// Operation that finished, such as `backup` or `prune`
+    pub operation: &'e str,
+
+    /// When the operation started
+    pub started_at: DateTime<Utc>,
+
+    /// How long the operation took
+    pub duration: Duration,
+
+    /// Restic's exit code, if it ran to completion
+    pub exit_code: Option<i32>,
+
+    /// Summary of the new snapshot, for successful backups
+    pub summary: Option<&'e BackupSummary>,
+
+    /// Which snapshots were kept and which were removed, for successful forgets
+    pub forget: Option<&'e ForgetReport>,
+
+    /// What's unusual about a successful backup's size compared to recent ones, if anything
+    pub anomaly: Option<String>,
+
+    /// Error message, if the operation failed
+    pub error: Option<String>,
+}
+
+/// Result of a hook command
+pub struct HookOutcome<'e> {
+    /// Operation the hook ran for, such as `backup`
+    pub operation: &'e str,
+
+    /// When the hook ran, such as `pre_backup` or `on_success`
+    pub stage: &'e str,
+
+    /// The hook command
+    pub command: &'e str,
+
+    /// How long the hook took
+    pub duration: Duration,
+
+    /// Error message, if the hook failed
+    pub error: Option<String>,
+}
+
+/// Receives events from operations, for example to report them somewhere. Sinks shouldn't fail, since reporting an
+/// operation shouldn't change its result, so errors are logged instead.
+pub trait Sink {
+    /// Handles an event from an operation on `restic`'s profile
+    fn handle(&self, restic: &Restic, event: &Event);
+
+    /// Whether this sink wants [`Progress`](Event::Progress) events. Restic only reports progress if some sink does.
+    fn wants_progress(&self) -> bool {
+        false
+    }
+}
+
+/// The sinks every profile reports to. Each one does nothing unless its reporting is configured.
+pub fn default_sinks() -> Vec<Box<dyn Sink>> {
+    vec![
+        Box::new(LogSink),
+        Box::new(StateSink),
+        Box::new(MetricsSink),
+        Box::new(HealthcheckSink),
+        Box::new(NotificationSink),
+        Box::new(ReportSink::default()),
+    ]
+}
+
+/// Logs events
+struct LogSink;
+
+impl Sink for LogSink {
+    fn handle(&self, restic: &Restic, event: &Event) {
+        let logger = restic.logger();
+        match event {
+            Event::Started { operation } => debug!(logger, "Started {}", operation),
+            Event::Progress {
+                operation,
+                progress,
+            } => trace!(
+                logger,
+                "{} is {:.1}% done",
+                operation,
+                progress.percent_done * 100.0
+            ),
+            Event::Hook(hook) => {
+                trace!(logger, "Finished {} hook in {:?}", hook.stage, hook.duration; "hook" => hook.command)
+            }
+            Event::Finished(outcome) => {
+                debug!(
+                    logger,
+                    "Finished {} in {:?}", outcome.operation, outcome.duration
+                );
+                if let Some(summary) = outcome.summary {
+                    summary.log(logger);
+                }
+                if let Some(report) = outcome.forget {
+                    report.log(logger);
+                }
+            }
+            Event::Failed(outcome) => {
+                debug!(logger, "{} failed after {:?}", outcome.operation, outcome.duration;
+                    "error" => outcome.error.as_deref().unwrap_or_default())
+            }
+        }
+    }
+}
+
+/// Records finished operations in the run history
+struct StateSink;
+
+impl Sink for StateSink {
+    fn handle(&self, restic: &Restic, event: &Event) {
+        let outcome = match event {
+            Event::Finished(outcome) | Event::Failed(outcome) => outcome,
+            _ => return,
+        };
+
+        let mut run = RunRecord::finished(
+            restic.profile_name(),
+            outcome.operation,
+            outcome.started_at,
+            outcome.exit_code,
+            outcome.error.clone(),
+        );
+        run.repository = Some(redact::url(restic.repository()).into_owned());
+        if let Some(summary) = outcome.summary {
+            run.snapshot_id = Some(summary.snapshot_id.clone());
+            run.bytes_added = Some(summary.data_added);
+            run.files = Some(summary.total_files_processed);
+        }
+        restic.record_run(run);
+    }
+}
+
+/// Writes Prometheus metrics for finished operations
+struct MetricsSink;
+
+impl Sink for MetricsSink {
+    fn handle(&self, restic: &Restic, event: &Event) {
+        match event {
+            Event::Finished(outcome) => {
+                let metrics = outcome
+                    .summary
+                    .map(|summary| summary.metrics())
+                    .unwrap_or_default();
+                restic.record_metrics(outcome.operation, true, outcome.duration, &metrics)
+            }
+            Event::Failed(outcome) => {
+                restic.record_metrics(outcome.operation, false, outcome.duration, &[])
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Pings the profile's healthcheck when backups start and finish
+struct HealthcheckSink;
+
+impl Sink for HealthcheckSink {
+    fn handle(&self, restic: &Restic, event: &Event) {
+        match event {
+            Event::Started {
+                operation: "backup",
+            } => restic.ping_healthcheck(Healthcheck::Start, None),
+            Event::Finished(outcome) if outcome.operation == "backup" => {
+                restic.ping_healthcheck(Healthcheck::Success, None)
+            }
+            Event::Failed(outcome) if outcome.operation == "backup" => {
+                restic.ping_healthcheck(Healthcheck::Failure, outcome.error.as_deref())
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Sends notifications when backups finish, and when forgets do if `notifications.forget` is set
+struct NotificationSink;
+
+impl Sink for NotificationSink {
+    fn handle(&self, restic: &Restic, event: &Event) {
+        let outcome = match event {
+            Event::Finished(outcome) | Event::Failed(outcome) => outcome,
+            _ => return,
+        };
+        let wanted = match outcome.operation {
+            "backup" => true,
+            "forget" => restic.config().notifications.forget,
+            _ => false,
+        };
+        if !wanted {
+            return;
+        }
+
+        restic.notify(&Notification {
+            profile: restic.profile_name(),
+            operation: outcome.operation,
+            summary: outcome.summary,
+            forget: outcome.forget,
+            anomaly: outcome.anomaly.clone(),
+            error: outcome.error.clone(),
+            duration: outcome.duration,
+        });
+    }
+}
+
+/// Writes a JSON report of each finished operation to `report_file`, including the hooks that ran for it
+#[derive(Default)]
+struct ReportSink {
+    /// Hooks that ran since the operation started
+    hooks: RefCell<Vec<HookReport>>,
+
+    /// Report of the last operation that finished. Hooks that run after an operation finishes, like `post_backup`, are
+    /// added to it, and the report is written again.
+    finished: RefCell<Option<Report>>,
+}
+
+impl Sink for ReportSink {
+    fn handle(&self, restic: &Restic, event: &Event) {
+        if restic.config().report_file.is_none() {
+            return;
+        }
+        match event {
+            Event::Started { .. } => {
+                self.hooks.borrow_mut().clear();
+                self.finished.replace(None);
+            }
+            Event::Hook(hook) => match *self.finished.borrow_mut() {
+                Some(ref mut report) => {
+                    report.hooks.push(HookReport::new(hook));
+                    restic.write_report(report);
+                }
+                None => self.hooks.borrow_mut().push(HookReport::new(hook)),
+            },
+            Event::Finished(outcome) | Event::Failed(outcome) => {
+                let report = Report::new(restic, outcome, self.hooks.take());
+                restic.write_report(&report);
+                self.finished.replace(Some(report));
+            }
+            Event::Progress { .. } => {}
+        }
+    }
+}
@@ -0,0 +1,86 @@
+//! Searching for files across snapshots
+
+use std::io::{self, prelude::*};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use slog::debug;
+
+use crate::json;
+use crate::ls::Node;
+use crate::output::OutputFormat;
+use crate::redact;
+use crate::restic::Restic;
+
+/// Matches in one snapshot, from `restic find --json`
+#[derive(Debug, Deserialize)]
+struct FindResult {
+    /// Snapshot containing the matches
+    snapshot: String,
+
+    /// Matching files
+    #[serde(default)]
+    matches: Vec<Node>,
+}
+
+/// Extends the Restic wrapper with file search
+impl<'a> Restic<'a> {
+    /// Prints files matching any of `patterns` in every snapshot, or only in `snapshots` if any are given. JSON output is passed
+    /// through from Restic unchanged.
+    pub fn find(
+        &self,
+        patterns: &[String],
+        snapshots: &[String],
+        ignore_case: bool,
+        output: OutputFormat,
+    ) -> Result<()> {
+        let mut cmd = self.new_command();
+        cmd.arg("find").arg("--json");
+        if ignore_case {
+            cmd.arg("--ignore-case");
+        }
+        for snapshot in snapshots {
+            cmd.arg("--snapshot").arg(snapshot);
+        }
+        cmd.arg("--").args(patterns);
+        debug!(self.logger(), "Finding files"; "command" => %redact::command_line(&cmd));
+
+        if output == OutputFormat::Json {
+            let status = self.execute_passthrough(&mut cmd)?.status;
+            if !status.success() {
+                bail!("Finding files failed: {}", status);
+            }
+            return Ok(());
+        }
+
+        let stdout = self
+            .execute_output(&mut cmd)
+            .context("Finding files failed")?;
+        // Restic prints nothing at all if there are no matches
+        let results: Vec<FindResult> = if stdout.trim().is_empty() {
+            Vec::new()
+        } else {
+            json::from_str(&stdout).context("Could not parse `restic find` output")?
+        };
+
+        let mut tw = tabwriter::TabWriter::new(io::stdout());
+        writeln!(tw, "Snapshot\tType\tSize\tModified\tPath")?;
+        writeln!(tw, "--------\t----\t----\t--------\t----")?;
+        let mut count = 0;
+        for result in results.iter() {
+            for node in result.matches.iter() {
+                writeln!(
+                    tw,
+                    "{}\t{}",
+                    &result.snapshot[..result.snapshot.len().min(8)],
+                    node.table_row()
+                )?;
+                count += 1;
+            }
+        }
+        tw.flush()?;
+        println!("{} matches in {} snapshots", count, results.len());
+
+        Ok(())
+    }
+}
@@ -1,18 +1,154 @@
 //! Forgetting and pruning snapshots
 
-use std::process::Command;
+use std::cmp::Reverse;
+use std::io::{self, prelude::*};
+use std::process::{Command, ExitStatus};
 use std::time::Instant;
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, Utc};
 use itertools::join;
-use slog::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use slog::{debug, error, info, warn, Logger};
 
-use crate::config::RetentionPolicy;
+use crate::config::{PruneOptions, RetentionPolicy};
+use crate::events::{Event, Outcome};
+use crate::json;
+use crate::output::{self, OutputFormat};
+use crate::redact;
 use crate::restic::Restic;
+use crate::snapshots::Snapshot;
+
+/// One group of snapshots the retention policy was applied to, from `restic forget --json`. Snapshots are grouped by host
+/// and paths by default.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ForgetGroup {
+    /// Host shared by the group's snapshots, if grouping by host
+    #[serde(default)]
+    pub host: Option<String>,
+
+    /// Paths shared by the group's snapshots, if grouping by paths
+    #[serde(default)]
+    pub paths: Option<Vec<String>>,
+
+    /// Tags shared by the group's snapshots, if grouping by tags
+    #[serde(default)]
+    pub tags: Option<Vec<String>>,
+
+    /// Snapshots the policy keeps
+    #[serde(default)]
+    pub keep: Vec<Snapshot>,
+
+    /// Snapshots the policy forgets
+    #[serde(default)]
+    pub remove: Vec<Snapshot>,
+
+    /// Why each kept snapshot is kept
+    #[serde(default)]
+    pub reasons: Vec<KeepReason>,
+}
+
+impl ForgetGroup {
+    fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(ref host) = self.host {
+            parts.push(format!("host {}", host));
+        }
+        if let Some(ref paths) = self.paths {
+            parts.push(format!("paths {}", paths.join(", ")));
+        }
+        if let Some(ref tags) = self.tags {
+            if !tags.is_empty() {
+                parts.push(format!("tags {}", tags.join(",")));
+            }
+        }
+        if parts.is_empty() {
+            "All snapshots:".to_string()
+        } else {
+            format!("Snapshots for {}:", parts.join(", "))
+        }
+    }
+
+    /// Rules that keep the snapshot with `id`, like `daily snapshot, monthly snapshot`
+    fn reasons(&self, id: &str) -> String {
+        self.reasons
+            .iter()
+            .find(|reason| reason.snapshot.id == id)
+            .map(|reason| reason.matches.join(", "))
+            .unwrap_or_default()
+    }
+}
+
+/// Why the retention policy keeps a snapshot
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct KeepReason {
+    /// The kept snapshot
+    pub snapshot: Snapshot,
+
+    /// Rules that matched the snapshot, like `daily snapshot`
+    #[serde(default)]
+    pub matches: Vec<String>,
+}
+
+/// Which snapshots `restic forget` kept and which it removed
+#[derive(Debug, Default)]
+pub struct ForgetReport {
+    /// Each group of snapshots the retention policy was applied to
+    pub groups: Vec<ForgetGroup>,
+}
+
+impl ForgetReport {
+    /// Parses `restic forget --json` output. Restic prints the groups as a single JSON array, but may print other messages
+    /// too. Returns `None` for lines that aren't the array.
+    fn parse(line: &str) -> Option<Result<ForgetReport>> {
+        if !line.starts_with('[') {
+            return None;
+        }
+        Some(
+            json::from_str(line)
+                .map(|groups| ForgetReport { groups })
+                .context("Could not parse `restic forget` output"),
+        )
+    }
+
+    /// Number of snapshots kept
+    pub fn kept(&self) -> usize {
+        self.groups.iter().map(|group| group.keep.len()).sum()
+    }
+
+    /// Number of snapshots removed
+    pub fn removed(&self) -> usize {
+        self.groups.iter().map(|group| group.remove.len()).sum()
+    }
+
+    /// Logs each removed snapshot and a summary. Kept snapshots, with the rules that kept them, are only logged at debug
+    /// level.
+    pub fn log(&self, logger: &Logger) {
+        for group in self.groups.iter() {
+            for snapshot in group.remove.iter() {
+                info!(logger, "Removed snapshot {}", snapshot.short_id();
+                    "id" => &snapshot.id, "time" => %snapshot.time.to_rfc3339(), "host" => &snapshot.hostname,
+                    "tags" => snapshot.tags.join(","));
+            }
+            for snapshot in group.keep.iter() {
+                debug!(logger, "Kept snapshot {}", snapshot.short_id();
+                    "id" => &snapshot.id, "time" => %snapshot.time.to_rfc3339(), "host" => &snapshot.hostname,
+                    "reasons" => group.reasons(&snapshot.id));
+            }
+        }
+        info!(
+            logger,
+            "Removed {} snapshots and kept {}",
+            self.removed(),
+            self.kept()
+        );
+    }
+}
 
 impl<'a> Restic<'a> {
     /// Forgets snapshots according to the configured retention policy.
     pub fn forget(&self, prune: bool) -> Result<()> {
+        self.require_full_access("forget")?;
         let policy = &self.profile().retention;
         if policy.is_empty() {
             warn!(
@@ -24,54 +160,230 @@ impl<'a> Restic<'a> {
 
         // TODO: check if repository exists and soft-fail or init?
 
-        let mut cmd = self.new_command();
-        cmd.arg("forget");
-        add_policy(policy, &mut cmd);
-
-        if prune {
+        // `--dry-run` would stop `restic forget --prune` from forgetting anything at all, so a dry-run prune runs on its
+        // own once the snapshots are forgotten
+        let prune_separately = prune && self.profile().prune.dry_run;
+        let mut cmd = self.forget_command();
+        cmd.arg("--json");
+        if prune && !prune_separately {
             cmd.arg("--prune");
+            add_prune_options(&self.profile().prune, &mut cmd);
         }
 
-        info!(self.logger(), "Forgetting snapshots"; "prune" => prune, "command" => ?cmd);
+        info!(self.logger(), "Forgetting snapshots"; "prune" => prune, "command" => %redact::command_line(&cmd));
+        let started_at = Utc::now();
         let start = Instant::now();
-        let status = cmd
-            .status()
-            .with_context(|| format!("Could not run {:?}", cmd))?;
+        self.emit(&Event::Started {
+            operation: "forget",
+        });
+        let mut report = None;
+        let mut on_stdout = |line: &str| match ForgetReport::parse(line) {
+            Some(Ok(parsed)) => report = Some(parsed),
+            Some(Err(err)) => warn!(self.logger(), "{:#}", err),
+            None => debug!(self.logger(), "Restic: {}", line),
+        };
+        let status = self
+            .lock_repository_for_maintenance()
+            .and_then(|_lock| self.execute(&mut cmd, Some(&mut on_stdout)))
+            .map(|execution| execution.status);
+        self.report_outcome("forget", started_at, start, &status, report.as_ref());
+        let status = status?;
         let duration = Instant::now() - start;
 
         if status.success() {
-            info!(self.logger(), "Forgot snapshots in {:?}", duration; "command" => ?cmd);
+            info!(self.logger(), "Forgot snapshots in {:?}", duration; "command" => %redact::command_line(&cmd));
+            if prune_separately {
+                return self.prune();
+            }
             Ok(())
         } else {
-            error!(self.logger(), "Forgetting snapshots failed"; "status" => %status, "command" => ?cmd);
-            Err(anyhow!("Restic forget failed with {}", status))
+            error!(self.logger(), "Forgetting snapshots failed"; "status" => %status, "command" => %redact::command_line(&cmd));
+            Err(self.failed("forget", status).into())
+        }
+    }
+
+    /// Shows which snapshots the retention policy would keep and which it would forget, without forgetting anything
+    pub fn forget_dry_run(&self, output: OutputFormat) -> Result<()> {
+        if self.profile().retention.is_empty() {
+            warn!(
+                self.logger(),
+                "Retention policy is empty, no snapshots would be forgotten"
+            );
+            return Ok(());
+        }
+
+        let mut cmd = self.forget_command();
+        cmd.arg("--dry-run").arg("--json");
+        debug!(self.logger(), "Checking which snapshots would be forgotten"; "command" => %redact::command_line(&cmd));
+        let stdout = self
+            .execute_output(&mut cmd)
+            .context("Restic forget failed")?;
+        let groups = match stdout.lines().find_map(ForgetReport::parse) {
+            Some(report) => report?.groups,
+            None => Vec::new(),
+        };
+
+        if output == OutputFormat::Json {
+            return output::print_json(&groups);
+        }
+
+        let (mut kept, mut removed) = (0, 0);
+        for group in groups.iter() {
+            println!("{}", group.describe());
+            let mut snapshots: Vec<(&str, &Snapshot)> = group
+                .keep
+                .iter()
+                .map(|snapshot| ("keep", snapshot))
+                .chain(group.remove.iter().map(|snapshot| ("remove", snapshot)))
+                .collect();
+            snapshots.sort_by_key(|(_, snapshot)| Reverse(snapshot.time));
+
+            let mut tw = tabwriter::TabWriter::new(io::stdout());
+            writeln!(tw, "Action\tID\tTime\tHost\tTags\tReasons")?;
+            writeln!(tw, "------\t--\t----\t----\t----\t-------")?;
+            for (action, snapshot) in snapshots {
+                writeln!(
+                    tw,
+                    "{}\t{}\t{}\t{}\t{}\t{}",
+                    action,
+                    snapshot.short_id(),
+                    snapshot
+                        .time
+                        .with_timezone(&Local)
+                        .format("%Y-%m-%d %H:%M:%S"),
+                    snapshot.hostname,
+                    snapshot.tags.join(","),
+                    group.reasons(&snapshot.id)
+                )?;
+            }
+            tw.flush()?;
+            println!();
+
+            kept += group.keep.len();
+            removed += group.remove.len();
+        }
+        println!(
+            "{} snapshots would be kept and {} would be forgotten",
+            kept, removed
+        );
+        Ok(())
+    }
+
+    /// Starts a `restic forget` command for the retention policy, restricted to this profile's snapshots by host and tags
+    fn forget_command(&self) -> Command {
+        let policy = &self.profile().retention;
+        let mut cmd = self.new_command();
+        cmd.arg("forget");
+        add_policy(policy, &mut cmd);
+
+        // Pinned snapshots are always kept
+        let pin_tag = self.pin_tag();
+        if !policy.keep_tags.iter().any(|tags| tags == &[pin_tag]) {
+            cmd.arg("--keep-tag").arg(pin_tag);
+        }
+
+        // Only forget snapshots created by this profile
+        let tags = if policy.tags.is_empty() {
+            &self.profile().tags
+        } else {
+            &policy.tags
+        };
+        if !tags.is_empty() {
+            cmd.arg("--tag").arg(join(tags, ","));
+        }
+        if let Some(host) = policy.host.as_ref().or(self.profile().host.as_ref()) {
+            cmd.arg("--host").arg(host);
         }
+        cmd
     }
 
     /// Prunes any unreferenced data in the repository (ex. from forgotten snapshots)
     pub fn prune(&self) -> Result<()> {
+        self.require_full_access("prune")?;
         // TODO: check if repository exists and soft-fail or init?
 
         let mut cmd = self.new_command();
         cmd.arg("prune");
+        add_prune_options(&self.profile().prune, &mut cmd);
+        if self.profile().prune.dry_run {
+            cmd.arg("--dry-run");
+        }
 
-        info!(self.logger(), "Pruning repository"; "command" => ?cmd);
+        info!(self.logger(), "Pruning repository"; "command" => %redact::command_line(&cmd));
         let start = Instant::now();
-        let status = cmd
-            .status()
-            .with_context(|| format!("Could not run {:?}", cmd))?;
+        let status = self.run_reported("prune", &mut cmd)?;
         let duration = Instant::now() - start;
 
         if status.success() {
-            info!(self.logger(), "Pruned repository in {:?}", duration; "command" => ?cmd);
+            info!(self.logger(), "Pruned repository in {:?}", duration; "command" => %redact::command_line(&cmd));
             Ok(())
         } else {
-            error!(self.logger(), "Pruning repository failed"; "status" => %status, "command" => ?cmd);
-            Err(anyhow!("Restic prune failed with {}", status))
+            error!(self.logger(), "Pruning repository failed"; "status" => %status, "command" => %redact::command_line(&cmd));
+            Err(self.failed("prune", status).into())
         }
     }
 }
 
+impl<'a> Restic<'a> {
+    /// Runs a forget, prune, or check command with the repository locked for maintenance, emitting events for it
+    pub fn run_reported(&self, operation: &str, cmd: &mut Command) -> Result<ExitStatus> {
+        let started_at = Utc::now();
+        let start = Instant::now();
+        self.emit(&Event::Started { operation });
+
+        let status = self
+            .lock_repository_for_maintenance()
+            .and_then(|_lock| self.execute(cmd, None))
+            .map(|execution| execution.status);
+        self.report_outcome(operation, started_at, start, &status, None);
+        status
+    }
+
+    /// Emits the event for a finished forget, prune, or check command
+    fn report_outcome(
+        &self,
+        operation: &str,
+        started_at: DateTime<Utc>,
+        start: Instant,
+        status: &Result<ExitStatus>,
+        forget: Option<&ForgetReport>,
+    ) {
+        let error = match status {
+            Ok(status) if status.success() => None,
+            Ok(status) => Some(format!("Restic {} failed with {}", operation, status)),
+            Err(err) => Some(format!("{:#}", err)),
+        };
+        let outcome = Outcome {
+            operation,
+            started_at,
+            duration: Instant::now() - start,
+            exit_code: status.as_ref().ok().and_then(|status| status.code()),
+            summary: None,
+            forget: forget.filter(|_| error.is_none()),
+            anomaly: None,
+            error,
+        };
+        self.emit(&match outcome.error {
+            None => Event::Finished(&outcome),
+            Some(_) => Event::Failed(&outcome),
+        });
+    }
+}
+
+fn add_prune_options(options: &PruneOptions, cmd: &mut Command) {
+    if let Some(max_unused) = &options.max_unused {
+        cmd.arg("--max-unused").arg(max_unused);
+    }
+
+    if let Some(max_repack_size) = &options.max_repack_size {
+        cmd.arg("--max-repack-size").arg(max_repack_size);
+    }
+
+    if options.repack_cacheable_only {
+        cmd.arg("--repack-cacheable-only");
+    }
+}
+
 fn add_policy(policy: &RetentionPolicy, cmd: &mut Command) {
     if let Some(keep_last) = policy.keep_last {
         cmd.arg("--keep-last").arg(keep_last.to_string());
@@ -104,4 +416,61 @@ fn add_policy(policy: &RetentionPolicy, cmd: &mut Command) {
     for taglist in policy.keep_tags.iter() {
         cmd.arg("--keep-tag").arg(join(taglist, ","));
     }
+
+    if !policy.group_by.is_empty() {
+        cmd.arg("--group-by").arg(join(
+            policy.group_by.iter().map(|group| group.as_str()),
+            ",",
+        ));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ForgetReport;
+    use crate::runner::MockResponse;
+    use crate::test::TestFixture;
+
+    const FORGET_OUTPUT: &str = r#"[{"tags":null,"host":"laptop","paths":["/home"],"keep":[{"time":"2020-09-02T10:00:00Z","hostname":"laptop","id":"fedcba9876543210"}],"remove":[{"time":"2020-09-01T10:00:00Z","hostname":"laptop","id":"0123456789abcdef"},{"time":"2020-08-31T10:00:00Z","hostname":"laptop","id":"1123456789abcdef"}],"reasons":[{"snapshot":{"time":"2020-09-02T10:00:00Z","hostname":"laptop","id":"fedcba9876543210"},"matches":["last snapshot","daily snapshot"]}]}]"#;
+
+    #[test]
+    fn test_forget_report() {
+        assert!(ForgetReport::parse("Applying Policy: keep 1 daily snapshots").is_none());
+        let report = ForgetReport::parse(FORGET_OUTPUT).unwrap().unwrap();
+        assert_eq!(report.kept(), 1);
+        assert_eq!(report.removed(), 2);
+        assert_eq!(
+            report.groups[0].reasons("fedcba9876543210"),
+            "last snapshot, daily snapshot"
+        );
+        assert_eq!(report.groups[0].reasons("0123456789abcdef"), "");
+
+        let mut fixture = TestFixture::new();
+        fixture.profile_mut().retention.keep_daily = Some(1);
+        let (restic, runner) = fixture.mock_restic();
+        runner.respond(MockResponse::success(&format!(
+            "Applying Policy: keep 1 daily snapshots\n{}\n",
+            FORGET_OUTPUT
+        )));
+        restic.forget(false).unwrap();
+        assert!(runner.invocations()[0].has_arg("--json"));
+    }
+
+    #[test]
+    fn test_forget_prune_dry_run() {
+        let mut fixture = TestFixture::new();
+        fixture.profile_mut().retention.keep_daily = Some(1);
+        fixture.profile_mut().prune.dry_run = true;
+        let (restic, runner) = fixture.mock_restic();
+
+        // Snapshots are really forgotten, and only the prune is a dry run
+        restic.forget(true).unwrap();
+        let invocations = runner.invocations();
+        assert_eq!(invocations.len(), 2);
+        assert!(invocations[0].has_arg("forget"));
+        assert!(!invocations[0].has_arg("--prune"));
+        assert!(!invocations[0].has_arg("--dry-run"));
+        assert!(invocations[1].has_arg("prune"));
+        assert!(invocations[1].has_arg("--dry-run"));
+    }
 }
@@ -0,0 +1,67 @@
+//! Human-friendly formatting of sizes and durations
+
+use std::time::Duration;
+
+/// Formats a size in bytes using binary units, like `1.5 GiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Formats a duration using its two most significant units, like `3d 4h` or `2m 10s`.
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let parts = [
+        (secs / 86400, "d"),
+        ((secs / 3600) % 24, "h"),
+        ((secs / 60) % 60, "m"),
+        (secs % 60, "s"),
+    ];
+
+    let first = parts.iter().position(|(n, _)| *n > 0);
+    match first {
+        Some(i) => parts[i..]
+            .iter()
+            .take(2)
+            .filter(|(n, _)| *n > 0)
+            .map(|(n, unit)| format!("{}{}", n, unit))
+            .collect::<Vec<_>>()
+            .join(" "),
+        None => format!("{}ms", duration.as_millis()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(5 * 1024 * 1024 * 1024), "5.0 GiB");
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_millis(250)), "250ms");
+        assert_eq!(format_duration(Duration::from_secs(130)), "2m 10s");
+        assert_eq!(
+            format_duration(Duration::from_secs(3 * 86400 + 4 * 3600 + 5)),
+            "3d 4h"
+        );
+        assert_eq!(format_duration(Duration::from_secs(3600)), "1h");
+    }
+}
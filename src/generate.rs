@@ -0,0 +1,388 @@
+//! Generating a commented starting configuration, so that new users don't have to write one from scratch. The answers
+//! to a few questions (where to back up to, what to back up, and how long to keep it) can be asked interactively, and
+//! everything else is left at its default.
+
+use std::fs;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use toml::Value;
+
+/// Where a generated profile stores its backups
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Backend {
+    /// A directory on this machine or a mounted drive
+    Local(String),
+
+    /// A directory on another machine, reached over SFTP, like `user@host:/srv/restic`
+    Sftp(String),
+
+    /// A rest-server URL
+    Rest(String),
+
+    /// An S3 bucket
+    S3(String),
+
+    /// A Backblaze B2 bucket
+    B2(String),
+
+    /// An rclone remote, like `gdrive:restic`
+    Rclone(String),
+}
+
+/// Answers to the questions asked by `ask`
+#[derive(Debug)]
+pub struct Answers {
+    pub profile: String,
+    pub backend: Backend,
+
+    /// Absolute paths to back up
+    pub paths: Vec<String>,
+
+    /// Daily, weekly, and monthly snapshots to keep. Zero leaves that rule out.
+    pub keep_daily: usize,
+    pub keep_weekly: usize,
+    pub keep_monthly: usize,
+
+    /// Directory that the password file, and any backend secret files, are expected in
+    pub secrets_directory: PathBuf,
+}
+
+impl Answers {
+    /// Answers used without `--interactive`: a local repository backing up the home directory
+    pub fn defaults(secrets_directory: PathBuf) -> Answers {
+        let home = dirs::home_dir()
+            .map(|home| home.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "/home".to_string());
+        Answers {
+            profile: "home".to_string(),
+            backend: Backend::Local("/srv/restic".to_string()),
+            paths: vec![home],
+            keep_daily: 7,
+            keep_weekly: 4,
+            keep_monthly: 12,
+            secrets_directory,
+        }
+    }
+}
+
+/// Asks for each answer on `output`, reading replies from `input`. An empty reply keeps the default in `answers`, and an
+/// invalid one asks again.
+pub fn ask(answers: &mut Answers, input: &mut dyn BufRead, output: &mut dyn Write) -> Result<()> {
+    let mut question = Question { input, output };
+
+    answers.profile = question.ask("Profile name", &answers.profile, |reply| {
+        if reply
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            Ok(reply.to_string())
+        } else {
+            bail!("Use only letters, numbers, `_`, and `-`")
+        }
+    })?;
+
+    let kind = question.ask(
+        "Repository type (local, sftp, rest, s3, b2, or rclone)",
+        "local",
+        |reply| match reply {
+            "local" | "sftp" | "rest" | "s3" | "b2" | "rclone" => Ok(reply.to_string()),
+            _ => bail!("Expected local, sftp, rest, s3, b2, or rclone"),
+        },
+    )?;
+    let required = |reply: &str| Ok(reply.to_string());
+    answers.backend = match kind.as_str() {
+        "local" => Backend::Local(question.ask("Repository directory", "/srv/restic", required)?),
+        "sftp" => Backend::Sftp(question.ask(
+            "SFTP location, like user@host:/srv/restic",
+            "",
+            required,
+        )?),
+        "rest" => Backend::Rest(question.ask(
+            "rest-server URL, like https://nas.lan:8000/laptop",
+            "",
+            required,
+        )?),
+        "s3" => Backend::S3(question.ask("S3 bucket", "", required)?),
+        "b2" => Backend::B2(question.ask("B2 bucket", "", required)?),
+        _ => Backend::Rclone(question.ask("rclone remote, like gdrive:restic", "", required)?),
+    };
+
+    answers.paths = question.ask(
+        "Paths to back up, separated by commas",
+        &answers.paths.join(", "),
+        |reply| {
+            let paths: Vec<String> = reply
+                .split(',')
+                .map(|path| path.trim().to_string())
+                .filter(|path| !path.is_empty())
+                .collect();
+            if let Some(path) = paths.iter().find(|path| !Path::new(path).is_absolute()) {
+                bail!("`{}` is not an absolute path", path);
+            }
+            Ok(paths)
+        },
+    )?;
+
+    let count = |reply: &str| -> Result<usize> {
+        match reply.parse() {
+            Ok(count) => Ok(count),
+            Err(_) => bail!("Expected a number"),
+        }
+    };
+    answers.keep_daily = question.ask(
+        "Daily snapshots to keep",
+        &answers.keep_daily.to_string(),
+        count,
+    )?;
+    answers.keep_weekly = question.ask(
+        "Weekly snapshots to keep",
+        &answers.keep_weekly.to_string(),
+        count,
+    )?;
+    answers.keep_monthly = question.ask(
+        "Monthly snapshots to keep",
+        &answers.keep_monthly.to_string(),
+        count,
+    )?;
+
+    Ok(())
+}
+
+/// A prompt and the stream its replies come from
+struct Question<'a> {
+    input: &'a mut dyn BufRead,
+    output: &'a mut dyn Write,
+}
+
+impl<'a> Question<'a> {
+    /// Asks until `parse` accepts the reply. An empty `default` means a reply is required.
+    fn ask<T>(
+        &mut self,
+        prompt: &str,
+        default: &str,
+        parse: impl Fn(&str) -> Result<T>,
+    ) -> Result<T> {
+        loop {
+            if default.is_empty() {
+                write!(self.output, "{}: ", prompt)?;
+            } else {
+                write!(self.output, "{} [{}]: ", prompt, default)?;
+            }
+            self.output.flush()?;
+
+            let mut reply = String::new();
+            if self.input.read_line(&mut reply)? == 0 {
+                bail!("Input ended before every question was answered");
+            }
+            let reply = match reply.trim() {
+                "" if default.is_empty() => {
+                    writeln!(self.output, "An answer is required")?;
+                    continue;
+                }
+                "" => default,
+                reply => reply,
+            };
+            match parse(reply) {
+                Ok(value) => return Ok(value),
+                Err(err) => writeln!(self.output, "{}", err)?,
+            }
+        }
+    }
+}
+
+/// Renders the configuration file for `answers`
+pub fn render(answers: &Answers) -> String {
+    let profile = &answers.profile;
+    let secret = |name: &str| quote(&answers.secrets_directory.join(name).to_string_lossy());
+    let backend = match answers.backend {
+        Backend::Local(ref directory) => format!(
+            "# Where to store backups. See https://restic.readthedocs.io/en/stable/030_preparing_a_new_repo.html for other\n\
+             # backends.\n\
+             repository = {}",
+            quote(directory)
+        ),
+        Backend::Sftp(ref location) => format!(
+            "# Where to store backups, over SFTP. Restic uses your SSH configuration and keys to connect.\n\
+             repository = {}",
+            quote(&format!("sftp:{}", location))
+        ),
+        Backend::Rest(ref url) => format!(
+            "# Where to store backups. Set `username` and `password_file` if the server requires them.\n\
+             rest = {{ url = {} }}",
+            quote(url)
+        ),
+        Backend::S3(ref bucket) => format!(
+            "# Where to store backups. Put the secret access key in `secret_access_key_file`, and set `region` and\n\
+             # `endpoint` if the bucket isn't in AWS's default region.\n\
+             s3 = {{ bucket = {}, access_key_id = \"your-access-key-id\", secret_access_key_file = {} }}",
+            quote(bucket),
+            secret("s3-secret-access-key")
+        ),
+        Backend::B2(ref bucket) => format!(
+            "# Where to store backups. Put the application key in `account_key_file`.\n\
+             b2 = {{ bucket = {}, account_id = \"your-key-id\", account_key_file = {} }}",
+            quote(bucket),
+            secret("b2-application-key")
+        ),
+        Backend::Rclone(ref remote) => format!(
+            "# Where to store backups, through rclone. Run `rclone config` first to set up the remote.\n\
+             rclone = {{ remote = {} }}",
+            quote(remote)
+        ),
+    };
+
+    let mut retention = String::new();
+    for (rule, count) in [
+        ("keep_daily", answers.keep_daily),
+        ("keep_weekly", answers.keep_weekly),
+        ("keep_monthly", answers.keep_monthly),
+    ] {
+        if count > 0 {
+            retention.push_str(&format!("{} = {}\n", rule, count));
+        }
+    }
+    if retention.is_empty() {
+        retention.push_str("# keep_daily = 7\n");
+    }
+
+    let paths: String = answers
+        .paths
+        .iter()
+        .map(|path| format!("    {},\n", quote(path)))
+        .collect();
+
+    format!(
+        r#"# Rustic configuration, generated by `rustic generate config`. See the README for every setting.
+
+# Path to the restic command. Set to `managed` to use the copy installed by `rustic self install-restic`.
+restic_command = "restic"
+
+[profiles.{profile}]
+{backend}
+
+# File containing the repository password. Create it before running `rustic init {profile}`, and keep a copy somewhere
+# safe: the backups can't be restored without it.
+password_file = {password_file}
+
+# Directory that the patterns below are relative to
+base_directory = "/"
+
+# Skip directories containing a CACHEDIR.TAG file, like many applications' caches
+exclude_caches = true
+
+# When to back up, once `rustic install-units {profile}` has installed a systemd timer
+# schedule = "*-*-* 03:00:00"
+
+# Snapshots to keep when running `rustic forget {profile}`
+[profiles.{profile}.retention]
+{retention}
+# What to back up
+[profiles.{profile}.include]
+patterns = [
+{paths}]
+
+# What to leave out
+[profiles.{profile}.exclude]
+patterns = [
+    "**/.cache",
+    "**/node_modules",
+    "**/target",
+]
+"#,
+        profile = profile,
+        backend = backend,
+        password_file = secret("password.txt"),
+        retention = retention,
+        paths = paths,
+    )
+}
+
+/// Writes a rendered configuration to `path`, creating its directory if needed. In a dry run, prints where it would be
+/// written instead. Returns whether it was written.
+pub fn write(path: &Path, contents: &str, dry_run: bool) -> Result<bool> {
+    if dry_run {
+        println!("Would write {}:\n{}", path.display(), contents);
+        return Ok(false);
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Could not create {}", parent.display()))?;
+    }
+    fs::write(path, contents).with_context(|| format!("Could not write {}", path.display()))?;
+    Ok(true)
+}
+
+/// Quotes a string for TOML
+fn quote(value: &str) -> String {
+    Value::String(value.to_string()).to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    use tempfile::TempDir;
+
+    use super::{ask, render, write, Answers, Backend};
+    use crate::config::Configuration;
+
+    #[test]
+    fn test_render() {
+        let mut answers = Answers::defaults(PathBuf::from("/etc/rustic"));
+        let mut prompts = Vec::new();
+        ask(
+            &mut answers,
+            &mut Cursor::new("laptop\nftp\nb2\n\nmy-bucket\n/home/me, /etc\n\n0\nlots\n6\n"),
+            &mut prompts,
+        )
+        .unwrap();
+        assert_eq!(answers.profile, "laptop");
+        assert_eq!(answers.backend, Backend::B2("my-bucket".to_string()));
+        assert_eq!(answers.paths, vec!["/home/me", "/etc"]);
+        assert_eq!(
+            (
+                answers.keep_daily,
+                answers.keep_weekly,
+                answers.keep_monthly
+            ),
+            (7, 0, 6)
+        );
+        let prompts = String::from_utf8(prompts).unwrap();
+        assert!(prompts.contains("Expected local, sftp, rest, s3, b2, or rclone"));
+        assert!(prompts.contains("An answer is required"));
+        assert!(prompts.contains("Expected a number"));
+
+        let config = Configuration::parse(&render(&answers)).unwrap();
+        let profile = &config.profiles["laptop"];
+        assert_eq!(profile.repository, "b2:my-bucket");
+        assert_eq!(profile.include.patterns, vec!["/home/me", "/etc"]);
+        assert_eq!(profile.retention.keep_daily, Some(7));
+        assert_eq!(profile.retention.keep_weekly, None);
+
+        // Every backend renders a valid configuration
+        for backend in [
+            Backend::Local("/srv/restic".to_string()),
+            Backend::Sftp("me@nas:/srv/restic".to_string()),
+            Backend::Rest("https://nas.lan:8000/laptop".to_string()),
+            Backend::S3("my-bucket".to_string()),
+            Backend::Rclone("gdrive:restic".to_string()),
+        ] {
+            answers.backend = backend;
+            Configuration::parse(&render(&answers)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_write_dry_run() {
+        let root = TempDir::new().unwrap();
+        let path = root.path().join("rustic").join("config.toml");
+        assert!(!write(&path, "[defaults]\n", true).unwrap());
+        assert!(!root.path().join("rustic").exists());
+
+        assert!(write(&path, "[defaults]\n", false).unwrap());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "[defaults]\n");
+    }
+}
@@ -0,0 +1,50 @@
+//! Running user-defined hook commands
+
+use std::ffi::OsStr;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use slog::{debug, Logger};
+
+/// Runs a hook command through the system shell, failing if it exits unsuccessfully.
+pub fn run_hook<I, K, V>(
+    logger: &Logger,
+    hook: &str,
+    working_directory: &Path,
+    env: I,
+) -> Result<()>
+where
+    I: IntoIterator<Item = (K, V)>,
+    K: AsRef<OsStr>,
+    V: AsRef<OsStr>,
+{
+    let mut cmd = shell_command(hook);
+    cmd.current_dir(working_directory).envs(env);
+
+    debug!(logger, "Running hook"; "hook" => hook);
+    let status = cmd
+        .status()
+        .with_context(|| format!("Could not run hook `{}`", hook))?;
+    if !status.success() {
+        bail!("Hook `{}` failed with {}", hook, status);
+    }
+
+    Ok(())
+}
+
+/// Creates a command that runs `command` with the system shell
+#[cfg(unix)]
+pub fn shell_command(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+/// Creates a command that runs `command` with the system shell
+#[cfg(windows)]
+pub fn shell_command(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
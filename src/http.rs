@@ -0,0 +1,135 @@
+//! Minimal HTTP client support, using `curl`
+
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context, Result};
+
+use crate::redact;
+
+/// How long to wait for an HTTP request to complete, in seconds
+const TIMEOUT_SECS: &str = "30";
+
+/// Sends an HTTP request, failing if the server responds with an error status. The URL, headers, and body are passed to
+/// `curl` as a config file over stdin, since any of them can hold a secret (like a bot token in the URL or an
+/// `Authorization` header), and command lines show up in process listings.
+pub fn send(method: &str, url: &str, headers: &[(&str, &str)], body: Option<&str>) -> Result<()> {
+    let mut cmd = Command::new("curl");
+    cmd.arg("--fail")
+        .arg("--silent")
+        .arg("--show-error")
+        .arg("--max-time")
+        .arg(TIMEOUT_SECS)
+        .arg("--retry")
+        .arg("3")
+        .arg("--output")
+        .arg(if cfg!(windows) { "NUL" } else { "/dev/null" })
+        .arg("--config")
+        .arg("-");
+    cmd.stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+
+    let mut child = cmd.spawn().context("Could not run curl")?;
+    {
+        let mut stdin = child.stdin.take().unwrap();
+        stdin
+            .write_all(curl_config(method, url, headers, body).as_bytes())
+            .context("Could not send request to curl")?;
+    }
+
+    let output = child.wait_with_output().context("Could not run curl")?;
+    if !output.status.success() {
+        bail!(
+            "{} {} failed: {}",
+            method,
+            redact::http_url(url),
+            redact::http_url(String::from_utf8_lossy(&output.stderr).trim())
+        );
+    }
+
+    Ok(())
+}
+
+/// Writes a request as a `curl` config file
+fn curl_config(method: &str, url: &str, headers: &[(&str, &str)], body: Option<&str>) -> String {
+    let mut config = String::new();
+    let mut option = |name: &str, value: &str| {
+        config.push_str(name);
+        config.push_str(" = \"");
+        for c in value.chars() {
+            match c {
+                '\\' => config.push_str("\\\\"),
+                '"' => config.push_str("\\\""),
+                '\n' => config.push_str("\\n"),
+                '\r' => config.push_str("\\r"),
+                '\t' => config.push_str("\\t"),
+                c => config.push(c),
+            }
+        }
+        config.push_str("\"\n");
+    };
+    option("request", method);
+    option("url", url);
+    for (name, value) in headers {
+        option("header", &format!("{}: {}", name, value));
+    }
+    // Unlike `data-binary`, `data-raw` doesn't read a file if the body starts with `@`
+    if let Some(body) = body {
+        option("data-raw", body);
+    }
+    config
+}
+
+/// Downloads `url` to `path`, following redirects
+pub fn download(url: &str, path: &Path) -> Result<()> {
+    let output = Command::new("curl")
+        .arg("--fail")
+        .arg("--silent")
+        .arg("--show-error")
+        .arg("--location")
+        .arg("--retry")
+        .arg("3")
+        .arg("--output")
+        .arg(path)
+        .arg("--")
+        .arg(url)
+        .stdin(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .context("Could not run curl")?;
+    if !output.status.success() {
+        bail!(
+            "Downloading {} failed: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::curl_config;
+
+    #[test]
+    fn test_curl_config() {
+        let body = format!("{}\n", r#"{"text":"say \"hi\""}"#);
+        let expected = r#"request = "POST"
+url = "https://example.com/hooks/abc"
+header = "Authorization: Bearer secret"
+data-raw = "{\"text\":\"say \\\"hi\\\"\"}\n"
+"#;
+        assert_eq!(
+            curl_config(
+                "POST",
+                "https://example.com/hooks/abc",
+                &[("Authorization", "Bearer secret")],
+                Some(&body),
+            ),
+            expected
+        );
+    }
+}
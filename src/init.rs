@@ -0,0 +1,57 @@
+//! Repository initialization
+
+use std::ffi::OsString;
+
+use anyhow::{bail, Context, Result};
+use slog::{debug, info};
+
+use crate::redact;
+use crate::restic::{add_password_source, Restic};
+
+/// Extends the Restic wrapper with repository initialization
+impl<'a> Restic<'a> {
+    /// Attempts to initialize the Restic repository, using the profile's `init` options. Note that this *does not* check if
+    /// the repository has already been initialized.
+    pub fn init(&self) -> Result<()> {
+        let options = &self.profile().init;
+
+        let mut cmd = self.new_command();
+        cmd.arg("init");
+        if let Some(ref version) = options.repository_version {
+            cmd.arg("--repository-version").arg(version);
+        }
+        if let Some(ref source_name) = options.copy_chunker_params_from {
+            let source = Restic::for_profile(self.config(), self.logger(), source_name.clone(), 0)
+                .with_context(|| {
+                    format!(
+                        "Could not copy chunker parameters from profile `{}`",
+                        source_name
+                    )
+                })?;
+            let source_profile = source.profile();
+
+            let mut args: Vec<OsString> =
+                vec!["--copy-chunker-params".into(), "--from-repo".into()];
+            args.push(source_profile.repository.clone().into());
+            let mut env = source.env().clone();
+            env.remove(&OsString::from("RESTIC_PASSWORD"));
+            add_password_source(
+                source_profile.password_settings(),
+                true,
+                &mut args,
+                &mut env,
+            )?;
+            cmd.args(&args).envs(&env);
+        }
+
+        info!(self.logger(), "Initializing Restic repository"; "command" => %redact::command_line(&cmd));
+        let status = self.execute(&mut cmd, None)?.status;
+
+        if status.success() {
+            debug!(self.logger(), "Restic repository initialized");
+            Ok(())
+        } else {
+            bail!(self.failed("init", status));
+        }
+    }
+}
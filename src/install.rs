@@ -0,0 +1,322 @@
+//! Installing a copy of Restic managed by Rustic, which profiles use by setting `restic_command = "managed"`
+
+use std::env::consts;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::{anyhow, bail, Context, Result};
+use slog::{debug, info, Logger};
+use tempfile::{NamedTempFile, TempDir};
+
+use crate::http;
+use crate::json;
+use crate::sha256;
+use crate::version::ResticVersion;
+
+/// `restic_command` value that refers to the managed copy of Restic
+pub const MANAGED_RESTIC: &str = "managed";
+
+/// Where Restic releases are published
+const RELEASES_URL: &str = "https://github.com/restic/restic/releases/download";
+
+/// GitHub API endpoint describing the latest Restic release
+const LATEST_RELEASE_URL: &str = "https://api.github.com/repos/restic/restic/releases/latest";
+
+/// Fingerprint of the key Restic releases are signed with, as listed in the Restic installation docs
+const RELEASE_KEY_FINGERPRINT: &str = "CF8F18F2844575973F79D4E191A6868BD3F7A907";
+
+/// Keyserver to fetch the release key from
+const KEYSERVER: &str = "hkps://keys.openpgp.org";
+
+/// Path the managed copy of Restic is installed to
+pub fn managed_restic_path() -> Result<PathBuf> {
+    let binary = if cfg!(windows) {
+        "restic.exe"
+    } else {
+        "restic"
+    };
+    dirs::data_local_dir()
+        .map(|dir| dir.join("rustic").join("bin").join(binary))
+        .ok_or_else(|| anyhow!("Could not determine where to install Restic"))
+}
+
+/// Resolves a `restic_command` setting to the command to run, replacing `managed` with the path to the managed copy of
+/// Restic
+pub fn resolve_restic_command(command: &str) -> Result<OsString> {
+    if command != MANAGED_RESTIC {
+        return Ok(command.into());
+    }
+
+    let path = managed_restic_path()?;
+    if !path.is_file() {
+        bail!("`restic_command` is `managed`, but Restic isn't installed yet. Run `rustic self install-restic` to install it.");
+    }
+    Ok(path.into_os_string())
+}
+
+/// Downloads an official Restic release and installs it as the managed copy of Restic, replacing any earlier version. If no
+/// version is given, the latest release is installed. In a dry run, only the release to download and where it would be
+/// installed are printed.
+///
+/// The download is checked against the release's `SHA256SUMS` file. If `verify_signature` is set, the signature on that file
+/// is checked as well, using `gpg` and the Restic release key.
+pub fn install_restic(
+    logger: &Logger,
+    version: Option<ResticVersion>,
+    verify_signature: bool,
+    dry_run: bool,
+) -> Result<PathBuf> {
+    let work_dir = TempDir::new().context("Could not create temporary directory")?;
+    let version = match version {
+        Some(version) => version,
+        None => latest_version(work_dir.path())?,
+    };
+    let (os, arch) = release_platform()?;
+    let extension = if cfg!(windows) { "zip" } else { "bz2" };
+    let asset = format!("restic_{}_{}_{}.{}", version, os, arch, extension);
+    let release_url = format!("{}/v{}", RELEASES_URL, version);
+    if dry_run {
+        let path = managed_restic_path()?;
+        println!(
+            "Would download {}/{} and install it to {}",
+            release_url,
+            asset,
+            path.display()
+        );
+        return Ok(path);
+    }
+    info!(logger, "Installing Restic {}", version; "asset" => &asset);
+
+    let checksums_path = work_dir.path().join("SHA256SUMS");
+    http::download(&format!("{}/SHA256SUMS", release_url), &checksums_path)?;
+    if verify_signature {
+        let signature_path = work_dir.path().join("SHA256SUMS.asc");
+        http::download(&format!("{}/SHA256SUMS.asc", release_url), &signature_path)?;
+        verify_checksums_signature(logger, work_dir.path(), &checksums_path, &signature_path)?;
+    }
+
+    let asset_path = work_dir.path().join(&asset);
+    http::download(&format!("{}/{}", release_url, asset), &asset_path)?;
+    let checksums = fs::read_to_string(&checksums_path).context("Could not read SHA256SUMS")?;
+    let expected = expected_checksum(&checksums, &asset)
+        .ok_or_else(|| anyhow!("SHA256SUMS does not list {}", asset))?;
+    let actual = sha256::hex_digest_file(&asset_path)
+        .with_context(|| format!("Could not read {}", asset_path.display()))?;
+    if !actual.eq_ignore_ascii_case(expected) {
+        bail!(
+            "Checksum mismatch for {}: expected {}, got {}",
+            asset,
+            expected,
+            actual
+        );
+    }
+    debug!(logger, "Verified checksum"; "asset" => &asset, "sha256" => &actual);
+
+    let path = managed_restic_path()?;
+    let install_dir = path.parent().unwrap();
+    fs::create_dir_all(install_dir)
+        .with_context(|| format!("Could not create {}", install_dir.display()))?;
+    let binary = NamedTempFile::new_in(install_dir)
+        .with_context(|| format!("Could not create file in {}", install_dir.display()))?;
+    extract(work_dir.path(), &asset_path, binary.as_file())?;
+    make_executable(binary.path())?;
+    binary
+        .persist(&path)
+        .with_context(|| format!("Could not install Restic to {}", path.display()))?;
+
+    info!(logger, "Installed Restic {} to {}", version, path.display());
+    Ok(path)
+}
+
+/// Looks up the version of the latest Restic release
+fn latest_version(work_dir: &Path) -> Result<ResticVersion> {
+    let release_path = work_dir.join("release.json");
+    http::download(LATEST_RELEASE_URL, &release_path)?;
+    let release = json::parse(&fs::read_to_string(&release_path)?)
+        .context("Could not parse the latest Restic release")?;
+    release
+        .get("tag_name")
+        .and_then(|tag| tag.as_str())
+        .ok_or_else(|| anyhow!("Could not find the latest Restic release"))?
+        .trim_start_matches('v')
+        .parse()
+}
+
+/// Restic's names for the current operating system and architecture
+fn release_platform() -> Result<(&'static str, &'static str)> {
+    let os = match consts::OS {
+        "linux" => "linux",
+        "macos" => "darwin",
+        "windows" => "windows",
+        "freebsd" => "freebsd",
+        "openbsd" => "openbsd",
+        "netbsd" => "netbsd",
+        os => bail!("Restic does not publish releases for {}", os),
+    };
+    let arch = match consts::ARCH {
+        "x86_64" => "amd64",
+        "x86" => "386",
+        "aarch64" => "arm64",
+        "arm" => "arm",
+        "riscv64" => "riscv64",
+        "s390x" => "s390x",
+        arch => bail!("Restic does not publish releases for {}", arch),
+    };
+    Ok((os, arch))
+}
+
+/// Finds the checksum for `asset` in the contents of a `SHA256SUMS` file
+fn expected_checksum<'c>(checksums: &'c str, asset: &str) -> Option<&'c str> {
+    checksums.lines().find_map(|line| {
+        let mut parts = line.split_whitespace();
+        let checksum = parts.next()?;
+        let name = parts.next()?.trim_start_matches('*');
+        if name == asset {
+            Some(checksum)
+        } else {
+            None
+        }
+    })
+}
+
+/// Checks the signature on `SHA256SUMS` with a throwaway `gpg` home directory containing only the Restic release key
+fn verify_checksums_signature(
+    logger: &Logger,
+    work_dir: &Path,
+    checksums: &Path,
+    signature: &Path,
+) -> Result<()> {
+    let gpg_home = work_dir.join("gnupg");
+    fs::create_dir(&gpg_home).context("Could not create gpg home directory")?;
+    let gpg = |args: &[&std::ffi::OsStr]| {
+        Command::new("gpg")
+            .arg("--homedir")
+            .arg(&gpg_home)
+            .arg("--batch")
+            .args(args)
+            .stdin(Stdio::null())
+            .output()
+            .context("Could not run gpg. Install GnuPG, or pass `--no-verify-signature` to only check checksums.")
+    };
+
+    let output = gpg(&[
+        "--keyserver".as_ref(),
+        KEYSERVER.as_ref(),
+        "--recv-keys".as_ref(),
+        RELEASE_KEY_FINGERPRINT.as_ref(),
+    ])?;
+    if !output.status.success() {
+        bail!(
+            "Could not fetch the Restic release key: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    let output = gpg(&[
+        "--status-fd".as_ref(),
+        "1".as_ref(),
+        "--verify".as_ref(),
+        signature.as_os_str(),
+        checksums.as_os_str(),
+    ])?;
+    let status = String::from_utf8_lossy(&output.stdout);
+    let valid = status.lines().any(|line| {
+        line.starts_with("[GNUPG:] VALIDSIG ") && line.ends_with(RELEASE_KEY_FINGERPRINT)
+    });
+    if !output.status.success() || !valid {
+        bail!(
+            "SHA256SUMS is not signed by the Restic release key: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    debug!(logger, "Verified SHA256SUMS signature"; "key" => RELEASE_KEY_FINGERPRINT);
+    Ok(())
+}
+
+/// Extracts the Restic binary from a release archive into `out`
+fn extract(work_dir: &Path, archive: &Path, out: &File) -> Result<()> {
+    if cfg!(windows) {
+        // Windows releases are zip files containing a single executable. Windows 10 and later come with bsdtar, which can
+        // extract them.
+        let extract_dir = work_dir.join("extracted");
+        fs::create_dir(&extract_dir)?;
+        let status = Command::new("tar")
+            .arg("-xf")
+            .arg(archive)
+            .arg("-C")
+            .arg(&extract_dir)
+            .status()
+            .context("Could not run tar")?;
+        if !status.success() {
+            bail!("Extracting {} failed with {}", archive.display(), status);
+        }
+        let executable = fs::read_dir(&extract_dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .find(|path| path.extension().is_some_and(|ext| ext == "exe"))
+            .ok_or_else(|| anyhow!("{} does not contain Restic", archive.display()))?;
+        std::io::copy(&mut File::open(executable)?, &mut &*out)?;
+    } else {
+        let status = Command::new("bunzip2")
+            .arg("--stdout")
+            .arg(archive)
+            .stdout(Stdio::from(out.try_clone()?))
+            .status()
+            .context("Could not run bunzip2")?;
+        if !status.success() {
+            bail!("Extracting {} failed with {}", archive.display(), status);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn make_executable(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755))
+        .with_context(|| format!("Could not make {} executable", path.display()))
+}
+
+#[cfg(not(unix))]
+fn make_executable(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use slog::{o, Discard, Logger};
+
+    use super::{expected_checksum, install_restic, managed_restic_path};
+    use crate::version::ResticVersion;
+
+    #[test]
+    fn test_expected_checksum() {
+        let checksums = "\
+aaaa  restic_0.17.3_darwin_arm64.bz2
+bbbb  restic_0.17.3_linux_amd64.bz2
+cccc *restic_0.17.3_windows_amd64.zip
+";
+        assert_eq!(
+            expected_checksum(checksums, "restic_0.17.3_linux_amd64.bz2"),
+            Some("bbbb")
+        );
+        assert_eq!(
+            expected_checksum(checksums, "restic_0.17.3_windows_amd64.zip"),
+            Some("cccc")
+        );
+        assert_eq!(
+            expected_checksum(checksums, "restic_0.17.3_linux_386.bz2"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_install_restic_dry_run() {
+        // Nothing can be downloaded in tests, so this only succeeds if nothing is
+        let logger = Logger::root(Discard, o!());
+        let path = install_restic(&logger, Some(ResticVersion::new(0, 17, 3)), true, true).unwrap();
+        assert_eq!(path, managed_restic_path().unwrap());
+    }
+}
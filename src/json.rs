@@ -0,0 +1,358 @@
+//! Minimal JSON support, for reading Restic's `--json` output and writing machine-readable output of our own.
+//!
+//! Rather than pulling in a separate JSON library, this reuses `toml::Value` as the document model, since it already implements
+//! serde's `Serializer` and `Deserializer` traits. The mapping is lossy in two ways: TOML has no `null`, so nulls are dropped from
+//! objects and arrays (which serde treats the same as a missing `Option`), and object keys come back out in sorted order.
+
+use std::fmt::Write;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use toml::value::{Table, Value};
+
+/// Parses a JSON document into a value of type `T`.
+pub fn from_str<T: DeserializeOwned>(s: &str) -> Result<T> {
+    let value = parse(s)?;
+    value.try_into().context("Unexpected JSON structure")
+}
+
+/// Serializes a value as indented JSON.
+pub fn to_string_pretty<T: Serialize>(value: &T) -> Result<String> {
+    let value = Value::try_from(value).context("Could not serialize value")?;
+    let mut out = String::new();
+    write_value(&mut out, &value, Some(0));
+    Ok(out)
+}
+
+/// Serializes a value as compact JSON, on a single line.
+pub fn to_string<T: Serialize>(value: &T) -> Result<String> {
+    let value = Value::try_from(value).context("Could not serialize value")?;
+    let mut out = String::new();
+    write_value(&mut out, &value, None);
+    Ok(out)
+}
+
+/// Parses a JSON document into an untyped value.
+pub fn parse(s: &str) -> Result<Value> {
+    let mut parser = Parser { input: s, pos: 0 };
+    let value = parser.parse_value()?;
+    parser.skip_whitespace();
+    if parser.pos != s.len() {
+        bail!("Trailing characters at position {}", parser.pos);
+    }
+    value.ok_or_else(|| anyhow!("JSON document is null"))
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<char> {
+        self.input[self.pos..].chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek() {
+            if c.is_ascii_whitespace() {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        match self.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => bail!(
+                "Expected `{}` but found `{}` at position {}",
+                expected,
+                c,
+                self.pos - c.len_utf8()
+            ),
+            None => bail!("Expected `{}` but found end of input", expected),
+        }
+    }
+
+    fn expect_literal(&mut self, literal: &str) -> Result<()> {
+        if self.input[self.pos..].starts_with(literal) {
+            self.pos += literal.len();
+            Ok(())
+        } else {
+            bail!("Invalid literal at position {}", self.pos)
+        }
+    }
+
+    /// Parses any JSON value. Returns `None` for `null`.
+    fn parse_value(&mut self) -> Result<Option<Value>> {
+        self.skip_whitespace();
+        let value = match self.peek() {
+            Some('{') => Value::Table(self.parse_object()?),
+            Some('[') => Value::Array(self.parse_array()?),
+            Some('"') => Value::String(self.parse_string()?),
+            Some('t') => {
+                self.expect_literal("true")?;
+                Value::Boolean(true)
+            }
+            Some('f') => {
+                self.expect_literal("false")?;
+                Value::Boolean(false)
+            }
+            Some('n') => {
+                self.expect_literal("null")?;
+                return Ok(None);
+            }
+            Some(c) if c == '-' || c.is_ascii_digit() => self.parse_number()?,
+            Some(c) => bail!("Unexpected character `{}` at position {}", c, self.pos),
+            None => bail!("Unexpected end of input"),
+        };
+        Ok(Some(value))
+    }
+
+    fn parse_object(&mut self) -> Result<Table> {
+        self.expect('{')?;
+        let mut table = Table::new();
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            return Ok(table);
+        }
+
+        loop {
+            self.skip_whitespace();
+            let key = self.parse_string()?;
+            self.skip_whitespace();
+            self.expect(':')?;
+            if let Some(value) = self.parse_value()? {
+                table.insert(key, value);
+            }
+            self.skip_whitespace();
+            match self.next() {
+                Some(',') => continue,
+                Some('}') => return Ok(table),
+                _ => bail!("Expected `,` or `}}` at position {}", self.pos),
+            }
+        }
+    }
+
+    fn parse_array(&mut self) -> Result<Vec<Value>> {
+        self.expect('[')?;
+        let mut array = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(']') {
+            self.pos += 1;
+            return Ok(array);
+        }
+
+        loop {
+            if let Some(value) = self.parse_value()? {
+                array.push(value);
+            }
+            self.skip_whitespace();
+            match self.next() {
+                Some(',') => continue,
+                Some(']') => return Ok(array),
+                _ => bail!("Expected `,` or `]` at position {}", self.pos),
+            }
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(s),
+                Some('\\') => match self.next() {
+                    Some('"') => s.push('"'),
+                    Some('\\') => s.push('\\'),
+                    Some('/') => s.push('/'),
+                    Some('b') => s.push('\u{8}'),
+                    Some('f') => s.push('\u{c}'),
+                    Some('n') => s.push('\n'),
+                    Some('r') => s.push('\r'),
+                    Some('t') => s.push('\t'),
+                    Some('u') => {
+                        let high = self.parse_hex4()?;
+                        let code = if (0xD800..0xDC00).contains(&high) {
+                            // Surrogate pair
+                            self.expect_literal("\\u")?;
+                            let low = self.parse_hex4()?;
+                            0x10000 + ((high - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF)
+                        } else {
+                            high
+                        };
+                        s.push(std::char::from_u32(code).unwrap_or('\u{FFFD}'));
+                    }
+                    _ => bail!("Invalid escape sequence at position {}", self.pos),
+                },
+                Some(c) => s.push(c),
+                None => bail!("Unterminated string"),
+            }
+        }
+    }
+
+    fn parse_hex4(&mut self) -> Result<u32> {
+        let digits = self
+            .input
+            .get(self.pos..self.pos + 4)
+            .ok_or_else(|| anyhow!("Truncated unicode escape"))?;
+        let code = u32::from_str_radix(digits, 16)
+            .with_context(|| format!("Invalid unicode escape at position {}", self.pos))?;
+        self.pos += 4;
+        Ok(code)
+    }
+
+    fn parse_number(&mut self) -> Result<Value> {
+        let start = self.pos;
+        let mut is_float = false;
+        while let Some(c) = self.peek() {
+            match c {
+                '0'..='9' | '-' | '+' => {}
+                '.' | 'e' | 'E' => is_float = true,
+                _ => break,
+            }
+            self.pos += 1;
+        }
+
+        let number = &self.input[start..self.pos];
+        if !is_float {
+            if let Ok(n) = number.parse::<i64>() {
+                return Ok(Value::Integer(n));
+            }
+        }
+        number
+            .parse::<f64>()
+            .map(Value::Float)
+            .with_context(|| format!("Invalid number `{}`", number))
+    }
+}
+
+fn write_value(out: &mut String, value: &Value, indent: Option<usize>) {
+    match value {
+        Value::String(s) => write_string(out, s),
+        Value::Integer(n) => write!(out, "{}", n).unwrap(),
+        Value::Float(f) if f.is_finite() => write!(out, "{}", f).unwrap(),
+        Value::Float(_) => out.push_str("null"),
+        Value::Boolean(b) => write!(out, "{}", b).unwrap(),
+        Value::Datetime(d) => write_string(out, &d.to_string()),
+        Value::Array(array) => {
+            if array.is_empty() {
+                out.push_str("[]");
+                return;
+            }
+            out.push('[');
+            for (i, element) in array.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                newline(out, indent.map(|n| n + 1));
+                write_value(out, element, indent.map(|n| n + 1));
+            }
+            newline(out, indent);
+            out.push(']');
+        }
+        Value::Table(table) => {
+            if table.is_empty() {
+                out.push_str("{}");
+                return;
+            }
+            out.push('{');
+            for (i, (key, element)) in table.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                newline(out, indent.map(|n| n + 1));
+                write_string(out, key);
+                out.push(':');
+                if indent.is_some() {
+                    out.push(' ');
+                }
+                write_value(out, element, indent.map(|n| n + 1));
+            }
+            newline(out, indent);
+            out.push('}');
+        }
+    }
+}
+
+fn newline(out: &mut String, indent: Option<usize>) {
+    if let Some(indent) = indent {
+        out.push('\n');
+        for _ in 0..indent {
+            out.push_str("  ");
+        }
+    }
+}
+
+fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod test {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Example {
+        name: String,
+        count: u64,
+        ratio: f64,
+        tags: Vec<String>,
+        parent: Option<String>,
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let input = r#"{"name": "café \"quoted\"\n", "count": 42, "ratio": 0.5e1, "tags": ["a", "b"], "parent": null, "extra": [1, {"x": true}]}"#;
+        let parsed: Example = parse(input).unwrap().try_into().unwrap();
+        assert_eq!(
+            parsed,
+            Example {
+                name: "café \"quoted\"\n".to_string(),
+                count: 42,
+                ratio: 5.0,
+                tags: vec!["a".to_string(), "b".to_string()],
+                parent: None,
+            }
+        );
+
+        let serialized = to_string_pretty(&parsed).unwrap();
+        assert_eq!(
+            serialized,
+            "{\n  \"count\": 42,\n  \"name\": \"café \\\"quoted\\\"\\n\",\n  \"ratio\": 5,\n  \"tags\": [\n    \"a\",\n    \"b\"\n  ]\n}"
+        );
+        assert_eq!(from_str::<Example>(&serialized).unwrap(), parsed);
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(parse("{\"a\": }").is_err());
+        assert!(parse("[1, 2").is_err());
+        assert!(parse("{} x").is_err());
+        assert!(parse("null").is_err());
+    }
+}
@@ -0,0 +1,133 @@
+//! Repository key management
+
+use std::io::{self, prelude::*};
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use slog::{error, info, warn};
+
+use crate::json;
+use crate::output::{self, OutputFormat};
+use crate::redact;
+use crate::restic::Restic;
+
+/// A repository key, from `restic key list --json`
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Key {
+    /// Key ID
+    pub id: String,
+
+    /// Whether this is the key the profile's password unlocks
+    #[serde(default)]
+    pub current: bool,
+
+    /// User who created the key
+    #[serde(default, rename = "userName")]
+    pub user_name: String,
+
+    /// Host the key was created on
+    #[serde(default, rename = "hostName")]
+    pub host_name: String,
+
+    /// When the key was created, formatted by Restic
+    #[serde(default)]
+    pub created: String,
+}
+
+/// Extends the Restic wrapper with key management
+impl<'a> Restic<'a> {
+    /// Lists the keys that can unlock the repository
+    pub fn list_keys(&self) -> Result<Vec<Key>> {
+        let mut cmd = self.new_command();
+        cmd.arg("key").arg("list").arg("--json");
+        let stdout = self
+            .execute_output(&mut cmd)
+            .context("Listing keys failed")?;
+        json::from_str(&stdout).context("Could not parse `restic key list` output")
+    }
+
+    /// Prints the keys that can unlock the repository, marking the one the profile uses
+    pub fn show_keys(&self, output: OutputFormat) -> Result<()> {
+        let keys = self.list_keys()?;
+        if output == OutputFormat::Json {
+            return output::print_json(&keys);
+        }
+
+        let mut tw = tabwriter::TabWriter::new(io::stdout());
+        writeln!(tw, " \tID\tUser\tHost\tCreated")?;
+        writeln!(tw, " \t--\t----\t----\t-------")?;
+        for key in keys.iter() {
+            writeln!(
+                tw,
+                "{}\t{}\t{}\t{}\t{}",
+                if key.current { "*" } else { " " },
+                &key.id[..key.id.len().min(8)],
+                key.user_name,
+                key.host_name,
+                key.created
+            )?;
+        }
+        tw.flush()?;
+        Ok(())
+    }
+
+    /// Adds a new key to the repository. If `new_password_file` isn't given, Restic prompts for the new password.
+    pub fn add_key(
+        &self,
+        new_password_file: Option<&Path>,
+        user: Option<&str>,
+        host: Option<&str>,
+    ) -> Result<()> {
+        let mut cmd = self.new_command();
+        cmd.arg("key").arg("add");
+        if let Some(file) = new_password_file {
+            cmd.arg("--new-password-file").arg(file);
+        }
+        if let Some(user) = user {
+            cmd.arg("--user").arg(user);
+        }
+        if let Some(host) = host {
+            cmd.arg("--host").arg(host);
+        }
+        self.run_key_command(cmd, "Adding key")
+    }
+
+    /// Removes a key from the repository. Restic refuses to remove the key the profile's password unlocks.
+    pub fn remove_key(&self, id: &str) -> Result<()> {
+        self.require_full_access("key remove")?;
+        let mut cmd = self.new_command();
+        cmd.arg("key").arg("remove").arg(id);
+        self.run_key_command(cmd, "Removing key")
+    }
+
+    /// Changes the password of the key the profile uses. If `new_password_file` isn't given, Restic prompts for the new
+    /// password. Afterwards, the profile's password settings must be updated to match.
+    pub fn change_password(&self, new_password_file: Option<&Path>) -> Result<()> {
+        let mut cmd = self.new_command();
+        cmd.arg("key").arg("passwd");
+        if let Some(file) = new_password_file {
+            cmd.arg("--new-password-file").arg(file);
+        }
+        self.run_key_command(cmd, "Changing password")?;
+        warn!(
+            self.logger(),
+            "Repository password changed, update the password for profile `{}` to match",
+            self.profile_name()
+        );
+        Ok(())
+    }
+
+    fn run_key_command(&self, mut cmd: Command, description: &str) -> Result<()> {
+        info!(self.logger(), "{}", description; "command" => %redact::command_line(&cmd));
+        // Not using execute(), since Restic's password prompts don't end in a newline and would be held back
+        let status = self.status(&mut cmd)?;
+        if !status.success() {
+            error!(self.logger(), "{} failed", description; "status" => %status, "command" => %redact::command_line(&cmd));
+            bail!("{} failed: Restic exited with {}", description, status);
+        }
+        info!(self.logger(), "{} succeeded", description);
+        Ok(())
+    }
+}
@@ -0,0 +1,196 @@
+//! Repository passwords stored in the operating system's keyring: the Secret Service (GNOME Keyring, KWallet, and so on) on
+//! Linux and other Unix-like systems, or the login Keychain on macOS. Rustic doesn't read the keyring itself, but has
+//! Restic run the platform's keyring tool as a `--password-command`, so the password never passes through Rustic.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context, Result};
+
+use crate::config::KeyringEntry;
+use crate::redact;
+use crate::restic::quote_command_arg;
+
+/// Command that prints the password stored in `entry`, in the form Restic's `--password-command` expects
+pub fn lookup_command(entry: &KeyringEntry) -> Result<String> {
+    let args = lookup_args(entry)?;
+    let quoted = args
+        .iter()
+        .map(|arg| quote_command_arg(arg))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(quoted.join(" "))
+}
+
+/// Reads the password stored in `entry`, for secrets Rustic has to pass to Restic itself rather than through a command
+pub fn lookup(entry: &KeyringEntry) -> Result<String> {
+    let args = lookup_args(entry)?;
+    let output = Command::new(&args[0])
+        .args(&args[1..])
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| format!("Could not run {}", args[0]))?;
+    if !output.status.success() {
+        bail!(
+            "Could not read {} / {} from the keyring: {}",
+            entry.service,
+            entry.account,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let password =
+        String::from_utf8(output.stdout).context("Keyring password is not valid UTF-8")?;
+    Ok(password.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+/// Asks for a password on the terminal and stores it in `entry`, replacing any password already there. The keyring tool
+/// does the prompting, so the password never passes through Rustic. In a dry run, the keyring tool's command line is
+/// printed instead. Returns whether the password was stored.
+pub fn store(entry: &KeyringEntry, label: &str, dry_run: bool) -> Result<bool> {
+    let args = store_args(entry, label)?;
+    let mut cmd = Command::new(&args[0]);
+    cmd.args(&args[1..]);
+    if dry_run {
+        println!("Would run: {}", redact::command_line(&cmd));
+        return Ok(false);
+    }
+    let status = cmd
+        .status()
+        .with_context(|| format!("Could not run {}", args[0]))?;
+    if !status.success() {
+        bail!("{} failed with {}", args[0], status);
+    }
+    Ok(true)
+}
+
+/// Stores `password` in `entry` until the user logs out, in the Secret Service's session collection, which is only kept in
+/// memory. The password is passed to the keyring tool on stdin, so it never appears in the process list.
+pub fn store_for_session(entry: &KeyringEntry, label: &str, password: &str) -> Result<()> {
+    let args = session_store_args(entry, label)?;
+    let mut child = Command::new(&args[0])
+        .args(&args[1..])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Could not run {}", args[0]))?;
+    // Dropping stdin after writing closes it, so the tool sees the end of the password
+    let written = child.stdin.take().unwrap().write_all(password.as_bytes());
+    let output = child
+        .wait_with_output()
+        .with_context(|| format!("Could not run {}", args[0]))?;
+    written.with_context(|| format!("Could not pass the password to {}", args[0]))?;
+    if !output.status.success() {
+        bail!(
+            "{} failed with {}: {}",
+            args[0],
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn lookup_args(entry: &KeyringEntry) -> Result<Vec<String>> {
+    Ok(vec![
+        "security".to_string(),
+        "find-generic-password".to_string(),
+        "-s".to_string(),
+        entry.service.clone(),
+        "-a".to_string(),
+        entry.account.clone(),
+        "-w".to_string(),
+    ])
+}
+
+#[cfg(target_os = "macos")]
+fn store_args(entry: &KeyringEntry, label: &str) -> Result<Vec<String>> {
+    // With nothing after `-w`, `security` prompts for the password. `-U` replaces an existing entry.
+    Ok(vec![
+        "security".to_string(),
+        "add-generic-password".to_string(),
+        "-U".to_string(),
+        "-l".to_string(),
+        label.to_string(),
+        "-s".to_string(),
+        entry.service.clone(),
+        "-a".to_string(),
+        entry.account.clone(),
+        "-w".to_string(),
+    ])
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn lookup_args(entry: &KeyringEntry) -> Result<Vec<String>> {
+    Ok(vec![
+        "secret-tool".to_string(),
+        "lookup".to_string(),
+        "service".to_string(),
+        entry.service.clone(),
+        "account".to_string(),
+        entry.account.clone(),
+    ])
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn store_args(entry: &KeyringEntry, label: &str) -> Result<Vec<String>> {
+    // `secret-tool store` prompts for the password when run in a terminal, and replaces any entry with the same attributes
+    Ok(vec![
+        "secret-tool".to_string(),
+        "store".to_string(),
+        "--label".to_string(),
+        label.to_string(),
+        "service".to_string(),
+        entry.service.clone(),
+        "account".to_string(),
+        entry.account.clone(),
+    ])
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn session_store_args(entry: &KeyringEntry, label: &str) -> Result<Vec<String>> {
+    // Without a terminal, `secret-tool store` reads the password from stdin
+    Ok(vec![
+        "secret-tool".to_string(),
+        "store".to_string(),
+        "--label".to_string(),
+        label.to_string(),
+        "--collection=session".to_string(),
+        "service".to_string(),
+        entry.service.clone(),
+        "account".to_string(),
+        entry.account.clone(),
+    ])
+}
+
+#[cfg(not(all(unix, not(target_os = "macos"))))]
+fn session_store_args(_entry: &KeyringEntry, _label: &str) -> Result<Vec<String>> {
+    bail!("`remember_prompted_password` needs the Secret Service, which is only available on Linux and other Unix-like systems");
+}
+
+#[cfg(windows)]
+fn lookup_args(_entry: &KeyringEntry) -> Result<Vec<String>> {
+    bail!("`password_keyring` is not supported on Windows yet. Use `password_file` or `password_command` instead.");
+}
+
+#[cfg(windows)]
+fn store_args(_entry: &KeyringEntry, _label: &str) -> Result<Vec<String>> {
+    bail!("`password_keyring` is not supported on Windows yet. Use `password_file` or `password_command` instead.");
+}
+
+#[cfg(test)]
+mod test {
+    use super::store;
+    use crate::config::KeyringEntry;
+
+    #[test]
+    #[cfg(unix)]
+    fn test_store_dry_run() {
+        let entry = KeyringEntry {
+            service: "rustic-test".to_string(),
+            account: "dry-run".to_string(),
+        };
+        // Stores nothing, and doesn't prompt for a password
+        assert!(!store(&entry, "Restic repository password for test", true).unwrap());
+    }
+}
@@ -0,0 +1,167 @@
+//! Rustic is a wrapper around [Restic](https://restic.net) that backs up, forgets, and prunes according to profiles in a
+//! configuration file. Besides the `rustic` command-line tool, it can be used as a library to run the same operations from
+//! another program:
+//!
+//! ```no_run
+//! use std::path::Path;
+//!
+//! use rustic_backup::config::Configuration;
+//! use rustic_backup::restic::Restic;
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let config = Configuration::load(Path::new("/etc/rustic/rustic.toml"))?;
+//! let logger = slog::Logger::root(slog::Discard, slog::o!());
+//!
+//! let restic = Restic::for_profile(&config, &logger, "home".to_string(), 0)?;
+//! let summary = restic.backup()?;
+//! println!("Created snapshot {}", summary.snapshot_id);
+//! restic.forget(true)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`Configuration`](config::Configuration) is the parsed configuration file, and [`Restic`](restic::Restic) runs Restic for
+//! one of its profiles. Operations like [`backup`](restic::Restic::backup), [`forget`](restic::Restic::forget), and
+//! [`list_snapshots`](restic::Restic::list_snapshots) are methods on `Restic`, defined in the module for each operation. Progress and
+//! results are reported to [sinks](events::Sink), which can be added with [`add_sink`](restic::Restic::add_sink).
+//!
+//! Errors are [`anyhow::Error`]s. Failures that callers may want to handle differently, like a missing profile or a locked
+//! repository, can be told apart with [`RusticError::find`](error::RusticError::find).
+//!
+//! Modules that aren't documented here exist to support the command-line tool and may change at any time.
+
+pub mod backup;
+pub mod config;
+pub mod error;
+pub mod events;
+pub mod forget;
+pub mod restic;
+pub mod snapshots;
+
+#[doc(hidden)]
+pub mod anomaly;
+#[doc(hidden)]
+pub mod aws;
+#[doc(hidden)]
+pub mod browse;
+#[doc(hidden)]
+pub mod cache;
+#[doc(hidden)]
+pub mod cadence;
+#[doc(hidden)]
+pub mod check;
+#[doc(hidden)]
+pub mod completions;
+#[doc(hidden)]
+pub mod conditions;
+#[doc(hidden)]
+pub mod containers;
+#[doc(hidden)]
+pub mod copy;
+#[doc(hidden)]
+pub mod databases;
+#[doc(hidden)]
+pub mod diff;
+#[doc(hidden)]
+pub mod doctor;
+#[doc(hidden)]
+pub mod dotenv;
+#[doc(hidden)]
+pub mod dump;
+#[doc(hidden)]
+pub mod duration;
+#[doc(hidden)]
+pub mod encrypted;
+#[doc(hidden)]
+pub mod find;
+#[doc(hidden)]
+pub mod format;
+#[doc(hidden)]
+pub mod generate;
+#[doc(hidden)]
+pub mod hooks;
+#[doc(hidden)]
+pub mod http;
+#[doc(hidden)]
+pub mod init;
+#[doc(hidden)]
+pub mod install;
+#[doc(hidden)]
+pub mod json;
+#[doc(hidden)]
+pub mod key;
+#[doc(hidden)]
+pub mod keyring;
+#[doc(hidden)]
+pub mod lock;
+#[doc(hidden)]
+pub mod logging;
+#[doc(hidden)]
+pub mod ls;
+#[doc(hidden)]
+pub mod maintain;
+#[doc(hidden)]
+pub mod metrics;
+#[doc(hidden)]
+pub mod monitoring;
+#[doc(hidden)]
+pub mod mount;
+#[doc(hidden)]
+pub mod notify;
+#[doc(hidden)]
+pub mod output;
+#[doc(hidden)]
+pub mod parallel;
+#[doc(hidden)]
+pub mod password_managers;
+#[doc(hidden)]
+pub mod platform;
+#[doc(hidden)]
+pub mod progress;
+#[doc(hidden)]
+pub mod prompt;
+#[doc(hidden)]
+pub mod redact;
+#[doc(hidden)]
+pub mod repo;
+#[doc(hidden)]
+pub mod report;
+#[doc(hidden)]
+pub mod restore;
+#[doc(hidden)]
+pub mod rewrite;
+#[doc(hidden)]
+pub mod run;
+#[doc(hidden)]
+pub mod runner;
+#[doc(hidden)]
+pub mod sha256;
+#[doc(hidden)]
+pub mod shell;
+#[doc(hidden)]
+pub mod size;
+#[doc(hidden)]
+pub mod state;
+#[doc(hidden)]
+pub mod stats;
+#[doc(hidden)]
+pub mod status;
+#[doc(hidden)]
+pub mod systemd;
+#[doc(hidden)]
+pub mod tag;
+#[doc(hidden)]
+pub mod template;
+#[doc(hidden)]
+pub mod validate;
+#[doc(hidden)]
+pub mod verify;
+#[doc(hidden)]
+pub mod version;
+#[doc(hidden)]
+pub mod watch;
+#[doc(hidden)]
+pub mod window;
+
+#[cfg(test)]
+mod test;
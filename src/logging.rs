@@ -0,0 +1,202 @@
+//! Log drains for sending Rustic's logs to the system log instead of the terminal
+
+use std::fmt::{self, Write};
+use std::io;
+
+use anyhow::Result;
+#[cfg(unix)]
+use anyhow::{bail, Context};
+use slog::{Drain, Key, Level, OwnedKVList, Record, Serializer, KV};
+
+use crate::config::LogTarget;
+
+/// Name Rustic logs under
+const IDENTIFIER: &str = "rustic";
+
+/// Where journald listens for structured log messages
+#[cfg(unix)]
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Where the syslog daemon listens for messages, in order of preference
+#[cfg(unix)]
+const SYSLOG_SOCKETS: [&str; 2] = ["/dev/log", "/var/run/syslog"];
+
+/// Syslog facility for user-level messages
+const SYSLOG_FACILITY_USER: u8 = 1;
+
+/// Creates a drain for a log target, or `None` for the terminal, which is set up separately
+pub fn drain(target: LogTarget) -> Result<Option<SystemLogDrain>> {
+    match target {
+        LogTarget::Terminal => Ok(None),
+        #[cfg(unix)]
+        LogTarget::Journald => SystemLogDrain::connect(Format::Journald, &[JOURNALD_SOCKET]),
+        #[cfg(unix)]
+        LogTarget::Syslog => SystemLogDrain::connect(Format::Syslog, &SYSLOG_SOCKETS),
+        #[cfg(not(unix))]
+        _ => anyhow::bail!("Logging to {:?} is only supported on Unix", target),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    /// journald's [native protocol](https://systemd.io/JOURNAL_NATIVE_PROTOCOL/), where key-value pairs become journal fields
+    Journald,
+    /// Traditional [BSD syslog](https://tools.ietf.org/html/rfc3164) messages, with key-value pairs appended to the message
+    Syslog,
+}
+
+/// Drain that sends each log message as a datagram to journald or the syslog daemon
+pub struct SystemLogDrain {
+    format: Format,
+    #[cfg(unix)]
+    socket: std::os::unix::net::UnixDatagram,
+}
+
+#[cfg(unix)]
+impl SystemLogDrain {
+    fn connect(format: Format, paths: &[&str]) -> Result<Option<SystemLogDrain>> {
+        let path = match paths
+            .iter()
+            .find(|path| std::path::Path::new(path).exists())
+        {
+            Some(path) => path,
+            None => bail!(
+                "Could not find a {:?} socket at {}",
+                format,
+                paths.join(" or ")
+            ),
+        };
+        let socket =
+            std::os::unix::net::UnixDatagram::unbound().context("Could not create log socket")?;
+        socket
+            .connect(path)
+            .with_context(|| format!("Could not connect to {}", path))?;
+        Ok(Some(SystemLogDrain { format, socket }))
+    }
+}
+
+impl Drain for SystemLogDrain {
+    type Ok = ();
+    type Err = io::Error;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> io::Result<()> {
+        let mut fields = Fields::default();
+        record.kv().serialize(record, &mut fields)?;
+        values.serialize(record, &mut fields)?;
+        let message = match self.format {
+            Format::Journald => journald_message(record, &fields.0),
+            Format::Syslog => syslog_message(record, &fields.0),
+        };
+        self.send(&message)
+    }
+}
+
+impl SystemLogDrain {
+    #[cfg(unix)]
+    fn send(&self, message: &[u8]) -> io::Result<()> {
+        self.socket.send(message).map(|_| ())
+    }
+
+    #[cfg(not(unix))]
+    fn send(&self, _message: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Key-value pairs attached to a log message
+#[derive(Default)]
+struct Fields(Vec<(String, String)>);
+
+impl Serializer for Fields {
+    fn emit_arguments(&mut self, key: Key, value: &fmt::Arguments) -> slog::Result {
+        self.0.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+}
+
+/// Syslog severity for a log level, which journald also uses for its `PRIORITY` field
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Critical => 2,
+        Level::Error => 3,
+        Level::Warning => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Encodes a message in journald's native format. Keys are converted to valid journal field names, so `profile` becomes
+/// `PROFILE`.
+fn journald_message(record: &Record, fields: &[(String, String)]) -> Vec<u8> {
+    let mut message = Vec::new();
+    append_journald_field(&mut message, "MESSAGE", &record.msg().to_string());
+    append_journald_field(
+        &mut message,
+        "PRIORITY",
+        &severity(record.level()).to_string(),
+    );
+    append_journald_field(&mut message, "SYSLOG_IDENTIFIER", IDENTIFIER);
+    append_journald_field(&mut message, "CODE_FILE", record.file());
+    append_journald_field(&mut message, "CODE_LINE", &record.line().to_string());
+    for (key, value) in fields {
+        let name: String = key
+            .chars()
+            .map(|c| match c {
+                'a'..='z' => c.to_ascii_uppercase(),
+                'A'..='Z' | '0'..='9' => c,
+                _ => '_',
+            })
+            .collect();
+        // Field names can't start with an underscore or a digit, since those are reserved for trusted fields
+        let name = name.trim_start_matches(|c: char| c == '_' || c.is_ascii_digit());
+        if !name.is_empty() {
+            append_journald_field(&mut message, name, value);
+        }
+    }
+    message
+}
+
+/// Appends a field to a journald message. Values with newlines are written as a length-prefixed binary field.
+fn append_journald_field(message: &mut Vec<u8>, name: &str, value: &str) {
+    message.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        message.push(b'\n');
+        message.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        message.push(b'=');
+    }
+    message.extend_from_slice(value.as_bytes());
+    message.push(b'\n');
+}
+
+/// Formats a message for syslog, like `<14>Oct 15 08:00:00 rustic[1234]: Backup complete, profile=home`
+fn syslog_message(record: &Record, fields: &[(String, String)]) -> Vec<u8> {
+    let mut message = format!(
+        "<{}>{} {}[{}]: {}",
+        SYSLOG_FACILITY_USER * 8 + severity(record.level()),
+        chrono::Local::now().format("%b %e %H:%M:%S"),
+        IDENTIFIER,
+        std::process::id(),
+        record.msg()
+    );
+    for (key, value) in fields {
+        let _ = write!(message, ", {}={}", key, value);
+    }
+    message.into_bytes()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_append_journald_field() {
+        let mut message = Vec::new();
+        append_journald_field(&mut message, "MESSAGE", "hello");
+        append_journald_field(&mut message, "ERROR", "a\nb");
+        assert_eq!(
+            message,
+            b"MESSAGE=hello\nERROR\n\x03\0\0\0\0\0\0\0a\nb\n".to_vec()
+        );
+    }
+}
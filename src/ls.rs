@@ -0,0 +1,155 @@
+//! Listing files in snapshots
+
+use std::io::{self, prelude::*};
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Local, Utc};
+use serde::Deserialize;
+use slog::{debug, warn};
+
+use crate::format::format_bytes;
+use crate::json;
+use crate::output::OutputFormat;
+use crate::redact;
+use crate::restic::Restic;
+
+/// A file or directory in a snapshot, as reported by `restic ls --json`
+#[derive(Debug, Clone, Deserialize)]
+pub struct Node {
+    /// File name. Not included in `restic find` output.
+    #[serde(default)]
+    pub name: String,
+
+    /// Node type, such as `file`, `dir`, or `symlink`
+    #[serde(rename = "type")]
+    pub kind: String,
+
+    /// Absolute path of the node within the snapshot
+    pub path: String,
+
+    /// Size in bytes, for regular files
+    #[serde(default)]
+    pub size: Option<u64>,
+
+    /// Last modification time
+    #[serde(default)]
+    pub mtime: Option<DateTime<Utc>>,
+}
+
+impl Node {
+    /// Whether this node is a directory
+    pub fn is_dir(&self) -> bool {
+        self.kind == "dir"
+    }
+
+    /// Formats the node as tab-separated type, size, modification time, and path columns
+    pub fn table_row(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}",
+            self.kind,
+            self.size.map(format_bytes).unwrap_or_default(),
+            self.mtime
+                .map(|mtime| mtime
+                    .with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string())
+                .unwrap_or_default(),
+            self.path
+        )
+    }
+}
+
+/// Extends the Restic wrapper with file listing
+impl<'a> Restic<'a> {
+    /// Lists files in a snapshot. If `directory` is given, only entries inside it are listed, and only its direct children
+    /// unless `recursive` is set. Otherwise, every file in the snapshot is listed.
+    pub fn list_files(
+        &self,
+        snapshot: &str,
+        directory: Option<&str>,
+        recursive: bool,
+    ) -> Result<Vec<Node>> {
+        let mut cmd = self.ls_command(snapshot, directory, recursive);
+        cmd.arg("--json");
+
+        debug!(self.logger(), "Listing files"; "command" => %redact::command_line(&cmd));
+        let stdout = self
+            .execute_output(&mut cmd)
+            .context("Listing files failed")?;
+
+        let mut nodes = Vec::new();
+        for line in stdout.lines() {
+            let value = match json::parse(line) {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+            // The first line describes the snapshot itself
+            if value.get("struct_type").and_then(|t| t.as_str()) != Some("node") {
+                continue;
+            }
+            match value.try_into::<Node>() {
+                // Restic includes the directory itself in the listing
+                Ok(node) if Some(node.path.as_str()) != directory => nodes.push(node),
+                Ok(_) => {}
+                Err(err) => {
+                    warn!(self.logger(), "Could not parse `restic ls` output: {}", err; "line" => line)
+                }
+            }
+        }
+
+        Ok(nodes)
+    }
+
+    /// Lists the entries directly inside `directory` in a snapshot, with directories first.
+    pub fn list_directory(&self, snapshot: &str, directory: &str) -> Result<Vec<Node>> {
+        let mut nodes = self.list_files(snapshot, Some(directory), false)?;
+        nodes.sort_by(|a, b| (!a.is_dir(), &a.name).cmp(&(!b.is_dir(), &b.name)));
+        Ok(nodes)
+    }
+
+    /// Prints files in a snapshot, as listed by [`list_files`](Restic::list_files). JSON output is passed through from Restic
+    /// unchanged.
+    pub fn show_files(
+        &self,
+        snapshot: &str,
+        directory: Option<&str>,
+        recursive: bool,
+        output: OutputFormat,
+    ) -> Result<()> {
+        if output == OutputFormat::Json {
+            let mut cmd = self.ls_command(snapshot, directory, recursive);
+            cmd.arg("--json");
+            debug!(self.logger(), "Listing files"; "command" => %redact::command_line(&cmd));
+            let status = self.execute_passthrough(&mut cmd)?.status;
+            if !status.success() {
+                bail!("Listing files failed: {}", status);
+            }
+            return Ok(());
+        }
+
+        let nodes = self.list_files(snapshot, directory, recursive)?;
+        let mut tw = tabwriter::TabWriter::new(io::stdout());
+        writeln!(tw, "Type\tSize\tModified\tPath")?;
+        writeln!(tw, "----\t----\t--------\t----")?;
+        for node in nodes.iter() {
+            writeln!(tw, "{}", node.table_row())?;
+        }
+        tw.flush()?;
+
+        Ok(())
+    }
+
+    fn ls_command(&self, snapshot: &str, directory: Option<&str>, recursive: bool) -> Command {
+        let mut cmd = self.new_command();
+        cmd.arg("ls");
+        if recursive && directory.is_some() {
+            cmd.arg("--recursive");
+        }
+        cmd.arg(snapshot);
+        if let Some(directory) = directory {
+            cmd.arg(directory);
+        }
+        cmd
+    }
+}
@@ -1,46 +1,75 @@
 use std::ffi::OsString;
-use std::fs;
 use std::io::{self, prelude::*};
 use std::path::{Path, PathBuf};
 use std::sync::Mutex;
+use std::time::Duration;
 
-use anyhow::{Context, Result};
-use slog::{debug, error, o, Drain, Level, LevelFilter, Logger};
+use anyhow::{bail, Context, Result};
+use chrono::Local;
+use serde::Serialize;
+use slog::{debug, error, info, o, Drain, Level, LevelFilter, Logger};
+use structopt::clap::{AppSettings, Shell};
 use structopt::StructOpt;
 
-mod backup;
-mod config;
-mod forget;
-mod restic;
-mod shell;
-mod snapshots;
-
-#[cfg(test)]
-mod test;
-
-use config::Configuration;
-use restic::Restic;
-
-// TODO: prometheus
-// TODO: builtin systemd-inhibit and caffeinate support?
-// TODO: nice/ionice support?
+use rustic_backup::backup::{self, BackupSummary};
+use rustic_backup::config::{self, Configuration};
+use rustic_backup::doctor::{self, Outcome};
+use rustic_backup::error::{self, RusticError};
+use rustic_backup::output::OutputFormat;
+use rustic_backup::repo::Repair;
+use rustic_backup::restic::Restic;
+use rustic_backup::rewrite::RewriteOptions;
+use rustic_backup::snapshots::SnapshotFilter;
+use rustic_backup::state::{RunRecord, StateStore};
+use rustic_backup::stats::StatsMode;
+use rustic_backup::systemd::UnitScope;
+use rustic_backup::tag::TagChanges;
+use rustic_backup::verify::SampleSize;
+use rustic_backup::version::ResticVersion;
+use rustic_backup::{
+    cache, completions, diff, duration, format, generate, install, keyring, logging, output,
+    parallel, progress, prompt, redact, report, status, systemd, validate, verify, watch,
+};
 
 #[derive(Debug, StructOpt)]
 struct Args {
-    /// Path to the Rustic configuration file
+    /// Path to the Rustic configuration file, or a directory of configuration files. If not given, Rustic looks for
+    /// `rustic/rustic.toml` in the user's configuration directory (`$XDG_CONFIG_HOME` or `~/.config`), then in the
+    /// platform's configuration directory, and finally `/etc/rustic/rustic.toml`.
     #[structopt(
         short = "c",
         long = "config",
         env = "RUSTIC_CONFIG",
         parse(from_os_str)
     )]
-    config_file: PathBuf,
+    config_file: Option<PathBuf>,
 
     /// Adjust the verbosity of log output. By default, only print errors and warnings. Pass `-v` for informational messages or
-    /// `-vv` for debug messages.
+    /// `-vv` for debug messages. This also controls how much output Restic prints.
     #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
     verbose: u8,
 
+    /// Output format for command results, either `table` or `json`. JSON output is meant for scripts and omits fields that
+    /// have no value.
+    #[structopt(short = "o", long = "output", default_value = "table")]
+    output: OutputFormat,
+
+    /// If another Rustic process is backing up, forgetting, pruning, tagging, rewriting, or repairing in the same
+    /// repository, wait this long (like `30m`) for it to finish instead of failing immediately
+    #[structopt(long = "wait", parse(try_from_str = duration::parse_duration))]
+    wait: Option<Duration>,
+
+    /// Print the Restic commands (and hooks and other commands) that would be run, with their environment, instead of
+    /// running them. Secrets in the environment are masked. Unlike the `--dry-run` flags of `forget` and `rewrite`, which
+    /// still run Restic to preview their changes, this doesn't run anything.
+    #[structopt(long = "print-commands")]
+    dry_run: bool,
+
+    /// Write a JSON report of each backup, forget, prune, or check to this file, overriding `report_file` in the
+    /// configuration. `{profile}` and `{operation}` in the path are replaced with the profile name and the operation.
+    #[structopt(long = "report", env = "RUSTIC_REPORT")]
+    report: Option<String>,
+
     #[structopt(subcommand)]
     command: Command,
 }
@@ -49,7 +78,38 @@ struct Args {
 enum Command {
     /// Run a backup
     Backup {
-        /// The profile to back up
+        /// The profiles to back up. Use `@<group>` to back up every profile in a group.
+        #[structopt(required_unless = "all")]
+        profiles: Vec<String>,
+
+        /// Back up every configured profile
+        #[structopt(short = "a", long = "all", conflicts_with = "profiles")]
+        all: bool,
+
+        /// Skip profiles that were backed up successfully within their `frequency`, for running on events like logging in or
+        /// connecting to a network
+        #[structopt(long = "if-due")]
+        if_due: bool,
+    },
+
+    /// Watch a profile's files, and back them up whenever they change. Runs until interrupted.
+    Watch {
+        /// Profile to watch
+        profile: String,
+
+        /// How long files must go without changing before backing up, like `30s` or `2m`. Defaults to 30 seconds.
+        #[structopt(long = "settle", parse(try_from_str = duration::parse_duration))]
+        settle: Option<Duration>,
+
+        /// Minimum time between backups, like `10m` or `1h`. Files that keep changing are backed up anyway once this
+        /// has passed. Defaults to 10 minutes.
+        #[structopt(long = "min-interval", parse(try_from_str = duration::parse_duration))]
+        min_interval: Option<Duration>,
+    },
+
+    /// Initialize the repository for a profile
+    Init {
+        /// Profile whose repository to initialize
         profile: String,
     },
 
@@ -61,6 +121,10 @@ enum Command {
         /// Automatically prune any forgotten snapshots
         #[structopt(short = "p", long = "prune")]
         prune: bool,
+
+        /// Show which snapshots would be kept and which would be forgotten, without forgetting anything
+        #[structopt(short = "n", long = "dry-run", conflicts_with = "prune")]
+        dry_run: bool,
     },
 
     /// Prune unreferenced data in the repository
@@ -69,16 +133,261 @@ enum Command {
         profile: String,
     },
 
+    /// Check the repository for errors
+    Check {
+        /// Profile to check
+        profile: String,
+    },
+
+    /// Forget, prune, and check, according to each profile's `maintenance` schedule. Only operations that are due, based
+    /// on when they last succeeded, are run.
+    Maintain {
+        /// Profiles to maintain. Use `@<group>` to maintain every profile in a group.
+        #[structopt(required_unless = "all")]
+        profiles: Vec<String>,
+
+        /// Maintain every configured profile
+        #[structopt(short = "a", long = "all", conflicts_with = "profiles")]
+        all: bool,
+    },
+
+    /// Copy snapshots to the profile's `copy_to` repository
+    Copy {
+        /// Profile to copy snapshots from
+        profile: String,
+    },
+
+    /// Remove stale locks from a repository
+    Unlock {
+        /// Profile to unlock
+        profile: String,
+
+        /// Remove all locks, even ones that might belong to a running Restic process
+        #[structopt(long = "remove-all")]
+        remove_all: bool,
+    },
+
     /// List snapshots in a repository
     Snapshots {
         /// Profile to list
         profile: String,
 
+        /// Only list snapshots with this tag. Pass a comma-separated list to require several tags, or repeat the flag to
+        /// match any of them.
+        #[structopt(long = "tag")]
+        tags: Vec<String>,
+
+        /// Only list snapshots from this host
+        #[structopt(long = "host")]
+        host: Option<String>,
+
+        /// Only list snapshots containing this path
+        #[structopt(long = "path")]
+        paths: Vec<String>,
+
         /// Additional arguments to pass to `restic snapshots`
         #[structopt(parse(from_os_str))]
         extra_args: Vec<OsString>,
     },
 
+    /// Add, remove, or replace the tags on snapshots, like `rustic tag my_profile --add keep latest`
+    Tag {
+        /// Profile whose repository to use
+        profile: String,
+
+        /// Tag to add. Can be given more than once.
+        #[structopt(long = "add", number_of_values = 1)]
+        add: Vec<String>,
+
+        /// Tag to remove. Can be given more than once.
+        #[structopt(long = "remove", number_of_values = 1)]
+        remove: Vec<String>,
+
+        /// Replace all tags with this one. Can be given more than once to set several tags.
+        #[structopt(long = "set", number_of_values = 1, conflicts_with_all = &["add", "remove"])]
+        set: Vec<String>,
+
+        /// Only change snapshots with this tag. Pass a comma-separated list to require several tags, or repeat the flag to
+        /// match any of them.
+        #[structopt(long = "tag", number_of_values = 1)]
+        tags: Vec<String>,
+
+        /// Only change snapshots from this host
+        #[structopt(long = "host")]
+        host: Option<String>,
+
+        /// Only change snapshots containing this path
+        #[structopt(long = "path", number_of_values = 1)]
+        paths: Vec<String>,
+
+        /// Snapshots to change, or `latest`. Required unless snapshots are selected by tag, host, or path.
+        snapshots: Vec<String>,
+    },
+
+    /// Rewrite snapshots without files that should have been excluded, like a cache directory that was backed up by
+    /// mistake. The original snapshots are kept unless `--forget` is passed.
+    Rewrite {
+        /// Profile whose repository to use
+        profile: String,
+
+        /// Pattern of files to remove. Can be given more than once.
+        #[structopt(long = "exclude", number_of_values = 1)]
+        exclude: Vec<String>,
+
+        /// Remove files matching the profile's current exclude patterns
+        #[structopt(long = "profile-excludes")]
+        profile_excludes: bool,
+
+        /// Forget the original snapshots. Run `rustic prune` afterwards to free up the space.
+        #[structopt(long = "forget")]
+        forget: bool,
+
+        /// Show what would be removed from each snapshot, without changing anything
+        #[structopt(short = "n", long = "dry-run")]
+        dry_run: bool,
+
+        /// Only rewrite snapshots with this tag. Pass a comma-separated list to require several tags, or repeat the flag
+        /// to match any of them. Defaults to the profile's tags if no snapshots are selected.
+        #[structopt(long = "tag", number_of_values = 1)]
+        tags: Vec<String>,
+
+        /// Only rewrite snapshots from this host
+        #[structopt(long = "host")]
+        host: Option<String>,
+
+        /// Only rewrite snapshots containing this path
+        #[structopt(long = "path", number_of_values = 1)]
+        paths: Vec<String>,
+
+        /// Snapshots to rewrite, or `latest`. Defaults to every snapshot selected by tag, host, or path.
+        snapshots: Vec<String>,
+    },
+
+    /// Pin snapshots, so that `rustic forget` always keeps them. This adds the retention policy's `pin_tag`.
+    Pin {
+        /// Profile whose repository to use
+        profile: String,
+
+        /// Snapshots to pin, or `latest`
+        #[structopt(required = true)]
+        snapshots: Vec<String>,
+    },
+
+    /// Unpin snapshots, so that `rustic forget` applies the retention policy to them again
+    Unpin {
+        /// Profile whose repository to use
+        profile: String,
+
+        /// Snapshots to unpin
+        #[structopt(required = true)]
+        snapshots: Vec<String>,
+    },
+
+    /// List files in a snapshot
+    Ls {
+        /// Profile whose repository to use
+        profile: String,
+
+        /// Snapshot to list files in, or `latest`
+        snapshot: String,
+
+        /// Only list the contents of this directory
+        directory: Option<String>,
+
+        /// When listing a directory, include the contents of its subdirectories
+        #[structopt(short = "r", long = "recursive")]
+        recursive: bool,
+    },
+
+    /// Find files matching a pattern in all snapshots
+    Find {
+        /// Profile whose repository to search
+        profile: String,
+
+        /// Patterns to search for, such as `*.pdf` or `/home/*/notes.txt`
+        #[structopt(required = true)]
+        patterns: Vec<String>,
+
+        /// Only search this snapshot. Can be given more than once.
+        #[structopt(short = "s", long = "snapshot")]
+        snapshots: Vec<String>,
+
+        /// Ignore case when matching patterns
+        #[structopt(short = "i", long = "ignore-case")]
+        ignore_case: bool,
+    },
+
+    /// Restore a random sample of files from the latest snapshot into a temporary directory, and check that they match the
+    /// snapshot and, where they haven't changed since, the live files
+    Verify {
+        /// Profile to verify
+        profile: String,
+
+        /// How many files to restore, either a number like `20` or a percentage like `5%`. Defaults to the profile's
+        /// `verify.sample`, or 20 files.
+        #[structopt(long = "sample")]
+        sample: Option<SampleSize>,
+    },
+
+    /// Show what changed between two snapshots
+    Diff {
+        /// Profile whose repository to use
+        profile: String,
+
+        /// Snapshot to compare from. Defaults to the second most recent snapshot.
+        from: Option<String>,
+
+        /// Snapshot to compare to. Defaults to the most recent snapshot.
+        to: Option<String>,
+
+        /// Only show the number of files added, removed, and modified, not each changed path
+        #[structopt(long = "summary")]
+        summary: bool,
+    },
+
+    /// Show how much data a repository holds, and how that has changed since the last time this was run
+    Stats {
+        /// Profile to show statistics for
+        profile: String,
+
+        /// Only count size in this mode: `restore-size` (the size of all files in all snapshots) or `raw-data` (the size of
+        /// the deduplicated data stored in the repository). By default, both are shown.
+        #[structopt(long = "mode")]
+        mode: Option<StatsMode>,
+    },
+
+    /// Mount the repository with FUSE, to browse snapshots as regular files. Press Ctrl-C to unmount.
+    Mount {
+        /// Profile whose repository to mount
+        profile: String,
+
+        /// Existing directory to mount the repository at
+        #[structopt(parse(from_os_str))]
+        mountpoint: PathBuf,
+    },
+
+    /// Extract a single file from a snapshot
+    Dump {
+        /// Profile whose repository to use
+        profile: String,
+
+        /// Snapshot to extract the file from, or `latest`
+        snapshot: String,
+
+        /// Path of the file in the snapshot. Directories are extracted as a tar archive.
+        path: String,
+
+        /// Write the file here instead of to stdout
+        #[structopt(long = "out")]
+        out: Option<PathBuf>,
+    },
+
+    /// Interactively browse snapshots and restore files from them
+    Browse {
+        /// Profile to browse
+        profile: String,
+    },
+
     /// Start an interactive shell configured for Restic.
     ///
     /// This sets `RESTIC_REPOSITORY`, `RESTIC_PASSWORD_*`, and any other configured
@@ -88,28 +397,315 @@ enum Command {
         profile: String,
     },
 
+    /// Run Restic with any arguments, using a profile's repository, password, and environment. Put `--` before the
+    /// Restic arguments, as in `rustic run my_profile -- check --read-data`.
+    Run {
+        /// Profile to use
+        profile: String,
+
+        /// Arguments to pass to Restic
+        #[structopt(parse(from_os_str), required = true)]
+        args: Vec<OsString>,
+    },
+
     /// List all profiles
     Profiles,
+
+    /// Show when each profile was last backed up. Exits with an error if any profile's last backup is older than its `max_age`.
+    Status,
+
+    /// Inspect the configuration file
+    Config {
+        #[structopt(subcommand)]
+        command: ConfigCommand,
+    },
+
+    /// Manage the keys that can unlock a profile's repository
+    Key {
+        #[structopt(subcommand)]
+        command: KeyCommand,
+    },
+
+    /// Upgrade or repair a profile's repository. These ask for confirmation first, unless `--yes` is passed.
+    Repo {
+        #[structopt(subcommand)]
+        command: RepoCommand,
+    },
+
+    /// Manage secrets stored outside the configuration file
+    Secret {
+        #[structopt(subcommand)]
+        command: SecretCommand,
+    },
+
+    /// Manage Restic's local cache of repository metadata
+    Cache {
+        #[structopt(subcommand)]
+        command: CacheCommand,
+    },
+
+    /// Check that the configuration loads, Restic runs, the cache is writable, and each profile's password and repository
+    /// can be reached. Exits with an error if any check fails.
+    Doctor {
+        /// Profiles to check. Use `@<group>` to check every profile in a group. Defaults to every profile.
+        profiles: Vec<String>,
+    },
+
+    /// Generate files to get started with
+    Generate {
+        #[structopt(subcommand)]
+        command: GenerateCommand,
+    },
+
+    /// Print a shell completion script, which also completes profile names from the configuration file. For example, add
+    /// `source <(rustic completions bash)` to `~/.bashrc`.
+    Completions {
+        /// Shell to complete commands in
+        #[structopt(possible_values = completions::SHELLS)]
+        shell: Shell,
+    },
+
+    /// Print the name of every profile and `@<group>`, for completion scripts
+    #[structopt(setting = AppSettings::Hidden)]
+    CompleteProfiles,
+
+    /// Show the history of backups and other operations on a profile
+    History {
+        /// Profile to show history for
+        profile: String,
+
+        /// Maximum number of runs to show
+        #[structopt(short = "n", long = "limit", default_value = "20")]
+        limit: usize,
+    },
+
+    /// Install systemd units that back up a profile on its configured schedule
+    InstallUnits {
+        /// Profile to back up, or `@<group>` to install units for every profile in a group
+        profile: String,
+
+        /// Install system-wide units instead of user units
+        #[structopt(long = "system")]
+        system: bool,
+
+        /// Print the units instead of installing them
+        #[structopt(long = "dry-run")]
+        dry_run: bool,
+    },
+
+    /// Manage Rustic's own installation
+    #[structopt(name = "self")]
+    Self_ {
+        #[structopt(subcommand)]
+        command: SelfCommand,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum GenerateCommand {
+    /// Write a commented example configuration with one profile, to edit from there
+    Config {
+        /// File to write the configuration to. Prints it if not set.
+        path: Option<PathBuf>,
+
+        /// Ask where to back up to, what to back up, and how long to keep snapshots, instead of using placeholders
+        #[structopt(short = "i", long = "interactive")]
+        interactive: bool,
+
+        /// Overwrite the file if it already exists
+        #[structopt(long = "force")]
+        force: bool,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum SelfCommand {
+    /// Download an official Restic release and install it for profiles with `restic_command = "managed"`. Replaces any
+    /// previously installed version.
+    InstallRestic {
+        /// Version to install, like `0.16.4`. Defaults to the latest release.
+        version: Option<ResticVersion>,
+
+        /// Only check the download against the release's checksums, without verifying their signature with gpg
+        #[structopt(long = "no-verify-signature")]
+        no_verify_signature: bool,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum ConfigCommand {
+    /// Check the configuration for problems without running Restic, such as missing filesets or password files. Exits with
+    /// an error if any are found.
+    Check,
+}
+
+#[derive(Debug, StructOpt)]
+enum CacheCommand {
+    /// Remove cached data for repositories that haven't been used recently
+    Cleanup {
+        /// Remove caches unused for this many days. Defaults to Restic's default of 30 days.
+        #[structopt(long = "max-age")]
+        max_age: Option<u32>,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum SecretCommand {
+    /// Prompt for a profile's repository password and store it in the keyring entry named by its `password_keyring`
+    Set {
+        /// Profile whose password to store
+        profile: String,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum KeyCommand {
+    /// List the repository's keys. The key the profile's password unlocks is marked with `*`.
+    List {
+        /// Profile whose repository to use
+        profile: String,
+    },
+
+    /// Add a key to the repository
+    Add {
+        /// Profile whose repository to use
+        profile: String,
+
+        /// Read the new key's password from this file instead of prompting for it
+        #[structopt(long = "new-password-file")]
+        new_password_file: Option<PathBuf>,
+
+        /// User name to record for the new key
+        #[structopt(long = "user")]
+        user: Option<String>,
+
+        /// Host name to record for the new key
+        #[structopt(long = "host")]
+        host: Option<String>,
+    },
+
+    /// Remove a key from the repository
+    Remove {
+        /// Profile whose repository to use
+        profile: String,
+
+        /// ID of the key to remove
+        id: String,
+    },
+
+    /// Change the password of the key the profile uses. Update the profile's password afterwards.
+    Passwd {
+        /// Profile whose repository to use
+        profile: String,
+
+        /// Read the new password from this file instead of prompting for it
+        #[structopt(long = "new-password-file")]
+        new_password_file: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum RepoCommand {
+    /// Upgrade the repository to format version 2, which supports compression. Restic versions older than 0.14 can't read
+    /// it afterwards.
+    Migrate {
+        /// Profile whose repository to use
+        profile: String,
+
+        /// Don't ask for confirmation
+        #[structopt(short = "y", long = "yes")]
+        yes: bool,
+    },
+
+    /// Repair a damaged repository, usually after `rustic check` reports errors
+    Repair {
+        #[structopt(subcommand)]
+        command: RepairCommand,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum RepairCommand {
+    /// Rebuild the index from the pack files in the repository
+    Index {
+        /// Profile whose repository to use
+        profile: String,
+
+        /// Read every pack file, instead of only those missing from the index
+        #[structopt(long = "read-all-packs")]
+        read_all_packs: bool,
+
+        /// Don't ask for confirmation
+        #[structopt(short = "y", long = "yes")]
+        yes: bool,
+    },
+
+    /// Remove references to missing data from snapshots. Restic keeps the damaged snapshots unless `--forget` is passed.
+    Snapshots {
+        /// Profile whose repository to use
+        profile: String,
+
+        /// Forget the damaged snapshots once they've been repaired
+        #[structopt(long = "forget")]
+        forget: bool,
+
+        /// Don't ask for confirmation
+        #[structopt(short = "y", long = "yes")]
+        yes: bool,
+
+        /// Snapshots to repair. Defaults to every snapshot.
+        snapshots: Vec<String>,
+    },
+
+    /// Salvage what can be read from damaged pack files, and remove them from the repository
+    Packs {
+        /// Profile whose repository to use
+        profile: String,
+
+        /// Don't ask for confirmation
+        #[structopt(short = "y", long = "yes")]
+        yes: bool,
+
+        /// IDs of the damaged pack files, as reported by `rustic check`
+        #[structopt(required = true)]
+        ids: Vec<String>,
+    },
 }
 
 fn load_config<P: AsRef<Path>>(logger: &Logger, path: P) -> Result<Configuration> {
     let path = path.as_ref();
     debug!(logger, "Loading configuration from {}", path.display());
 
-    let config_str = fs::read_to_string(path)
-        .with_context(|| format!("Could not read configuration file {}", path.display()))?;
-
-    let config = toml::from_str(&config_str)
-        .with_context(|| format!("Could not parse configuration file {}", path.display()))?;
+    Configuration::load(path)
+        .map_err(RusticError::Config)
+        .with_context(|| format!("Could not load configuration from {}", path.display()))
+}
 
-    Ok(config)
+#[derive(Serialize)]
+struct ProfileInfo<'a> {
+    name: &'a str,
+    repository: &'a str,
 }
 
-fn list_profiles(config: &Configuration) -> Result<()> {
+fn list_profiles(config: &Configuration, output: OutputFormat) -> Result<()> {
+    let mut profiles: Vec<_> = config.profiles.iter().collect();
+    profiles.sort_by_key(|(name, _)| *name);
+
+    if output == OutputFormat::Json {
+        let profiles: Vec<ProfileInfo> = profiles
+            .into_iter()
+            .map(|(name, profile)| ProfileInfo {
+                name,
+                repository: &profile.repository,
+            })
+            .collect();
+        return output::print_json(&profiles);
+    }
+
     let mut tw = tabwriter::TabWriter::new(io::stdout());
     writeln!(tw, "Profile\tRepository")?;
     writeln!(tw, "-------\t----------")?;
-    for (name, profile) in config.profiles.iter() {
+    for (name, profile) in profiles {
         writeln!(tw, "{}\t{}", name, profile.repository)?;
     }
     tw.flush()?;
@@ -117,69 +713,802 @@ fn list_profiles(config: &Configuration) -> Result<()> {
     Ok(())
 }
 
-fn run(args: Args, logger: &Logger) -> Result<()> {
-    let config = load_config(logger, &args.config_file)?;
+fn show_history(config: &Configuration, profile: &str, limit: usize) -> Result<()> {
+    let profile = &config.resolve_profile_name(profile)?;
+    if !config.profiles.contains_key(profile) {
+        bail!(RusticError::ProfileNotFound(profile.to_string()));
+    }
+
+    let store = StateStore::open(config)?;
+    let mut runs: Vec<&RunRecord> = store.runs_for_profile(profile).rev().take(limit).collect();
+    runs.reverse();
+
+    let mut tw = tabwriter::TabWriter::new(io::stdout());
+    writeln!(tw, "Started\tOperation\tDuration\tResult\tSnapshot\tAdded")?;
+    writeln!(tw, "-------\t---------\t--------\t------\t--------\t-----")?;
+    for run in runs {
+        let duration = (run.finished_at - run.started_at)
+            .to_std()
+            .unwrap_or_default();
+        writeln!(
+            tw,
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            run.started_at
+                .with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M:%S"),
+            run.operation,
+            format::format_duration(duration),
+            if run.success { "succeeded" } else { "failed" },
+            run.snapshot_id
+                .as_ref()
+                .map(|id| &id[..id.len().min(8)])
+                .unwrap_or(""),
+            run.bytes_added
+                .map(format::format_bytes)
+                .unwrap_or_default(),
+        )?;
+    }
+    tw.flush()?;
+
+    Ok(())
+}
+
+/// Machine-readable result of backing up one profile
+#[derive(Serialize)]
+struct BackupResult<'a> {
+    profile: &'a str,
+    success: bool,
+    error: Option<String>,
+    summary: Option<&'a BackupSummary>,
+    /// Why the backup was skipped, if it was
+    skipped: Option<&'a str>,
+    /// Why the repository couldn't be reached, if that's why the backup failed
+    unreachable: Option<&'a str>,
+}
+
+/// Flags that apply to every profile `backup_profiles` backs up
+struct BackupOptions {
+    wait: Option<Duration>,
+    dry_run: bool,
+    if_due: bool,
+}
+
+/// What happened when backing up one profile
+enum BackupRun {
+    Completed(BackupSummary),
+    /// The backup was skipped, for this reason
+    Skipped(String),
+}
+
+/// Why a backup failed to reach its repository in time, if that's why it failed
+fn unreachable_reason(result: &Result<BackupRun>) -> Option<&str> {
+    match result.as_ref().err().and_then(RusticError::find) {
+        Some(RusticError::RepositoryUnreachable(reason)) => Some(reason),
+        _ => None,
+    }
+}
+
+/// Backs up each of the given profiles, up to `max_parallel` at a time. Profiles using the same repository are backed up
+/// one after another. A failed backup does not stop other profiles from being backed up, but this returns an error if any
+/// of them failed.
+fn backup_profiles(
+    config: &Configuration,
+    logger: &Logger,
+    profiles: Vec<String>,
+    verbosity: u8,
+    options: &BackupOptions,
+    output: OutputFormat,
+) -> Result<()> {
+    let multiple = profiles.len() > 1;
+    // Progress bars for several backups at once would draw over each other
+    let show_progress = !multiple || config.max_parallel <= 1;
+    let mut results = parallel::run_grouped(
+        profiles,
+        |profile| config.profiles.get(profile).map(|p| &p.repository),
+        config.max_parallel,
+        |profile| {
+            let result =
+                backup_profile(config, logger, &profile, verbosity, options, show_progress);
+            if let (true, Err(ref err)) = (multiple, &result) {
+                error!(logger, "Backup failed: {:?}", err; "profile" => &profile);
+            }
+            (profile, result)
+        },
+    );
+
+    match output {
+        OutputFormat::Json => {
+            let report: Vec<BackupResult> = results
+                .iter()
+                .map(|(profile, result)| BackupResult {
+                    profile,
+                    success: result.is_ok(),
+                    error: result.as_ref().err().map(|err| format!("{:#}", err)),
+                    summary: match result {
+                        Ok(BackupRun::Completed(summary)) => Some(summary),
+                        _ => None,
+                    },
+                    skipped: match result {
+                        Ok(BackupRun::Skipped(reason)) => Some(reason),
+                        _ => None,
+                    },
+                    unreachable: unreachable_reason(result),
+                })
+                .collect();
+            output::print_json(&report)?;
+        }
+        // With only one profile, the error message is enough
+        OutputFormat::Table if !multiple => return results.pop().unwrap().1.map(|_| ()),
+        OutputFormat::Table => {
+            let mut tw = tabwriter::TabWriter::new(io::stdout());
+            writeln!(tw, "Profile\tResult")?;
+            writeln!(tw, "-------\t------")?;
+            for (profile, result) in results.iter() {
+                match result {
+                    Ok(BackupRun::Completed(_)) => writeln!(tw, "{}\tsucceeded", profile)?,
+                    Ok(BackupRun::Skipped(reason)) => {
+                        writeln!(tw, "{}\tskipped: {}", profile, reason)?
+                    }
+                    Err(err) => match unreachable_reason(result) {
+                        Some(reason) => writeln!(tw, "{}\tunreachable: {}", profile, reason)?,
+                        None => writeln!(tw, "{}\tfailed: {}", profile, err)?,
+                    },
+                }
+            }
+            tw.flush()?;
+        }
+    }
+
+    // Exit with the code for the first profile that failed
+    let total = results.len();
+    let failed = results.iter().filter(|(_, result)| result.is_err()).count();
+    if let Some(err) = results.into_iter().find_map(|(_, result)| result.err()) {
+        return Err(err.context(format!("{} of {} backups failed", failed, total)));
+    }
+
+    Ok(())
+}
+
+/// Backs up one profile for `backup_profiles`, unless it's skipped
+fn backup_profile(
+    config: &Configuration,
+    logger: &Logger,
+    profile: &str,
+    verbosity: u8,
+    options: &BackupOptions,
+    show_progress: bool,
+) -> Result<BackupRun> {
+    if !options.dry_run {
+        report::remove_reports(config, logger, profile, &["backup"]);
+    }
+    // Checked before opening the profile, which may ask a password manager for the repository password
+    if options.if_due {
+        let logger = logger.new(o!("profile" => profile.to_string()));
+        if let Some(reason) = backup::not_due_reason(config, profile, &logger)? {
+            info!(logger, "Skipping backup: {}", reason);
+            return Ok(BackupRun::Skipped(reason));
+        }
+    }
+
+    let mut restic = open_profile(
+        config,
+        logger,
+        profile.to_string(),
+        verbosity,
+        options.dry_run,
+    )?;
+    if let Some(reason) = restic.backup_skip_reason()? {
+        info!(restic.logger(), "Skipping backup: {}", reason);
+        return Ok(BackupRun::Skipped(reason));
+    }
+    restic.require_repository()?;
+    restic.set_lock_wait(options.wait);
+    if show_progress {
+        if let Some(progress_bar) = progress::ProgressBar::for_terminal() {
+            restic.add_sink(Box::new(progress_bar));
+        }
+    }
+    restic.backup().map(BackupRun::Completed)
+}
+
+fn generate_config(
+    logger: &Logger,
+    path: Option<PathBuf>,
+    interactive: bool,
+    force: bool,
+    dry_run: bool,
+) -> Result<()> {
+    if let Some(ref path) = path {
+        if path.exists() && !force {
+            bail!(
+                "{} already exists. Pass --force to overwrite it.",
+                path.display()
+            );
+        }
+    }
+
+    // Secrets are expected next to the configuration file
+    let secrets_directory = match path {
+        Some(ref path) => path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .map(Path::to_path_buf)
+            .unwrap_or(std::env::current_dir()?),
+        None => dirs::config_dir()
+            .map(|dir| dir.join("rustic"))
+            .unwrap_or_else(|| PathBuf::from("/etc/rustic")),
+    };
+    let mut answers = generate::Answers::defaults(secrets_directory);
+    if interactive {
+        // Prompts go to stderr, so that the configuration can be redirected when printed
+        generate::ask(&mut answers, &mut io::stdin().lock(), &mut io::stderr())?;
+    }
+    let contents = generate::render(&answers);
+
+    match path {
+        Some(path) => {
+            if generate::write(&path, &contents, dry_run)? {
+                info!(logger, "Wrote configuration"; "path" => %path.display());
+                println!(
+                    "Wrote {0}. Next, create the password file it names, then run:\n    rustic -c {0} init {1}",
+                    path.display(),
+                    answers.profile
+                );
+            }
+        }
+        None => print!("{}", contents),
+    }
+    Ok(())
+}
+
+fn run_doctor(
+    logger: &Logger,
+    config_file: Option<&Path>,
+    profiles: &[String],
+    verbosity: u8,
+    output: OutputFormat,
+) -> Result<()> {
+    let report = doctor::diagnose(logger, config_file, profiles, verbosity);
+
+    if output == OutputFormat::Json {
+        output::print_json(&report)?;
+    } else {
+        let mut tw = tabwriter::TabWriter::new(io::stdout());
+        writeln!(tw, "Check\tResult\tDetails")?;
+        writeln!(tw, "-----\t------\t-------")?;
+        for diagnosis in report.iter() {
+            writeln!(
+                tw,
+                "{}\t{}\t{}",
+                diagnosis.check,
+                diagnosis.outcome.label(),
+                diagnosis.detail
+            )?;
+        }
+        tw.flush()?;
+    }
+
+    let failed = report
+        .iter()
+        .filter(|diagnosis| diagnosis.outcome == Outcome::Fail)
+        .count();
+    if failed > 0 {
+        bail!("{} of {} checks failed", failed, report.len());
+    }
+    Ok(())
+}
+
+/// Asks before changing a repository in a way that can't be undone, unless `--yes` or `--print-commands` was passed
+fn confirm(restic: &Restic, action: &str, yes: bool) -> Result<()> {
+    if yes || restic.is_dry_run() {
+        return Ok(());
+    }
+    let question = format!(
+        "{} {} for profile `{}`?",
+        action,
+        redact::url(restic.repository()),
+        restic.profile_name()
+    );
+    if !prompt::confirm(&question)? {
+        bail!("Cancelled");
+    }
+    Ok(())
+}
+
+/// Sets up Restic for a profile, in dry-run mode if `--print-commands` was passed
+fn open_profile<'a>(
+    config: &'a Configuration,
+    logger: &Logger,
+    profile: String,
+    verbosity: u8,
+    dry_run: bool,
+) -> Result<Restic<'a>> {
+    let mut restic = Restic::for_profile(config, logger, profile, verbosity)?;
+    restic.set_dry_run(dry_run);
+    Ok(restic)
+}
+
+fn run(args: Args, logger: &mut Logger) -> Result<()> {
+    // Installing Restic doesn't need a configuration file, which may not exist yet on a new machine
+    if let Command::Self_ {
+        command:
+            SelfCommand::InstallRestic {
+                version,
+                no_verify_signature,
+            },
+    } = args.command
+    {
+        install::install_restic(logger, version, !no_verify_signature, args.dry_run)?;
+        return Ok(());
+    }
+
+    if let Command::Completions { shell } = args.command {
+        print!("{}", completions::generate(&mut Args::clap(), shell)?);
+        return Ok(());
+    }
+
+    // Generating a configuration is how a new machine gets one in the first place
+    if let Command::Generate {
+        command:
+            GenerateCommand::Config {
+                path,
+                interactive,
+                force,
+            },
+    } = args.command
+    {
+        return generate_config(logger, path, interactive, force, args.dry_run);
+    }
+
+    // The doctor reports a broken or missing configuration instead of failing on it
+    if let Command::Doctor { ref profiles } = args.command {
+        return run_doctor(
+            logger,
+            args.config_file.as_deref(),
+            profiles,
+            args.verbose,
+            args.output,
+        );
+    }
+
+    let config_file = match args.config_file {
+        Some(config_file) => config_file,
+        None => config::find_default_config().map_err(RusticError::Config)?,
+    };
+    let mut config = load_config(logger, &config_file)?;
+    if args.report.is_some() {
+        config.report_file = args.report;
+    }
+    if let Some(drain) = logging::drain(config.logging.target)? {
+        *logger = root_logger(drain.ignore_res(), args.verbose);
+    }
+    let logger = &*logger;
+    debug!(logger, "Logging to {:?}", config.logging.target);
+    let verbosity = args.verbose;
+    let output = args.output;
+    let wait = args.wait;
+    let dry_run = args.dry_run;
 
-    // TODO: pass verbosity flag along to restic
     match args.command {
-        Command::Backup { profile } => {
-            let restic = Restic::for_profile(&config, logger, profile)?;
-            restic.backup()?;
+        Command::Backup {
+            profiles,
+            all,
+            if_due,
+        } => {
+            let profiles = if all {
+                let mut names: Vec<String> = config.profiles.keys().cloned().collect();
+                names.sort();
+                names
+            } else {
+                config.expand_profiles(&profiles)?
+            };
+            let options = BackupOptions {
+                wait,
+                dry_run,
+                if_due,
+            };
+            backup_profiles(&config, logger, profiles, verbosity, &options, output)?;
+        }
+        Command::Watch {
+            profile,
+            settle,
+            min_interval,
+        } => {
+            let mut restic = open_profile(&config, logger, profile, verbosity, dry_run)?;
+            restic.set_lock_wait(wait);
+            restic.watch(
+                settle.unwrap_or(watch::DEFAULT_SETTLE),
+                min_interval.unwrap_or(watch::DEFAULT_MIN_INTERVAL),
+            )?;
         }
-        Command::Forget { profile, prune } => {
-            let restic = Restic::for_profile(&config, logger, profile)?;
-            restic.forget(prune)?;
+        Command::Init { profile } => {
+            let restic = open_profile(&config, logger, profile, verbosity, dry_run)?;
+            restic.init()?;
+        }
+        Command::Forget {
+            profile,
+            prune,
+            dry_run: preview,
+        } => {
+            let profile = config.resolve_profile_name(&profile)?;
+            if !preview && !dry_run {
+                let operations: &[&str] = if prune {
+                    &["forget", "prune"]
+                } else {
+                    &["forget"]
+                };
+                report::remove_reports(&config, logger, &profile, operations);
+            }
+            let mut restic = open_profile(&config, logger, profile, verbosity, dry_run)?;
+            if preview {
+                restic.forget_dry_run(output)?;
+            } else {
+                restic.set_lock_wait(wait);
+                restic.forget(prune)?;
+            }
         }
         Command::Prune { profile } => {
-            let restic = Restic::for_profile(&config, logger, profile)?;
+            let profile = config.resolve_profile_name(&profile)?;
+            if !dry_run {
+                report::remove_reports(&config, logger, &profile, &["prune"]);
+            }
+            let mut restic = open_profile(&config, logger, profile, verbosity, dry_run)?;
+            restic.set_lock_wait(wait);
             restic.prune()?;
         }
+        Command::Check { profile } => {
+            let profile = config.resolve_profile_name(&profile)?;
+            if !dry_run {
+                report::remove_reports(&config, logger, &profile, &["check"]);
+            }
+            let restic = open_profile(&config, logger, profile, verbosity, dry_run)?;
+            restic.check()?;
+        }
+        Command::Maintain { profiles, all } => {
+            let profiles = if all {
+                let mut names: Vec<String> = config.profiles.keys().cloned().collect();
+                names.sort();
+                names
+            } else {
+                config.expand_profiles(&profiles)?
+            };
+            let total = profiles.len();
+            let mut first_error = None;
+            let mut failed = 0;
+            for profile in profiles {
+                let result = open_profile(&config, logger, profile.clone(), verbosity, dry_run)
+                    .and_then(|mut restic| {
+                        restic.set_lock_wait(wait);
+                        restic.maintain()
+                    });
+                if let Err(err) = result {
+                    error!(logger, "Maintenance failed: {:#}", err; "profile" => &profile);
+                    failed += 1;
+                    first_error.get_or_insert(err);
+                }
+            }
+            if let Some(err) = first_error {
+                return Err(err.context(format!(
+                    "Maintenance of {} of {} profiles failed",
+                    failed, total
+                )));
+            }
+        }
+        Command::Copy { profile } => {
+            let restic = open_profile(&config, logger, profile, verbosity, dry_run)?;
+            restic.copy()?;
+        }
+        Command::Unlock {
+            profile,
+            remove_all,
+        } => {
+            let restic = open_profile(&config, logger, profile, verbosity, dry_run)?;
+            restic.unlock(remove_all)?;
+        }
         Command::Snapshots {
             profile,
+            tags,
+            host,
+            paths,
             extra_args,
         } => {
-            let restic = Restic::for_profile(&config, logger, profile)?;
-            restic.dump_snapshots(&extra_args)?;
+            let restic = open_profile(&config, logger, profile, verbosity, dry_run)?;
+            let filter = SnapshotFilter { tags, host, paths };
+            restic.show_snapshots(&filter, &extra_args, output)?;
+        }
+        Command::Tag {
+            profile,
+            add,
+            remove,
+            set,
+            tags,
+            host,
+            paths,
+            snapshots,
+        } => {
+            let mut restic = open_profile(&config, logger, profile, verbosity, dry_run)?;
+            restic.set_lock_wait(wait);
+            let changes = TagChanges { add, remove, set };
+            let filter = SnapshotFilter { tags, host, paths };
+            restic.tag(&changes, &snapshots, &filter)?;
+        }
+        Command::Rewrite {
+            profile,
+            exclude,
+            profile_excludes,
+            forget,
+            dry_run: preview,
+            tags,
+            host,
+            paths,
+            snapshots,
+        } => {
+            let mut restic = open_profile(&config, logger, profile, verbosity, dry_run)?;
+            restic.set_lock_wait(wait);
+            let options = RewriteOptions {
+                exclude,
+                profile_excludes,
+                forget,
+                dry_run: preview,
+            };
+            let filter = SnapshotFilter { tags, host, paths };
+            restic.rewrite(&options, &snapshots, &filter)?;
+        }
+        Command::Pin { profile, snapshots } => {
+            let mut restic = open_profile(&config, logger, profile, verbosity, dry_run)?;
+            restic.set_lock_wait(wait);
+            restic.pin(&snapshots)?;
+        }
+        Command::Unpin { profile, snapshots } => {
+            let mut restic = open_profile(&config, logger, profile, verbosity, dry_run)?;
+            restic.set_lock_wait(wait);
+            restic.unpin(&snapshots)?;
+        }
+        Command::Ls {
+            profile,
+            snapshot,
+            directory,
+            recursive,
+        } => {
+            let restic = open_profile(&config, logger, profile, verbosity, dry_run)?;
+            restic.show_files(&snapshot, directory.as_deref(), recursive, output)?;
+        }
+        Command::Find {
+            profile,
+            patterns,
+            snapshots,
+            ignore_case,
+        } => {
+            let restic = open_profile(&config, logger, profile, verbosity, dry_run)?;
+            restic.find(&patterns, &snapshots, ignore_case, output)?;
+        }
+        Command::Diff {
+            profile,
+            from,
+            to,
+            summary,
+        } => {
+            let restic = open_profile(&config, logger, profile, verbosity, dry_run)?;
+            let diff = restic.diff(from, to)?;
+            diff::print_diff(&diff, output, summary)?;
+        }
+        Command::Verify { profile, sample } => {
+            let restic = open_profile(&config, logger, profile, verbosity, dry_run)?;
+            let verification = restic.verify(sample)?;
+            verify::print_verification(&verification, output)?;
+            if !verification.discrepancies.is_empty() {
+                bail!(
+                    "{} of {} restored files failed verification",
+                    verification.discrepancies.len(),
+                    verification.restored_files
+                );
+            }
+        }
+        Command::Stats { profile, mode } => {
+            let restic = open_profile(&config, logger, profile, verbosity, dry_run)?;
+            let modes = match mode {
+                Some(mode) => vec![mode],
+                None => vec![StatsMode::RestoreSize, StatsMode::RawData],
+            };
+            restic.show_stats(&modes, output)?;
+        }
+        Command::Mount {
+            profile,
+            mountpoint,
+        } => {
+            let restic = open_profile(&config, logger, profile, verbosity, dry_run)?;
+            restic.mount(&mountpoint)?;
+        }
+        Command::Dump {
+            profile,
+            snapshot,
+            path,
+            out,
+        } => {
+            let restic = open_profile(&config, logger, profile, verbosity, dry_run)?;
+            restic.dump(&snapshot, &path, out.as_deref())?;
+        }
+        Command::Browse { profile } => {
+            let restic = open_profile(&config, logger, profile, verbosity, dry_run)?;
+            restic.browse()?;
         }
         Command::Shell { profile } => {
-            let restic = Restic::for_profile(&config, logger, profile)?;
+            let restic = open_profile(&config, logger, profile, verbosity, dry_run)?;
             restic.shell()?;
         }
+        Command::Run { profile, args } => {
+            let restic = open_profile(&config, logger, profile, verbosity, dry_run)?;
+            restic.run(&args)?;
+        }
         Command::Profiles => {
-            list_profiles(&config)?;
+            list_profiles(&config, output)?;
+        }
+        Command::Status => {
+            status::show_status(&config, output)?;
+        }
+        Command::Config {
+            command: ConfigCommand::Check,
+        } => {
+            validate::check_config(&config)?;
+        }
+        Command::Key { command } => match command {
+            KeyCommand::List { profile } => {
+                let restic = open_profile(&config, logger, profile, verbosity, dry_run)?;
+                restic.show_keys(output)?;
+            }
+            KeyCommand::Add {
+                profile,
+                new_password_file,
+                user,
+                host,
+            } => {
+                let restic = open_profile(&config, logger, profile, verbosity, dry_run)?;
+                restic.add_key(
+                    new_password_file.as_deref(),
+                    user.as_deref(),
+                    host.as_deref(),
+                )?;
+            }
+            KeyCommand::Remove { profile, id } => {
+                let restic = open_profile(&config, logger, profile, verbosity, dry_run)?;
+                restic.remove_key(&id)?;
+            }
+            KeyCommand::Passwd {
+                profile,
+                new_password_file,
+            } => {
+                let restic = open_profile(&config, logger, profile, verbosity, dry_run)?;
+                restic.change_password(new_password_file.as_deref())?;
+            }
+        },
+        Command::Repo { command } => match command {
+            RepoCommand::Migrate { profile, yes } => {
+                let mut restic = open_profile(&config, logger, profile, verbosity, dry_run)?;
+                restic.set_lock_wait(wait);
+                confirm(&restic, "Upgrade repository", yes)?;
+                restic.migrate_repository()?;
+            }
+            RepoCommand::Repair { command } => {
+                let (profile, yes, repair) = match command {
+                    RepairCommand::Index {
+                        profile,
+                        read_all_packs,
+                        yes,
+                    } => (profile, yes, Repair::Index { read_all_packs }),
+                    RepairCommand::Snapshots {
+                        profile,
+                        forget,
+                        yes,
+                        snapshots,
+                    } => (profile, yes, Repair::Snapshots { forget, snapshots }),
+                    RepairCommand::Packs { profile, yes, ids } => {
+                        (profile, yes, Repair::Packs { ids })
+                    }
+                };
+                let mut restic = open_profile(&config, logger, profile, verbosity, dry_run)?;
+                restic.set_lock_wait(wait);
+                confirm(&restic, "Repair repository", yes)?;
+                restic.repair(&repair)?;
+            }
+        },
+        Command::Secret {
+            command: SecretCommand::Set { profile },
+        } => {
+            let profile = config.resolve_profile_name(&profile)?;
+            let entry = match config.profiles.get(&profile) {
+                Some(settings) => match settings.password_keyring {
+                    Some(ref entry) => entry,
+                    None => bail!("Profile `{}` does not set `password_keyring`", profile),
+                },
+                None => bail!(RusticError::ProfileNotFound(profile)),
+            };
+            let label = format!("Restic repository password for {}", profile);
+            if keyring::store(entry, &label, dry_run)? {
+                info!(logger, "Stored password in keyring"; "service" => &entry.service, "account" => &entry.account);
+            }
+        }
+        Command::Cache {
+            command: CacheCommand::Cleanup { max_age },
+        } => {
+            cache::cleanup(logger, &config, max_age, dry_run)?;
+        }
+        Command::History { profile, limit } => {
+            show_history(&config, &profile, limit)?;
+        }
+        Command::InstallUnits {
+            profile,
+            system,
+            dry_run: print_units,
+        } => {
+            let scope = if system {
+                UnitScope::System
+            } else {
+                UnitScope::User
+            };
+            for profile in config.expand_profiles(&[profile])? {
+                systemd::install_units(
+                    logger,
+                    &config,
+                    &config_file,
+                    &profile,
+                    scope,
+                    print_units || dry_run,
+                )?;
+            }
+        }
+        Command::CompleteProfiles => {
+            for name in completions::profile_names(&config) {
+                println!("{}", name);
+            }
+        }
+        Command::Self_ { .. }
+        | Command::Doctor { .. }
+        | Command::Generate { .. }
+        | Command::Completions { .. } => {
+            unreachable!("handled before loading the configuration")
         }
     };
 
     Ok(())
 }
 
-#[paw::main]
-fn main(args: Args) {
-    human_panic::setup_panic!();
-
+/// Creates the root logger, filtering out messages below the level for Rustic's `-v` flag
+fn root_logger<D>(drain: D, verbose: u8) -> Logger
+where
+    D: Drain<Ok = (), Err = slog::Never> + Send + 'static,
+{
     // The clap-verbosity-flag crate adds a `-q` flag for setting the log level below the default. However, for a backup program, it seems
     // risky to allow hiding errors/warnings
-    let slog_level = match args.verbose {
+    let slog_level = match verbose {
         0 => Level::Warning,
         1 => Level::Info,
         2 => Level::Debug,
         _ => Level::Trace,
     };
 
+    // Despite the slog docs, we're using a Mutex for the thread-safe drain rather than slog_async. Logging comes from a few threads at most (parallel
+    // backups, and the threads forwarding Restic's output), so the mutex is rarely contested, and holding it for each record keeps messages from
+    // different threads from interleaving. This also means we can use the logger below without having to worry about flushing it before calling
+    // std::process::exit
+    let drain = Mutex::new(drain);
+    let filtered = LevelFilter::new(drain, slog_level).fuse();
+    Logger::root(filtered, o!("rustic_version" => env!("CARGO_PKG_VERSION")))
+}
+
+#[paw::main]
+#[allow(deprecated)] // human-panic's setup_panic! still refers to std::panic::PanicInfo
+fn main(args: Args) {
+    human_panic::setup_panic!();
+
     let decorator = slog_term::TermDecorator::new().build();
     let term_drain = slog_term::FullFormat::new(decorator)
         .use_local_timestamp()
         .build()
         .fuse();
-    // Despite the slog docs, we're using a Mutex for the thread-safe drain rather than slog_async. Since this is a single-threaded program, there's
-    // probably more overhead adding a thread for logging than letting the main thread use a mutex uncontested. This also means we can use the logger
-    // below without having to worry about flushing it before calling std::process::exit
-    let drain = Mutex::new(term_drain);
-    let filtered = LevelFilter::new(drain, slog_level).fuse();
-    let root = Logger::root(filtered, o!("rustic_version" => env!("CARGO_PKG_VERSION")));
+    let mut root = root_logger(term_drain, args.verbose);
 
-    if let Err(err) = run(args, &root) {
+    if let Err(err) = run(args, &mut root) {
         error!(root, "Fatal error: {:?}", err);
-        std::process::exit(1);
+        std::process::exit(error::exit_code(&err));
     }
 }
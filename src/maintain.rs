@@ -0,0 +1,103 @@
+//! Running whichever maintenance operations are due, so that one scheduled `rustic maintain` can replace separate
+//! schedules for forgetting, pruning, and checking
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Local};
+use slog::{error, info, warn};
+
+use crate::restic::Restic;
+use crate::state::StateStore;
+
+/// Extends the Restic wrapper with scheduled maintenance
+impl<'a> Restic<'a> {
+    /// Runs each maintenance operation in the profile's `maintenance` schedule that is due, based on when it last succeeded
+    /// according to the run history. Operations are run in order (forget, prune, then check), and a failure doesn't stop
+    /// the ones after it. Returns the operations that were run.
+    pub fn maintain(&self) -> Result<Vec<&'static str>> {
+        let schedule = &self.profile().maintenance;
+        let operations: Vec<_> = schedule
+            .operations()
+            .into_iter()
+            .filter_map(|(operation, cadence)| cadence.map(|cadence| (operation, cadence)))
+            .collect();
+        if operations.is_empty() {
+            warn!(
+                self.logger(),
+                "No maintenance is scheduled. Set a cadence in the profile's `maintenance` section."
+            );
+            return Ok(Vec::new());
+        }
+
+        let store = StateStore::open(self.config())?;
+        let now = Local::now();
+        let mut ran = Vec::new();
+        let mut failed = Vec::new();
+        for (operation, cadence) in operations {
+            let last: Option<DateTime<Local>> = store
+                .last_success(self.profile_name(), operation)
+                .map(|run| run.finished_at.into());
+            if !cadence.is_due(last, now) {
+                info!(self.logger(), "Skipping {}, since it isn't due yet", operation;
+                    "cadence" => %cadence, "last_success" => last.map(|last| last.to_rfc3339()));
+                continue;
+            }
+
+            info!(self.logger(), "Running {}", operation; "cadence" => %cadence,
+                "last_success" => last.map(|last| last.to_rfc3339()));
+            let result = match operation {
+                "forget" => self.forget(false),
+                "prune" => self.prune(),
+                _ => self.check(),
+            };
+            if let Err(err) = result {
+                error!(self.logger(), "Maintenance {} failed: {:#}", operation, err);
+                failed.push(operation);
+            }
+            ran.push(operation);
+        }
+
+        if !failed.is_empty() {
+            bail!(
+                "{} of {} maintenance operations failed: {}",
+                failed.len(),
+                ran.len(),
+                failed.join(", ")
+            );
+        }
+        Ok(ran)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{Duration, Utc};
+
+    use crate::cadence::Cadence;
+    use crate::state::{RunRecord, StateStore};
+    use crate::test::TestFixture;
+
+    #[test]
+    fn test_maintain() {
+        let mut fixture = TestFixture::new();
+        fixture.profile_mut().retention.keep_daily = Some(7);
+        fixture.profile_mut().maintenance.forget = Some(Cadence::Daily);
+        fixture.profile_mut().maintenance.prune =
+            Some(Cadence::Every(std::time::Duration::from_secs(24 * 60 * 60)));
+        fixture.profile_mut().maintenance.check = Some(Cadence::Monthly);
+        let (restic, runner) = fixture.mock_restic();
+
+        let mut recent = RunRecord::finished("test", "prune", Utc::now(), Some(0), None);
+        recent.finished_at = Utc::now() - Duration::minutes(5);
+        let mut store = StateStore::open(restic.config()).unwrap();
+        store.record(recent).unwrap();
+
+        assert_eq!(restic.maintain().unwrap(), vec!["forget", "check"]);
+        let invocations = runner.invocations();
+        assert_eq!(invocations.len(), 2);
+        assert!(invocations[0].has_arg("forget"));
+        assert!(invocations[1].has_arg("check"));
+
+        // Each successful run was recorded, so nothing is due any more
+        assert!(restic.maintain().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,243 @@
+//! Prometheus metrics, written in node_exporter's [textfile collector](https://github.com/prometheus/node_exporter#textfile-collector)
+//! format.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::io::Write as _;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use slog::{debug, warn};
+use tempfile::NamedTempFile;
+
+use crate::restic::Restic;
+
+/// An operation-specific metric value, as a `(name, help text, value)` tuple. The name is prefixed with `rustic_<operation>_`.
+pub type Metric<'m> = (&'m str, &'m str, f64);
+
+/// Extends the Restic wrapper with metrics reporting
+impl<'a> Restic<'a> {
+    /// Records metrics for a completed operation (such as `backup` or `prune`), if a metrics directory is configured. Each profile and
+    /// operation gets its own metrics file, which is replaced every time the operation runs.
+    ///
+    /// Failing to write metrics is logged, but otherwise ignored, since it shouldn't cause the operation itself to fail.
+    pub fn record_metrics(
+        &self,
+        operation: &str,
+        success: bool,
+        duration: Duration,
+        metrics: &[Metric],
+    ) {
+        let directory = match self.config().metrics_directory {
+            Some(ref directory) => directory,
+            None => return,
+        };
+
+        let file_name = format!(
+            "rustic_{}_{}.prom",
+            sanitize(self.profile_name()),
+            operation
+        );
+        let path = directory.join(file_name);
+        debug!(self.logger(), "Writing metrics"; "path" => %path.display());
+        if let Err(err) = self.write_metrics(&path, operation, success, duration, metrics) {
+            warn!(self.logger(), "Could not write metrics: {:#}", err; "path" => %path.display());
+        }
+    }
+
+    fn write_metrics(
+        &self,
+        path: &Path,
+        operation: &str,
+        success: bool,
+        duration: Duration,
+        metrics: &[Metric],
+    ) -> Result<()> {
+        let labels = format!("{{profile=\"{}\"}}", escape_label(self.profile_name()));
+        let last_success_name = format!("rustic_last_{}_timestamp", operation);
+
+        // Carry over the last success time from the previous metrics file if this run failed, so alerts on stale backups keep working
+        let last_success = if success {
+            Some(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs_f64(),
+            )
+        } else {
+            previous_value(path, &last_success_name)
+        };
+
+        let mut out = String::new();
+        write_metric(
+            &mut out,
+            &format!("rustic_{}_success", operation),
+            &format!("Whether the last {} succeeded (1) or failed (0)", operation),
+            &labels,
+            if success { 1.0 } else { 0.0 },
+        );
+        write_metric(
+            &mut out,
+            &format!("rustic_{}_duration_seconds", operation),
+            &format!("How long the last {} took", operation),
+            &labels,
+            duration.as_secs_f64(),
+        );
+        if let Some(last_success) = last_success {
+            write_metric(
+                &mut out,
+                &last_success_name,
+                &format!("Unix time of the last successful {}", operation),
+                &labels,
+                last_success,
+            );
+        }
+        for (name, help, value) in metrics {
+            write_metric(
+                &mut out,
+                &format!("rustic_{}_{}", operation, name),
+                help,
+                &labels,
+                *value,
+            );
+        }
+
+        // Write to a temporary file and rename it into place so node_exporter never sees a partially-written file
+        let directory = path.parent().unwrap_or_else(|| Path::new("."));
+        let mut file = NamedTempFile::new_in(directory)
+            .with_context(|| format!("Could not create file in {}", directory.display()))?;
+        file.write_all(out.as_bytes())
+            .context("Could not write metrics")?;
+        // Temporary files are only readable by their owner, but node_exporter probably runs as a different user
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.as_file()
+                .set_permissions(fs::Permissions::from_mode(0o644))
+                .context("Could not set metrics file permissions")?;
+        }
+        file.persist(path)
+            .with_context(|| format!("Could not replace {}", path.display()))?;
+
+        Ok(())
+    }
+}
+
+fn write_metric(out: &mut String, name: &str, help: &str, labels: &str, value: f64) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} gauge", name).unwrap();
+    writeln!(out, "{}{} {}", name, labels, value).unwrap();
+}
+
+/// Reads the value of a metric from an existing metrics file, if present.
+fn previous_value(path: &Path, name: &str) -> Option<f64> {
+    let contents = fs::read_to_string(path).ok()?;
+    contents
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .find(|line| line.starts_with(name) && line[name.len()..].starts_with('{'))
+        .and_then(|line| line.rsplit(' ').next())
+        .and_then(|value| value.parse().ok())
+}
+
+/// Escapes a Prometheus label value
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Converts a profile name into something safe to use in a file name
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::time::Duration;
+
+    use super::{escape_label, previous_value};
+    use crate::backup::BackupSummary;
+    use crate::test::TestFixture;
+
+    #[test]
+    fn test_record_metrics() {
+        let mut fixture = TestFixture::new();
+        let directory = fixture.content_root().join("metrics");
+        fs::create_dir(&directory).unwrap();
+        fixture.config_mut().metrics_directory = Some(directory.clone());
+        let restic = fixture.restic();
+
+        let summary = BackupSummary {
+            files_new: 2,
+            data_added: 1024,
+            total_files_processed: 2,
+            snapshot_id: "0123abcd".to_string(),
+            ..BackupSummary::default()
+        };
+        restic.record_metrics(
+            "backup",
+            true,
+            Duration::from_millis(1500),
+            &summary.metrics(),
+        );
+
+        let path = directory.join("rustic_test_backup.prom");
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(
+            &lines[..6],
+            &[
+                "# HELP rustic_backup_success Whether the last backup succeeded (1) or failed (0)",
+                "# TYPE rustic_backup_success gauge",
+                "rustic_backup_success{profile=\"test\"} 1",
+                "# HELP rustic_backup_duration_seconds How long the last backup took",
+                "# TYPE rustic_backup_duration_seconds gauge",
+                "rustic_backup_duration_seconds{profile=\"test\"} 1.5",
+            ]
+        );
+        assert!(lines.contains(&"rustic_backup_files_new{profile=\"test\"} 2"));
+        assert!(lines.contains(&"rustic_backup_bytes_added{profile=\"test\"} 1024"));
+        // Every sample has its own help text and type
+        for (i, line) in lines.iter().enumerate() {
+            if !line.starts_with('#') {
+                let name = &line[..line.find('{').unwrap()];
+                assert!(lines[i - 2].starts_with(&format!("# HELP {} ", name)));
+                assert_eq!(lines[i - 1], format!("# TYPE {} gauge", name));
+            }
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o644);
+        }
+
+        // A failed backup keeps the time of the last successful one
+        let last_success = previous_value(&path, "rustic_last_backup_timestamp").unwrap();
+        restic.record_metrics("backup", false, Duration::from_secs(1), &[]);
+        assert_eq!(previous_value(&path, "rustic_backup_success"), Some(0.0));
+        assert_eq!(
+            previous_value(&path, "rustic_last_backup_timestamp"),
+            Some(last_success)
+        );
+        assert_eq!(previous_value(&path, "rustic_backup_files_new"), None);
+    }
+
+    #[test]
+    fn test_escape_label() {
+        assert_eq!(escape_label("home"), "home");
+        assert_eq!(escape_label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
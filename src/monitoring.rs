@@ -0,0 +1,40 @@
+//! Reporting backup status to external monitoring services
+
+use slog::{debug, warn};
+
+use crate::http;
+use crate::redact;
+use crate::restic::Restic;
+
+/// Signals that can be sent to a [Healthchecks.io](https://healthchecks.io/docs/http_api/)-style check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Healthcheck {
+    /// The backup is starting
+    Start,
+    /// The backup succeeded
+    Success,
+    /// The backup failed
+    Failure,
+}
+
+/// Extends the Restic wrapper with monitoring support
+impl<'a> Restic<'a> {
+    /// Pings the profile's healthcheck URL, if one is configured. Failing pings are logged, but otherwise ignored.
+    pub fn ping_healthcheck(&self, signal: Healthcheck, body: Option<&str>) {
+        let base_url = match self.profile().monitoring.healthcheck_url {
+            Some(ref url) => url.trim_end_matches('/'),
+            None => return,
+        };
+
+        let url = match signal {
+            Healthcheck::Start => format!("{}/start", base_url),
+            Healthcheck::Success => base_url.to_string(),
+            Healthcheck::Failure => format!("{}/fail", base_url),
+        };
+
+        debug!(self.logger(), "Pinging healthcheck"; "url" => redact::http_url(&url));
+        if let Err(err) = http::send("POST", &url, &[], body) {
+            warn!(self.logger(), "Could not ping healthcheck: {:#}", err; "signal" => ?signal);
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//! Mounting repositories with FUSE to browse snapshots as regular files
+
+use std::path::Path;
+#[cfg(unix)]
+use std::process::{Command, ExitStatus};
+#[cfg(unix)]
+use std::thread;
+#[cfg(unix)]
+use std::time::Duration;
+
+#[cfg(unix)]
+use anyhow::Context;
+use anyhow::{bail, Result};
+#[cfg(unix)]
+use slog::{debug, info};
+
+#[cfg(unix)]
+use crate::platform::{self, signals};
+use crate::redact;
+use crate::restic::Restic;
+
+/// Extends the Restic wrapper with FUSE mounting
+impl<'a> Restic<'a> {
+    /// Mounts the repository at `mountpoint` and blocks until it's unmounted. Interrupting Rustic (for example, with Ctrl-C or
+    /// by stopping a service) tells Restic to unmount the repository cleanly, instead of leaving a stale mount behind.
+    #[cfg(unix)]
+    pub fn mount(&self, mountpoint: &Path) -> Result<()> {
+        if !mountpoint.is_dir() {
+            bail!(
+                "Mountpoint {} does not exist or is not a directory",
+                mountpoint.display()
+            );
+        }
+
+        let mut cmd = self.new_command();
+        cmd.arg("mount").arg(mountpoint);
+        debug!(self.logger(), "Mounting repository"; "command" => %redact::command_line(&cmd));
+        if self.is_dry_run() {
+            println!("Would run: {}", redact::command_line(&cmd));
+            return Ok(());
+        }
+
+        signals::install();
+        let result = self.wait_for_unmount(&mut cmd, mountpoint);
+        signals::restore();
+
+        let (status, interrupted) = result?;
+        if !status.success() && !interrupted {
+            bail!(self.failed("mount", status));
+        }
+        info!(self.logger(), "Unmounted repository");
+        Ok(())
+    }
+
+    /// Runs `restic mount`, forwarding any termination signals to it. Returns Restic's exit status and whether it was
+    /// interrupted.
+    #[cfg(unix)]
+    fn wait_for_unmount(&self, cmd: &mut Command, mountpoint: &Path) -> Result<(ExitStatus, bool)> {
+        let mut child = cmd
+            .spawn()
+            .with_context(|| format!("Could not run {}", redact::command_line(cmd)))?;
+        info!(
+            self.logger(),
+            "Mounted repository at {}, press Ctrl-C to unmount",
+            mountpoint.display()
+        );
+
+        let mut interrupted = false;
+        loop {
+            if let Some(status) = child.try_wait().context("Could not wait for Restic")? {
+                return Ok((status, interrupted));
+            }
+            if let Some(signal) = signals::take() {
+                debug!(self.logger(), "Received signal {}, unmounting", signal);
+                interrupted = true;
+                // Restic unmounts the repository when interrupted
+                platform::interrupt(&mut child).context("Could not stop Restic")?;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    /// Mounting requires FUSE, which Restic only supports on Unix-like systems
+    #[cfg(not(unix))]
+    pub fn mount(&self, _mountpoint: &Path) -> Result<()> {
+        bail!("Mounting repositories is not supported on this platform");
+    }
+}
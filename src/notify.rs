@@ -0,0 +1,451 @@
+//! Notifying chat services, push services, and other webhooks when backups (and optionally forgets) finish
+
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use slog::{debug, warn};
+use toml::value::{Table, Value};
+
+use crate::backup::BackupSummary;
+use crate::config::{Pushover, Telegram, Webhook};
+use crate::forget::ForgetReport;
+use crate::http;
+use crate::json;
+use crate::platform;
+use crate::redact;
+use crate::restic::Restic;
+use crate::template;
+
+/// Pushover's message API
+const PUSHOVER_URL: &str = "https://api.pushover.net/1/messages.json";
+
+/// Message sent to chat and push services after a successful backup, unless they set their own
+const DEFAULT_SUCCESS_MESSAGE: &str =
+    "Backup of {profile} on {hostname} succeeded, adding {bytes_added} bytes";
+
+/// Message sent to chat and push services after a successful backup that looks unusual, unless they set their own
+const DEFAULT_ANOMALY_MESSAGE: &str =
+    "Backup of {profile} on {hostname} succeeded, but looks unusual: {anomaly}";
+
+/// Message sent to chat and push services after a failed backup, unless they set their own
+const DEFAULT_FAILURE_MESSAGE: &str = "Backup of {profile} on {hostname} failed: {error}";
+
+/// Message sent to chat and push services after a successful forget, unless they set their own
+const DEFAULT_FORGET_SUCCESS_MESSAGE: &str =
+    "Forgetting snapshots of {profile} on {hostname} removed {snapshots_removed} and kept {snapshots_kept}";
+
+/// Message sent to chat and push services after a failed forget, unless they set their own
+const DEFAULT_FORGET_FAILURE_MESSAGE: &str =
+    "Forgetting snapshots of {profile} on {hostname} failed: {error}";
+
+/// Details of a finished backup or forget, which notification templates can refer to
+pub struct Notification<'n> {
+    /// Name of the profile that was backed up
+    pub profile: &'n str,
+
+    /// Operation that finished, `backup` or `forget`
+    pub operation: &'n str,
+
+    /// Summary of the backup, if it succeeded
+    pub summary: Option<&'n BackupSummary>,
+
+    /// Which snapshots were kept and removed, if a forget succeeded
+    pub forget: Option<&'n ForgetReport>,
+
+    /// What's unusual about the backup's size, if it succeeded but looks unusual
+    pub anomaly: Option<String>,
+
+    /// Error message, if the backup failed
+    pub error: Option<String>,
+
+    /// How long the operation took
+    pub duration: Duration,
+}
+
+impl<'n> Notification<'n> {
+    fn succeeded(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// Whether services that only want to hear about failures should be notified. Unusual backups count, since they're
+    /// often failures in disguise, like a backup of a disk that wasn't mounted.
+    fn needs_attention(&self) -> bool {
+        !self.succeeded() || self.anomaly.is_some()
+    }
+
+    /// Looks up the value of a template variable
+    fn variable(&self, name: &str) -> Result<String> {
+        Ok(match name {
+            "profile" => self.profile.to_string(),
+            "operation" => self.operation.to_string(),
+            "status" => if self.succeeded() { "success" } else { "failure" }.to_string(),
+            "hostname" => platform::hostname().unwrap_or_default(),
+            "snapshot_id" => self
+                .summary
+                .map(|summary| summary.snapshot_id.clone())
+                .unwrap_or_default(),
+            "bytes_added" => self
+                .summary
+                .map(|summary| summary.data_added.to_string())
+                .unwrap_or_default(),
+            "snapshots_removed" => self
+                .forget
+                .map(|report| report.removed().to_string())
+                .unwrap_or_default(),
+            "snapshots_kept" => self
+                .forget
+                .map(|report| report.kept().to_string())
+                .unwrap_or_default(),
+            "duration" => format!("{:.0}", self.duration.as_secs_f64()),
+            "anomaly" => self.anomaly.clone().unwrap_or_default(),
+            "error" => self.error.clone().unwrap_or_default(),
+            _ => bail!(
+                "Unknown notification variable `{}`, expected one of `profile`, `operation`, `status`, `hostname`, \
+                 `snapshot_id`, `bytes_added`, `snapshots_removed`, `snapshots_kept`, `anomaly`, `duration`, or `error`",
+                name
+            ),
+        })
+    }
+
+    /// Message for chat and push services, from the service's `message` for backups or `forget_message` for forgets, if
+    /// it sets the one that applies
+    fn message(&self, message: Option<&str>, forget_message: Option<&str>) -> Result<String> {
+        let template = match (self.operation, self.succeeded()) {
+            ("forget", true) => forget_message.unwrap_or(DEFAULT_FORGET_SUCCESS_MESSAGE),
+            ("forget", false) => forget_message.unwrap_or(DEFAULT_FORGET_FAILURE_MESSAGE),
+            (_, true) if self.anomaly.is_some() => message.unwrap_or(DEFAULT_ANOMALY_MESSAGE),
+            (_, true) => message.unwrap_or(DEFAULT_SUCCESS_MESSAGE),
+            (_, false) => message.unwrap_or(DEFAULT_FAILURE_MESSAGE),
+        };
+        template::expand(template, |name| self.variable(name))
+    }
+
+    /// The payload sent if a webhook doesn't set its own
+    fn default_payload(&self) -> Value {
+        let mut payload = Table::new();
+        let keys: &[&str] = if self.operation == "forget" {
+            &[
+                "profile",
+                "operation",
+                "status",
+                "hostname",
+                "snapshots_removed",
+                "snapshots_kept",
+                "duration",
+                "error",
+            ]
+        } else {
+            &[
+                "profile",
+                "operation",
+                "status",
+                "hostname",
+                "snapshot_id",
+                "bytes_added",
+                "anomaly",
+                "duration",
+                "error",
+            ]
+        };
+        for key in keys.iter() {
+            payload.insert(key.to_string(), Value::String(format!("{{{}}}", key)));
+        }
+        Value::Table(payload)
+    }
+}
+
+/// Extends the Restic wrapper with notifications
+impl<'a> Restic<'a> {
+    /// Sends notifications about a finished backup or forget to every configured service. Failures are logged, but
+    /// otherwise ignored, since they shouldn't change the result of the operation.
+    pub fn notify(&self, notification: &Notification) {
+        let notifications = &self.config().notifications;
+        let wanted = |failures_only: bool| !failures_only || notification.needs_attention();
+
+        if let Some(ref webhook) = notifications.webhook {
+            if wanted(webhook.failures_only) {
+                debug!(self.logger(), "Sending webhook notification"; "url" => redact::http_url(&webhook.url));
+                if let Err(err) = send_webhook(webhook, notification) {
+                    warn!(
+                        self.logger(),
+                        "Could not send webhook notification: {:#}", err
+                    );
+                }
+            }
+        }
+
+        if let Some(ref pushover) = notifications.pushover {
+            if wanted(pushover.failures_only) {
+                debug!(self.logger(), "Sending Pushover notification");
+                if let Err(err) = send_pushover(pushover, notification) {
+                    warn!(
+                        self.logger(),
+                        "Could not send Pushover notification: {:#}", err
+                    );
+                }
+            }
+        }
+
+        if let Some(ref telegram) = notifications.telegram {
+            if wanted(telegram.failures_only) {
+                debug!(self.logger(), "Sending Telegram notification"; "chat_id" => &telegram.chat_id);
+                if let Err(err) = send_telegram(telegram, notification) {
+                    warn!(
+                        self.logger(),
+                        "Could not send Telegram notification: {:#}", err
+                    );
+                }
+            }
+        }
+    }
+}
+
+fn send_webhook(webhook: &Webhook, notification: &Notification) -> Result<()> {
+    let lookup = |name: &str| notification.variable(name);
+    let url = template::expand(&webhook.url, lookup)?;
+
+    let mut headers = Vec::with_capacity(webhook.headers.len() + 1);
+    for (name, value) in webhook.headers.iter() {
+        headers.push((name.clone(), template::expand(value, lookup)?));
+    }
+    if !headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+    {
+        headers.push(("Content-Type".to_string(), "application/json".to_string()));
+    }
+    let headers: Vec<(&str, &str)> = headers
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .collect();
+
+    let payload = match webhook.payload {
+        Some(ref payload) => expand_payload(payload, &lookup)?,
+        None => expand_payload(&notification.default_payload(), &lookup)?,
+    };
+    let body = json::to_string(&payload)?;
+
+    http::send(&webhook.method, &url, &headers, Some(&body))
+}
+
+fn send_pushover(pushover: &Pushover, notification: &Notification) -> Result<()> {
+    let body = pushover_body(pushover, notification)?;
+    http::send(
+        "POST",
+        PUSHOVER_URL,
+        &[("Content-Type", "application/json")],
+        Some(&body),
+    )
+}
+
+/// Request body for Pushover's message API
+fn pushover_body(pushover: &Pushover, notification: &Notification) -> Result<String> {
+    let mut payload = Table::new();
+    payload.insert("token".into(), Value::String(pushover.token.clone()));
+    payload.insert("user".into(), Value::String(pushover.user.clone()));
+    payload.insert(
+        "title".into(),
+        Value::String(format!("Rustic: {}", notification.profile)),
+    );
+    payload.insert(
+        "message".into(),
+        Value::String(notification.message(
+            pushover.message.as_deref(),
+            pushover.forget_message.as_deref(),
+        )?),
+    );
+    if let Some(priority) = pushover.priority {
+        payload.insert("priority".into(), Value::Integer(priority.into()));
+    }
+    json::to_string(&Value::Table(payload))
+}
+
+fn send_telegram(telegram: &Telegram, notification: &Notification) -> Result<()> {
+    let (url, body) = telegram_request(telegram, notification)?;
+    http::send(
+        "POST",
+        &url,
+        &[("Content-Type", "application/json")],
+        Some(&body),
+    )
+}
+
+/// URL and request body for the Telegram Bot API's `sendMessage` method. The bot token is part of the URL.
+fn telegram_request(telegram: &Telegram, notification: &Notification) -> Result<(String, String)> {
+    let mut payload = Table::new();
+    payload.insert("chat_id".into(), Value::String(telegram.chat_id.clone()));
+    payload.insert(
+        "text".into(),
+        Value::String(notification.message(
+            telegram.message.as_deref(),
+            telegram.forget_message.as_deref(),
+        )?),
+    );
+
+    let url = format!(
+        "https://api.telegram.org/bot{}/sendMessage",
+        telegram.bot_token
+    );
+    Ok((url, json::to_string(&Value::Table(payload))?))
+}
+
+/// Expands placeholders in every string in a payload, including table keys
+fn expand_payload<F>(payload: &Value, lookup: &F) -> Result<Value>
+where
+    F: Fn(&str) -> Result<String>,
+{
+    Ok(match payload {
+        Value::String(s) => Value::String(template::expand(s, lookup)?),
+        Value::Array(array) => Value::Array(
+            array
+                .iter()
+                .map(|element| expand_payload(element, lookup))
+                .collect::<Result<_>>()?,
+        ),
+        Value::Table(table) => {
+            let mut expanded = Table::new();
+            for (key, value) in table.iter() {
+                expanded.insert(
+                    template::expand(key, lookup)?,
+                    expand_payload(value, lookup)?,
+                );
+            }
+            Value::Table(expanded)
+        }
+        other => other.clone(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{CapturedLog, TestFixture};
+
+    #[test]
+    fn test_expand_payload() {
+        let notification = Notification {
+            profile: "home",
+            operation: "backup",
+            summary: None,
+            forget: None,
+            anomaly: None,
+            error: Some("Restic backup failed with \"exit status: 1\"".to_string()),
+            duration: Duration::from_secs(90),
+        };
+        let payload: Value = toml::from_str(
+            r#"
+            text = "Backup of {profile} {status} after {duration}s: {error}"
+            attachments = [{ color = "danger", fields = ["{snapshot_id}"] }]
+            priority = 5
+            "#,
+        )
+        .unwrap();
+
+        let expanded = expand_payload(&payload, &|name: &str| notification.variable(name)).unwrap();
+        assert_eq!(
+            json::to_string(&expanded).unwrap(),
+            r#"{"attachments":[{"color":"danger","fields":[""]}],"priority":5,"text":"Backup of home failure after 90s: Restic backup failed with \"exit status: 1\""}"#
+        );
+
+        assert!(
+            expand_payload(&Value::String("{nope}".into()), &|name: &str| notification
+                .variable(name))
+            .is_err()
+        );
+    }
+
+    fn failed_backup() -> Notification<'static> {
+        Notification {
+            profile: "home",
+            operation: "backup",
+            summary: None,
+            forget: None,
+            anomaly: None,
+            error: Some("Restic backup failed".to_string()),
+            duration: Duration::from_secs(90),
+        }
+    }
+
+    #[test]
+    fn test_pushover_body() {
+        let pushover = Pushover {
+            token: "app-token".to_string(),
+            user: "user-key".to_string(),
+            priority: Some(1),
+            message: Some("{profile} {status}".to_string()),
+            forget_message: None,
+            failures_only: false,
+        };
+        assert_eq!(
+            pushover_body(&pushover, &failed_backup()).unwrap(),
+            r#"{"message":"home failure","priority":1,"title":"Rustic: home","token":"app-token","user":"user-key"}"#
+        );
+
+        // The backup message doesn't apply to forgets
+        let forget = Notification {
+            operation: "forget",
+            ..failed_backup()
+        };
+        let body = pushover_body(&pushover, &forget).unwrap();
+        assert!(
+            body.contains(r#""message":"Forgetting snapshots of home on "#),
+            "{}",
+            body
+        );
+        let pushover = Pushover {
+            forget_message: Some("Forget {status}: {error}".to_string()),
+            ..pushover
+        };
+        let body = pushover_body(&pushover, &forget).unwrap();
+        assert!(
+            body.contains(r#""message":"Forget failure: Restic backup failed""#),
+            "{}",
+            body
+        );
+    }
+
+    #[test]
+    fn test_failed_notification_hides_url() {
+        let mut fixture = TestFixture::new();
+        fixture.config_mut().notifications.webhook = Some(
+            toml::from_str(r#"url = "http://127.0.0.1:9/hooks/T0KEN-SECRET?key=K3Y-SECRET""#)
+                .unwrap(),
+        );
+        let captured = CapturedLog::default();
+
+        fixture
+            .restic_with_logger(&captured.logger())
+            .notify(&failed_backup());
+        let logs = captured.contents();
+        assert!(
+            logs.contains("Could not send webhook notification"),
+            "{}",
+            logs
+        );
+        assert!(logs.contains("http://127.0.0.1:9/***"), "{}", logs);
+        assert!(!logs.contains("SECRET"), "{}", logs);
+    }
+
+    #[test]
+    fn test_telegram_request() {
+        let telegram = Telegram {
+            bot_token: "123456:secret".to_string(),
+            chat_id: "-100200300".to_string(),
+            message: None,
+            forget_message: None,
+            failures_only: false,
+        };
+        let (url, body) = telegram_request(&telegram, &failed_backup()).unwrap();
+        assert_eq!(url, "https://api.telegram.org/bot123456:secret/sendMessage");
+        assert!(
+            body.starts_with(r#"{"chat_id":"-100200300","text":"Backup of home on "#),
+            "{}",
+            body
+        );
+        assert!(
+            body.ends_with(r#" failed: Restic backup failed"}"#),
+            "{}",
+            body
+        );
+    }
+}
@@ -0,0 +1,37 @@
+//! Choosing between human-readable and machine-readable output for Rustic's own commands
+
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use anyhow::{bail, Error, Result};
+use serde::Serialize;
+
+use crate::json;
+
+/// How commands print their results
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// Aligned tables, for people
+    Table,
+
+    /// JSON documents, for scripts
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<OutputFormat> {
+        match s {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            _ => bail!("Unknown output format `{}`, expected `table` or `json`", s),
+        }
+    }
+}
+
+/// Prints a value to stdout as JSON
+pub fn print_json<T: Serialize>(value: &T) -> Result<()> {
+    writeln!(io::stdout(), "{}", json::to_string_pretty(value)?)?;
+    Ok(())
+}
@@ -0,0 +1,104 @@
+//! Running tasks in parallel, while keeping tasks that share a resource from running at the same time
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::thread;
+
+/// Runs `task` on each of `items`, with up to `max_parallel` tasks running at once. Items with the same key are run one
+/// after another, in the order given, so that tasks using the same resource (like profiles backing up to the same
+/// repository) never overlap. Returns the results in the same order as `items`.
+///
+/// With a `max_parallel` of 1 or less, every task runs in turn on the current thread.
+pub fn run_grouped<T, K, R, F, G>(items: Vec<T>, key: G, max_parallel: usize, task: F) -> Vec<R>
+where
+    T: Send,
+    K: PartialEq,
+    R: Send,
+    F: Fn(T) -> R + Sync,
+    G: Fn(&T) -> K,
+{
+    if max_parallel <= 1 {
+        return items.into_iter().map(task).collect();
+    }
+
+    let count = items.len();
+    let mut groups: Vec<(K, Vec<(usize, T)>)> = Vec::new();
+    for (index, item) in items.into_iter().enumerate() {
+        let item_key = key(&item);
+        match groups
+            .iter_mut()
+            .find(|(group_key, _)| *group_key == item_key)
+        {
+            Some((_, group)) => group.push((index, item)),
+            None => groups.push((item_key, vec![(index, item)])),
+        }
+    }
+
+    let workers = groups.len().min(max_parallel);
+    let queue: Mutex<VecDeque<Vec<(usize, T)>>> =
+        Mutex::new(groups.into_iter().map(|(_, group)| group).collect());
+    let results: Mutex<Vec<Option<R>>> = Mutex::new((0..count).map(|_| None).collect());
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let group = match queue.lock().unwrap().pop_front() {
+                    Some(group) => group,
+                    None => break,
+                };
+                for (index, item) in group {
+                    let result = task(item);
+                    results.lock().unwrap()[index] = Some(result);
+                }
+            });
+        }
+    });
+
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|result| result.expect("Task did not run"))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::run_grouped;
+
+    #[test]
+    fn test_run_grouped() {
+        let items = vec![("a", 1), ("b", 2), ("a", 3), ("c", 4), ("b", 5)];
+        let running: Mutex<HashMap<&str, bool>> = Mutex::new(HashMap::new());
+        let results = run_grouped(
+            items,
+            |(group, _)| *group,
+            2,
+            |(group, value)| {
+                let overlapped = running.lock().unwrap().insert(group, true) == Some(true);
+                thread::sleep(Duration::from_millis(20));
+                running.lock().unwrap().insert(group, false);
+                (value * 10, overlapped)
+            },
+        );
+        assert_eq!(
+            results,
+            vec![
+                (10, false),
+                (20, false),
+                (30, false),
+                (40, false),
+                (50, false)
+            ]
+        );
+
+        assert_eq!(
+            run_grouped(vec![1, 2, 3], |_| (), 1, |x| x + 1),
+            vec![2, 3, 4]
+        );
+    }
+}
@@ -0,0 +1,308 @@
+//! Repository passwords stored in 1Password or Bitwarden, read with their command-line tools (`op` and `bw`). Rustic
+//! reads each password itself and passes it to Restic through the environment, so that a vault is only unlocked once
+//! per run, and each password only read once, however many profiles and commands use it.
+
+use std::collections::HashMap;
+use std::process::{Command, Output, Stdio};
+use std::sync::Mutex;
+
+use anyhow::{bail, Context, Result};
+use lazy_static::lazy_static;
+
+/// Password managers Rustic can read repository passwords from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PasswordManager {
+    OnePassword,
+    Bitwarden,
+}
+
+impl PasswordManager {
+    /// Name of the password manager, for error messages
+    fn name(self) -> &'static str {
+        match self {
+            PasswordManager::OnePassword => "1Password",
+            PasswordManager::Bitwarden => "Bitwarden",
+        }
+    }
+
+    /// Command-line tool for the password manager
+    fn program(self) -> &'static str {
+        match self {
+            PasswordManager::OnePassword => "op",
+            PasswordManager::Bitwarden => "bw",
+        }
+    }
+
+    /// Arguments that print the password for `item` without prompting
+    fn read_args(self, item: &str) -> Vec<&str> {
+        match self {
+            PasswordManager::OnePassword => vec!["read", "--no-newline", item],
+            PasswordManager::Bitwarden => vec!["get", "password", item, "--nointeraction"],
+        }
+    }
+
+    /// Arguments that sign in or unlock the vault, prompting on the terminal if needed, and print a session token
+    fn unlock_args(self) -> Vec<&'static str> {
+        match self {
+            // Without `--raw`, `op` prints the session as a shell command that sets `OP_SESSION_<account>`, which is the
+            // only way to learn which variable it goes in
+            PasswordManager::OnePassword => vec!["signin"],
+            PasswordManager::Bitwarden => vec!["unlock", "--raw"],
+        }
+    }
+
+    /// Parses the output of the unlock command into the environment variable to pass the session token in, and the token.
+    /// Session tokens are passed in the environment rather than as arguments, so that other users can't see them in the
+    /// process list.
+    fn parse_session(self, output: &str) -> Option<(String, String)> {
+        match self {
+            PasswordManager::OnePassword => output.lines().find_map(|line| {
+                let line = line.trim();
+                let assignment = line
+                    .strip_prefix("export ")
+                    .or_else(|| line.strip_prefix("$env:"))
+                    .or_else(|| line.strip_prefix("set -x "))?;
+                let (name, token) = assignment.split_once(['=', ' '])?;
+                let token = token.trim().trim_matches(&['"', '\''][..]);
+                if name.starts_with("OP_SESSION_") && !token.is_empty() {
+                    Some((name.to_string(), token.to_string()))
+                } else {
+                    None
+                }
+            }),
+            PasswordManager::Bitwarden => Some(output.trim())
+                .filter(|token| !token.is_empty())
+                .map(|token| ("BW_SESSION".to_string(), token.to_string())),
+        }
+    }
+
+    /// Whether a failed read failed because the vault needs to be signed in to or unlocked first
+    fn is_locked(self, stderr: &str) -> bool {
+        match self {
+            PasswordManager::OnePassword => {
+                stderr.contains("not currently signed in")
+                    || stderr.contains("account is not signed in")
+                    || stderr.contains("session expired")
+            }
+            PasswordManager::Bitwarden => stderr.contains("Vault is locked"),
+        }
+    }
+}
+
+/// Reads a password from a password manager, unlocking its vault first if needed. Passwords and session tokens are
+/// cached for the rest of the run.
+pub fn read_password(manager: PasswordManager, item: &str) -> Result<String> {
+    VAULTS
+        .lock()
+        .unwrap()
+        .read_password(manager, manager.program(), item)
+}
+
+lazy_static! {
+    /// Shared by every profile, and locked while reading so that parallel backups don't each prompt to unlock a vault
+    static ref VAULTS: Mutex<Vaults> = Mutex::new(Vaults::default());
+}
+
+/// Session tokens, with the environment variables they're passed in, and passwords read so far
+#[derive(Default)]
+struct Vaults {
+    sessions: HashMap<PasswordManager, (String, String)>,
+    passwords: HashMap<(PasswordManager, String), String>,
+}
+
+impl Vaults {
+    /// Reads a password with `program`, which should be the password manager's command-line tool
+    fn read_password(
+        &mut self,
+        manager: PasswordManager,
+        program: &str,
+        item: &str,
+    ) -> Result<String> {
+        let key = (manager, item.to_string());
+        if let Some(password) = self.passwords.get(&key) {
+            return Ok(password.clone());
+        }
+
+        let mut output = self.run_read(manager, program, item)?;
+        if !output.status.success() && manager.is_locked(&String::from_utf8_lossy(&output.stderr)) {
+            let session = unlock(manager, program)?;
+            self.sessions.insert(manager, session);
+            output = self.run_read(manager, program, item)?;
+        }
+        if !output.status.success() {
+            bail!(
+                "Could not read `{}` from {}: {}",
+                item,
+                manager.name(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+
+        let password = String::from_utf8(output.stdout)
+            .with_context(|| format!("Password for `{}` is not valid UTF-8", item))?
+            .trim_end_matches(&['\r', '\n'][..])
+            .to_string();
+        if password.is_empty() {
+            bail!("{} item `{}` has an empty password", manager.name(), item);
+        }
+        self.passwords.insert(key, password.clone());
+        Ok(password)
+    }
+
+    /// Runs the password manager's read command, using the current session if there is one
+    fn run_read(&self, manager: PasswordManager, program: &str, item: &str) -> Result<Output> {
+        let mut command = Command::new(program);
+        command.args(manager.read_args(item)).stdin(Stdio::null());
+        if let Some((variable, token)) = self.sessions.get(&manager) {
+            command.env(variable, token);
+        }
+        command
+            .output()
+            .with_context(|| format!("Could not run {}", program))
+    }
+}
+
+/// Signs in to or unlocks a password manager's vault, returning the environment variable to pass the session token in,
+/// and the token. The password manager prompts on the terminal for anything it needs.
+fn unlock(manager: PasswordManager, program: &str) -> Result<(String, String)> {
+    let output = Command::new(program)
+        .args(manager.unlock_args())
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()
+        .with_context(|| format!("Could not run {}", program))?;
+    if !output.status.success() {
+        bail!(
+            "Could not unlock {} ({} failed with {})",
+            manager.name(),
+            program,
+            output.status
+        );
+    }
+    match manager.parse_session(&String::from_utf8_lossy(&output.stdout)) {
+        Some(session) => Ok(session),
+        None => bail!("{} did not print a session token", program),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    use tempfile::TempDir;
+
+    use super::{PasswordManager, Vaults};
+
+    #[test]
+    fn test_read_password() {
+        let dir = TempDir::new().unwrap();
+        let bw = dir.path().join("bw");
+        let log = dir.path().join("bw.log");
+        fs::write(
+            &bw,
+            format!(
+                r#"#!/bin/sh
+echo "$1 $BW_SESSION" >> '{}'
+case "$1" in
+  unlock) echo session-token ;;
+  get) if [ "$BW_SESSION" != session-token ]; then echo "Vault is locked." >&2; exit 1; fi
+       case "$3" in missing) echo "Not found." >&2; exit 1 ;; esac
+       echo "password for $3" ;;
+esac
+"#,
+                log.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&bw, fs::Permissions::from_mode(0o755)).unwrap();
+        let bw = bw.to_str().unwrap();
+
+        let mut vaults = Vaults::default();
+        assert_eq!(
+            vaults
+                .read_password(PasswordManager::Bitwarden, bw, "home")
+                .unwrap(),
+            "password for home"
+        );
+        assert_eq!(
+            vaults
+                .read_password(PasswordManager::Bitwarden, bw, "home")
+                .unwrap(),
+            "password for home"
+        );
+        assert_eq!(
+            vaults
+                .read_password(PasswordManager::Bitwarden, bw, "offsite")
+                .unwrap(),
+            "password for offsite"
+        );
+        let err = vaults
+            .read_password(PasswordManager::Bitwarden, bw, "missing")
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Could not read `missing` from Bitwarden: Not found."
+        );
+
+        // The vault is only unlocked once, and each password only read once
+        assert_eq!(
+            fs::read_to_string(&log).unwrap(),
+            "get \nunlock \nget session-token\nget session-token\nget session-token\n"
+        );
+    }
+
+    #[test]
+    fn test_read_password_one_password() {
+        let dir = TempDir::new().unwrap();
+        let op = dir.path().join("op");
+        let log = dir.path().join("op.log");
+        fs::write(
+            &op,
+            format!(
+                r##"#!/bin/sh
+echo "$* $OP_SESSION_my" >> '{}'
+case "$1" in
+  signin) echo 'export OP_SESSION_my="session-token"'
+          echo "# This command is meant to be used with your shell's eval function." ;;
+  read) if [ "$OP_SESSION_my" != session-token ]; then echo "[ERROR] account is not signed in" >&2; exit 1; fi
+        printf 'password for %s' "$3" ;;
+esac
+"##,
+                log.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&op, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut vaults = Vaults::default();
+        assert_eq!(
+            vaults
+                .read_password(
+                    PasswordManager::OnePassword,
+                    op.to_str().unwrap(),
+                    "op://vault/home"
+                )
+                .unwrap(),
+            "password for op://vault/home"
+        );
+        // The session token is passed in the environment, never as an argument
+        assert_eq!(
+            fs::read_to_string(&log).unwrap(),
+            "read --no-newline op://vault/home \nsignin \nread --no-newline op://vault/home session-token\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_session() {
+        assert_eq!(
+            PasswordManager::OnePassword.parse_session("$env:OP_SESSION_ABC123=\"token\"\r\n"),
+            Some(("OP_SESSION_ABC123".to_string(), "token".to_string()))
+        );
+        assert_eq!(PasswordManager::OnePassword.parse_session("token\n"), None);
+        assert_eq!(
+            PasswordManager::Bitwarden.parse_session("token\n"),
+            Some(("BW_SESSION".to_string(), "token".to_string()))
+        );
+    }
+}
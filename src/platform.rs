@@ -0,0 +1,320 @@
+//! Platform-specific process wrappers
+
+use std::env;
+#[cfg(unix)]
+use std::ffi::CStr;
+use std::ffi::OsString;
+use std::io;
+use std::process::{Child, ExitStatus};
+
+use crate::config::IoClass;
+
+/// Returns a command prefix that keeps the system from going to sleep while the wrapped command runs, or `None` if that isn't
+/// supported on this platform.
+pub fn inhibit_sleep_prefix(reason: &str) -> Option<Vec<OsString>> {
+    if cfg!(target_os = "linux") && program_exists("systemd-inhibit") {
+        Some(systemd_inhibit_prefix(reason))
+    } else if cfg!(target_os = "macos") && program_exists("caffeinate") {
+        Some(caffeinate_prefix())
+    } else {
+        None
+    }
+}
+
+/// Builds a [`systemd-inhibit`](https://www.freedesktop.org/software/systemd/man/systemd-inhibit.html) command line that blocks
+/// idle and sleep while the wrapped command runs.
+fn systemd_inhibit_prefix(reason: &str) -> Vec<OsString> {
+    vec![
+        "systemd-inhibit".into(),
+        "--what=sleep:idle".into(),
+        "--who=rustic".into(),
+        format!("--why={}", reason).into(),
+        "--mode=block".into(),
+    ]
+}
+
+/// Builds a `caffeinate` command line that prevents idle and system sleep while the wrapped command runs.
+fn caffeinate_prefix() -> Vec<OsString> {
+    vec!["caffeinate".into(), "-i".into(), "-s".into()]
+}
+
+/// Returns a command prefix that runs the wrapped command with the given niceness, or `None` if that isn't supported on this
+/// platform.
+pub fn nice_prefix(niceness: i32) -> Option<Vec<OsString>> {
+    if cfg!(unix) && program_exists("nice") {
+        Some(vec![
+            "nice".into(),
+            "-n".into(),
+            niceness.to_string().into(),
+        ])
+    } else {
+        None
+    }
+}
+
+/// Returns a command prefix that runs the wrapped command with the given I/O scheduling class, or `None` if that isn't supported
+/// on this platform.
+pub fn ionice_prefix(class: IoClass) -> Option<Vec<OsString>> {
+    if cfg!(target_os = "linux") && program_exists("ionice") {
+        Some(ionice_args(class))
+    } else {
+        None
+    }
+}
+
+fn ionice_args(class: IoClass) -> Vec<OsString> {
+    let class = match class {
+        IoClass::Realtime => "1",
+        IoClass::BestEffort => "2",
+        IoClass::Idle => "3",
+    };
+    vec!["ionice".into(), "-c".into(), class.into()]
+}
+
+/// Returns this machine's hostname
+#[cfg(unix)]
+pub fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    // Safe because the buffer is valid for its whole length
+    let result = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if result != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8(buf[..len].to_vec()).ok()
+}
+
+/// Returns this machine's hostname
+#[cfg(windows)]
+pub fn hostname() -> Option<String> {
+    env::var("COMPUTERNAME").ok()
+}
+
+/// Returns the name of the user running Rustic
+#[cfg(unix)]
+pub fn username() -> Option<String> {
+    if let Ok(user) = env::var("USER") {
+        return Some(user);
+    }
+
+    // Safe because getpwuid returns either null or a pointer to a valid passwd entry, which is only read before any other
+    // call that could overwrite it
+    unsafe {
+        let passwd = libc::getpwuid(libc::geteuid());
+        if passwd.is_null() {
+            return None;
+        }
+        CStr::from_ptr((*passwd).pw_name)
+            .to_str()
+            .ok()
+            .map(String::from)
+    }
+}
+
+/// Returns the name of the user running Rustic
+#[cfg(windows)]
+pub fn username() -> Option<String> {
+    env::var("USERNAME").ok()
+}
+
+/// Returns the effective user ID of the Rustic process
+#[cfg(unix)]
+pub fn user_id() -> u32 {
+    // Safe because geteuid has no preconditions and always succeeds
+    unsafe { libc::geteuid() }
+}
+
+/// Checks if a process with the given ID is running
+#[cfg(unix)]
+pub fn process_exists(pid: u32) -> bool {
+    // Signal 0 doesn't send anything, but still checks if the process exists. EPERM means it exists but belongs to someone else.
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Checks if a process with the given ID is running. This isn't supported on Windows, so it conservatively assumes the process
+/// does exist.
+#[cfg(windows)]
+pub fn process_exists(_pid: u32) -> bool {
+    true
+}
+
+/// Asks a child process to stop, giving it a chance to clean up (Restic removes its repository lock when interrupted)
+#[cfg(unix)]
+pub fn interrupt(child: &mut Child) -> io::Result<()> {
+    // Safe because kill has no memory safety requirements
+    if unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGINT) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Stops a child process. Windows has no equivalent to SIGINT for background processes, so it's killed outright.
+#[cfg(windows)]
+pub fn interrupt(child: &mut Child) -> io::Result<()> {
+    child.kill()
+}
+
+/// Creates the exit status of a process that exited with `code`
+#[cfg(unix)]
+pub fn exit_status(code: i32) -> ExitStatus {
+    use std::os::unix::process::ExitStatusExt;
+    ExitStatus::from_raw(code << 8)
+}
+
+/// Creates the exit status of a process that exited with `code`
+#[cfg(windows)]
+pub fn exit_status(code: i32) -> ExitStatus {
+    use std::os::windows::process::ExitStatusExt;
+    ExitStatus::from_raw(code as u32)
+}
+
+/// Checks if an executable named `program` is on the `PATH`.
+pub fn program_exists(program: &str) -> bool {
+    let path = match env::var_os("PATH") {
+        Some(path) => path,
+        None => return false,
+    };
+
+    env::split_paths(&path).any(|dir| {
+        let candidate = dir.join(program);
+        candidate.is_file() || (cfg!(windows) && candidate.with_extension("exe").is_file())
+    })
+}
+
+/// Prompts for a password on the controlling terminal without echoing it, even if stdin or stdout are redirected
+#[cfg(unix)]
+pub fn prompt_password(prompt: &str) -> io::Result<String> {
+    use std::fs::OpenOptions;
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::io::AsRawFd;
+
+    let mut tty = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+    let fd = tty.as_raw_fd();
+    let mut original = unsafe { std::mem::zeroed::<libc::termios>() };
+    if unsafe { libc::tcgetattr(fd, &mut original) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut hidden = original;
+    hidden.c_lflag &= !libc::ECHO;
+    hidden.c_lflag |= libc::ECHONL;
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &hidden) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let result = tty.write_all(prompt.as_bytes()).and_then(|_| {
+        let mut line = String::new();
+        BufReader::new(&tty).read_line(&mut line)?;
+        Ok(line)
+    });
+    unsafe {
+        libc::tcsetattr(fd, libc::TCSANOW, &original);
+    }
+    Ok(result?.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+#[cfg(windows)]
+pub fn prompt_password(_prompt: &str) -> io::Result<String> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "Prompting for passwords is not supported on Windows",
+    ))
+}
+
+/// Catches termination signals while Restic is running, so they can be forwarded to it or cleaned up after instead of
+/// killing Rustic first
+#[cfg(unix)]
+pub mod signals {
+    use std::sync::atomic::{AtomicI32, Ordering};
+    use std::sync::Mutex;
+
+    const SIGNALS: [libc::c_int; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+    static RECEIVED: AtomicI32 = AtomicI32::new(0);
+
+    /// How many callers currently need signals caught, since backups running in parallel each install the handler
+    static HOLDERS: Mutex<usize> = Mutex::new(0);
+
+    extern "C" fn handle(signal: libc::c_int) {
+        RECEIVED.store(signal, Ordering::SeqCst);
+    }
+
+    /// Starts catching termination signals. Calls nest, so signals stay caught until every `install` is matched by a
+    /// `restore`.
+    pub fn install() {
+        let mut holders = HOLDERS.lock().unwrap_or_else(|err| err.into_inner());
+        if *holders == 0 {
+            RECEIVED.store(0, Ordering::SeqCst);
+            for signal in SIGNALS.iter() {
+                // Safe because the handler only touches an atomic
+                unsafe {
+                    libc::signal(*signal, handle as *const () as libc::sighandler_t);
+                }
+            }
+        }
+        *holders += 1;
+    }
+
+    /// Stops catching termination signals, unless another caller still needs them caught. Returns whether the default
+    /// handlers were restored.
+    pub fn restore() -> bool {
+        let mut holders = HOLDERS.lock().unwrap_or_else(|err| err.into_inner());
+        *holders = holders.saturating_sub(1);
+        if *holders > 0 {
+            return false;
+        }
+        for signal in SIGNALS.iter() {
+            unsafe {
+                libc::signal(*signal, libc::SIG_DFL);
+            }
+        }
+        true
+    }
+
+    /// Sends `signal` to Rustic itself with its default handler, to exit the way it would have if the signal hadn't been caught
+    pub fn raise(signal: i32) {
+        unsafe {
+            libc::signal(signal, libc::SIG_DFL);
+            libc::raise(signal);
+        }
+    }
+
+    /// Returns the most recently received signal, if there is one that hasn't been handled yet
+    pub fn take() -> Option<i32> {
+        match RECEIVED.swap(0, Ordering::SeqCst) {
+            0 => None,
+            signal => Some(signal),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_systemd_inhibit_prefix() {
+        assert_eq!(
+            systemd_inhibit_prefix("Backing up home"),
+            vec![
+                "systemd-inhibit",
+                "--what=sleep:idle",
+                "--who=rustic",
+                "--why=Backing up home",
+                "--mode=block"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ionice_args() {
+        assert_eq!(ionice_args(IoClass::Idle), vec!["ionice", "-c", "3"]);
+        assert_eq!(ionice_args(IoClass::BestEffort), vec!["ionice", "-c", "2"]);
+    }
+
+    #[test]
+    fn test_caffeinate_prefix() {
+        assert_eq!(caffeinate_prefix(), vec!["caffeinate", "-i", "-s"]);
+    }
+}
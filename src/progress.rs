@@ -0,0 +1,145 @@
+//! Progress bar for backups running in a terminal
+
+use std::cell::Cell;
+use std::env;
+use std::io::{self, IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+use crate::events::{Event, Progress, Sink};
+use crate::format::{format_bytes, format_duration};
+use crate::restic::Restic;
+
+/// Minimum time between redraws, so that fast status updates don't flood the terminal
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Width of the bar itself, not counting the text around it
+const BAR_WIDTH: usize = 25;
+
+/// Sink that draws a single-line progress bar on stderr while a backup runs
+pub struct ProgressBar {
+    last_draw: Cell<Option<Instant>>,
+}
+
+impl ProgressBar {
+    /// Creates a progress bar if stderr is a terminal. Otherwise, the bar would only clutter logs.
+    pub fn for_terminal() -> Option<ProgressBar> {
+        if io::stderr().is_terminal() {
+            Some(ProgressBar {
+                last_draw: Cell::new(None),
+            })
+        } else {
+            None
+        }
+    }
+
+    fn draw(&self, progress: &Progress) {
+        let now = Instant::now();
+        if self
+            .last_draw
+            .get()
+            .is_some_and(|last| now.duration_since(last) < REDRAW_INTERVAL)
+        {
+            return;
+        }
+        self.last_draw.set(Some(now));
+
+        let line = render(progress, terminal_width());
+        let mut stderr = io::stderr().lock();
+        let _ = write!(stderr, "\r\x1b[K{}", line);
+        let _ = stderr.flush();
+    }
+
+    fn clear(&self) {
+        if self.last_draw.take().is_some() {
+            let mut stderr = io::stderr().lock();
+            let _ = write!(stderr, "\r\x1b[K");
+            let _ = stderr.flush();
+        }
+    }
+}
+
+impl Sink for ProgressBar {
+    fn handle(&self, _restic: &Restic, event: &Event) {
+        match event {
+            Event::Progress { progress, .. } => self.draw(progress),
+            Event::Finished(_) | Event::Failed(_) => self.clear(),
+            Event::Started { .. } | Event::Hook(_) => (),
+        }
+    }
+
+    fn wants_progress(&self) -> bool {
+        true
+    }
+}
+
+/// Width of the terminal, from `COLUMNS` if the shell exports it
+fn terminal_width() -> usize {
+    env::var("COLUMNS")
+        .ok()
+        .and_then(|columns| columns.parse().ok())
+        .unwrap_or(80)
+}
+
+/// Renders a progress line, like `[#####-----] 45.2%  1.2 GiB / 3.0 GiB  12.0 MiB/s  ETA 2m 10s  120/500 files  /home/ben/a.txt`,
+/// truncated to fit in `width` columns.
+fn render(progress: &Progress, width: usize) -> String {
+    let fraction = progress.percent_done.clamp(0.0, 1.0);
+    let filled = (fraction * BAR_WIDTH as f64).round() as usize;
+    let mut line = format!(
+        "[{}{}] {:5.1}%  {} / {}",
+        "#".repeat(filled),
+        "-".repeat(BAR_WIDTH - filled),
+        fraction * 100.0,
+        format_bytes(progress.bytes_done),
+        format_bytes(progress.total_bytes),
+    );
+
+    if let Some(throughput) = progress.bytes_done.checked_div(progress.seconds_elapsed) {
+        line.push_str(&format!("  {}/s", format_bytes(throughput)));
+    }
+    if let Some(remaining) = progress.seconds_remaining {
+        line.push_str(&format!(
+            "  ETA {}",
+            format_duration(Duration::from_secs(remaining))
+        ));
+    }
+    line.push_str(&format!(
+        "  {}/{} files",
+        progress.files_done, progress.total_files
+    ));
+    if let Some(file) = progress.current_files.first() {
+        line.push_str("  ");
+        line.push_str(file);
+    }
+
+    // Wrapping would break the carriage return that redraws the line
+    match line.char_indices().nth(width.saturating_sub(1)) {
+        Some((end, _)) => line[..end].to_string(),
+        None => line,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let progress = Progress {
+            percent_done: 0.5,
+            seconds_elapsed: 10,
+            seconds_remaining: Some(130),
+            total_files: 500,
+            files_done: 120,
+            total_bytes: 2048 * 1024,
+            bytes_done: 1024 * 1024,
+            current_files: vec!["/home/ben/a.txt".to_string()],
+        };
+        assert_eq!(
+            render(&progress, 200),
+            "[#############------------]  50.0%  1.0 MiB / 2.0 MiB  102.4 KiB/s  ETA 2m 10s  120/500 files  /home/ben/a.txt"
+        );
+        // One column is left free, so the cursor doesn't wrap
+        assert_eq!(render(&progress, 20), "[#############-----");
+    }
+}
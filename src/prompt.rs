@@ -0,0 +1,116 @@
+//! Prompting for repository passwords that aren't configured anywhere, which is handy for one-off commands like restores
+//! on a machine without the profile's secrets, and for confirmation before risky operations. Prompted passwords can be
+//! remembered in the keyring until logging out, so that a run of commands only asks once.
+
+use std::collections::HashMap;
+use std::io::{self, prelude::*, IsTerminal};
+use std::sync::Mutex;
+
+use anyhow::{bail, Context, Result};
+use lazy_static::lazy_static;
+
+use crate::config::KeyringEntry;
+use crate::keyring;
+use crate::platform;
+use crate::redact;
+
+/// Keyring service that prompted passwords are remembered under
+const REMEMBERED_PASSWORD_SERVICE: &str = "rustic-prompt";
+
+lazy_static! {
+    /// Passwords entered so far, by repository, so that each is only asked for once per run
+    static ref PASSWORDS: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+}
+
+/// Whether Rustic is running interactively, and so can fall back to prompting for passwords
+pub fn can_prompt() -> bool {
+    io::stdin().is_terminal()
+}
+
+/// Prompts on the terminal for the password to `repository`, unless it was already entered during this run. With
+/// `remember`, the password is also looked up in and saved to the keyring, until the user logs out.
+pub fn repository_password(repository: &str, remember: bool) -> Result<String> {
+    prompt_repository_password(repository, remember, can_prompt())
+}
+
+fn prompt_repository_password(
+    repository: &str,
+    remember: bool,
+    interactive: bool,
+) -> Result<String> {
+    // Held while prompting, so that profiles backing up in parallel don't ask at the same time
+    let mut passwords = PASSWORDS.lock().unwrap();
+    if let Some(password) = passwords.get(repository) {
+        return Ok(password.clone());
+    }
+
+    let shown = redact::url(repository);
+    // The repository's credentials, if it has any, aren't part of the keyring entry
+    let entry = KeyringEntry {
+        service: REMEMBERED_PASSWORD_SERVICE.to_string(),
+        account: shown.to_string(),
+    };
+    if remember {
+        // Nothing remembered yet is the usual case, so lookup failures aren't reported
+        if let Ok(password) = keyring::lookup(&entry) {
+            if !password.is_empty() {
+                passwords.insert(repository.to_string(), password.clone());
+                return Ok(password);
+            }
+        }
+    }
+
+    if !interactive {
+        bail!(
+            "Cannot prompt for the password to {} without a terminal. Set a password source like `password_file` to \
+             run without one.",
+            shown
+        );
+    }
+    let password = platform::prompt_password(&format!("Password for {}: ", shown))
+        .with_context(|| format!("Could not prompt for the password to {}", shown))?;
+    if password.is_empty() {
+        bail!("No password entered for {}", shown);
+    }
+    if remember {
+        // The password is already known, so failing to remember it only means being asked again next time
+        let label = format!("Restic repository password for {}", shown);
+        if let Err(err) = keyring::store_for_session(&entry, &label, &password) {
+            eprintln!("Could not remember the password in the keyring: {:#}", err);
+        }
+    }
+    passwords.insert(repository.to_string(), password.clone());
+    Ok(password)
+}
+
+/// Asks a yes or no question on the terminal, defaulting to no. Fails if Rustic isn't running interactively, since
+/// nobody can answer.
+pub fn confirm(question: &str) -> Result<bool> {
+    if !can_prompt() {
+        bail!("Cannot ask for confirmation without a terminal. Pass `--yes` to go ahead anyway.");
+    }
+    eprint!("{} [y/N] ", question);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin()
+        .lock()
+        .read_line(&mut answer)
+        .context("Could not read confirmation")?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::prompt_repository_password;
+
+    #[test]
+    fn test_prompt_without_terminal() {
+        let err =
+            prompt_repository_password("sftp:backup@nas:/srv/restic", false, false).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cannot prompt for the password to sftp:backup@nas:/srv/restic without a terminal. Set a password source like \
+             `password_file` to run without one."
+        );
+    }
+}
@@ -0,0 +1,178 @@
+//! Repository upgrades and repairs, so that recovering a damaged repository can be done with a profile's credentials
+//! instead of setting up Restic's environment by hand
+
+use std::time::Instant;
+
+use anyhow::Result;
+use slog::{error, info};
+
+use crate::redact;
+use crate::restic::Restic;
+use crate::version::ResticVersion;
+
+/// First Restic release that can upgrade repositories to format version 2
+const MIGRATE_VERSION: ResticVersion = ResticVersion::new(0, 14, 0);
+
+/// First Restic release with the `repair` commands
+const REPAIR_VERSION: ResticVersion = ResticVersion::new(0, 16, 0);
+
+/// Part of a repository to repair
+#[derive(Debug, Clone, PartialEq)]
+pub enum Repair {
+    /// Rebuild the index from the pack files in the repository
+    Index {
+        /// Read every pack file instead of only those missing from the index, which is much slower
+        read_all_packs: bool,
+    },
+
+    /// Rewrite snapshots that refer to missing data, leaving out the damaged files and directories
+    Snapshots {
+        /// Forget the damaged snapshots once they've been repaired
+        forget: bool,
+
+        /// Snapshots to repair. Every snapshot is checked if this is empty.
+        snapshots: Vec<String>,
+    },
+
+    /// Salvage what can be read from damaged pack files, and remove them
+    Packs {
+        /// IDs of the damaged pack files, as reported by `restic check`
+        ids: Vec<String>,
+    },
+}
+
+impl Repair {
+    /// Restic `repair` subcommand for this repair
+    fn subcommand(&self) -> &'static str {
+        match self {
+            Repair::Index { .. } => "index",
+            Repair::Snapshots { .. } => "snapshots",
+            Repair::Packs { .. } => "packs",
+        }
+    }
+}
+
+/// Extends the Restic wrapper with repository upgrades and repairs
+impl<'a> Restic<'a> {
+    /// Upgrades the repository to format version 2, which supports compression. Older Restic versions can't read the
+    /// repository afterwards. Existing data stays uncompressed until it's repacked, as by `prune --repack-uncompressed`.
+    pub fn migrate_repository(&self) -> Result<()> {
+        self.require_full_access("migrate")?;
+        self.require_version(MIGRATE_VERSION, "Upgrading repositories")?;
+
+        let mut cmd = self.new_command();
+        cmd.arg("migrate").arg("upgrade_repo_v2");
+        self.run_repository_command(
+            &mut cmd,
+            "migrate",
+            "Upgrading repository",
+            "Upgraded repository",
+        )
+    }
+
+    /// Runs one of Restic's repair commands. Repairs need the repository to themselves, so they wait for other operations
+    /// like maintenance does.
+    pub fn repair(&self, repair: &Repair) -> Result<()> {
+        self.require_full_access("repair")?;
+        self.require_version(REPAIR_VERSION, "Repairing repositories")?;
+
+        let mut cmd = self.new_command();
+        cmd.arg("repair").arg(repair.subcommand());
+        match repair {
+            Repair::Index { read_all_packs } => {
+                if *read_all_packs {
+                    cmd.arg("--read-all-packs");
+                }
+            }
+            Repair::Snapshots { forget, snapshots } => {
+                if *forget {
+                    cmd.arg("--forget");
+                }
+                cmd.args(snapshots);
+            }
+            Repair::Packs { ids } => {
+                cmd.args(ids);
+            }
+        }
+        self.run_repository_command(
+            &mut cmd,
+            "repair",
+            &format!("Repairing {}", repair.subcommand()),
+            &format!("Repaired {}", repair.subcommand()),
+        )
+    }
+
+    /// Runs a command that changes the whole repository, with the repository locked for maintenance
+    fn run_repository_command(
+        &self,
+        cmd: &mut std::process::Command,
+        operation: &str,
+        starting: &str,
+        finished: &str,
+    ) -> Result<()> {
+        info!(self.logger(), "{}", starting; "command" => %redact::command_line(cmd));
+        let start = Instant::now();
+        let status = self
+            .lock_repository_for_maintenance()
+            .and_then(|_lock| self.execute(cmd, None))?
+            .status;
+        let duration = Instant::now() - start;
+
+        if status.success() {
+            info!(self.logger(), "{} in {:?}", finished, duration; "command" => %redact::command_line(cmd));
+            Ok(())
+        } else {
+            error!(self.logger(), "{} failed", starting; "status" => %status, "command" => %redact::command_line(cmd));
+            Err(self.failed(operation, status).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Repair;
+    use crate::runner::MockResponse;
+    use crate::test::TestFixture;
+
+    #[test]
+    fn test_repair() {
+        let fixture = TestFixture::new();
+        let (restic, runner) = fixture.mock_restic();
+
+        runner.respond(MockResponse::success(
+            "restic 0.16.0 compiled with go1.20 on linux/amd64",
+        ));
+        restic.migrate_repository().unwrap();
+        assert!(runner.invocations()[1].has_arg("upgrade_repo_v2"));
+
+        runner.respond(MockResponse::success(
+            "restic 0.16.0 compiled with go1.20 on linux/amd64",
+        ));
+        restic
+            .repair(&Repair::Snapshots {
+                forget: true,
+                snapshots: vec!["0123abcd".to_string()],
+            })
+            .unwrap();
+        let args = &runner.invocations()[3].args;
+        let repair = args.iter().position(|arg| arg == "repair").unwrap();
+        assert_eq!(
+            args[repair..],
+            ["repair", "snapshots", "--forget", "0123abcd"]
+        );
+
+        runner.respond(MockResponse::success(
+            "restic 0.15.2 compiled with go1.20 on linux/amd64",
+        ));
+        assert!(restic
+            .repair(&Repair::Index {
+                read_all_packs: false
+            })
+            .is_err());
+
+        // Append-only profiles can't change the repository
+        let mut fixture = TestFixture::new();
+        fixture.profile_mut().append_only = true;
+        assert!(fixture.restic().migrate_repository().is_err());
+    }
+}
@@ -0,0 +1,285 @@
+//! JSON reports of finished operations, for orchestration tools like Ansible or Nomad that want a file to collect instead of
+//! logs to scrape
+
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use slog::{debug, warn, Logger};
+use tempfile::NamedTempFile;
+
+use crate::backup::BackupSummary;
+use crate::config::Configuration;
+use crate::events::{HookOutcome, Outcome};
+use crate::forget::ForgetGroup;
+use crate::json;
+use crate::redact;
+use crate::restic::Restic;
+use crate::template;
+
+/// Report of one finished operation on a profile
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    /// Name of the profile
+    pub profile: String,
+
+    /// Operation that finished, such as `backup` or `prune`
+    pub operation: String,
+
+    /// Repository the operation ran against, with any credentials removed
+    pub repository: String,
+
+    /// Whether the operation succeeded
+    pub success: bool,
+
+    /// When the operation started
+    pub started_at: DateTime<Utc>,
+
+    /// When the operation finished
+    pub finished_at: DateTime<Utc>,
+
+    /// How long the operation took, in seconds
+    pub duration_seconds: f64,
+
+    /// Restic's exit code, if it ran to completion
+    pub exit_code: Option<i32>,
+
+    /// Error message, if the operation failed
+    pub error: Option<String>,
+
+    /// Summary of the new snapshot, for backups
+    pub summary: Option<BackupSummary>,
+
+    /// What's unusual about the backup's size compared to recent ones, if anything
+    pub anomaly: Option<String>,
+
+    /// Which snapshots were kept and which were removed, for forgets
+    pub forget: Option<Vec<ForgetGroup>>,
+
+    /// Every hook that ran, in order
+    pub hooks: Vec<HookReport>,
+}
+
+/// Result of one hook in a [`Report`]
+#[derive(Debug, Clone, Serialize)]
+pub struct HookReport {
+    /// When the hook ran, like `pre_backup` or `on_success`
+    pub stage: String,
+
+    /// The hook command
+    pub command: String,
+
+    /// Whether the hook succeeded
+    pub success: bool,
+
+    /// How long the hook took, in seconds
+    pub duration_seconds: f64,
+
+    /// Why the hook failed, if it did
+    pub error: Option<String>,
+}
+
+impl HookReport {
+    /// Creates the report of a hook that finished
+    pub fn new(hook: &HookOutcome) -> HookReport {
+        HookReport {
+            stage: hook.stage.to_string(),
+            command: hook.command.to_string(),
+            success: hook.error.is_none(),
+            duration_seconds: hook.duration.as_secs_f64(),
+            error: hook.error.clone(),
+        }
+    }
+}
+
+impl Report {
+    /// Creates a report of an operation on `restic`'s profile, with the hooks that ran before it finished
+    pub fn new(restic: &Restic, outcome: &Outcome, hooks: Vec<HookReport>) -> Report {
+        Report {
+            profile: restic.profile_name().to_string(),
+            operation: outcome.operation.to_string(),
+            repository: redact::url(restic.repository()).into_owned(),
+            success: outcome.error.is_none(),
+            started_at: outcome.started_at,
+            finished_at: Utc::now(),
+            duration_seconds: outcome.duration.as_secs_f64(),
+            exit_code: outcome.exit_code,
+            error: outcome.error.clone(),
+            summary: outcome.summary.cloned(),
+            anomaly: outcome.anomaly.clone(),
+            forget: outcome.forget.map(|report| report.groups.clone()),
+            hooks,
+        }
+    }
+}
+
+/// Expands the `{profile}` and `{operation}` placeholders in a `report_file` setting
+pub fn report_path(template: &str, profile: &str, operation: &str) -> Result<PathBuf> {
+    let path = template::expand(template, |name| match name {
+        "profile" => Ok(profile.to_string()),
+        "operation" => Ok(operation.to_string()),
+        _ => bail!("Unknown placeholder `{{{}}}` in `report_file`", name),
+    })?;
+    Ok(PathBuf::from(path))
+}
+
+/// Removes the reports that an earlier run of each of `operations` on `profile` left behind, before running them again.
+/// Otherwise, a run that stops before the operation starts, like one whose repository password can't be read or whose
+/// backup isn't due yet, would leave the earlier run's report looking like its own.
+///
+/// Failing to remove a report is logged, but otherwise ignored, like failing to write one.
+pub fn remove_reports(config: &Configuration, logger: &Logger, profile: &str, operations: &[&str]) {
+    let template = match config.report_file {
+        Some(ref template) => template,
+        None => return,
+    };
+    for operation in operations {
+        let result = report_path(template, profile, operation).and_then(|path| {
+            match fs::remove_file(&path) {
+                Ok(()) => debug!(logger, "Removed earlier report"; "path" => %path.display()),
+                Err(err) if err.kind() == ErrorKind::NotFound => (),
+                Err(err) => {
+                    return Err(err).with_context(|| format!("Could not remove {}", path.display()))
+                }
+            }
+            Ok(())
+        });
+        if let Err(err) = result {
+            warn!(logger, "Could not remove earlier report: {:#}", err; "profile" => profile);
+        }
+    }
+}
+
+/// Extends the Restic wrapper with run reports
+impl<'a> Restic<'a> {
+    /// Writes `report` to the configured `report_file`, if there is one, replacing the report of an earlier run.
+    ///
+    /// Failing to write the report is logged, but otherwise ignored, since it shouldn't cause the operation itself to fail.
+    pub fn write_report(&self, report: &Report) {
+        let template = match self.config().report_file {
+            Some(ref template) => template,
+            None => return,
+        };
+        let path = match report_path(template, &report.profile, &report.operation) {
+            Ok(path) => path,
+            Err(err) => {
+                warn!(self.logger(), "Could not write report: {:#}", err);
+                return;
+            }
+        };
+        debug!(self.logger(), "Writing report"; "path" => %path.display());
+        if let Err(err) = write_report_file(&path, report) {
+            warn!(self.logger(), "Could not write report: {:#}", err; "path" => %path.display());
+        }
+    }
+}
+
+fn write_report_file(path: &Path, report: &Report) -> Result<()> {
+    let directory = match path.parent() {
+        Some(directory) if !directory.as_os_str().is_empty() => directory,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(directory)
+        .with_context(|| format!("Could not create {}", directory.display()))?;
+
+    // Write to a temporary file and rename it into place so a collector never sees a partially-written report
+    let contents = json::to_string_pretty(report)?;
+    let mut file = NamedTempFile::new_in(directory)
+        .with_context(|| format!("Could not create file in {}", directory.display()))?;
+    file.write_all(contents.as_bytes())
+        .and_then(|_| file.write_all(b"\n"))
+        .context("Could not write report")?;
+    file.persist(path)
+        .with_context(|| format!("Could not replace {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use slog::{o, Discard, Logger};
+
+    use super::{remove_reports, report_path};
+    use crate::json;
+    use crate::runner::MockResponse;
+    use crate::test::TestFixture;
+
+    #[test]
+    fn test_report_path() {
+        assert_eq!(
+            report_path(
+                "/var/lib/rustic/{profile}-{operation}.json",
+                "home",
+                "backup"
+            )
+            .unwrap(),
+            std::path::PathBuf::from("/var/lib/rustic/home-backup.json")
+        );
+        assert!(report_path("/tmp/{hostname}.json", "home", "backup").is_err());
+    }
+
+    #[test]
+    fn test_report() {
+        let mut fixture = TestFixture::new();
+        let content_root = fixture.content_root().to_path_buf();
+        let report_file = content_root.join("reports/{operation}.json");
+        fixture.config_mut().report_file = Some(report_file.display().to_string());
+        fixture.profile_mut().include.patterns = vec![".".to_string()];
+        fixture.profile_mut().hooks.pre_backup = vec!["true".to_string()];
+        fixture.profile_mut().hooks.post_backup = vec!["exit 3".to_string()];
+
+        let (restic, runner) = fixture.mock_restic();
+        runner.respond(MockResponse::success("[]"));
+        runner.respond(MockResponse::success(
+            r#"{"message_type":"summary","files_new":2,"files_changed":0,"files_unmodified":0,"dirs_new":1,"dirs_changed":0,"dirs_unmodified":0,"data_blobs":2,"tree_blobs":1,"data_added":1024,"total_files_processed":2,"total_bytes_processed":2048,"total_duration":1.5,"snapshot_id":"0123abcd"}"#,
+        ));
+        restic.backup().unwrap();
+
+        let path = content_root.join("reports/backup.json");
+        let report: toml::Value = json::parse(&fs::read_to_string(path).unwrap()).unwrap();
+        assert_eq!(report["profile"].as_str(), Some("test"));
+        assert_eq!(report["success"].as_bool(), Some(true));
+        assert_eq!(report["exit_code"].as_integer(), Some(0));
+        assert_eq!(report["summary"]["snapshot_id"].as_str(), Some("0123abcd"));
+        // The post-backup hook ran after the backup finished, and was added to the report afterwards
+        let hooks = report["hooks"].as_array().unwrap();
+        assert_eq!(hooks.len(), 2);
+        assert_eq!(hooks[0]["stage"].as_str(), Some("pre_backup"));
+        assert_eq!(hooks[0]["success"].as_bool(), Some(true));
+        assert_eq!(hooks[1]["stage"].as_str(), Some("post_backup"));
+        assert_eq!(hooks[1]["success"].as_bool(), Some(false));
+    }
+
+    #[test]
+    fn test_remove_reports() {
+        let mut fixture = TestFixture::new();
+        let content_root = fixture.content_root().to_path_buf();
+        fixture.config_mut().report_file = Some(
+            content_root
+                .join("{profile}-{operation}.json")
+                .display()
+                .to_string(),
+        );
+        fs::write(content_root.join("test-backup.json"), "{}").unwrap();
+        fs::write(content_root.join("test-check.json"), "{}").unwrap();
+
+        let logger = Logger::root(Discard, o!());
+        remove_reports(fixture.config_mut(), &logger, "test", &["backup", "prune"]);
+        assert!(!content_root.join("test-backup.json").exists());
+        assert!(content_root.join("test-check.json").exists());
+
+        // Profiles given as a one-profile group are resolved first, so the report removed is the profile's own
+        fixture
+            .config_mut()
+            .groups
+            .insert("nightly".to_string(), vec!["test".to_string()]);
+        let config = &*fixture.config_mut();
+        let profile = config.resolve_profile_name("@nightly").unwrap();
+        remove_reports(config, &logger, &profile, &["check"]);
+        assert!(!content_root.join("test-check.json").exists());
+    }
+}
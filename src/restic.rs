@@ -1,110 +1,822 @@
 //! Shared functions for interacting with Restic (mostly generating command lines)
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
 use std::collections::HashMap;
 use std::ffi::OsString;
 use std::fs;
-use std::process::{Command, Stdio};
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::Duration;
 
 use anyhow::{bail, Context, Result};
-use slog::{debug, o, Logger};
-use toml;
+use chrono::{Local, NaiveDateTime};
+use lazy_static::lazy_static;
+use regex::Regex;
+use slog::{crit, debug, error, info, o, trace, warn, Level, Logger};
 
-use crate::config::{Configuration, Profile};
+use crate::aws;
+use crate::config::{Configuration, EnvironmentFileFormat, PasswordSettings, Profile};
+use crate::dotenv;
+use crate::encrypted;
+use crate::error::RusticError;
+use crate::events::{self, Event, Sink};
+use crate::hooks;
+use crate::install;
+use crate::keyring;
+use crate::lock;
+use crate::password_managers::{self, PasswordManager};
+use crate::platform;
+use crate::prompt;
+use crate::redact;
+use crate::runner::{DryRunRunner, ResticRunner, SystemRunner};
+use crate::version::ResticVersion;
+
+/// Delay before the first retry if the profile doesn't set `retry_delay`
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// First Restic release with the `--compression` flag
+const COMPRESSION_VERSION: ResticVersion = ResticVersion::new(0, 14, 0);
+
+/// First Restic release with the `--pack-size` flag
+const PACK_SIZE_VERSION: ResticVersion = ResticVersion::new(0, 15, 0);
+
+/// First Restic release that reads rest-server credentials from `RESTIC_REST_USERNAME` and `RESTIC_REST_PASSWORD`
+const REST_CREDENTIALS_VERSION: ResticVersion = ResticVersion::new(0, 17, 0);
+
+/// Pattern for Restic error messages meaning that a repository's backend couldn't be reached, like network errors and
+/// server errors from HTTP backends. Status codes only count in an HTTP context, so that a number in a path doesn't.
+const BACKEND_UNREACHABLE: &str = r"connection (?:reset|refused|timed out)|i/o timeout|TLS handshake timeout|temporary failure in name resolution|no such host|network is unreachable|no route to host|(?:status|response|code)\W+50[0234]\b|internal server error|bad gateway|service unavailable|gateway timeout";
+
+lazy_static! {
+    /// Matches Restic error messages for failures that are likely to go away on their own, like network errors, temporary
+    /// backend outages, and repository locks held by other processes
+    static ref TRANSIENT_ERROR: Regex = Regex::new(&format!(
+        r"(?i){}|unexpected EOF|broken pipe|too many requests|repository is already locked|unable to create lock",
+        BACKEND_UNREACHABLE
+    ))
+    .unwrap();
+
+    /// Matches Restic error messages meaning that it couldn't reach a repository's backend at all, as opposed to reaching
+    /// it and finding no repository there
+    static ref UNREACHABLE_ERROR: Regex = Regex::new(&format!(r"(?i){}", BACKEND_UNREACHABLE)).unwrap();
+}
 
 /// Wrapper around the Restic CLI
 pub struct Restic<'a> {
     logger: Logger,
     config: &'a Configuration,
+    profile_name: String,
     profile: &'a Profile,
+    verbosity: u8,
+    command_prefix: Vec<OsString>,
     shared_args: Vec<OsString>,
     shared_env: HashMap<OsString, OsString>,
+    shell_env: HashMap<OsString, OsString>,
+    lock_wait: Option<Duration>,
+    sinks: Vec<Box<dyn Sink>>,
+    runner: Box<dyn ResticRunner>,
+    dry_run: bool,
+    /// Which of the profile's repositories commands run against: 0 for `repository`, or one more than the index into
+    /// `failover_repositories`
+    repository_index: Cell<usize>,
 }
 
 impl<'a> Restic<'a> {
     /// Creates a new Restic wrapper for the specified profile. This performs some precomputation and validation of Restic
     /// flags, and will return an error if that validation fails (for example, if the profile does not exist or does not set
     /// a repository password).
+    ///
+    /// The `verbosity` is the number of times Rustic's `-v` flag was passed, and controls how much output Restic prints.
     pub fn for_profile(
         config: &'a Configuration,
         logger: &Logger,
         profile_name: String,
+        verbosity: u8,
     ) -> Result<Restic<'a>> {
+        let profile_name = config.resolve_profile_name(&profile_name)?;
         let profile = match config.profiles.get(&profile_name) {
             Some(profile) => profile,
-            None => bail!("Profile `{}` does not exist", profile_name),
+            None => bail!(RusticError::ProfileNotFound(profile_name)),
         };
-        let logger = logger.new(o!("profile" => profile_name));
+        let logger = logger.new(o!("profile" => profile_name.clone()));
 
         let mut shared_args = Vec::new();
         let mut shared_env = HashMap::new();
+        let mut shell_env = HashMap::new();
         add_password(profile, &mut shared_args, &mut shared_env)?;
         add_credentials(profile, &mut shared_env)?;
-        shared_args.push("--repo".into());
-        shared_args.push(profile.repository.to_string().into());
-
+        add_rclone_options(profile, &mut shared_args, &mut shared_env)?;
+        let mut version_requirements = Vec::new();
+        add_rest_options(
+            profile,
+            &mut shared_args,
+            &mut shared_env,
+            &mut shell_env,
+            &mut version_requirements,
+        )?;
         if let Some(ref cache_directory) = config.cache_directory {
-            shared_env.insert("RESTIC_CACHE_DIR".into(), cache_directory.into());
+            add_global_option(
+                &mut shared_args,
+                &mut shell_env,
+                "--cache-dir",
+                "RESTIC_CACHE_DIR",
+                cache_directory,
+            );
+        }
+        if let Some(min_version) = profile.min_restic_version {
+            version_requirements.push((min_version, format!("Profile `{}`", profile_name)));
+        }
+        if let Some(compression) = profile.compression {
+            add_global_option(
+                &mut shared_args,
+                &mut shell_env,
+                "--compression",
+                "RESTIC_COMPRESSION",
+                compression.as_str(),
+            );
+            version_requirements.push((COMPRESSION_VERSION, "`compression`".to_string()));
+        }
+        let (limit_upload, limit_download) =
+            bandwidth_limits(&logger, profile, Local::now().naive_local());
+        if let Some(limit) = limit_upload {
+            shared_args.push("--limit-upload".into());
+            shared_args.push(limit.to_string().into());
+        }
+        if let Some(limit) = limit_download {
+            shared_args.push("--limit-download".into());
+            shared_args.push(limit.to_string().into());
+        }
+        if let Some(pack_size) = profile.pack_size {
+            add_global_option(
+                &mut shared_args,
+                &mut shell_env,
+                "--pack-size",
+                "RESTIC_PACK_SIZE",
+                pack_size.to_string(),
+            );
+            version_requirements.push((PACK_SIZE_VERSION, "`pack_size`".to_string()));
         }
 
-        Ok(Restic {
+        let mut command_prefix = Vec::new();
+        if profile.prevent_sleep {
+            match platform::inhibit_sleep_prefix(&format!(
+                "Running Restic for profile {}",
+                profile_name
+            )) {
+                Some(prefix) => command_prefix.extend(prefix),
+                None => warn!(
+                    logger,
+                    "`prevent_sleep` is set, but preventing sleep is not supported on this system"
+                ),
+            }
+        }
+        if let Some(niceness) = profile.niceness {
+            match platform::nice_prefix(niceness) {
+                Some(prefix) => command_prefix.extend(prefix),
+                None => warn!(
+                    logger,
+                    "`niceness` is set, but changing process priority is not supported on this system"
+                ),
+            }
+        }
+        if let Some(io_class) = profile.io_class {
+            match platform::ionice_prefix(io_class) {
+                Some(prefix) => command_prefix.extend(prefix),
+                None => warn!(
+                    logger,
+                    "`io_class` is set, but changing I/O priority is not supported on this system"
+                ),
+            }
+        }
+        command_prefix.push(install::resolve_restic_command(
+            profile
+                .restic_command
+                .as_ref()
+                .unwrap_or(&config.restic_command),
+        )?);
+
+        let restic = Restic {
             config,
+            profile_name,
             profile,
+            verbosity,
             logger,
+            command_prefix,
             shared_args,
             shared_env,
-        })
+            shell_env,
+            lock_wait: None,
+            sinks: events::default_sinks(),
+            runner: Box::new(SystemRunner),
+            dry_run: false,
+            repository_index: Cell::new(0),
+        };
+
+        restic.require_versions(&version_requirements)?;
+
+        Ok(restic)
     }
 
     /// Starts building a Restic command line. The returned command has all shared
     /// environment variables and flags set (such as the repository and credentials), but
-    /// no operation-specific flags.
+    /// no operation-specific flags. If needed, Restic is wrapped in another command (for
+    /// example, to keep the system awake or lower its priority).
+    ///
+    /// The verbosity flag always comes last, right before where the operation goes, so that the operation can be found again
+    /// to look up its timeout.
     pub fn new_command(&self) -> Command {
-        let mut cmd = Command::new(&self.config.restic_command);
-        cmd.current_dir(&self.profile.base_directory)
+        let mut cmd = self.bare_command();
+        cmd.arg("--repo")
+            .arg(self.repository())
+            .args(&self.shared_args)
+            .arg(self.verbosity_flag())
+            .envs(&self.shared_env);
+        cmd
+    }
+
+    /// Starts building a Restic command line for arbitrary arguments from the user. Like [`new_command`](Restic::new_command),
+    /// it has the repository and credentials set, but it only has a verbosity flag if Rustic's `-v` flag was passed, so that
+    /// Restic's own defaults apply otherwise.
+    pub fn new_passthrough_command(&self) -> Command {
+        let mut cmd = self.bare_command();
+        cmd.arg("--repo")
+            .arg(self.repository())
             .args(&self.shared_args)
             .envs(&self.shared_env);
+        if self.verbosity > 0 {
+            cmd.arg(self.verbosity_flag());
+        }
+        cmd
+    }
+
+    /// Starts building a Restic command line without any repository flags or credentials. Most operations should use
+    /// [`new_command`](Restic::new_command) instead.
+    pub fn bare_command(&self) -> Command {
+        let mut cmd = Command::new(&self.command_prefix[0]);
+        cmd.args(&self.command_prefix[1..])
+            .current_dir(&self.profile.base_directory);
         cmd
     }
 
+    /// The `restic` binary this profile uses
+    pub fn restic_command(&self) -> &str {
+        self.profile
+            .restic_command
+            .as_deref()
+            .unwrap_or(&self.config.restic_command)
+    }
+
     /// Overall Rustic configuration this is derived from
     pub fn config(&self) -> &Configuration {
         self.config
     }
 
+    /// Name of the profile defining this Restic repository
+    pub fn profile_name(&self) -> &str {
+        &self.profile_name
+    }
+
     /// Returns the profile defining this Restic repository
     pub fn profile(&self) -> &Profile {
         self.profile
     }
 
+    /// Repository that commands run against. This is the profile's `repository`, unless
+    /// [`fail_over`](Restic::fail_over) switched to one of its `failover_repositories`.
+    pub fn repository(&self) -> &str {
+        match self.repository_index.get() {
+            0 => &self.profile.repository,
+            index => &self.profile.failover_repositories[index - 1],
+        }
+    }
+
+    /// Switches to the profile's next failover repository, returning it, or `None` if there are none left
+    pub fn fail_over(&self) -> Option<&str> {
+        let next = self.repository_index.get() + 1;
+        if next > self.profile.failover_repositories.len() {
+            return None;
+        }
+        self.repository_index.set(next);
+        Some(self.repository())
+    }
+
     /// Environment variables set when running commands under this profile.
     pub fn env(&self) -> &HashMap<OsString, OsString> {
         &self.shared_env
     }
 
+    /// Environment variables equivalent to the global flags that Rustic passes to Restic, like `RESTIC_CACHE_DIR` for
+    /// `--cache-dir`. These are for shells from `rustic shell`, where Restic is run without those flags.
+    pub fn shell_env(&self) -> &HashMap<OsString, OsString> {
+        &self.shell_env
+    }
+
+    /// Restic flag for the requested verbosity level. Commands built by hand should put it right before the operation, like
+    /// [`new_command`](Restic::new_command) does.
+    pub fn verbosity_flag(&self) -> &'static str {
+        // Quiet mode also turns off progress reports
+        if self.verbosity == 0 && self.wants_progress() {
+            "--verbose=0"
+        } else {
+            verbosity_flag(self.verbosity)
+        }
+    }
+
+    /// Whether any sink wants progress reports from Restic
+    pub fn wants_progress(&self) -> bool {
+        self.sinks.iter().any(|sink| sink.wants_progress())
+    }
+
+    /// How long [`lock_repository`](Restic::lock_repository) waits for another Rustic process to release the repository
+    pub fn lock_wait(&self) -> Option<Duration> {
+        self.lock_wait
+    }
+
+    /// Sets how long to wait for other Rustic processes using the repository to finish. By default, operations fail
+    /// immediately if another process holds the repository lock.
+    pub fn set_lock_wait(&mut self, wait: Option<Duration>) {
+        self.lock_wait = wait;
+    }
+
+    /// Adds a sink that receives events from operations on this profile, in addition to the default ones
+    pub fn add_sink(&mut self, sink: Box<dyn Sink>) {
+        self.sinks.push(sink);
+    }
+
+    /// Replaces how Restic commands are run, for example with a [`MockRunner`](crate::runner::MockRunner) in tests
+    pub fn set_runner(&mut self, runner: Box<dyn ResticRunner>) {
+        self.runner = runner;
+    }
+
+    /// Turns dry-run mode on or off. In dry-run mode, Restic commands are printed instead of run, and so are hooks and other
+    /// commands that operations would run. No events are emitted, so nothing is recorded in the history or reported.
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+        self.runner = if dry_run {
+            Box::new(DryRunRunner)
+        } else {
+            Box::new(SystemRunner)
+        };
+    }
+
+    /// Whether this is a dry run, where commands are only printed
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Runs a Restic command to completion with its stdio left as configured, for commands that interact with the user
+    /// directly or must not be retried. Returns Restic's exit status.
+    pub fn status(&self, cmd: &mut Command) -> Result<ExitStatus> {
+        self.runner.status(cmd)
+    }
+
+    /// Sends an event to every sink, unless this is a dry run
+    pub fn emit(&self, event: &Event) {
+        if self.dry_run {
+            return;
+        }
+        for sink in self.sinks.iter() {
+            sink.handle(self, event);
+        }
+    }
+
     /// Returns a logger scoped to this Restic repository
     pub fn logger(&self) -> &Logger {
         &self.logger
     }
 
+    /// Runs a Restic command to completion. Restic's stderr is logged at the level set by `restic_output.stderr`, and also
+    /// captured so that errors can be inspected. If `on_stdout` is given, Restic's stdout is captured and passed to it
+    /// line-by-line. Otherwise, it's logged at the level set by `restic_output.stdout`.
+    ///
+    /// If the command fails because the repository is locked by a Restic process that no longer exists and `auto_unlock`
+    /// is set, this removes the stale lock and tries again. If it fails because of a transient error and the profile sets
+    /// `retries`, it's retried with exponential backoff. Note that `on_stdout` sees the output of every attempt.
+    ///
+    /// If the profile sets a timeout for the operation and Restic runs past it, Restic is stopped and an error is returned.
+    pub fn execute(
+        &self,
+        cmd: &mut Command,
+        mut on_stdout: Option<&mut dyn FnMut(&str)>,
+    ) -> Result<Execution> {
+        self.with_retries(|| {
+            let stdout = match on_stdout.as_deref_mut() {
+                Some(on_stdout) => Stdout::Lines(on_stdout),
+                None => Stdout::Log,
+            };
+            self.execute_once(cmd, stdout, None, None)
+        })
+    }
+
+    /// Runs a Restic command to completion like [`execute`](Restic::execute), with the output of `stdin` piped into Restic.
+    /// The command is started again for each attempt. If it fails, that's an error even if Restic succeeded, since Restic
+    /// can't tell a complete stream from one that was cut short.
+    pub fn execute_with_stdin(
+        &self,
+        cmd: &mut Command,
+        stdin: &StdinCommand,
+        on_stdout: &mut dyn FnMut(&str),
+    ) -> Result<Execution> {
+        self.with_retries(|| {
+            self.execute_once(cmd, Stdout::Lines(&mut *on_stdout), Some(stdin), None)
+        })
+    }
+
+    /// Runs a Restic command to completion like [`execute`](Restic::execute), or like
+    /// [`execute_with_stdin`](Restic::execute_with_stdin) if `stdin` is given, passing each line of Restic's stderr to
+    /// `on_stderr` as well. Lines that `on_stderr` doesn't handle are logged as usual. This is for commands like
+    /// `restic backup --json`, which report errors as JSON on stderr.
+    pub fn execute_with_stderr(
+        &self,
+        cmd: &mut Command,
+        stdin: Option<&StdinCommand>,
+        on_stdout: &mut dyn FnMut(&str),
+        on_stderr: StderrHandler,
+    ) -> Result<Execution> {
+        self.with_retries(|| {
+            self.execute_once(cmd, Stdout::Lines(&mut *on_stdout), stdin, Some(on_stderr))
+        })
+    }
+
+    /// Runs a Restic command to completion like [`execute`](Restic::execute), but leaves its stdout as configured on `cmd`
+    /// (by default, inherited from Rustic). This is for commands whose output is the result, like `restic dump`. The
+    /// command isn't retried, since output from a failed attempt can't be taken back once it's been passed on.
+    pub fn execute_passthrough(&self, cmd: &mut Command) -> Result<Execution> {
+        self.execute_once(cmd, Stdout::Keep, None, None)
+    }
+
+    /// Runs a Restic command to completion like [`execute`](Restic::execute), writing its stdout to the file at `path`. The
+    /// file is truncated before each attempt, so a retried command doesn't leave output from a failed attempt behind.
+    pub fn execute_to_file(&self, cmd: &mut Command, path: &Path) -> Result<Execution> {
+        self.with_retries(|| {
+            let file = fs::OpenOptions::new()
+                .write(true)
+                .truncate(true)
+                .open(path)
+                .with_context(|| format!("Could not open {}", path.display()))?;
+            cmd.stdout(Stdio::from(file));
+            self.execute_once(cmd, Stdout::Keep, None, None)
+        })
+    }
+
+    /// Runs a Restic command to completion like [`execute`](Restic::execute), but captures its stdout and returns it. Returns
+    /// an error if Restic fails. If the command is retried, only the output of the last attempt is returned.
+    pub fn execute_output(&self, cmd: &mut Command) -> Result<String> {
+        let mut stdout = String::new();
+        let execution = self.with_retries(|| {
+            stdout.clear();
+            self.execute_once(
+                cmd,
+                Stdout::Lines(&mut |line: &str| {
+                    stdout.push_str(line);
+                    stdout.push('\n');
+                }),
+                None,
+                None,
+            )
+        })?;
+        if !execution.status.success() {
+            let operation = self.operation(cmd).unwrap_or("command");
+            bail!(self.failed(operation, execution.status));
+        }
+        // Commands whose output Rustic reads can't go any further without running them
+        if self.dry_run && stdout.is_empty() {
+            let operation = self.operation(cmd).unwrap_or("command");
+            bail!(
+                "Stopping, since Rustic needs the output of `restic {}` and this is a dry run",
+                operation
+            );
+        }
+        Ok(stdout)
+    }
+
+    fn execute_once(
+        &self,
+        cmd: &mut Command,
+        mut stdout: Stdout,
+        stdin: Option<&StdinCommand>,
+        on_stderr: Option<StderrHandler>,
+    ) -> Result<Execution> {
+        let timeout = self.timeout(cmd);
+        let execution = self.execute_piped(cmd, stdout.reborrow(), on_stderr, timeout, stdin)?;
+        if execution.status.success() || !self.profile.auto_unlock {
+            return Ok(execution);
+        }
+
+        match lock::StaleLock::detect(&execution.stderr) {
+            Some(lock) => {
+                warn!(self.logger, "Repository is locked by a process that no longer exists, removing stale lock";
+                    "pid" => lock.pid, "host" => &lock.host);
+                self.unlock(false)
+                    .context("Could not remove stale repository lock")?;
+                self.execute_piped(cmd, stdout, on_stderr, timeout, stdin)
+            }
+            None => Ok(execution),
+        }
+    }
+
+    /// Runs a Restic command once like [`execute_command`](Restic::execute_command), first starting `stdin` (if given) with
+    /// its output piped into Restic
+    fn execute_piped(
+        &self,
+        cmd: &mut Command,
+        stdout: Stdout,
+        on_stderr: Option<StderrHandler>,
+        timeout: Option<Duration>,
+        stdin: Option<&StdinCommand>,
+    ) -> Result<Execution> {
+        let stdin = match stdin {
+            Some(stdin) => stdin,
+            None => return self.execute_command(cmd, stdout, on_stderr, timeout),
+        };
+        if self.dry_run {
+            println!(
+                "Would pipe the output of: {}",
+                redact::command_line(stdin.command())
+            );
+            return self.execute_command(cmd, stdout, on_stderr, timeout);
+        }
+
+        debug!(self.logger, "Starting {}", stdin.description);
+        let mut producer = stdin
+            .spawn()
+            .with_context(|| format!("Could not run {}", stdin.description))?;
+        cmd.stdin(producer.stdout.take().unwrap());
+        let execution = self.execute_command(cmd, stdout, on_stderr, timeout);
+        // Close our copy of the pipe, so that the command gets an error instead of blocking forever if Restic exited without
+        // reading all of its output
+        cmd.stdin(Stdio::null());
+        let status = producer
+            .wait()
+            .with_context(|| format!("Could not wait for {}", stdin.description))?;
+
+        // If Restic failed, the command probably did too because Restic stopped reading, so Restic's error is the one to report
+        let execution = execution?;
+        if !status.success() && execution.status.success() {
+            bail!("{} failed with {}", stdin.description, status);
+        }
+        Ok(execution)
+    }
+
+    /// Runs a Restic command once, logging its output as configured
+    fn execute_command(
+        &self,
+        cmd: &mut Command,
+        stdout: Stdout,
+        on_stderr: Option<StderrHandler>,
+        timeout: Option<Duration>,
+    ) -> Result<Execution> {
+        let levels = self.config.restic_output;
+        let stdout_logger = self.logger.new(o!("stream" => "stdout"));
+        let mut log_stdout = |line: &str| log_line(&stdout_logger, levels.stdout.level(), line);
+        let on_stdout: Option<&mut dyn FnMut(&str)> = match stdout {
+            Stdout::Keep => None,
+            Stdout::Lines(on_stdout) => Some(on_stdout),
+            Stdout::Log => Some(&mut log_stdout),
+        };
+        let stderr_logger = self.logger.new(o!("stream" => "stderr"));
+        self.runner.run(
+            cmd,
+            on_stdout,
+            Box::new(move |line| {
+                if !on_stderr.is_some_and(|on_stderr| on_stderr(&stderr_logger, line)) {
+                    log_line(&stderr_logger, levels.stderr.level(), line)
+                }
+            }),
+            timeout,
+        )
+    }
+
+    /// Timeout for a Restic command, based on which Restic subcommand it runs
+    fn timeout(&self, cmd: &Command) -> Option<Duration> {
+        self.profile.timeouts.for_operation(self.operation(cmd)?)
+    }
+
+    /// Restic subcommand that `cmd` runs, which is the argument right after the verbosity flag
+    fn operation<'c>(&self, cmd: &'c Command) -> Option<&'c str> {
+        let verbosity_flag = self.verbosity_flag();
+        let mut args = cmd.get_args().skip(self.command_prefix.len() - 1);
+        args.position(|arg| arg == verbosity_flag)?;
+        args.next()?.to_str()
+    }
+
+    /// Refuses to run a destructive operation, like `forget` or `prune`, with an append-only profile's credentials,
+    /// pointing at its maintenance profile instead
+    pub fn require_full_access(&self, operation: &str) -> Result<()> {
+        if !self.profile.append_only {
+            return Ok(());
+        }
+        match self.profile.maintenance_profile {
+            Some(ref maintenance) => bail!(
+                "Profile `{}` is append-only, so Rustic won't run `restic {}` with it. Use its maintenance profile instead, as in `rustic {} {}`.",
+                self.profile_name,
+                operation,
+                operation,
+                maintenance
+            ),
+            None => bail!(
+                "Profile `{}` is append-only, so Rustic won't run `restic {}` with it. Set up a profile with full access to the repository, and point to it with `maintenance_profile`.",
+                self.profile_name,
+                operation
+            ),
+        }
+    }
+
+    /// Error for a Restic operation that exited with `status`. Restic exits with code 10 if the repository doesn't exist and
+    /// 11 if it couldn't lock the repository.
+    pub fn failed(&self, operation: &str, status: ExitStatus) -> RusticError {
+        let repository = self.repository().to_string();
+        match status.code() {
+            Some(10) => RusticError::RepositoryNotFound { repository },
+            Some(11) => RusticError::RepositoryLocked { repository },
+            _ => RusticError::restic_failed(operation, status),
+        }
+    }
+
+    /// Calls `attempt` until it succeeds, fails with an error that isn't transient, or the profile's retries run out
+    fn with_retries<F: FnMut() -> Result<Execution>>(&self, mut attempt: F) -> Result<Execution> {
+        let base_delay = self.profile.retry_delay.unwrap_or(DEFAULT_RETRY_DELAY);
+        let mut retry = 0;
+        loop {
+            let execution = attempt()?;
+            if execution.status.success()
+                || retry >= self.profile.retries
+                || !is_transient_failure(&execution)
+            {
+                return Ok(execution);
+            }
+
+            retry += 1;
+            let delay = backoff_delay(base_delay, retry);
+            warn!(self.logger, "Restic failed with a transient error, retrying in {:?}", delay;
+                "status" => %execution.status, "retry" => retry, "retries" => self.profile.retries);
+            thread::sleep(delay);
+        }
+    }
+
     /// Checks if the repository already exists. This uses the method suggested [in the Restic docs](https://restic.readthedocs.io/en/latest/075_scripting.html),
     /// running `restic snapshots`.
     pub fn repository_exists(&self) -> Result<bool> {
+        Ok(self.repository_state()? == RepositoryState::Exists)
+    }
+
+    /// Checks whether the repository exists, doesn't exist yet, or can't be reached at all, by running `restic snapshots`
+    pub fn repository_state(&self) -> Result<RepositoryState> {
         let mut cmd = self.new_command();
         cmd.arg("snapshots")
             .arg("--compact")
             .arg("--last")
-            .stdin(Stdio::null())
-            .stdout(Stdio::null())
-            .stderr(Stdio::null());
-        let result = cmd
-            .status()
-            .with_context(|| format!("Could not run {:?}", cmd))?;
-        if result.success() {
-            debug!(&self.logger, "`restic snapshots` succeeded, repository exists"; "command" => ?cmd);
-            Ok(true)
+            .stdin(Stdio::null());
+        let execution = self
+            .runner
+            .run(&mut cmd, Some(&mut |_| {}), Box::new(|_| {}), None)?;
+        if execution.status.success() {
+            debug!(&self.logger, "`restic snapshots` succeeded, repository exists"; "command" => %redact::command_line(&cmd));
+            Ok(RepositoryState::Exists)
+        } else if let Some(reason) = UNREACHABLE_ERROR.find(&execution.stderr) {
+            debug!(&self.logger, "`restic snapshots` failed, repository is unreachable"; "command" => %redact::command_line(&cmd), "reason" => reason.as_str());
+            Ok(RepositoryState::Unreachable(reason.as_str().to_string()))
         } else {
-            debug!(&self.logger, "`restic snapshots` failed, assuming repository does not exist"; "command" => ?cmd);
-            Ok(false)
+            debug!(&self.logger, "`restic snapshots` failed, assuming repository does not exist"; "command" => %redact::command_line(&cmd));
+            Ok(RepositoryState::Missing)
+        }
+    }
+}
+
+/// Whether Restic could open a repository
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RepositoryState {
+    /// The repository exists
+    Exists,
+
+    /// Restic could reach where the repository should be, but there's no repository there
+    Missing,
+
+    /// Restic couldn't reach the repository's backend, for the given reason
+    Unreachable(String),
+}
+
+/// A command whose output is piped into Restic, such as a database dump
+pub struct StdinCommand {
+    command: Command,
+    description: String,
+}
+
+impl StdinCommand {
+    /// Pipes the output of `command` into Restic. `description` refers to the command in logs and errors, and shouldn't
+    /// include secrets like passwords passed through the environment.
+    pub fn new(command: Command, description: String) -> StdinCommand {
+        StdinCommand {
+            command,
+            description,
+        }
+    }
+
+    /// Pipes the output of a shell command, run in `working_directory`, into Restic
+    pub fn shell(command: &str, working_directory: &Path) -> StdinCommand {
+        let mut cmd = hooks::shell_command(command);
+        cmd.current_dir(working_directory);
+        StdinCommand::new(cmd, format!("`{}`", command))
+    }
+
+    /// The command that's run
+    pub fn command(&self) -> &Command {
+        &self.command
+    }
+
+    /// Starts a fresh copy of the command with its stdout piped, since a `Command` can't be reused once its output is
+    /// connected to Restic
+    fn spawn(&self) -> io::Result<Child> {
+        let mut cmd = Command::new(self.command.get_program());
+        cmd.args(self.command.get_args());
+        for (key, value) in self.command.get_envs() {
+            match value {
+                Some(value) => cmd.env(key, value),
+                None => cmd.env_remove(key),
+            };
         }
+        if let Some(dir) = self.command.get_current_dir() {
+            cmd.current_dir(dir);
+        }
+        cmd.stdin(Stdio::null()).stdout(Stdio::piped()).spawn()
+    }
+}
+
+/// Handles a line of Restic's stderr for [`execute_with_stderr`](Restic::execute_with_stderr), returning whether it did.
+/// This is called on another thread, with the logger for Restic's stderr.
+pub type StderrHandler = fn(&Logger, &str) -> bool;
+
+/// What to do with Restic's stdout
+enum Stdout<'f> {
+    /// Log each line
+    Log,
+    /// Leave it as configured on the command
+    Keep,
+    /// Pass each line to a callback
+    Lines(&'f mut (dyn FnMut(&str) + 'f)),
+}
+
+impl Stdout<'_> {
+    fn reborrow(&mut self) -> Stdout<'_> {
+        match self {
+            Stdout::Log => Stdout::Log,
+            Stdout::Keep => Stdout::Keep,
+            Stdout::Lines(on_stdout) => Stdout::Lines(&mut **on_stdout),
+        }
+    }
+}
+
+/// Logs a line of Restic output at `level`
+fn log_line(logger: &Logger, level: Level, line: &str) {
+    match level {
+        Level::Critical => crit!(logger, "{}", line),
+        Level::Error => error!(logger, "{}", line),
+        Level::Warning => warn!(logger, "{}", line),
+        Level::Info => info!(logger, "{}", line),
+        Level::Debug => debug!(logger, "{}", line),
+        Level::Trace => trace!(logger, "{}", line),
+    }
+}
+
+/// Outcome of running a Restic command
+#[derive(Debug)]
+pub struct Execution {
+    /// Restic's exit status
+    pub status: ExitStatus,
+
+    /// Everything Restic wrote to stderr
+    pub stderr: String,
+}
+
+/// Whether a failed Restic command might succeed if it's run again
+fn is_transient_failure(execution: &Execution) -> bool {
+    match execution.status.code() {
+        // Restic 0.17 and later exit with 11 if they couldn't lock the repository
+        Some(11) => true,
+        // A backup that couldn't read some files still created a snapshot, so there's nothing to retry
+        Some(3) => false,
+        _ => TRANSIENT_ERROR.is_match(&execution.stderr),
+    }
+}
+
+/// How long to wait before the `retry`th retry: the base delay, doubled for each earlier retry, plus up to 50% jitter so
+/// that several profiles failing at once don't all retry at the same moment
+fn backoff_delay(base: Duration, retry: u32) -> Duration {
+    let delay = base * 2u32.saturating_pow(retry.saturating_sub(1).min(16));
+    let jitter = RandomState::new().build_hasher().finish() % 1000;
+    delay + delay * jitter as u32 / 2000
+}
+
+/// Converts Rustic's verbosity level into the matching Restic flag. By default, Rustic only logs warnings and errors, so Restic
+/// is told to be quiet as well.
+fn verbosity_flag(verbosity: u8) -> &'static str {
+    match verbosity {
+        0 => "--quiet",
+        1 => "--verbose",
+        _ => "--verbose=2",
     }
 }
 
@@ -114,35 +826,132 @@ fn add_password(
     args: &mut Vec<OsString>,
     env: &mut HashMap<OsString, OsString>,
 ) -> Result<()> {
-    if let Some(password) = &profile.password {
-        if profile.password_file.is_some() {
-            bail!("Cannot set both `password` and `password_file`");
-        }
+    add_password_source(profile.password_settings(), false, args, env)
+}
 
-        if profile.password_command.is_some() {
-            bail!("Cannot set both `password` and `password_command`");
-        }
+/// Names of the password sources that are set
+pub fn password_sources(settings: PasswordSettings) -> Vec<&'static str> {
+    let sources = [
+        ("password", settings.password.is_some()),
+        ("password_file", settings.password_file.is_some()),
+        ("password_command", settings.password_command.is_some()),
+        ("password_keyring", settings.password_keyring.is_some()),
+        ("password_op_item", settings.password_op_item.is_some()),
+        (
+            "password_bitwarden_item",
+            settings.password_bitwarden_item.is_some(),
+        ),
+        ("password_prompt", settings.password_prompt),
+    ];
+    sources
+        .iter()
+        .filter(|(_, set)| *set)
+        .map(|(name, _)| *name)
+        .collect()
+}
 
-        env.insert("RESTIC_PASSWORD".into(), password.into());
-    } else if let Some(password_file) = &profile.password_file {
-        if profile.password_command.is_some() {
-            bail!("Cannot set both `password_file` and `password_command`");
-        }
+/// Checks that at most one password source is set
+pub fn check_password_source(settings: PasswordSettings) -> Result<()> {
+    if let [first, second, ..] = password_sources(settings)[..] {
+        bail!("Cannot set both `{}` and `{}`", first, second);
+    }
+    Ok(())
+}
+
+/// Adds a repository password from one of the supported sources to a command template. If `source_repository` is true, the
+/// password is for the repository that commands like `restic copy` read from, rather than the main repository.
+/// Passwords in 1Password or Bitwarden are read here, which may prompt to unlock the vault, and Rustic prompts for the
+/// password itself if no source is set.
+pub fn add_password_source(
+    settings: PasswordSettings,
+    source_repository: bool,
+    args: &mut Vec<OsString>,
+    env: &mut HashMap<OsString, OsString>,
+) -> Result<()> {
+    check_password_source(settings)?;
+    let (flag_prefix, env_var) = if source_repository {
+        ("--from-", "RESTIC_FROM_PASSWORD")
+    } else {
+        ("--", "RESTIC_PASSWORD")
+    };
 
-        args.push("--password-file".into());
+    if let Some(password) = settings.password {
+        env.insert(env_var.into(), password.into());
+    } else if let Some(password_file) = settings.password_file {
+        args.push(format!("{}password-file", flag_prefix).into());
         args.push(password_file.into());
-    } else if let Some(password_command) = &profile.password_command {
-        args.push("--password-command".into());
+    } else if let Some(password_command) = settings.password_command {
+        args.push(format!("{}password-command", flag_prefix).into());
         args.push(password_command.into());
+    } else if let Some(entry) = settings.password_keyring {
+        args.push(format!("{}password-command", flag_prefix).into());
+        args.push(keyring::lookup_command(entry)?.into());
+    } else if let Some(item) = settings.password_op_item {
+        let password = password_managers::read_password(PasswordManager::OnePassword, item)?;
+        env.insert(env_var.into(), password.into());
+    } else if let Some(item) = settings.password_bitwarden_item {
+        let password = password_managers::read_password(PasswordManager::Bitwarden, item)?;
+        env.insert(env_var.into(), password.into());
+    } else if settings.password_prompt || prompt::can_prompt() {
+        let password =
+            prompt::repository_password(settings.repository, settings.remember_prompted_password)?;
+        env.insert(env_var.into(), password.into());
     } else {
-        bail!("Must set one of `password`, `password_file`, or `password_command`");
+        bail!("Must set one of `password`, `password_file`, `password_command`, `password_keyring`, `password_op_item`, `password_bitwarden_item`, or `password_prompt`, or run Rustic in a terminal to be prompted for the password");
     }
 
     Ok(())
 }
 
+/// Quotes an argument to a command that Restic runs itself, like a `--password-command` or rclone. Restic splits these
+/// command lines on whitespace outside of single or double quotes, but has no way to escape a quote or backslash.
+pub fn quote_command_arg(arg: &str) -> Result<String> {
+    if arg.contains('\\') {
+        bail!(
+            "`{}` contains a backslash, which Restic can't pass on to commands it runs",
+            arg
+        );
+    }
+    if !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || "'\"".contains(c)) {
+        Ok(arg.to_string())
+    } else if !arg.contains('\'') {
+        Ok(format!("'{}'", arg))
+    } else if !arg.contains('"') {
+        Ok(format!("\"{}\"", arg))
+    } else {
+        bail!(
+            "`{}` contains both single and double quotes, which Restic can't pass on to commands it runs",
+            arg
+        );
+    }
+}
+
 /// Add credential environment variables to the command.
 fn add_credentials(profile: &Profile, env: &mut HashMap<OsString, OsString>) -> Result<()> {
+    // The typed backend tables come first, so that variables set explicitly can override them
+    check_backend_credentials(profile)?;
+    if let Some(ref s3) = profile.s3 {
+        env.insert("AWS_ACCESS_KEY_ID".into(), (&s3.access_key_id).into());
+        let secret_access_key = backend_secret(
+            profile,
+            s3.secret_access_key.as_deref(),
+            s3.secret_access_key_file.as_deref(),
+        )?;
+        env.insert("AWS_SECRET_ACCESS_KEY".into(), secret_access_key.into());
+        if let Some(ref region) = s3.region {
+            env.insert("AWS_DEFAULT_REGION".into(), region.into());
+        }
+    }
+    if let Some(ref b2) = profile.b2 {
+        env.insert("B2_ACCOUNT_ID".into(), (&b2.account_id).into());
+        let account_key = backend_secret(
+            profile,
+            b2.account_key.as_deref(),
+            b2.account_key_file.as_deref(),
+        )?;
+        env.insert("B2_ACCOUNT_KEY".into(), account_key.into());
+    }
+
     for (var, value) in profile.environment.iter() {
         env.insert(var.into(), value.into());
     }
@@ -151,47 +960,519 @@ fn add_credentials(profile: &Profile, env: &mut HashMap<OsString, OsString>) ->
         // .join will resolve environment_file against base_directory if it's relative, but returns
         // environment_file itself if it's absolute.
         let environment_file = &profile.base_directory.join(environment_file);
-        let env_contents = fs::read_to_string(environment_file).with_context(|| {
+        let age_identity_file = profile
+            .age_identity_file
+            .as_ref()
+            .map(|identity_file| profile.base_directory.join(identity_file));
+        let env_contents =
+            encrypted::read_environment_file(environment_file, age_identity_file.as_deref())?;
+        let format = profile
+            .environment_file_format
+            .unwrap_or_else(|| EnvironmentFileFormat::detect(environment_file));
+        let env_vars: HashMap<String, String> = match format {
+            EnvironmentFileFormat::Toml => {
+                toml::from_str(&env_contents).map_err(anyhow::Error::from)
+            }
+            EnvironmentFileFormat::Dotenv => dotenv::parse(&env_contents),
+        }
+        .with_context(|| {
             format!(
-                "Could not read environment file {}",
+                "Could not parse environment file {}",
                 environment_file.display()
             )
         })?;
-        let env_vars: HashMap<String, String> =
-            toml::from_str(&env_contents).with_context(|| {
-                format!(
-                    "Could not parse environment file {}",
-                    environment_file.display()
-                )
-            })?;
         for (var, value) in env_vars {
             env.insert(var.into(), value.into());
         }
     }
 
+    if let Some(arn) = &profile.environment_from_aws_secret {
+        for (var, value) in aws::secret_environment(arn)? {
+            env.insert(var.into(), value.into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that a profile's `rest`, `s3`, and `b2` tables set their secrets consistently, and that `s3` and `b2` match the
+/// kind of repository
+pub fn check_backend_credentials(profile: &Profile) -> Result<()> {
+    if let Some(ref s3) = profile.s3 {
+        check_secret(
+            "s3.secret_access_key",
+            s3.secret_access_key.is_some(),
+            s3.secret_access_key_file.is_some(),
+        )?;
+        if !profile.repository.starts_with("s3:") {
+            bail!("`s3` is set, but the repository isn't an S3 repository");
+        }
+        if s3.endpoint.is_some() && s3.bucket.is_none() {
+            bail!("`s3.endpoint` only applies along with `s3.bucket`. Put the endpoint in `repository` instead.");
+        }
+    }
+    if let Some(ref rest) = profile.rest {
+        let passwords = [
+            ("rest.password", rest.password.is_some()),
+            ("rest.password_file", rest.password_file.is_some()),
+            ("rest.password_keyring", rest.password_keyring.is_some()),
+        ];
+        let mut set = passwords
+            .iter()
+            .filter(|(_, set)| *set)
+            .map(|(name, _)| name);
+        match (set.next(), set.next()) {
+            (Some(first), Some(second)) => bail!("Cannot set both `{}` and `{}`", first, second),
+            (Some(first), None) if rest.username.is_none() => {
+                bail!("`{}` is set, but `rest.username` isn't", first)
+            }
+            _ => (),
+        }
+    }
+    if let Some(ref b2) = profile.b2 {
+        check_secret(
+            "b2.account_key",
+            b2.account_key.is_some(),
+            b2.account_key_file.is_some(),
+        )?;
+        if !profile.repository.starts_with("b2:") {
+            bail!("`b2` is set, but the repository isn't a B2 repository");
+        }
+    }
+    Ok(())
+}
+
+/// Checks that exactly one of a secret and its `_file` variant is set
+fn check_secret(name: &str, secret: bool, secret_file: bool) -> Result<()> {
+    match (secret, secret_file) {
+        (true, true) => bail!("Cannot set both `{}` and `{}_file`", name, name),
+        (false, false) => bail!("Must set either `{}` or `{}_file`", name, name),
+        _ => Ok(()),
+    }
+}
+
+/// Reads a backend secret that's either given inline or in a file relative to the profile's base directory
+fn backend_secret(
+    profile: &Profile,
+    secret: Option<&str>,
+    secret_file: Option<&Path>,
+) -> Result<String> {
+    match (secret, secret_file) {
+        (Some(secret), _) => Ok(secret.to_string()),
+        (None, Some(secret_file)) => {
+            let secret_file = profile.base_directory.join(secret_file);
+            let secret = fs::read_to_string(&secret_file)
+                .with_context(|| format!("Could not read secret file {}", secret_file.display()))?;
+            Ok(secret.trim_end_matches(&['\r', '\n'][..]).to_string())
+        }
+        (None, None) => Ok(String::new()),
+    }
+}
+
+/// Adds the options and credentials for a profile's `rest` settings, if it has any. Credentials go through the
+/// environment rather than the repository URL, so they don't show up in logs or the process list.
+fn add_rest_options(
+    profile: &Profile,
+    args: &mut Vec<OsString>,
+    env: &mut HashMap<OsString, OsString>,
+    shell_env: &mut HashMap<OsString, OsString>,
+    version_requirements: &mut Vec<(ResticVersion, String)>,
+) -> Result<()> {
+    let rest = match profile.rest {
+        Some(ref rest) => rest,
+        None => return Ok(()),
+    };
+
+    if let Some(ref ca_cert) = rest.ca_cert {
+        add_global_option(
+            args,
+            shell_env,
+            "--cacert",
+            "RESTIC_CACERT",
+            profile.base_directory.join(ca_cert),
+        );
+    }
+    if let Some(ref client_cert) = rest.client_cert {
+        add_global_option(
+            args,
+            shell_env,
+            "--tls-client-cert",
+            "RESTIC_TLS_CLIENT_CERT",
+            profile.base_directory.join(client_cert),
+        );
+    }
+
+    if let Some(ref username) = rest.username {
+        env.insert("RESTIC_REST_USERNAME".into(), username.into());
+        let password = if let Some(ref password) = rest.password {
+            Some(password.clone())
+        } else if let Some(ref password_file) = rest.password_file {
+            Some(backend_secret(profile, None, Some(password_file))?)
+        } else if let Some(ref entry) = rest.password_keyring {
+            Some(keyring::lookup(entry)?)
+        } else {
+            None
+        };
+        if let Some(password) = password {
+            env.insert("RESTIC_REST_PASSWORD".into(), password.into());
+        }
+        version_requirements.push((REST_CREDENTIALS_VERSION, "`rest.username`".to_string()));
+    }
+
     Ok(())
 }
 
+/// Adds a global Restic flag to `args`, and the environment variable Restic reads the same setting from to `shell_env`, so
+/// that shells from `rustic shell` get the same settings as the commands Rustic runs
+fn add_global_option(
+    args: &mut Vec<OsString>,
+    shell_env: &mut HashMap<OsString, OsString>,
+    flag: &str,
+    env_var: &str,
+    value: impl Into<OsString>,
+) {
+    let value = value.into();
+    args.push(flag.into());
+    args.push(value.clone());
+    shell_env.insert(env_var.into(), value);
+}
+
+/// Arguments Restic runs rclone with by default, which extra rclone arguments are added to
+const DEFAULT_RCLONE_ARGS: &str = "serve restic --stdio --b2-hard-delete";
+
+/// Adds the options for a profile's `rclone` settings, if it has any
+fn add_rclone_options(
+    profile: &Profile,
+    args: &mut Vec<OsString>,
+    env: &mut HashMap<OsString, OsString>,
+) -> Result<()> {
+    let rclone = match profile.rclone {
+        Some(ref rclone) => rclone,
+        None => return Ok(()),
+    };
+
+    if let Some(ref program) = rclone.program {
+        args.push("--option".into());
+        args.push(format!("rclone.program={}", program).into());
+    }
+
+    let mut extra_args = Vec::new();
+    if let Some(ref bwlimit) = rclone.bwlimit {
+        extra_args.push("--bwlimit".to_string());
+        extra_args.push(quote_command_arg(bwlimit)?);
+    }
+    for arg in rclone.args.iter() {
+        extra_args.push(quote_command_arg(arg)?);
+    }
+    if !extra_args.is_empty() {
+        args.push("--option".into());
+        args.push(
+            format!(
+                "rclone.args={} {}",
+                DEFAULT_RCLONE_ARGS,
+                extra_args.join(" ")
+            )
+            .into(),
+        );
+    }
+
+    // Restic passes its environment on to rclone
+    if let Some(ref config) = rclone.config {
+        env.insert(
+            "RCLONE_CONFIG".into(),
+            profile.base_directory.join(config).into(),
+        );
+    }
+
+    Ok(())
+}
+
+/// Upload and download limits for Restic at `now`, from the first `bandwidth_schedule` window containing it or else the
+/// profile's `limit_upload` and `limit_download`
+fn bandwidth_limits(
+    logger: &Logger,
+    profile: &Profile,
+    now: NaiveDateTime,
+) -> (Option<u32>, Option<u32>) {
+    match profile
+        .bandwidth_schedule
+        .iter()
+        .find(|window| window.hours.contains_on(&window.days, now))
+    {
+        Some(window) => {
+            debug!(logger, "Using bandwidth limits for {}", window.hours; "limit_upload" => ?window.limit_upload, "limit_download" => ?window.limit_download);
+            (window.limit_upload, window.limit_download)
+        }
+        None => (profile.limit_upload, profile.limit_download),
+    }
+}
+
 #[cfg(test)]
 mod test {
+    use std::ffi::OsString;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use super::{
+        backoff_delay, check_backend_credentials, quote_command_arg, RepositoryState, StdinCommand,
+        TRANSIENT_ERROR,
+    };
+    use crate::config::{Rest, B2};
+    use crate::runner::MockResponse;
     use crate::test::TestFixture;
 
+    #[test]
+    fn test_backend_credentials() {
+        let mut fixture = TestFixture::new();
+        fs::write(fixture.content_root().join("b2-key"), "K001secret\n").unwrap();
+        fixture.profile_mut().repository = "b2:my-bucket:laptop".to_string();
+        fixture
+            .profile_mut()
+            .environment
+            .insert("B2_ACCOUNT_ID".to_string(), "overridden".to_string());
+        fixture.profile_mut().b2 = Some(B2 {
+            account_id: "0012ab".to_string(),
+            account_key: None,
+            account_key_file: Some(PathBuf::from("b2-key")),
+            bucket: None,
+        });
+        let restic = fixture.restic();
+        assert_eq!(
+            restic.env()[&OsString::from("B2_ACCOUNT_KEY")],
+            "K001secret"
+        );
+        assert_eq!(restic.env()[&OsString::from("B2_ACCOUNT_ID")], "overridden");
+
+        // The credentials have to match the repository
+        fixture.profile_mut().repository = "s3:s3.amazonaws.com/my-bucket".to_string();
+        assert!(check_backend_credentials(fixture.profile()).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_shell_env() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut fixture = TestFixture::new();
+        // `pack_size` needs a Restic new enough to support it
+        let script = fixture.content_root().join("restic");
+        fs::write(
+            &script,
+            "#!/bin/sh\necho 'restic 0.17.0 compiled with go1.22.5 on linux/amd64'\n",
+        )
+        .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        fixture.profile_mut().restic_command = Some(script.display().to_string());
+        fixture.profile_mut().rest = Some(Rest {
+            url: "https://nas.lan:8000/laptop".to_string(),
+            username: None,
+            password: None,
+            password_file: None,
+            password_keyring: None,
+            ca_cert: Some(PathBuf::from("nas.pem")),
+            client_cert: Some(PathBuf::from("laptop.pem")),
+        });
+        fixture.profile_mut().pack_size = Some(64);
+        let ca_cert = fixture.profile().base_directory.join("nas.pem");
+        let restic = fixture.restic();
+
+        // The flags Restic gets are also in the shell's environment
+        let cmd = restic.new_command();
+        let args: Vec<_> = cmd.get_args().collect();
+        assert!(args
+            .windows(2)
+            .any(|arg| arg[0] == "--cacert" && arg[1] == ca_cert.as_os_str()));
+        assert_eq!(
+            restic.shell_env()[&OsString::from("RESTIC_CACERT")],
+            ca_cert.as_os_str()
+        );
+        assert!(restic
+            .shell_env()
+            .contains_key(&OsString::from("RESTIC_TLS_CLIENT_CERT")));
+        assert_eq!(
+            restic.shell_env()[&OsString::from("RESTIC_PACK_SIZE")],
+            "64"
+        );
+    }
+
+    #[test]
+    fn test_require_full_access() {
+        let mut fixture = TestFixture::new();
+        assert!(fixture.restic().require_full_access("forget").is_ok());
+
+        fixture.profile_mut().append_only = true;
+        fixture.profile_mut().maintenance_profile = Some("test-maintenance".to_string());
+        let err = fixture.restic().prune().unwrap_err();
+        assert!(err.to_string().contains("`rustic prune test-maintenance`"));
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let fixture = TestFixture::new();
+        let mut restic = fixture.restic();
+        restic.set_dry_run(true);
+
+        // There's no Restic to run in tests, so these only succeed if nothing is run
+        restic.prune().unwrap();
+        assert_eq!(restic.repository_state().unwrap(), RepositoryState::Exists);
+
+        let mut cmd = restic.new_command();
+        cmd.arg("snapshots");
+        let err = restic.execute_output(&mut cmd).unwrap_err();
+        assert!(err.to_string().contains("this is a dry run"));
+    }
+
+    #[test]
+    fn test_quote_command_arg() {
+        assert_eq!(quote_command_arg("home-repo").unwrap(), "home-repo");
+        assert_eq!(quote_command_arg("my backups").unwrap(), "'my backups'");
+        assert_eq!(quote_command_arg("ben's repo").unwrap(), "\"ben's repo\"");
+        assert_eq!(quote_command_arg("").unwrap(), "''");
+        assert!(quote_command_arg("'\"").is_err());
+        assert!(quote_command_arg("domain\\user").is_err());
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        let base = Duration::from_secs(10);
+        for (retry, expected) in [(1, 10), (2, 20), (3, 40)].iter() {
+            let delay = backoff_delay(base, *retry);
+            let expected = Duration::from_secs(*expected);
+            assert!(
+                delay >= expected && delay <= expected + expected / 2,
+                "Retry {} waited {:?}",
+                retry,
+                delay
+            );
+        }
+    }
+
+    #[test]
+    fn test_transient_errors() {
+        assert!(TRANSIENT_ERROR.is_match("Fatal: unable to open repository: Get \"https://example.com/config\": dial tcp: lookup example.com: no such host"));
+        assert!(TRANSIENT_ERROR.is_match("Load(<data/abc>, 0, 0) returned error, retrying after 1s: read tcp: connection reset by peer"));
+        assert!(TRANSIENT_ERROR.is_match("Fatal: unable to create lock in backend: repository is already locked by PID 12 on host by user"));
+        assert!(TRANSIENT_ERROR.is_match(
+            "Fatal: unable to open config file: unexpected HTTP response (502): 502 Bad Gateway"
+        ));
+        assert!(TRANSIENT_ERROR.is_match("Save(<data/abc>) returned error: response status 503"));
+        assert!(!TRANSIENT_ERROR.is_match("Fatal: wrong password or no key found"));
+        assert!(!TRANSIENT_ERROR.is_match(
+            "Fatal: unable to open config file: stat /srv/restic/config: no such file or directory"
+        ));
+        assert!(!TRANSIENT_ERROR.is_match(
+            "Fatal: unable to open config file: stat /srv/restic/502/config: no such file or directory"
+        ));
+    }
+
     #[test]
     fn test_repository_exists() {
         let fixture = TestFixture::new();
         let restic = fixture.restic();
 
-        assert_eq!(
-            restic.repository_exists().unwrap(),
-            false,
+        assert!(
+            !restic.repository_exists().unwrap(),
             "Repository does not exist yet"
         );
 
         restic.init().expect("Could not initialize repository");
-        assert_eq!(
+        assert!(
             restic.repository_exists().unwrap(),
-            true,
             "Repository should now exist"
         );
     }
+
+    #[test]
+    fn test_retries() {
+        let mut fixture = TestFixture::new();
+        fixture.profile_mut().retries = 2;
+        fixture.profile_mut().retry_delay = Some(Duration::from_millis(1));
+        let (restic, runner) = fixture.mock_restic();
+        runner
+            .respond(MockResponse::failure(
+                1,
+                "read tcp: connection reset by peer",
+            ))
+            .respond(MockResponse::success("[]"));
+
+        let mut cmd = restic.new_command();
+        cmd.arg("snapshots").arg("--json");
+        assert_eq!(restic.execute_output(&mut cmd).unwrap(), "[]\n");
+        assert_eq!(runner.invocations().len(), 2);
+        assert!(runner.invocations()[1].has_arg("snapshots"));
+
+        // Errors that won't go away on their own aren't retried
+        runner.respond(MockResponse::failure(
+            1,
+            "Fatal: wrong password or no key found",
+        ));
+        assert!(restic.execute_output(&mut cmd).is_err());
+        assert_eq!(runner.invocations().len(), 3);
+    }
+
+    #[test]
+    fn test_execute_with_stdin() {
+        let fixture = TestFixture::new();
+        let (restic, runner) = fixture.mock_restic();
+        let mut cmd = restic.new_command();
+        cmd.arg("backup").arg("--stdin");
+
+        let stdin = |command| StdinCommand::shell(command, fixture.content_root());
+        // The mock runner doesn't read its input, so these commands don't write any
+        runner.respond(MockResponse::success(""));
+        assert!(restic
+            .execute_with_stdin(&mut cmd, &stdin("exit 0"), &mut |_| ())
+            .unwrap()
+            .status
+            .success());
+
+        // Restic can't tell that the command failed, so Rustic has to
+        runner.respond(MockResponse::success(""));
+        let err = restic
+            .execute_with_stdin(&mut cmd, &stdin("exit 3"), &mut |_| ())
+            .unwrap_err();
+        assert!(err.to_string().contains("`exit 3` failed"), "{}", err);
+
+        // But if Restic failed too, its error is the one that matters
+        runner.respond(MockResponse::failure(11, "Fatal: unable to lock"));
+        let execution = restic
+            .execute_with_stdin(&mut cmd, &stdin("exit 3"), &mut |_| ())
+            .unwrap();
+        assert_eq!(execution.status.code(), Some(11));
+    }
+
+    #[test]
+    fn test_repository_failover() {
+        let mut fixture = TestFixture::new();
+        fixture.profile_mut().failover_repositories = vec!["rest:http://backup.lan/".to_string()];
+        let (restic, runner) = fixture.mock_restic();
+
+        runner.respond(MockResponse::failure(
+            1,
+            "Fatal: unable to open config file: Head \"https://s3.amazonaws.com/bucket/config\": dial tcp: lookup s3.amazonaws.com: no such host",
+        ));
+        assert_eq!(
+            restic.repository_state().unwrap(),
+            RepositoryState::Unreachable("no such host".to_string())
+        );
+        runner.respond(MockResponse::failure(
+            10,
+            "Fatal: repository does not exist: unable to open config file",
+        ));
+        assert_eq!(restic.repository_state().unwrap(), RepositoryState::Missing);
+        // A status code in the repository's path isn't a server error
+        runner.respond(MockResponse::failure(
+            10,
+            "Fatal: unable to open config file: stat /srv/restic/502/config: no such file or directory",
+        ));
+        assert_eq!(restic.repository_state().unwrap(), RepositoryState::Missing);
+
+        assert_eq!(restic.fail_over(), Some("rest:http://backup.lan/"));
+        assert_eq!(restic.repository(), "rest:http://backup.lan/");
+        runner.respond(MockResponse::success(""));
+        assert_eq!(restic.repository_state().unwrap(), RepositoryState::Exists);
+        assert!(runner.invocations()[3].has_arg("rest:http://backup.lan/"));
+        assert_eq!(restic.fail_over(), None);
+    }
 }
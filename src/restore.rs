@@ -0,0 +1,100 @@
+//! Restoring files from snapshots
+
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{Context, Result};
+use slog::{error, info};
+use tempfile::NamedTempFile;
+
+use crate::redact;
+use crate::restic::Restic;
+use crate::version::ResticVersion;
+
+/// First Restic release with the `--include-file` flag for `restore`
+const INCLUDE_FILE_VERSION: ResticVersion = ResticVersion::new(0, 17, 0);
+
+/// Extends the Restic wrapper with restores
+impl<'a> Restic<'a> {
+    /// Restores files from a snapshot into `target`. If `include` is non-empty, only those paths (and everything under
+    /// them) are restored.
+    pub fn restore(&self, snapshot: &str, target: &Path, include: &[String]) -> Result<()> {
+        let mut cmd = self.new_command();
+        cmd.arg("restore").arg(snapshot).arg("--target").arg(target);
+        for path in include {
+            cmd.arg("--include").arg(path);
+        }
+        self.run_restore(snapshot, target, &mut cmd)
+    }
+
+    /// Restores exactly `paths` from a snapshot into `target`. Unlike [`restore`](Restic::restore), the paths are taken
+    /// literally, even if they contain wildcards, and are passed in a file, so that restoring many of them doesn't run into
+    /// the limit on command line length.
+    pub fn restore_paths(&self, snapshot: &str, target: &Path, paths: &[String]) -> Result<()> {
+        self.require_version(INCLUDE_FILE_VERSION, "Restoring a list of files")?;
+        let mut include_file = NamedTempFile::new().context("Could not create include file")?;
+        for path in paths {
+            writeln!(include_file, "{}", escape_pattern(path))
+                .context("Could not write include file")?;
+        }
+        include_file
+            .flush()
+            .context("Could not write include file")?;
+
+        let mut cmd = self.new_command();
+        cmd.arg("restore")
+            .arg(snapshot)
+            .arg("--target")
+            .arg(target)
+            .arg("--include-file")
+            .arg(include_file.path());
+        self.run_restore(snapshot, target, &mut cmd)
+    }
+
+    fn run_restore(&self, snapshot: &str, target: &Path, cmd: &mut Command) -> Result<()> {
+        info!(self.logger(), "Restoring snapshot {}", snapshot; "command" => %redact::command_line(cmd));
+        let status = self.execute(cmd, None)?.status;
+        if status.success() {
+            info!(
+                self.logger(),
+                "Restored snapshot {} to {}",
+                snapshot,
+                target.display()
+            );
+            Ok(())
+        } else {
+            error!(self.logger(), "Restore failed"; "status" => %status, "command" => %redact::command_line(cmd));
+            Err(self.failed("restore", status).into())
+        }
+    }
+}
+
+/// Escapes a path so that Restic's include patterns match it literally. Restic's patterns treat `*`, `?`, `[`, and `\` as
+/// wildcards or escapes, and a line starting with `#` in a pattern file as a comment.
+fn escape_pattern(path: &str) -> String {
+    let mut escaped = String::with_capacity(path.len());
+    for (i, c) in path.chars().enumerate() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') || (i == 0 && c == '#') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::escape_pattern;
+
+    #[test]
+    fn test_escape_pattern() {
+        assert_eq!(escape_pattern("/home/ben/notes.txt"), "/home/ben/notes.txt");
+        assert_eq!(
+            escape_pattern("/home/ben/[draft] what?*.txt"),
+            "/home/ben/\\[draft\\] what\\?\\*.txt"
+        );
+        assert_eq!(escape_pattern("#notes"), "\\#notes");
+        assert_eq!(escape_pattern("a\\b"), "a\\\\b");
+    }
+}
@@ -0,0 +1,144 @@
+//! Rewriting existing snapshots to remove files from them, for things that should have been excluded from the start
+
+use std::time::Instant;
+
+use anyhow::{bail, Result};
+use slog::{error, info};
+
+use crate::redact;
+use crate::restic::Restic;
+use crate::snapshots::SnapshotFilter;
+use crate::version::ResticVersion;
+
+/// First Restic release with the `rewrite` command
+const REWRITE_VERSION: ResticVersion = ResticVersion::new(0, 15, 0);
+
+/// What to remove from snapshots, and what to do with the originals
+#[derive(Debug, Default)]
+pub struct RewriteOptions {
+    /// Patterns of files to remove, in addition to the profile's excludes if `profile_excludes` is set
+    pub exclude: Vec<String>,
+
+    /// Remove files matching the profile's current exclude patterns, including those of its sources
+    pub profile_excludes: bool,
+
+    /// Forget the original snapshots once they've been rewritten. Their data is only removed from the repository by the
+    /// next prune.
+    pub forget: bool,
+
+    /// Only show what would be removed, without changing anything
+    pub dry_run: bool,
+}
+
+/// Extends the Restic wrapper with snapshot rewriting
+impl<'a> Restic<'a> {
+    /// Rewrites `snapshots` (IDs, or `latest`) and every snapshot matching `filter`, leaving out files that match the
+    /// exclude patterns. If no snapshots or restrictions are given, every snapshot with the profile's tags is rewritten.
+    /// Restic keeps the original snapshots unless `forget` is set, and tags the new ones with `rewrite`.
+    pub fn rewrite(
+        &self,
+        options: &RewriteOptions,
+        snapshots: &[String],
+        filter: &SnapshotFilter,
+    ) -> Result<()> {
+        let mut exclude = options.exclude.clone();
+        let mut iexclude = Vec::new();
+        if options.profile_excludes {
+            let (globs, insensitive) = self.exclude_patterns()?;
+            exclude.extend(globs);
+            iexclude.extend(insensitive);
+        }
+        if exclude.is_empty() && iexclude.is_empty() {
+            bail!("Nothing to remove. Pass patterns to exclude, or use the profile's excludes.");
+        }
+        if !options.dry_run {
+            self.require_full_access("rewrite")?;
+        }
+        self.require_version(REWRITE_VERSION, "Rewriting snapshots")?;
+
+        let mut cmd = self.new_command();
+        cmd.arg("rewrite");
+        for pattern in exclude.iter() {
+            cmd.arg("--exclude").arg(pattern);
+        }
+        for pattern in iexclude.iter() {
+            cmd.arg("--iexclude").arg(pattern);
+        }
+        if options.forget {
+            cmd.arg("--forget");
+        }
+        if options.dry_run {
+            cmd.arg("--dry-run");
+        }
+        filter.add_args(&mut cmd);
+        if snapshots.is_empty() && filter.is_empty() && !self.profile().tags.is_empty() {
+            cmd.arg("--tag").arg(self.profile().tags.join(","));
+        }
+        cmd.args(snapshots);
+
+        info!(self.logger(), "Rewriting snapshots"; "command" => %redact::command_line(&cmd));
+        let start = Instant::now();
+        let status = self
+            .lock_repository()
+            .and_then(|_lock| self.execute(&mut cmd, None))?
+            .status;
+        let duration = Instant::now() - start;
+
+        if status.success() {
+            info!(self.logger(), "Rewrote snapshots in {:?}", duration; "command" => %redact::command_line(&cmd));
+            Ok(())
+        } else {
+            error!(self.logger(), "Rewriting snapshots failed"; "status" => %status, "command" => %redact::command_line(&cmd));
+            Err(self.failed("rewrite", status).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RewriteOptions;
+    use crate::runner::MockResponse;
+    use crate::snapshots::SnapshotFilter;
+    use crate::test::TestFixture;
+
+    #[test]
+    fn test_rewrite() {
+        let mut fixture = TestFixture::new();
+        fixture.profile_mut().tags = vec!["home".to_string()];
+        fixture.profile_mut().exclude.patterns = vec!["/home/me/.cache".to_string()];
+        let (restic, runner) = fixture.mock_restic();
+
+        let options = RewriteOptions {
+            exclude: vec!["*.iso".to_string()],
+            profile_excludes: true,
+            forget: true,
+            ..RewriteOptions::default()
+        };
+        runner.respond(MockResponse::success(
+            "restic 0.16.4 compiled with go1.21.6 on linux/amd64\n",
+        ));
+        restic
+            .rewrite(&options, &[], &SnapshotFilter::default())
+            .unwrap();
+        let args = &runner.invocations()[1].args;
+        let rewrite = args.iter().position(|arg| arg == "rewrite").unwrap();
+        assert_eq!(
+            args[rewrite..],
+            [
+                "rewrite",
+                "--exclude",
+                "*.iso",
+                "--exclude",
+                "/home/me/.cache",
+                "--forget",
+                "--tag",
+                "home"
+            ]
+        );
+
+        assert!(restic
+            .rewrite(&RewriteOptions::default(), &[], &SnapshotFilter::default())
+            .is_err());
+        assert_eq!(runner.invocations().len(), 2);
+    }
+}
@@ -0,0 +1,30 @@
+//! Running arbitrary Restic commands with a profile's configuration
+
+use std::ffi::OsString;
+
+use anyhow::{bail, Result};
+use slog::debug;
+
+use crate::redact;
+use crate::restic::Restic;
+
+/// Extends the Restic wrapper with a command for running arbitrary Restic commands
+impl<'a> Restic<'a> {
+    /// Runs Restic with `args`, using this profile's repository, credentials, and environment. This is an escape hatch for
+    /// Restic commands that Rustic doesn't wrap, so Restic runs attached to the terminal and isn't retried.
+    pub fn run(&self, args: &[OsString]) -> Result<()> {
+        let mut cmd = self.new_passthrough_command();
+        cmd.args(args);
+
+        debug!(self.logger(), "Running Restic"; "command" => %redact::command_line(&cmd));
+        let status = self.status(&mut cmd)?;
+        if !status.success() {
+            let operation = args
+                .first()
+                .and_then(|arg| arg.to_str())
+                .unwrap_or("command");
+            bail!(self.failed(operation, status));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,358 @@
+//! Running Restic processes. Everything that runs Restic on behalf of a [`Restic`](crate::restic::Restic) goes through a
+//! [`ResticRunner`], so that tests can swap in a [`MockRunner`] instead of needing a real Restic binary and repository.
+
+use std::cell::RefCell;
+use std::ffi::OsStr;
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::rc::Rc;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+
+use crate::error::RusticError;
+use crate::platform;
+use crate::redact;
+use crate::restic::Execution;
+
+/// How long to wait for Restic to exit after interrupting it for running past its timeout, before killing it
+const TERMINATION_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+/// Runs Restic commands
+pub trait ResticRunner {
+    /// Runs `cmd` to completion, passing each line it writes to stderr to `on_stderr`. If `on_stdout` is given, each line
+    /// written to stdout is passed to it. Otherwise, stdout is left as configured on `cmd`. If `timeout` is given and the
+    /// command runs past it, it's stopped and an error is returned.
+    fn run(
+        &self,
+        cmd: &mut Command,
+        on_stdout: Option<&mut dyn FnMut(&str)>,
+        on_stderr: Box<dyn Fn(&str) + Send>,
+        timeout: Option<Duration>,
+    ) -> Result<Execution>;
+
+    /// Runs `cmd` to completion with its stdio left as configured, for commands that interact with the user directly
+    fn status(&self, cmd: &mut Command) -> Result<ExitStatus>;
+}
+
+/// Runner that spawns real Restic processes
+pub struct SystemRunner;
+
+impl ResticRunner for SystemRunner {
+    fn run(
+        &self,
+        cmd: &mut Command,
+        on_stdout: Option<&mut dyn FnMut(&str)>,
+        on_stderr: Box<dyn Fn(&str) + Send>,
+        timeout: Option<Duration>,
+    ) -> Result<Execution> {
+        if on_stdout.is_some() {
+            cmd.stdout(Stdio::piped());
+        }
+        cmd.stderr(Stdio::piped());
+        let mut child = cmd
+            .spawn()
+            .with_context(|| format!("Could not run {}", redact::command_line(cmd)))?;
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        // Read stderr on another thread, so that neither pipe fills up and blocks Restic while we're reading the other one
+        let stderr = child.stderr.take().unwrap();
+        let stderr_reader = thread::spawn(move || {
+            let mut captured = String::new();
+            for line in BufReader::new(stderr).lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => break,
+                };
+                on_stderr(&line);
+                captured.push_str(&line);
+                captured.push('\n');
+            }
+            captured
+        });
+
+        if let Some(on_stdout) = on_stdout {
+            // Read stdout on another thread as well, so that Restic can be stopped if it hangs without printing anything
+            let stdout = child.stdout.take().unwrap();
+            let (sender, receiver) = mpsc::channel();
+            thread::spawn(move || {
+                for line in BufReader::new(stdout).lines() {
+                    if sender.send(line).is_err() {
+                        break;
+                    }
+                }
+            });
+
+            loop {
+                let line = match deadline {
+                    Some(deadline) => {
+                        match receiver
+                            .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                        {
+                            Ok(line) => line,
+                            Err(RecvTimeoutError::Timeout) => {
+                                return Err(stop(&mut child, timeout.unwrap()))
+                            }
+                            Err(RecvTimeoutError::Disconnected) => break,
+                        }
+                    }
+                    None => match receiver.recv() {
+                        Ok(line) => line,
+                        Err(_) => break,
+                    },
+                };
+                let line = match line {
+                    Ok(line) => line,
+                    Err(err) => {
+                        // Don't leave Restic running, holding its repository lock, with nothing reading its output
+                        terminate(&mut child);
+                        return Err(err).context("Could not read Restic output");
+                    }
+                };
+                on_stdout(&line);
+            }
+        }
+
+        let status = match deadline {
+            Some(deadline) => loop {
+                if let Some(status) = child
+                    .try_wait()
+                    .with_context(|| format!("Could not run {}", redact::command_line(cmd)))?
+                {
+                    break status;
+                }
+                if Instant::now() >= deadline {
+                    return Err(stop(&mut child, timeout.unwrap()));
+                }
+                thread::sleep(Duration::from_millis(100));
+            },
+            None => child
+                .wait()
+                .with_context(|| format!("Could not run {}", redact::command_line(cmd)))?,
+        };
+        let stderr = stderr_reader.join().unwrap_or_default();
+
+        Ok(Execution { status, stderr })
+    }
+
+    fn status(&self, cmd: &mut Command) -> Result<ExitStatus> {
+        cmd.status()
+            .with_context(|| format!("Could not run {}", redact::command_line(cmd)))
+    }
+}
+
+/// Runner for `--print-commands`, which prints each command instead of running it, and reports that it succeeded without any
+/// output. Only `restic version` is actually run, since version checks need a real answer and it doesn't touch any
+/// repository.
+pub struct DryRunRunner;
+
+impl DryRunRunner {
+    fn print(&self, cmd: &Command) {
+        println!("Would run: {}", redact::command_line(cmd));
+    }
+}
+
+/// Whether `cmd` is `restic version`
+fn is_version_command(cmd: &Command) -> bool {
+    cmd.get_args().last() == Some(OsStr::new("version"))
+}
+
+impl ResticRunner for DryRunRunner {
+    fn run(
+        &self,
+        cmd: &mut Command,
+        on_stdout: Option<&mut dyn FnMut(&str)>,
+        on_stderr: Box<dyn Fn(&str) + Send>,
+        timeout: Option<Duration>,
+    ) -> Result<Execution> {
+        if is_version_command(cmd) {
+            return SystemRunner.run(cmd, on_stdout, on_stderr, timeout);
+        }
+        self.print(cmd);
+        Ok(Execution {
+            status: platform::exit_status(0),
+            stderr: String::new(),
+        })
+    }
+
+    fn status(&self, cmd: &mut Command) -> Result<ExitStatus> {
+        self.print(cmd);
+        Ok(platform::exit_status(0))
+    }
+}
+
+/// Stops a Restic process that ran past its timeout, with [`terminate`]. Returns the error to report for the timeout.
+fn stop(child: &mut Child, timeout: Duration) -> anyhow::Error {
+    terminate(child);
+    RusticError::Timeout(timeout).into()
+}
+
+/// Stops a Restic process and waits for it to exit. Restic is interrupted first so it can remove its repository lock, and
+/// killed if it doesn't exit within a grace period.
+fn terminate(child: &mut Child) {
+    let _ = platform::interrupt(child);
+    let grace_deadline = Instant::now() + TERMINATION_GRACE_PERIOD;
+    while Instant::now() < grace_deadline {
+        match child.try_wait() {
+            Ok(Some(_)) | Err(_) => break,
+            Ok(None) => thread::sleep(Duration::from_millis(100)),
+        }
+    }
+    if let Ok(None) = child.try_wait() {
+        let _ = child.kill();
+    }
+    let _ = child.wait();
+}
+
+/// A command run by a [`MockRunner`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invocation {
+    /// Program that was run. Since Restic may be wrapped in other commands, this isn't necessarily Restic itself.
+    pub program: String,
+
+    /// Arguments to the program
+    pub args: Vec<String>,
+}
+
+impl Invocation {
+    /// Whether the invocation included `arg`
+    pub fn has_arg(&self, arg: &str) -> bool {
+        self.args.iter().any(|a| a == arg)
+    }
+}
+
+/// Canned result for a command run by a [`MockRunner`]
+#[derive(Debug, Clone, Default)]
+pub struct MockResponse {
+    /// Exit code to report
+    pub exit_code: i32,
+
+    /// Output to pass to the stdout callback
+    pub stdout: String,
+
+    /// Output to pass to the stderr callback
+    pub stderr: String,
+}
+
+impl MockResponse {
+    /// A successful run that prints `stdout`
+    pub fn success(stdout: &str) -> MockResponse {
+        MockResponse {
+            stdout: stdout.to_string(),
+            ..MockResponse::default()
+        }
+    }
+
+    /// A failed run that exits with `exit_code` and prints `stderr`
+    pub fn failure(exit_code: i32, stderr: &str) -> MockResponse {
+        MockResponse {
+            exit_code,
+            stderr: stderr.to_string(),
+            ..MockResponse::default()
+        }
+    }
+}
+
+#[derive(Default)]
+struct MockState {
+    invocations: Vec<Invocation>,
+    responses: Vec<MockResponse>,
+}
+
+/// Runner that records commands instead of running them, and replies with canned responses. Clones share the same
+/// invocations and responses, so a test can keep a clone to inspect after handing the runner to a
+/// [`Restic`](crate::restic::Restic).
+#[derive(Clone, Default)]
+pub struct MockRunner {
+    state: Rc<RefCell<MockState>>,
+}
+
+impl MockRunner {
+    /// Creates a runner where every command succeeds without printing anything
+    pub fn new() -> MockRunner {
+        MockRunner::default()
+    }
+
+    /// Queues a response for the next command. Once the queue is empty, commands succeed without printing anything.
+    pub fn respond(&self, response: MockResponse) -> &Self {
+        self.state.borrow_mut().responses.push(response);
+        self
+    }
+
+    /// Every command run so far, in order
+    pub fn invocations(&self) -> Vec<Invocation> {
+        self.state.borrow().invocations.clone()
+    }
+
+    fn next_response(&self, cmd: &Command) -> MockResponse {
+        let mut state = self.state.borrow_mut();
+        state.invocations.push(Invocation {
+            program: cmd.get_program().to_string_lossy().into_owned(),
+            args: cmd
+                .get_args()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect(),
+        });
+        if state.responses.is_empty() {
+            MockResponse::default()
+        } else {
+            state.responses.remove(0)
+        }
+    }
+}
+
+impl ResticRunner for MockRunner {
+    fn run(
+        &self,
+        cmd: &mut Command,
+        on_stdout: Option<&mut dyn FnMut(&str)>,
+        on_stderr: Box<dyn Fn(&str) + Send>,
+        _timeout: Option<Duration>,
+    ) -> Result<Execution> {
+        let response = self.next_response(cmd);
+        if let Some(on_stdout) = on_stdout {
+            for line in response.stdout.lines() {
+                on_stdout(line);
+            }
+        }
+        for line in response.stderr.lines() {
+            on_stderr(line);
+        }
+        Ok(Execution {
+            status: platform::exit_status(response.exit_code),
+            stderr: response.stderr,
+        })
+    }
+
+    fn status(&self, cmd: &mut Command) -> Result<ExitStatus> {
+        let response = self.next_response(cmd);
+        Ok(platform::exit_status(response.exit_code))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::process::Command;
+
+    use tempfile::TempDir;
+
+    use super::{ResticRunner, SystemRunner};
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_unreadable_output() {
+        // Output that isn't UTF-8 can't be read as lines, so the process is interrupted and waited for, rather than being
+        // left to run
+        let directory = TempDir::new().unwrap();
+        let marker = directory.path().join("interrupted");
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c")
+            .arg("trap 'touch \"$0\"; exit 1' INT; printf '\\377\\n'; while :; do sleep 0.1; done")
+            .arg(&marker);
+        let result = SystemRunner.run(&mut cmd, Some(&mut |_| ()), Box::new(|_| ()), None);
+        assert!(result.is_err());
+        assert!(marker.exists());
+    }
+}
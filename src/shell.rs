@@ -5,10 +5,13 @@ use slog::{debug, error};
 
 use anyhow::{bail, Context, Result};
 
+use crate::keyring;
+use crate::redact;
 use crate::restic::Restic;
 
 /// Extends the Restic wrapper with a command for spawning shells with Restic configuration
 impl<'a> Restic<'a> {
+    /// Spawns an interactive shell with the repository location, password, and credentials for this profile set in its environment.
     pub fn shell(&self) -> Result<()> {
         let shell = match shell_command() {
             Some(shell) => shell,
@@ -18,20 +21,26 @@ impl<'a> Restic<'a> {
 
         let mut command = Command::new(&shell);
 
-        for (k, v) in self.env().iter() {
+        for (k, v) in self.env().iter().chain(self.shell_env()) {
             command.env(k, v);
         }
 
-        command.env("RESTIC_REPOSITORY", &self.profile().repository);
+        command.env("RESTIC_REPOSITORY", self.repository());
 
         // if set, RESTIC_PASSWORD is already in shared_env
         if let Some(password_file) = &self.profile().password_file {
             command.env("RESTIC_PASSWORD_FILE", password_file);
         } else if let Some(password_command) = &self.profile().password_command {
             command.env("RESTIC_PASSWORD_COMMAND", password_command);
+        } else if let Some(entry) = &self.profile().password_keyring {
+            command.env("RESTIC_PASSWORD_COMMAND", keyring::lookup_command(entry)?);
         }
 
         command.current_dir(&self.profile().base_directory);
+        if self.is_dry_run() {
+            println!("Would run: {}", redact::command_line(&command));
+            return Ok(());
+        }
 
         let status = command.status().context("Could not start shell")?;
         if !status.success() {
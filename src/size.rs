@@ -0,0 +1,63 @@
+//! Parsing human-friendly sizes like `500M` or `2G` from the configuration
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Deserializer};
+
+/// Parses a size made up of a number and an optional unit, `K`, `M`, `G`, or `T`, which are powers of 1024 like Restic's.
+/// The unit may be followed by `iB` or `B`, as in `2GiB`, and a bare number is interpreted as bytes. Units are
+/// case-insensitive.
+pub fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    if s.is_empty() {
+        bail!("Size is empty");
+    }
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| anyhow!("Invalid size `{}`: expected a number like `500M`", s))?;
+
+    let unit = unit.trim().to_ascii_uppercase();
+    let multiplier: u64 = match unit
+        .strip_suffix("IB")
+        .or_else(|| unit.strip_suffix('B'))
+        .unwrap_or(&unit)
+    {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => bail!("Invalid size `{}`: unknown unit `{}`", s, unit),
+    };
+    Ok((number * multiplier as f64).round() as u64)
+}
+
+/// Deserializes an optional size using [`parse_size`], for use with `#[serde(deserialize_with = "...")]`.
+pub fn deserialize_option<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<u64>, D::Error> {
+    let value: Option<String> = Option::deserialize(deserializer)?;
+    value
+        .map(|s| parse_size(&s).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert_eq!(parse_size("500K").unwrap(), 500 * 1024);
+        assert_eq!(parse_size("2G").unwrap(), 2 * 1024 * 1024 * 1024);
+        assert_eq!(parse_size("1.5 MiB").unwrap(), 3 * 512 * 1024);
+        assert_eq!(parse_size("10mb").unwrap(), 10 * 1024 * 1024);
+        assert!(parse_size("").is_err());
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("5X").is_err());
+    }
+}
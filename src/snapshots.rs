@@ -1,27 +1,197 @@
 //! List backup snapshots
 
 use std::ffi::OsString;
+use std::io::{self, prelude::*};
+use std::process::Command;
 
 use anyhow::{Context, Result};
+use chrono::{DateTime, Local, Utc};
+use serde::{Deserialize, Serialize};
 use slog::debug;
 
+use crate::format::{format_bytes, format_duration};
+use crate::json;
+use crate::output::{self, OutputFormat};
+use crate::redact;
 use crate::restic::Restic;
 
-// Could parse and print JSON instead of passing through to Restic
+/// A snapshot, as reported by `restic snapshots --json`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Snapshot {
+    /// Full snapshot ID
+    pub id: String,
+
+    /// When the snapshot was taken
+    pub time: DateTime<Utc>,
+
+    /// Host the snapshot was taken on
+    pub hostname: String,
+
+    /// User who took the snapshot
+    #[serde(default)]
+    pub username: Option<String>,
+
+    /// Paths included in the snapshot
+    #[serde(default)]
+    pub paths: Vec<String>,
+
+    /// Tags attached to the snapshot
+    #[serde(default)]
+    pub tags: Vec<String>,
+
+    /// ID of the snapshot this one was based on, if any
+    #[serde(default)]
+    pub parent: Option<String>,
+
+    /// Backup statistics, only recorded by Restic 0.17 and later
+    #[serde(default)]
+    pub summary: Option<SnapshotSummary>,
+}
+
+/// Statistics Restic records in a snapshot about the backup that created it
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SnapshotSummary {
+    /// Bytes of new data added to the repository
+    #[serde(default)]
+    pub data_added: u64,
+
+    /// Total number of files in the snapshot
+    #[serde(default)]
+    pub total_files_processed: u64,
+
+    /// Total size of all files in the snapshot
+    #[serde(default)]
+    pub total_bytes_processed: u64,
+}
+
+impl Snapshot {
+    /// Abbreviated snapshot ID, as Restic prints it
+    pub fn short_id(&self) -> &str {
+        &self.id[..self.id.len().min(8)]
+    }
+}
+
+/// Restrictions on which snapshots to list. Each restriction is passed through to Restic.
+#[derive(Debug, Default)]
+pub struct SnapshotFilter {
+    /// Only include snapshots with these tags. Each entry may be a comma-separated list of tags that must all be present.
+    pub tags: Vec<String>,
+
+    /// Only include snapshots from this host
+    pub host: Option<String>,
+
+    /// Only include snapshots containing these paths
+    pub paths: Vec<String>,
+}
+
+impl SnapshotFilter {
+    /// Whether the filter has no restrictions, and so matches every snapshot
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty() && self.host.is_none() && self.paths.is_empty()
+    }
+
+    /// Adds the restrictions to a Restic command that selects snapshots
+    pub fn add_args(&self, cmd: &mut Command) {
+        for tag in self.tags.iter() {
+            cmd.arg("--tag").arg(tag);
+        }
+        if let Some(ref host) = self.host {
+            cmd.arg("--host").arg(host);
+        }
+        for path in self.paths.iter() {
+            cmd.arg("--path").arg(path);
+        }
+    }
+}
 
 /// Extends the Restic wrapper with snapshot commands
 impl<'a> Restic<'a> {
-    /// List snapshots to stdout. This is a simple wrapper around the `restic snapshots` command.
-    /// Extra args are added directly to the command line.
-    pub fn dump_snapshots(&self, extra_args: &[OsString]) -> Result<()> {
+    /// Lists snapshots in the repository matching `filter`, oldest first. Extra args are added directly to the
+    /// `restic snapshots` command line.
+    pub fn list_snapshots(
+        &self,
+        filter: &SnapshotFilter,
+        extra_args: &[OsString],
+    ) -> Result<Vec<Snapshot>> {
         let mut cmd = self.new_command();
-        cmd.arg("snapshots");
+        cmd.arg("snapshots").arg("--json");
+        filter.add_args(&mut cmd);
         cmd.args(extra_args);
 
-        debug!(self.logger(), "Listing snapshots"; "command" => ?cmd);
-        cmd.status()
-            .with_context(|| format!("Could not run {:?}", cmd))?;
+        debug!(self.logger(), "Listing snapshots"; "command" => %redact::command_line(&cmd));
+        let stdout = self
+            .execute_output(&mut cmd)
+            .context("Listing snapshots failed")?;
+
+        json::from_str(&stdout).context("Could not parse `restic snapshots` output")
+    }
+
+    /// Prints snapshots matching `filter` to stdout, either as a table or as JSON.
+    pub fn show_snapshots(
+        &self,
+        filter: &SnapshotFilter,
+        extra_args: &[OsString],
+        output: OutputFormat,
+    ) -> Result<()> {
+        let snapshots = self.list_snapshots(filter, extra_args)?;
+        match output {
+            OutputFormat::Json => output::print_json(&snapshots),
+            OutputFormat::Table => print_table(&snapshots),
+        }
+    }
+}
+
+fn print_table(snapshots: &[Snapshot]) -> Result<()> {
+    let now = Utc::now();
+    let mut tw = tabwriter::TabWriter::new(io::stdout());
+    writeln!(tw, "ID\tTime\tAge\tHost\tTags\tSize\tPaths")?;
+    writeln!(tw, "--\t----\t---\t----\t----\t----\t-----")?;
+    for snapshot in snapshots {
+        let age = (now - snapshot.time).to_std().unwrap_or_default();
+        writeln!(
+            tw,
+            "{}\t{}\t{} ago\t{}\t{}\t{}\t{}",
+            snapshot.short_id(),
+            snapshot
+                .time
+                .with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M:%S"),
+            format_duration(age),
+            snapshot.hostname,
+            snapshot.tags.join(","),
+            snapshot
+                .summary
+                .as_ref()
+                .map(|summary| format_bytes(summary.total_bytes_processed))
+                .unwrap_or_default(),
+            snapshot.paths.join(", "),
+        )?;
+    }
+    tw.flush()?;
+    println!("{} snapshots", snapshots.len());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
 
-        Ok(())
+    #[test]
+    fn test_parse_snapshots() {
+        let output = r#"[{"time":"2020-08-01T12:30:00.123456789-04:00","tree":"abc","paths":["/home/ben"],"hostname":"laptop","username":"ben","id":"0123456789abcdef","short_id":"01234567"},{"time":"2020-08-02T08:00:00Z","parent":"0123456789abcdef","tree":"def","paths":["/home/ben"],"hostname":"laptop","tags":["daily"],"id":"fedcba9876543210","summary":{"data_added":10,"total_files_processed":2,"total_bytes_processed":2048}}]"#;
+        let snapshots: Vec<Snapshot> = json::from_str(output).unwrap();
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(snapshots[0].short_id(), "01234567");
+        assert_eq!(
+            snapshots[0].time.to_rfc3339(),
+            "2020-08-01T16:30:00.123456789+00:00"
+        );
+        assert!(snapshots[0].tags.is_empty());
+        assert_eq!(snapshots[1].tags, vec!["daily".to_string()]);
+        assert_eq!(
+            snapshots[1].summary.as_ref().unwrap().total_bytes_processed,
+            2048
+        );
     }
 }
@@ -0,0 +1,309 @@
+//! Local record of past Rustic runs, so questions like "when did this profile last back up?" can be answered without
+//! querying the repository.
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use slog::{debug, warn};
+use tempfile::NamedTempFile;
+
+use crate::config::Configuration;
+use crate::json;
+use crate::restic::Restic;
+use crate::stats::RepositoryStats;
+
+/// How many runs of each operation to keep for each profile
+const MAX_RUNS_PER_OPERATION: usize = 100;
+
+/// Outcome of a single operation on a profile
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunRecord {
+    /// Name of the profile
+    pub profile: String,
+
+    /// Operation that was run, such as `backup` or `prune`
+    pub operation: String,
+
+    /// When the operation started
+    pub started_at: DateTime<Utc>,
+
+    /// When the operation finished
+    pub finished_at: DateTime<Utc>,
+
+    /// Whether the operation succeeded
+    pub success: bool,
+
+    /// Repository the operation ran against, which is only different from the profile's `repository` if it failed over
+    #[serde(default)]
+    pub repository: Option<String>,
+
+    /// Restic's exit code, if it ran to completion
+    #[serde(default)]
+    pub exit_code: Option<i32>,
+
+    /// ID of the snapshot that was created, for backups
+    #[serde(default)]
+    pub snapshot_id: Option<String>,
+
+    /// Bytes added to the repository, for backups
+    #[serde(default)]
+    pub bytes_added: Option<u64>,
+
+    /// Number of files in the snapshot, for backups
+    #[serde(default)]
+    pub files: Option<u64>,
+
+    /// Error message, if the operation failed
+    #[serde(default)]
+    pub error: Option<String>,
+
+    /// Repository statistics, for `rustic stats`
+    #[serde(default)]
+    pub stats: Option<RepositoryStats>,
+}
+
+impl RunRecord {
+    /// Creates a record for an operation that just finished. The operation succeeded if there is no error.
+    pub fn finished(
+        profile: &str,
+        operation: &str,
+        started_at: DateTime<Utc>,
+        exit_code: Option<i32>,
+        error: Option<String>,
+    ) -> RunRecord {
+        RunRecord {
+            profile: profile.to_string(),
+            operation: operation.to_string(),
+            started_at,
+            finished_at: Utc::now(),
+            success: error.is_none(),
+            repository: None,
+            exit_code,
+            snapshot_id: None,
+            bytes_added: None,
+            files: None,
+            error,
+            stats: None,
+        }
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct State {
+    #[serde(default)]
+    runs: Vec<RunRecord>,
+
+    /// Subset of pack data each profile last checked successfully, for rotating `check.read_data_subset`
+    #[serde(default)]
+    check_subsets: BTreeMap<String, String>,
+}
+
+/// Persistent store of run history, backed by a JSON file
+pub struct StateStore {
+    path: PathBuf,
+    state: State,
+}
+
+impl StateStore {
+    /// Opens the state store for a configuration. If the state file doesn't exist yet, the store starts out empty.
+    pub fn open(config: &Configuration) -> Result<StateStore> {
+        let path = state_file(config)?;
+
+        let state = match fs::read_to_string(&path) {
+            Ok(contents) => json::from_str(&contents)
+                .with_context(|| format!("Could not parse state file {}", path.display()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => State::default(),
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("Could not read state file {}", path.display()))
+            }
+        };
+
+        Ok(StateStore { path, state })
+    }
+
+    /// Path to the backing state file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// All runs for a profile, from oldest to newest
+    pub fn runs_for_profile<'s>(
+        &'s self,
+        profile: &'s str,
+    ) -> impl DoubleEndedIterator<Item = &'s RunRecord> + 's {
+        self.state
+            .runs
+            .iter()
+            .filter(move |run| run.profile == profile)
+    }
+
+    /// Runs of an operation for a profile, from oldest to newest
+    pub fn runs<'s>(
+        &'s self,
+        profile: &'s str,
+        operation: &'s str,
+    ) -> impl DoubleEndedIterator<Item = &'s RunRecord> + 's {
+        self.state
+            .runs
+            .iter()
+            .filter(move |run| run.profile == profile && run.operation == operation)
+    }
+
+    /// The most recent successful run of an operation for a profile
+    pub fn last_success(&self, profile: &str, operation: &str) -> Option<&RunRecord> {
+        self.state
+            .runs
+            .iter()
+            .rev()
+            .find(|run| run.profile == profile && run.operation == operation && run.success)
+    }
+
+    /// The subset of pack data, like `3/10`, that the last successful check of a profile with `check.rotate_subset` read
+    pub fn last_check_subset(&self, profile: &str) -> Option<&str> {
+        self.state.check_subsets.get(profile).map(String::as_str)
+    }
+
+    /// Saves the subset of pack data that a profile's check just read successfully
+    pub fn set_check_subset(&mut self, profile: &str, subset: &str) -> Result<()> {
+        self.state
+            .check_subsets
+            .insert(profile.to_string(), subset.to_string());
+        self.save()
+    }
+
+    /// Adds a run to the store and saves it, discarding the oldest runs of the same operation if there are too many.
+    pub fn record(&mut self, run: RunRecord) -> Result<()> {
+        let count = self.runs(&run.profile, &run.operation).count();
+        if count >= MAX_RUNS_PER_OPERATION {
+            let mut to_remove = count + 1 - MAX_RUNS_PER_OPERATION;
+            let (profile, operation) = (run.profile.clone(), run.operation.clone());
+            self.state.runs.retain(|existing| {
+                if to_remove > 0 && existing.profile == profile && existing.operation == operation {
+                    to_remove -= 1;
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+
+        self.state.runs.push(run);
+        self.save()
+    }
+
+    fn save(&self) -> Result<()> {
+        let directory = self
+            .path
+            .parent()
+            .ok_or_else(|| anyhow!("Invalid state file path {}", self.path.display()))?;
+        fs::create_dir_all(directory)
+            .with_context(|| format!("Could not create {}", directory.display()))?;
+
+        let contents = json::to_string_pretty(&self.state)?;
+        let mut file = NamedTempFile::new_in(directory)
+            .with_context(|| format!("Could not create file in {}", directory.display()))?;
+        file.write_all(contents.as_bytes())
+            .context("Could not write state file")?;
+        file.persist(&self.path)
+            .with_context(|| format!("Could not replace {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+/// Location of the state file: the configuration's `state_file`, or `rustic/state.json` in the local data directory
+pub fn state_file(config: &Configuration) -> Result<PathBuf> {
+    match config.state_file {
+        Some(ref path) => Ok(path.clone()),
+        None => dirs::data_local_dir()
+            .map(|dir| dir.join("rustic").join("state.json"))
+            .ok_or_else(|| anyhow!("Could not determine a location for the state file, set `state_file` in the configuration")),
+    }
+}
+
+/// Location of the lock file that serializes updates to the state file: the state file's path with `.lock` appended
+pub fn state_lock_file(config: &Configuration) -> Result<PathBuf> {
+    let mut path = OsString::from(state_file(config)?);
+    path.push(".lock");
+    Ok(PathBuf::from(path))
+}
+
+/// Locks the state file against other updates, from this process or others, so that backups running in parallel don't
+/// overwrite each other's runs. The state file itself is replaced on every save, so the lock is on a separate file next to
+/// it. It's released when the returned file is dropped.
+fn lock_state(config: &Configuration) -> Result<File> {
+    let path = state_lock_file(config)?;
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)
+            .with_context(|| format!("Could not create {}", directory.display()))?;
+    }
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("Could not open lock file {}", path.display()))?;
+    file.lock()
+        .with_context(|| format!("Could not lock {}", path.display()))?;
+    Ok(file)
+}
+
+/// Extends the Restic wrapper with run history
+impl<'a> Restic<'a> {
+    /// Saves the outcome of an operation to the state store, unless this is a dry run. Failures are logged, but otherwise
+    /// ignored, since they shouldn't cause the operation itself to fail.
+    pub fn record_run(&self, run: RunRecord) {
+        if self.is_dry_run() {
+            return;
+        }
+        let result = lock_state(self.config()).and_then(|_lock| {
+            let mut store = StateStore::open(self.config())?;
+            debug!(self.logger(), "Recording run"; "path" => %store.path().display(), "operation" => &run.operation);
+            store.record(run)
+        });
+        if let Err(err) = result {
+            warn!(self.logger(), "Could not record run history: {:#}", err);
+        }
+    }
+
+    /// Saves the subset of pack data a successful check read, so the next check moves on to the next subset. Failures are
+    /// logged, but otherwise ignored, like in [`record_run`](Restic::record_run).
+    pub fn record_check_subset(&self, subset: &str) {
+        if self.is_dry_run() {
+            return;
+        }
+        let result = lock_state(self.config()).and_then(|_lock| {
+            StateStore::open(self.config())?.set_check_subset(self.profile_name(), subset)
+        });
+        if let Err(err) = result {
+            warn!(self.logger(), "Could not record checked subset: {:#}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{File, TryLockError};
+
+    use super::{lock_state, state_lock_file};
+    use crate::test::TestFixture;
+
+    #[test]
+    fn test_lock_state() {
+        let mut fixture = TestFixture::new();
+        let config = &*fixture.config_mut();
+        let lock = lock_state(config).unwrap();
+
+        // Another open file description, like another process would have, can't lock it until the first is released
+        let other = File::open(state_lock_file(config).unwrap()).unwrap();
+        assert!(matches!(other.try_lock(), Err(TryLockError::WouldBlock)));
+        drop(lock);
+        other.try_lock().unwrap();
+    }
+}
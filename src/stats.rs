@@ -0,0 +1,281 @@
+//! Repository size statistics
+
+use std::io::{self, prelude::*};
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Error, Result};
+use chrono::{Local, Utc};
+use serde::{Deserialize, Serialize};
+use slog::debug;
+
+use crate::format::format_bytes;
+use crate::json;
+use crate::output::{self, OutputFormat};
+use crate::redact;
+use crate::restic::Restic;
+use crate::state::{RunRecord, StateStore};
+
+/// Ways of counting repository size, matching `restic stats --mode`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StatsMode {
+    /// Size of all files in all snapshots, as if they were restored
+    RestoreSize,
+
+    /// Size of the deduplicated (and possibly compressed) data actually stored in the repository
+    RawData,
+}
+
+impl StatsMode {
+    fn flag(self) -> &'static str {
+        match self {
+            StatsMode::RestoreSize => "restore-size",
+            StatsMode::RawData => "raw-data",
+        }
+    }
+}
+
+impl FromStr for StatsMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<StatsMode> {
+        match s {
+            "restore-size" => Ok(StatsMode::RestoreSize),
+            "raw-data" => Ok(StatsMode::RawData),
+            _ => bail!(
+                "Unknown stats mode `{}`, expected `restore-size` or `raw-data`",
+                s
+            ),
+        }
+    }
+}
+
+/// Output of `restic stats --json`. Which fields are set depends on the mode.
+#[derive(Deserialize)]
+struct ResticStats {
+    total_size: u64,
+    #[serde(default)]
+    total_file_count: Option<u64>,
+    #[serde(default)]
+    total_blob_count: Option<u64>,
+    #[serde(default)]
+    total_uncompressed_size: Option<u64>,
+    #[serde(default)]
+    compression_ratio: Option<f64>,
+    #[serde(default)]
+    snapshots_count: Option<u64>,
+}
+
+/// Size statistics for a repository, combining the results of each mode that was run
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RepositoryStats {
+    /// Number of snapshots in the repository
+    #[serde(default)]
+    pub snapshot_count: Option<u64>,
+
+    /// Total size of all files in all snapshots
+    #[serde(default)]
+    pub restore_size: Option<u64>,
+
+    /// Number of files in all snapshots
+    #[serde(default)]
+    pub file_count: Option<u64>,
+
+    /// Size of the data stored in the repository
+    #[serde(default)]
+    pub raw_data_size: Option<u64>,
+
+    /// Size of the data stored in the repository, before compression
+    #[serde(default)]
+    pub uncompressed_size: Option<u64>,
+
+    /// Number of blobs stored in the repository
+    #[serde(default)]
+    pub blob_count: Option<u64>,
+
+    /// Compression ratio of the stored data
+    #[serde(default)]
+    pub compression_ratio: Option<f64>,
+}
+
+/// Machine-readable output of `rustic stats`
+#[derive(Serialize)]
+struct StatsReport<'a> {
+    profile: &'a str,
+    current: &'a RepositoryStats,
+    previous: Option<&'a RunRecord>,
+}
+
+/// Extends the Restic wrapper with repository statistics
+impl<'a> Restic<'a> {
+    /// Gathers repository statistics in the given modes.
+    pub fn stats(&self, modes: &[StatsMode]) -> Result<RepositoryStats> {
+        let mut stats = RepositoryStats::default();
+        for mode in modes {
+            let mut cmd = self.new_command();
+            cmd.arg("stats")
+                .arg("--json")
+                .arg("--mode")
+                .arg(mode.flag());
+            debug!(self.logger(), "Gathering repository statistics"; "command" => %redact::command_line(&cmd));
+
+            let output = self
+                .execute_output(&mut cmd)
+                .with_context(|| format!("Could not get {} statistics", mode.flag()))?;
+            let result: ResticStats =
+                json::from_str(&output).context("Could not parse `restic stats` output")?;
+
+            stats.snapshot_count = result.snapshots_count.or(stats.snapshot_count);
+            match mode {
+                StatsMode::RestoreSize => {
+                    stats.restore_size = Some(result.total_size);
+                    stats.file_count = result.total_file_count;
+                }
+                StatsMode::RawData => {
+                    stats.raw_data_size = Some(result.total_size);
+                    stats.uncompressed_size = result.total_uncompressed_size;
+                    stats.blob_count = result.total_blob_count;
+                    stats.compression_ratio = result.compression_ratio;
+                }
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Prints repository statistics, along with how much the repository has grown since the last time statistics were
+    /// gathered. The statistics are saved in the state file for the next comparison.
+    pub fn show_stats(&self, modes: &[StatsMode], output: OutputFormat) -> Result<()> {
+        let previous = StateStore::open(self.config())?
+            .last_success(self.profile_name(), "stats")
+            .cloned();
+
+        let started_at = Utc::now();
+        let stats = match self.stats(modes) {
+            Ok(stats) => stats,
+            Err(err) => {
+                self.record_run(RunRecord::finished(
+                    self.profile_name(),
+                    "stats",
+                    started_at,
+                    None,
+                    Some(format!("{:#}", err)),
+                ));
+                return Err(err);
+            }
+        };
+        let mut run = RunRecord::finished(self.profile_name(), "stats", started_at, Some(0), None);
+        run.stats = Some(stats.clone());
+        self.record_run(run);
+
+        match output {
+            OutputFormat::Json => output::print_json(&StatsReport {
+                profile: self.profile_name(),
+                current: &stats,
+                previous: previous.as_ref(),
+            }),
+            OutputFormat::Table => print_table(&stats, previous.as_ref()),
+        }
+    }
+}
+
+fn print_table(stats: &RepositoryStats, previous: Option<&RunRecord>) -> Result<()> {
+    let previous_stats = previous.and_then(|run| run.stats.as_ref());
+
+    let mut tw = tabwriter::TabWriter::new(io::stdout());
+    writeln!(tw, "Statistic\tValue\tChange")?;
+    writeln!(tw, "---------\t-----\t------")?;
+
+    let rows = [
+        (
+            "Snapshots",
+            stats.snapshot_count,
+            previous_stats.and_then(|p| p.snapshot_count),
+            false,
+        ),
+        (
+            "Restore size",
+            stats.restore_size,
+            previous_stats.and_then(|p| p.restore_size),
+            true,
+        ),
+        (
+            "Files",
+            stats.file_count,
+            previous_stats.and_then(|p| p.file_count),
+            false,
+        ),
+        (
+            "Stored size",
+            stats.raw_data_size,
+            previous_stats.and_then(|p| p.raw_data_size),
+            true,
+        ),
+        (
+            "Uncompressed size",
+            stats.uncompressed_size,
+            previous_stats.and_then(|p| p.uncompressed_size),
+            true,
+        ),
+        (
+            "Blobs",
+            stats.blob_count,
+            previous_stats.and_then(|p| p.blob_count),
+            false,
+        ),
+    ];
+    for (name, current, previous, bytes) in rows.iter() {
+        let current = match current {
+            Some(current) => *current,
+            None => continue,
+        };
+        let format = |n: u64| {
+            if *bytes {
+                format_bytes(n)
+            } else {
+                n.to_string()
+            }
+        };
+        let change = match previous {
+            Some(previous) if *previous > current => format!("-{}", format(previous - current)),
+            Some(previous) => format!("+{}", format(current - previous)),
+            None => String::new(),
+        };
+        writeln!(tw, "{}\t{}\t{}", name, format(current), change)?;
+    }
+    if let Some(ratio) = stats.compression_ratio {
+        writeln!(tw, "Compression ratio\t{:.2}x\t", ratio)?;
+    }
+    tw.flush()?;
+
+    if let Some(run) = previous {
+        println!(
+            "Changes are since {}",
+            run.finished_at
+                .with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M:%S")
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::StatsMode;
+    use crate::output::OutputFormat;
+    use crate::state::StateStore;
+    use crate::test::TestFixture;
+
+    #[test]
+    fn test_stats_dry_run() {
+        let fixture = TestFixture::new();
+        let mut restic = fixture.restic();
+        restic.set_dry_run(true);
+
+        // Statistics need Restic's output, so a dry run stops before showing them, and doesn't record the failure either
+        assert!(restic
+            .show_stats(&[StatsMode::RestoreSize], OutputFormat::Json)
+            .is_err());
+        let store = StateStore::open(restic.config()).unwrap();
+        assert_eq!(store.runs_for_profile("test").count(), 0);
+    }
+}
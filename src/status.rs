@@ -0,0 +1,108 @@
+//! Summarizing how recently each profile was backed up
+
+use std::io::{self, prelude::*};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Local, Utc};
+use serde::Serialize;
+
+use crate::config::Configuration;
+use crate::format::format_duration;
+use crate::output::{self, OutputFormat};
+use crate::state::StateStore;
+
+/// Backup status of a single profile
+#[derive(Serialize)]
+struct ProfileStatus<'a> {
+    profile: &'a str,
+    last_backup: Option<DateTime<Utc>>,
+    age_seconds: Option<u64>,
+    max_age_seconds: Option<u64>,
+    status: &'static str,
+}
+
+/// Prints the time of each profile's last successful backup, based on the local run history. Returns an error if any profile's
+/// last backup is older than its `max_age`, so this can be used as a monitoring probe.
+pub fn show_status(config: &Configuration, output: OutputFormat) -> Result<()> {
+    let store = StateStore::open(config)?;
+    let now = Utc::now();
+
+    let mut names: Vec<&String> = config.profiles.keys().collect();
+    names.sort();
+
+    let statuses: Vec<ProfileStatus> = names
+        .into_iter()
+        .map(|name| {
+            let max_age = config.profiles[name].max_age;
+            let last_backup = store
+                .last_success(name, "backup")
+                .map(|run| run.finished_at);
+            let age = last_backup.map(|time| (now - time).to_std().unwrap_or_default());
+            let status = match (age, max_age) {
+                (Some(age), Some(max_age)) if age > max_age => "stale",
+                (None, Some(_)) => "stale",
+                (None, None) => "never",
+                _ => "ok",
+            };
+            ProfileStatus {
+                profile: name,
+                last_backup,
+                age_seconds: age.map(|age| age.as_secs()),
+                max_age_seconds: max_age.map(|max_age| max_age.as_secs()),
+                status,
+            }
+        })
+        .collect();
+
+    match output {
+        OutputFormat::Json => output::print_json(&statuses)?,
+        OutputFormat::Table => print_table(&statuses)?,
+    }
+
+    let stale: Vec<&str> = statuses
+        .iter()
+        .filter(|status| status.status == "stale")
+        .map(|status| status.profile)
+        .collect();
+    if !stale.is_empty() {
+        bail!(
+            "Profiles have not been backed up recently: {}",
+            stale.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+fn print_table(statuses: &[ProfileStatus]) -> Result<()> {
+    let mut tw = tabwriter::TabWriter::new(io::stdout());
+    writeln!(tw, "Profile\tLast Backup\tAge\tMax Age\tStatus")?;
+    writeln!(tw, "-------\t-----------\t---\t-------\t------")?;
+    for status in statuses {
+        writeln!(
+            tw,
+            "{}\t{}\t{}\t{}\t{}",
+            status.profile,
+            status
+                .last_backup
+                .map(|time| time
+                    .with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string())
+                .unwrap_or_else(|| "never".to_string()),
+            status
+                .age_seconds
+                .map(|secs| format_duration(Duration::from_secs(secs)))
+                .unwrap_or_default(),
+            status
+                .max_age_seconds
+                .map(|secs| format_duration(Duration::from_secs(secs)))
+                .unwrap_or_default(),
+            status.status
+        )?;
+    }
+    tw.flush()?;
+
+    Ok(())
+}
@@ -0,0 +1,174 @@
+//! Generating systemd units for scheduled backups
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use slog::{info, Logger};
+
+use crate::config::Configuration;
+use crate::error::RusticError;
+
+/// Where to install units
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnitScope {
+    /// The current user's systemd instance
+    User,
+    /// The system-wide systemd instance
+    System,
+}
+
+impl UnitScope {
+    fn directory(self) -> Result<PathBuf> {
+        match self {
+            UnitScope::User => dirs::config_dir()
+                .map(|dir| dir.join("systemd").join("user"))
+                .ok_or_else(|| anyhow!("Could not determine user configuration directory")),
+            UnitScope::System => Ok(PathBuf::from("/etc/systemd/system")),
+        }
+    }
+
+    fn systemctl(self) -> &'static str {
+        match self {
+            UnitScope::User => "systemctl --user",
+            UnitScope::System => "systemctl",
+        }
+    }
+}
+
+/// Writes a `rustic-backup@<profile>.service` unit that backs up the profile, and a matching timer that runs it on the
+/// profile's `schedule`. The timer is persistent, so a backup missed while the machine was off or asleep runs when it comes
+/// back, after the profile's `schedule_jitter`. If `dry_run` is true, the units are printed to stdout instead.
+pub fn install_units(
+    logger: &Logger,
+    config: &Configuration,
+    config_path: &Path,
+    profile_name: &str,
+    scope: UnitScope,
+    dry_run: bool,
+) -> Result<()> {
+    let profile = match config.profiles.get(profile_name) {
+        Some(profile) => profile,
+        None => bail!(RusticError::ProfileNotFound(profile_name.to_string())),
+    };
+    let schedule = match profile.schedule {
+        Some(ref schedule) => schedule,
+        None => bail!("Profile `{}` does not have a `schedule`", profile_name),
+    };
+
+    let executable = std::env::current_exe().context("Could not find the Rustic executable")?;
+    let config_path = config_path.canonicalize().with_context(|| {
+        format!(
+            "Could not resolve configuration file {}",
+            config_path.display()
+        )
+    })?;
+
+    let unit_name = format!("rustic-backup@{}", profile_name);
+    let service = service_unit(&executable, &config_path, profile_name);
+    let timer = timer_unit(profile_name, schedule, profile.schedule_jitter);
+
+    if dry_run {
+        println!("# {}.service\n{}", unit_name, service);
+        println!("# {}.timer\n{}", unit_name, timer);
+        return Ok(());
+    }
+
+    let directory = scope.directory()?;
+    fs::create_dir_all(&directory)
+        .with_context(|| format!("Could not create {}", directory.display()))?;
+    for (extension, contents) in [("service", &service), ("timer", &timer)].iter() {
+        let path = directory.join(format!("{}.{}", unit_name, extension));
+        fs::write(&path, contents)
+            .with_context(|| format!("Could not write {}", path.display()))?;
+        info!(logger, "Wrote unit file"; "path" => %path.display());
+    }
+
+    println!(
+        "Installed units. To enable scheduled backups, run:\n    {0} daemon-reload\n    {0} enable --now {1}.timer",
+        scope.systemctl(),
+        unit_name
+    );
+    Ok(())
+}
+
+fn service_unit(executable: &Path, config_path: &Path, profile_name: &str) -> String {
+    format!(
+        "[Unit]
+Description=Rustic backup of profile {profile}
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=oneshot
+ExecStart={executable} --config {config} backup {profile_arg}
+",
+        profile = escape_specifiers(profile_name),
+        executable = quote(&executable.display().to_string()),
+        config = quote(&config_path.display().to_string()),
+        profile_arg = quote(profile_name),
+    )
+}
+
+fn timer_unit(profile_name: &str, schedule: &str, jitter: Option<Duration>) -> String {
+    format!(
+        "[Unit]
+Description=Scheduled Rustic backup of profile {profile}
+
+[Timer]
+OnCalendar={schedule}
+Persistent=true
+{jitter}
+[Install]
+WantedBy=timers.target
+",
+        profile = escape_specifiers(profile_name),
+        schedule = schedule,
+        jitter = jitter
+            .map(|jitter| format!("RandomizedDelaySec={}\n", jitter.as_secs()))
+            .unwrap_or_default(),
+    )
+}
+
+/// Escapes `%`, which systemd treats as the start of a specifier
+fn escape_specifiers(value: &str) -> String {
+    value.replace('%', "%%")
+}
+
+/// Quotes a command-line argument for an `ExecStart` line
+fn quote(arg: &str) -> String {
+    let escaped = escape_specifiers(arg)
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"");
+    format!("\"{}\"", escaped)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_service_unit() {
+        let unit = service_unit(
+            Path::new("/usr/local/bin/rustic"),
+            Path::new("/etc/rustic/my config.toml"),
+            "100%",
+        );
+        assert!(unit.contains("Description=Rustic backup of profile 100%%\n"));
+        assert!(unit.contains(
+            "ExecStart=\"/usr/local/bin/rustic\" --config \"/etc/rustic/my config.toml\" backup \"100%%\"\n"
+        ));
+    }
+
+    #[test]
+    fn test_timer_unit() {
+        let unit = timer_unit("home", "*-*-* 03:00:00", None);
+        assert!(unit.contains("OnCalendar=*-*-* 03:00:00\n"));
+        assert!(unit.contains("Persistent=true\n"));
+        assert!(!unit.contains("RandomizedDelaySec"));
+
+        let unit = timer_unit("home", "daily", Some(Duration::from_secs(15 * 60)));
+        assert!(unit.contains("Persistent=true\nRandomizedDelaySec=900\n\n[Install]"));
+    }
+}
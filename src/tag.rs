@@ -0,0 +1,183 @@
+//! Changing the tags on existing snapshots, including pinning snapshots so that retention policies keep them
+
+use std::time::Instant;
+
+use anyhow::{bail, Result};
+use slog::{error, info};
+
+use crate::redact;
+use crate::restic::Restic;
+use crate::snapshots::SnapshotFilter;
+
+/// Tag that `pin` adds, unless the retention policy sets `pin_tag`
+const DEFAULT_PIN_TAG: &str = "pinned";
+
+/// Changes to make to the tags of each selected snapshot. `set` replaces every tag, so it can't be combined with `add` or
+/// `remove`.
+#[derive(Debug, Default)]
+pub struct TagChanges {
+    /// Tags to add
+    pub add: Vec<String>,
+
+    /// Tags to remove
+    pub remove: Vec<String>,
+
+    /// Tags to replace the existing ones with
+    pub set: Vec<String>,
+}
+
+/// Extends the Restic wrapper with snapshot tagging
+impl<'a> Restic<'a> {
+    /// Changes the tags on `snapshots` (IDs, or `latest`) and on every snapshot matching `filter`. At least one snapshot or
+    /// restriction is required, since Restic would otherwise change every snapshot in the repository.
+    pub fn tag(
+        &self,
+        changes: &TagChanges,
+        snapshots: &[String],
+        filter: &SnapshotFilter,
+    ) -> Result<()> {
+        if changes.add.is_empty() && changes.remove.is_empty() && changes.set.is_empty() {
+            bail!("Nothing to change. Pass tags to add, remove, or set.");
+        }
+        if !changes.set.is_empty() && (!changes.add.is_empty() || !changes.remove.is_empty()) {
+            bail!("Tags can't be set and added or removed at the same time");
+        }
+        if snapshots.is_empty() && filter.is_empty() {
+            bail!("No snapshots selected. Pass snapshot IDs or `latest`, or select snapshots by host, path, or tag.");
+        }
+        self.require_full_access("tag")?;
+
+        let mut cmd = self.new_command();
+        cmd.arg("tag");
+        for tag in changes.add.iter() {
+            cmd.arg("--add").arg(tag);
+        }
+        for tag in changes.remove.iter() {
+            cmd.arg("--remove").arg(tag);
+        }
+        for tag in changes.set.iter() {
+            cmd.arg("--set").arg(tag);
+        }
+        filter.add_args(&mut cmd);
+        cmd.args(snapshots);
+
+        info!(self.logger(), "Changing snapshot tags"; "command" => %redact::command_line(&cmd));
+        let start = Instant::now();
+        let status = self
+            .lock_repository()
+            .and_then(|_lock| self.execute(&mut cmd, None))?
+            .status;
+        let duration = Instant::now() - start;
+
+        if status.success() {
+            info!(self.logger(), "Changed snapshot tags in {:?}", duration; "command" => %redact::command_line(&cmd));
+            Ok(())
+        } else {
+            error!(self.logger(), "Changing snapshot tags failed"; "status" => %status, "command" => %redact::command_line(&cmd));
+            Err(self.failed("tag", status).into())
+        }
+    }
+
+    /// Tag marking pinned snapshots, which `forget` always keeps
+    pub fn pin_tag(&self) -> &str {
+        self.profile()
+            .retention
+            .pin_tag
+            .as_deref()
+            .unwrap_or(DEFAULT_PIN_TAG)
+    }
+
+    /// Pins `snapshots` (IDs, or `latest`), so that they're kept no matter what the retention policy says
+    pub fn pin(&self, snapshots: &[String]) -> Result<()> {
+        let changes = TagChanges {
+            add: vec![self.pin_tag().to_string()],
+            ..TagChanges::default()
+        };
+        self.tag(&changes, snapshots, &SnapshotFilter::default())
+    }
+
+    /// Unpins `snapshots`, leaving them to the retention policy again
+    pub fn unpin(&self, snapshots: &[String]) -> Result<()> {
+        let changes = TagChanges {
+            remove: vec![self.pin_tag().to_string()],
+            ..TagChanges::default()
+        };
+        self.tag(&changes, snapshots, &SnapshotFilter::default())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TagChanges;
+    use crate::snapshots::SnapshotFilter;
+    use crate::test::TestFixture;
+
+    #[test]
+    fn test_tag() {
+        let fixture = TestFixture::new();
+        let (restic, runner) = fixture.mock_restic();
+
+        let changes = TagChanges {
+            add: vec!["keep".to_string()],
+            ..TagChanges::default()
+        };
+        let filter = SnapshotFilter {
+            host: Some("laptop".to_string()),
+            ..SnapshotFilter::default()
+        };
+        restic
+            .tag(&changes, &["latest".to_string()], &filter)
+            .unwrap();
+        let args = &runner.invocations()[0].args;
+        let tag = args.iter().position(|arg| arg == "tag").unwrap();
+        assert_eq!(
+            args[tag..],
+            ["tag", "--add", "keep", "--host", "laptop", "latest"]
+        );
+
+        // Restic would change every snapshot without a selection
+        assert!(restic
+            .tag(&changes, &[], &SnapshotFilter::default())
+            .is_err());
+        let conflicting = TagChanges {
+            remove: vec!["daily".to_string()],
+            set: vec!["weekly".to_string()],
+            ..TagChanges::default()
+        };
+        assert!(restic
+            .tag(&conflicting, &["latest".to_string()], &filter)
+            .is_err());
+        assert_eq!(runner.invocations().len(), 1);
+    }
+
+    #[test]
+    fn test_pin() {
+        let mut fixture = TestFixture::new();
+        fixture.profile_mut().retention.keep_daily = Some(7);
+        let (restic, runner) = fixture.mock_restic();
+
+        restic.pin(&["0123abcd".to_string()]).unwrap();
+        restic.unpin(&["0123abcd".to_string()]).unwrap();
+        restic.forget(false).unwrap();
+        let invocations = runner.invocations();
+        assert!(invocations[0].args.ends_with(&[
+            "--add".to_string(),
+            "pinned".to_string(),
+            "0123abcd".to_string()
+        ]));
+        assert!(invocations[1].has_arg("--remove"));
+        let forget = &invocations[2].args;
+        let keep_tag = forget.iter().position(|arg| arg == "--keep-tag").unwrap();
+        assert_eq!(forget[keep_tag + 1], "pinned");
+
+        // A policy that already keeps the pin tag doesn't pass it twice
+        drop(restic);
+        fixture.profile_mut().retention.pin_tag = Some("keep".to_string());
+        fixture.profile_mut().retention.keep_tags = vec![vec!["keep".to_string()]];
+        let mut restic = fixture.restic();
+        restic.set_runner(Box::new(runner.clone()));
+        restic.forget(false).unwrap();
+        let forget = &runner.invocations()[3].args;
+        assert_eq!(forget.iter().filter(|arg| *arg == "--keep-tag").count(), 1);
+    }
+}
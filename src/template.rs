@@ -0,0 +1,80 @@
+//! Expanding `{variable}` placeholders in configuration values, so that one configuration file can be shared across machines
+
+use anyhow::{bail, Result};
+
+/// Expands placeholders like `{hostname}` in `template`, using `lookup` to get each variable's value. To include a literal
+/// brace, write it twice (`{{` or `}}`). Unmatched braces are an error, as is any error from `lookup`.
+pub fn expand<F>(template: &str, lookup: F) -> Result<String>
+where
+    F: Fn(&str) -> Result<String>,
+{
+    let mut expanded = String::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                expanded.push('{');
+            }
+            '{' => {
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => name.push(c),
+                        None => bail!("Unclosed `{{` in `{}`", template),
+                    }
+                }
+                expanded.push_str(&lookup(name.trim())?);
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                expanded.push('}');
+            }
+            '}' => bail!(
+                "Unmatched `}}` in `{}` (write `}}}}` for a literal brace)",
+                template
+            ),
+            c => expanded.push(c),
+        }
+    }
+
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::anyhow;
+
+    use super::*;
+
+    fn lookup(name: &str) -> Result<String> {
+        match name {
+            "hostname" => Ok("laptop".to_string()),
+            "user" => Ok("ben".to_string()),
+            _ => Err(anyhow!("Unknown template variable `{}`", name)),
+        }
+    }
+
+    #[test]
+    fn test_expand() {
+        assert_eq!(
+            expand("s3:bucket/{hostname}/{user}", lookup).unwrap(),
+            "s3:bucket/laptop/ben"
+        );
+        assert_eq!(
+            expand("no placeholders", lookup).unwrap(),
+            "no placeholders"
+        );
+        assert_eq!(
+            expand("{{literal}} {{{user}}}", lookup).unwrap(),
+            "{literal} {ben}"
+        );
+        assert_eq!(
+            expand("{hostnme}", lookup).unwrap_err().to_string(),
+            "Unknown template variable `hostnme`"
+        );
+        assert!(expand("{user", lookup).is_err());
+        assert!(expand("user}", lookup).is_err());
+    }
+}
@@ -1,14 +1,19 @@
+// Not every test uses every fixture helper
+#![allow(dead_code)]
+
 use std::collections::HashMap;
 use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
 use slog::{o, Drain, Logger};
-use slog_term::{FullFormat, TermDecorator};
+use slog_term::{FullFormat, PlainSyncDecorator, TermDecorator};
 use tempfile::TempDir;
 
 use crate::config::*;
 use crate::restic::Restic;
+use crate::runner::MockRunner;
 
 pub const TEST_REPOSITORY_PASSWORD: &str = "test-password";
 
@@ -21,6 +26,12 @@ pub struct TestFixture {
     logger: Logger,
 }
 
+impl Default for TestFixture {
+    fn default() -> Self {
+        TestFixture::new()
+    }
+}
+
 impl TestFixture {
     pub fn new() -> TestFixture {
         let root = TempDir::new().expect("Could not create temporary directory");
@@ -30,19 +41,76 @@ impl TestFixture {
 
         let profile = Profile {
             repository: format!("local:{}", repository_path.display()),
+            failover_repositories: Vec::new(),
+            rclone: None,
+            rest: None,
+            s3: None,
+            b2: None,
+            restic_command: None,
+            min_restic_version: None,
             auto_init: false,
+            init: InitOptions::default(),
+            auto_unlock: false,
+            retries: 0,
+            retry_delay: None,
             base_directory: content_root.clone(),
             password: Some(TEST_REPOSITORY_PASSWORD.to_string()),
             password_file: None,
             password_command: None,
+            password_keyring: None,
+            password_op_item: None,
+            password_bitwarden_item: None,
+            password_prompt: false,
+            remember_prompted_password: false,
             environment: HashMap::new(),
             environment_file: None,
+            environment_file_format: None,
+            age_identity_file: None,
+            environment_from_aws_secret: None,
+            tags: Vec::new(),
+            host: None,
             include: Fileset::default(),
             exclude: Fileset::default(),
+            stdin_command: None,
+            stdin_filename: None,
+            sources: Vec::new(),
+            databases: Databases::default(),
+            containers: Containers::default(),
             exclude_caches: false,
+            exclude_if_present: Vec::new(),
+            exclude_larger_than: None,
             one_file_system: false,
             ignore_inode: false,
+            fail_on_partial: false,
+            compression: None,
+            pack_size: None,
+            prevent_sleep: false,
+            niceness: None,
+            io_class: None,
+            limit_upload: None,
+            limit_download: None,
+            bandwidth_schedule: Vec::new(),
             retention: RetentionPolicy::default(),
+            check: CheckOptions::default(),
+            verify: Default::default(),
+            anomaly: Default::default(),
+            sanity: Default::default(),
+            prune: PruneOptions::default(),
+            maintenance: MaintenanceSchedule::default(),
+            hooks: Hooks::default(),
+            copy_to: None,
+            copy_after_backup: false,
+            append_only: false,
+            maintenance_profile: None,
+            monitoring: Monitoring::default(),
+            timeouts: Timeouts::default(),
+            schedule: None,
+            schedule_jitter: None,
+            max_age: None,
+            frequency: None,
+            require_ac_power: false,
+            skip_on_metered: false,
+            preconditions: Default::default(),
         };
 
         let config = Configuration {
@@ -53,7 +121,15 @@ impl TestFixture {
                 profiles
             },
             cache_directory: None,
+            notifications: Notifications::default(),
+            restic_output: ResticOutput::default(),
+            logging: Logging::default(),
+            metrics_directory: None,
+            state_file: Some(root.path().join("state.json")),
+            max_parallel: 1,
+            report_file: None,
             filesets: HashMap::new(),
+            groups: HashMap::new(),
         };
 
         let decorator = TermDecorator::new().build();
@@ -80,6 +156,10 @@ impl TestFixture {
         &self.repository_path
     }
 
+    pub fn config_mut(&mut self) -> &mut Configuration {
+        &mut self.config
+    }
+
     pub fn profile(&self) -> &Profile {
         &self.config.profiles["test"]
     }
@@ -88,7 +168,48 @@ impl TestFixture {
         self.config.profiles.get_mut("test").unwrap()
     }
 
-    pub fn restic(&self) -> Restic {
-        Restic::for_profile(&self.config, &self.logger, "test".to_string()).unwrap()
+    pub fn restic(&self) -> Restic<'_> {
+        self.restic_with_logger(&self.logger)
+    }
+
+    /// Restic wrapper that logs to `logger`, for tests that check what's logged
+    pub fn restic_with_logger(&self, logger: &Logger) -> Restic<'_> {
+        Restic::for_profile(&self.config, logger, "test".to_string(), 0).unwrap()
+    }
+
+    /// Restic wrapper that runs commands with a mock runner instead of Restic, along with the runner for setting up
+    /// responses and checking what ran
+    pub fn mock_restic(&self) -> (Restic<'_>, MockRunner) {
+        let runner = MockRunner::new();
+        let mut restic = self.restic();
+        restic.set_runner(Box::new(runner.clone()));
+        (restic, runner)
+    }
+}
+
+/// Log output captured for a test to check
+#[derive(Clone, Default)]
+pub struct CapturedLog(Arc<Mutex<Vec<u8>>>);
+
+impl CapturedLog {
+    /// Logger that writes to this capture
+    pub fn logger(&self) -> Logger {
+        let decorator = PlainSyncDecorator::new(self.clone());
+        Logger::root(FullFormat::new(decorator).build().fuse(), o!())
+    }
+
+    /// Everything logged so far
+    pub fn contents(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+impl io::Write for CapturedLog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
     }
 }
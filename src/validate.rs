@@ -0,0 +1,466 @@
+//! Validating configuration files without running Restic
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use anyhow::{bail, Result};
+
+use crate::aws;
+use crate::check::parse_fraction;
+use crate::config::{Configuration, Fileset};
+use crate::databases;
+use crate::report;
+use crate::restic::{check_backend_credentials, check_password_source, password_sources};
+
+/// How serious a configuration problem is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Probably a mistake, but Rustic can still run
+    Warning,
+
+    /// Rustic will fail when it uses this part of the configuration
+    Error,
+}
+
+/// A problem found in the configuration
+#[derive(Debug)]
+pub struct Problem {
+    pub severity: Severity,
+    pub message: String,
+}
+
+/// Checks a configuration for problems that would otherwise only show up when running Restic, such as missing filesets or
+/// password files.
+pub fn validate(config: &Configuration) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut error = |message: String| {
+        problems.push(Problem {
+            severity: Severity::Error,
+            message,
+        })
+    };
+
+    let mut fileset_names: Vec<&String> = config.filesets.keys().collect();
+    fileset_names.sort();
+    for name in fileset_names.iter() {
+        check_fileset_references(
+            &config.filesets[*name],
+            &config.filesets,
+            &format!("fileset `{}`", name),
+            &mut error,
+        );
+    }
+    for cycle in fileset_cycles(&config.filesets) {
+        error(format!("Fileset inheritance cycle: {}", cycle.join(" -> ")));
+    }
+
+    if config.max_parallel == 0 {
+        error("`max_parallel` must be at least 1".to_string());
+    }
+
+    if let Some(ref report_file) = config.report_file {
+        if let Err(err) = report::report_path(report_file, "profile", "backup") {
+            error(format!("{:#}", err));
+        }
+    }
+
+    let mut group_names: Vec<&String> = config.groups.keys().collect();
+    group_names.sort();
+    for name in group_names {
+        for member in config.groups[name].iter() {
+            if !config.profiles.contains_key(member) {
+                error(format!(
+                    "group `{}`: profile `{}` does not exist",
+                    name, member
+                ));
+            }
+        }
+    }
+
+    let mut profile_names: Vec<&String> = config.profiles.keys().collect();
+    profile_names.sort();
+    for name in profile_names.iter() {
+        let profile = &config.profiles[*name];
+        let context = format!("profile `{}`", name);
+
+        check_fileset_references(
+            &profile.include,
+            &config.filesets,
+            &format!("{} include", context),
+            &mut error,
+        );
+        check_fileset_references(
+            &profile.exclude,
+            &config.filesets,
+            &format!("{} exclude", context),
+            &mut error,
+        );
+        for (i, source) in profile.sources.iter().enumerate() {
+            let context = format!("{} source {}", context, i + 1);
+            check_fileset_references(
+                &source.include,
+                &config.filesets,
+                &format!("{} include", context),
+                &mut error,
+            );
+            check_fileset_references(
+                &source.exclude,
+                &config.filesets,
+                &format!("{} exclude", context),
+                &mut error,
+            );
+            if source.stdin_command.is_some() && includes_files(&source.include) {
+                error(format!(
+                    "{}: cannot set both `stdin_command` and `include`",
+                    context
+                ));
+            }
+        }
+        if profile.stdin_command.is_some() && includes_files(&profile.include) {
+            error(format!(
+                "{}: cannot set both `stdin_command` and `include`. Add the files as a source instead.",
+                context
+            ));
+        }
+
+        if let Err(err) = check_password_source(profile.password_settings()) {
+            error(format!("{}: {}", context, err));
+        }
+        if let Some(ref password_file) = profile.password_file {
+            check_file_exists(&profile.base_directory, password_file, &context, &mut error);
+        }
+        if let Some(ref environment_file) = profile.environment_file {
+            check_file_exists(
+                &profile.base_directory,
+                environment_file,
+                &context,
+                &mut error,
+            );
+        }
+        if let Err(err) = check_backend_credentials(profile) {
+            error(format!("{}: {}", context, err));
+        }
+        let backend_files = [
+            profile
+                .rest
+                .as_ref()
+                .and_then(|rest| rest.password_file.as_ref()),
+            profile.rest.as_ref().and_then(|rest| rest.ca_cert.as_ref()),
+            profile
+                .rest
+                .as_ref()
+                .and_then(|rest| rest.client_cert.as_ref()),
+            profile
+                .s3
+                .as_ref()
+                .and_then(|s3| s3.secret_access_key_file.as_ref()),
+            profile
+                .b2
+                .as_ref()
+                .and_then(|b2| b2.account_key_file.as_ref()),
+        ];
+        for backend_file in backend_files.iter().flatten() {
+            check_file_exists(&profile.base_directory, backend_file, &context, &mut error);
+        }
+        if let Some(ref age_identity_file) = profile.age_identity_file {
+            check_file_exists(
+                &profile.base_directory,
+                age_identity_file,
+                &context,
+                &mut error,
+            );
+        }
+        if let Some(ref arn) = profile.environment_from_aws_secret {
+            if let Err(err) = aws::secret_args(arn) {
+                error(format!("{}: {}", context, err));
+            }
+        }
+
+        for (kind, database) in databases::all(&profile.databases) {
+            if let Some(ref password_file) = database.password_file {
+                check_file_exists(
+                    &profile.base_directory,
+                    password_file,
+                    &format!("{} {} database `{}`", context, kind.name(), database.name),
+                    &mut error,
+                );
+            }
+        }
+
+        for container in profile.containers.pause.iter() {
+            if profile.containers.stop.contains(container) {
+                error(format!(
+                    "{}: container `{}` is listed in both `containers.pause` and `containers.stop`",
+                    context, container
+                ));
+            }
+        }
+
+        if let Some(ref copy_to) = profile.copy_to {
+            if let Err(err) = check_password_source(copy_to.password_settings()) {
+                error(format!("{} copy_to: {}", context, err));
+            }
+            if let Some(ref password_file) = copy_to.password_file {
+                check_file_exists(
+                    &profile.base_directory,
+                    password_file,
+                    &format!("{} copy_to", context),
+                    &mut error,
+                );
+            }
+        }
+
+        if let Some(ref maintenance) = profile.maintenance_profile {
+            match config.profiles.get(maintenance) {
+                None => error(format!(
+                    "{}: `maintenance_profile` refers to profile `{}`, which does not exist",
+                    context, maintenance
+                )),
+                Some(other) if other.append_only => error(format!(
+                    "{}: maintenance profile `{}` is append-only itself",
+                    context, maintenance
+                )),
+                Some(other) if other.repository != profile.repository => error(format!(
+                    "{}: maintenance profile `{}` uses a different repository",
+                    context, maintenance
+                )),
+                Some(_) => (),
+            }
+        }
+
+        if let Some(ref source) = profile.init.copy_chunker_params_from {
+            if !config.profiles.contains_key(source) {
+                error(format!(
+                    "{}: `init.copy_chunker_params_from` refers to profile `{}`, which does not exist",
+                    context, source
+                ));
+            }
+        }
+
+        if let Some(ref pin_tag) = profile.retention.pin_tag {
+            if pin_tag.is_empty() || pin_tag.contains(',') {
+                error(format!(
+                    "{}: `retention.pin_tag` must be a single, non-empty tag",
+                    context
+                ));
+            }
+        }
+
+        if profile.check.read_data && profile.check.read_data_subset.is_some() {
+            error(format!(
+                "{}: cannot set both `check.read_data` and `check.read_data_subset`",
+                context
+            ));
+        }
+        if profile.check.rotate_subset
+            && profile
+                .check
+                .read_data_subset
+                .as_deref()
+                .and_then(parse_fraction)
+                .is_none()
+        {
+            error(format!(
+                "{}: `check.rotate_subset` requires `check.read_data_subset` to be a fraction like `1/10`",
+                context
+            ));
+        }
+        if profile
+            .anomaly
+            .max_deviation
+            .is_some_and(|deviation| deviation <= 0.0 || !deviation.is_finite())
+        {
+            error(format!(
+                "{}: `anomaly.max_deviation` must be a number greater than 0",
+                context
+            ));
+        }
+    }
+
+    for name in profile_names {
+        if config.profiles[name].retention.is_empty() {
+            problems.push(Problem {
+                severity: Severity::Warning,
+                message: format!(
+                    "profile `{}`: retention policy is empty, so `rustic forget` will not forget any snapshots",
+                    name
+                ),
+            });
+        }
+        if password_sources(config.profiles[name].password_settings()).is_empty() {
+            problems.push(Problem {
+                severity: Severity::Warning,
+                message: format!(
+                    "profile `{}`: no password source is set, so Rustic will prompt for the password. Set `password_prompt = true` if that's intended.",
+                    name
+                ),
+            });
+        }
+    }
+
+    problems
+}
+
+/// Validates the configuration and prints any problems found. Returns an error if there are any errors, but not if there are
+/// only warnings.
+pub fn check_config(config: &Configuration) -> Result<()> {
+    let problems = validate(config);
+    for problem in problems.iter() {
+        let label = match problem.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        println!("{}: {}", label, problem.message);
+    }
+
+    let errors = problems
+        .iter()
+        .filter(|problem| problem.severity == Severity::Error)
+        .count();
+    if errors > 0 {
+        bail!("Configuration has {} errors", errors);
+    }
+
+    if problems.is_empty() {
+        println!("Configuration is valid");
+    }
+    Ok(())
+}
+
+/// Whether a fileset includes any files, directly or through inheritance
+fn includes_files(fileset: &Fileset) -> bool {
+    !fileset.patterns.is_empty() || !fileset.inherits.is_empty()
+}
+
+fn check_fileset_references(
+    fileset: &Fileset,
+    filesets: &HashMap<String, Fileset>,
+    context: &str,
+    error: &mut impl FnMut(String),
+) {
+    for inherited in fileset.inherits.iter() {
+        if !filesets.contains_key(inherited) {
+            error(format!(
+                "{} inherits from fileset `{}`, which does not exist",
+                context, inherited
+            ));
+        }
+    }
+}
+
+fn check_file_exists<P: AsRef<Path>>(
+    base_directory: &Path,
+    file: P,
+    context: &str,
+    error: &mut impl FnMut(String),
+) {
+    // Restic runs in the base directory, so relative paths are resolved against it
+    let path = base_directory.join(file);
+    if !path.is_file() {
+        error(format!(
+            "{}: file {} does not exist",
+            context,
+            path.display()
+        ));
+    }
+}
+
+/// Finds cycles in fileset inheritance, each given as the chain of fileset names that leads back to its start
+fn fileset_cycles(filesets: &HashMap<String, Fileset>) -> Vec<Vec<String>> {
+    let mut names: Vec<&String> = filesets.keys().collect();
+    names.sort();
+
+    let mut cycles = Vec::new();
+    let mut finished = HashSet::new();
+    for name in names {
+        let mut chain = Vec::new();
+        find_cycles(name, filesets, &mut chain, &mut finished, &mut cycles);
+    }
+    cycles
+}
+
+fn find_cycles<'f>(
+    name: &'f str,
+    filesets: &'f HashMap<String, Fileset>,
+    chain: &mut Vec<&'f str>,
+    finished: &mut HashSet<&'f str>,
+    cycles: &mut Vec<Vec<String>>,
+) {
+    if let Some(start) = chain.iter().position(|n| *n == name) {
+        let mut cycle: Vec<String> = chain[start..].iter().map(|n| n.to_string()).collect();
+        cycle.push(name.to_string());
+        cycles.push(cycle);
+        return;
+    }
+    if finished.contains(name) {
+        return;
+    }
+
+    if let Some(fileset) = filesets.get(name) {
+        chain.push(name);
+        for inherited in fileset.inherits.iter() {
+            find_cycles(inherited, filesets, chain, finished, cycles);
+        }
+        chain.pop();
+    }
+    finished.insert(name);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let config = Configuration::parse(
+            r#"
+            [profiles.home]
+            repository = "local:/backups"
+            base_directory = "/"
+            password = "hunter2"
+            password_file = "/nonexistent/password"
+            include = { inherits = ["documents", "missing"] }
+            retention = { keep_daily = 7 }
+
+            [profiles.laptop]
+            repository = "local:/backups"
+            base_directory = "/"
+            include = { patterns = ["/home"] }
+            retention = { keep_daily = 7 }
+
+            [profiles.work]
+            repository = "local:/backups"
+            base_directory = "/"
+            password_command = "pass show backups"
+            stdin_command = "pg_dump work"
+            include = { patterns = ["/srv"] }
+
+            [filesets.documents]
+            inherits = ["photos"]
+            patterns = ["Documents"]
+
+            [filesets.photos]
+            inherits = ["documents"]
+            patterns = ["Pictures"]
+            "#,
+        )
+        .unwrap();
+
+        let messages: Vec<(Severity, String)> = validate(&config)
+            .into_iter()
+            .map(|problem| (problem.severity, problem.message))
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                (Severity::Error, "Fileset inheritance cycle: documents -> photos -> documents".to_string()),
+                (Severity::Error, "profile `home` include inherits from fileset `missing`, which does not exist".to_string()),
+                (Severity::Error, "profile `home`: Cannot set both `password` and `password_file`".to_string()),
+                (Severity::Error, "profile `home`: file /nonexistent/password does not exist".to_string()),
+                (Severity::Error, "profile `work`: cannot set both `stdin_command` and `include`. Add the files as a source instead.".to_string()),
+                (Severity::Warning, "profile `laptop`: no password source is set, so Rustic will prompt for the password. Set `password_prompt = true` if that's intended.".to_string()),
+                (Severity::Warning, "profile `work`: retention policy is empty, so `rustic forget` will not forget any snapshots".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,336 @@
+//! Test restores, which restore a random sample of files from the latest snapshot and check them. A backup that has never
+//! been restored from is untested.
+
+use std::collections::hash_map::RandomState;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::hash::BuildHasher;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use slog::{info, warn};
+use tempfile::TempDir;
+
+use crate::ls::Node;
+use crate::output::{self, OutputFormat};
+use crate::platform;
+use crate::restic::Restic;
+use crate::sha256;
+use crate::snapshots::SnapshotFilter;
+
+/// How many files `verify` restores, unless the profile's `verify.sample` or `--sample` says otherwise
+const DEFAULT_SAMPLE: SampleSize = SampleSize::Files(20);
+
+/// How many files to restore for a test restore, either a number of files like `20` or a percentage of the files in the
+/// snapshot like `5%`
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum SampleSize {
+    /// This many files
+    Files(usize),
+
+    /// This percentage of the files, rounded up
+    Percent(f64),
+}
+
+impl SampleSize {
+    /// Number of files to pick out of `total`. A percentage always picks at least one file, if there are any.
+    pub fn of(&self, total: usize) -> usize {
+        match *self {
+            SampleSize::Files(files) => files.min(total),
+            SampleSize::Percent(percent) => {
+                ((total as f64 * percent / 100.0).ceil() as usize).clamp(total.min(1), total)
+            }
+        }
+    }
+}
+
+impl FromStr for SampleSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<SampleSize> {
+        let invalid = || {
+            anyhow!(
+                "Invalid sample size `{}`, expected a number of files like `20` or a percentage like `5%`",
+                s
+            )
+        };
+        let s = s.trim();
+        match s.strip_suffix('%') {
+            Some(percent) => match percent.trim().parse::<f64>() {
+                Ok(percent) if percent > 0.0 && percent <= 100.0 => {
+                    Ok(SampleSize::Percent(percent))
+                }
+                _ => Err(invalid()),
+            },
+            None => match s.parse::<usize>() {
+                Ok(files) if files > 0 => Ok(SampleSize::Files(files)),
+                _ => Err(invalid()),
+            },
+        }
+    }
+}
+
+impl TryFrom<String> for SampleSize {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<SampleSize> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for SampleSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SampleSize::Files(files) => write!(f, "{}", files),
+            SampleSize::Percent(percent) => write!(f, "{}%", percent),
+        }
+    }
+}
+
+/// A problem with a restored file
+#[derive(Debug, Serialize)]
+pub struct Discrepancy {
+    /// Path of the file in the snapshot
+    pub path: String,
+
+    /// What's wrong with it
+    pub problem: String,
+}
+
+/// Results of a test restore
+#[derive(Debug, Serialize)]
+pub struct Verification {
+    /// ID of the snapshot files were restored from
+    pub snapshot: String,
+
+    /// Number of files in the snapshot
+    pub total_files: usize,
+
+    /// Number of files restored
+    pub restored_files: usize,
+
+    /// Number of restored files that were compared to the live file system. Files that changed since the snapshot was
+    /// taken are only checked against the snapshot's listing.
+    pub compared_files: usize,
+
+    /// Every problem found
+    pub discrepancies: Vec<Discrepancy>,
+}
+
+/// Extends the Restic wrapper with test restores
+impl<'a> Restic<'a> {
+    /// Restores a random sample of files from the latest snapshot into a temporary directory and checks them. Each restored
+    /// file must exist and have the size recorded in the snapshot. If the live file hasn't changed since the snapshot was
+    /// taken (same size and modification time), the contents must match it too. Only snapshots from this machine (or the
+    /// profile's `host`) with the profile's tags are considered when picking the latest one, since the live files are only
+    /// meaningful for those.
+    pub fn verify(&self, sample: Option<SampleSize>) -> Result<Verification> {
+        let sample = sample
+            .or(self.profile().verify.sample)
+            .unwrap_or(DEFAULT_SAMPLE);
+
+        let mut filter = SnapshotFilter::default();
+        if !self.profile().tags.is_empty() {
+            filter.tags.push(self.profile().tags.join(","));
+        }
+        filter.host = self.profile().host.clone().or_else(platform::hostname);
+        let snapshot = match self.list_snapshots(&filter, &[])?.pop() {
+            Some(snapshot) => snapshot.id,
+            None => bail!("Repository has no snapshots to verify"),
+        };
+
+        let files: Vec<Node> = self
+            .list_files(&snapshot, None, true)?
+            .into_iter()
+            .filter(|node| node.kind == "file")
+            .collect();
+        let total_files = files.len();
+        if files.is_empty() {
+            bail!("Snapshot {} has no files to verify", snapshot);
+        }
+        let files = random_sample(files, sample.of(total_files));
+
+        let target = match self.profile().verify.restore_directory {
+            Some(ref directory) => {
+                let directory = self.profile().base_directory.join(directory);
+                fs::create_dir_all(&directory)
+                    .with_context(|| format!("Could not create {}", directory.display()))?;
+                TempDir::new_in(&directory)
+            }
+            None => TempDir::new(),
+        }
+        .context("Could not create a directory to restore into")?;
+        let paths: Vec<String> = files.iter().map(|node| node.path.clone()).collect();
+        info!(self.logger(), "Restoring {} of {} files to verify them", files.len(), total_files;
+            "snapshot" => &snapshot, "target" => %target.path().display());
+        self.restore_paths(&snapshot, target.path(), &paths)?;
+
+        let mut verification = Verification {
+            snapshot,
+            total_files,
+            restored_files: files.len(),
+            compared_files: 0,
+            discrepancies: Vec::new(),
+        };
+        for node in files.iter() {
+            match verify_file(node, target.path()) {
+                Ok(true) => verification.compared_files += 1,
+                Ok(false) => (),
+                Err(problem) => {
+                    warn!(self.logger(), "Restored file doesn't match: {}", problem; "path" => &node.path);
+                    verification.discrepancies.push(Discrepancy {
+                        path: node.path.clone(),
+                        problem,
+                    });
+                }
+            }
+        }
+        Ok(verification)
+    }
+}
+
+/// Picks `size` of `items` at random
+fn random_sample(mut items: Vec<Node>, size: usize) -> Vec<Node> {
+    let state = RandomState::new();
+    items.sort_by_cached_key(|node| state.hash_one(&node.path));
+    items.truncate(size);
+    items
+}
+
+/// Where `path` from a snapshot was restored to under `target`
+fn restored_path(target: &Path, path: &str) -> PathBuf {
+    let relative: PathBuf = Path::new(path)
+        .components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect();
+    target.join(relative)
+}
+
+/// Checks one restored file against the snapshot listing and the live file system. Returns whether it was compared to the
+/// live file, or what's wrong with it.
+fn verify_file(node: &Node, target: &Path) -> std::result::Result<bool, String> {
+    let restored = restored_path(target, &node.path);
+    let metadata = fs::metadata(&restored).map_err(|_| "Missing from the restore".to_string())?;
+    if let Some(size) = node.size {
+        if metadata.len() != size {
+            return Err(format!(
+                "Restored {} bytes, but the snapshot lists {} bytes",
+                metadata.len(),
+                size
+            ));
+        }
+    }
+
+    // Only compare to the live file if it hasn't changed since the backup
+    let live = Path::new(&node.path);
+    let unchanged = match fs::metadata(live) {
+        Ok(live_metadata) => {
+            Some(live_metadata.len()) == node.size
+                && live_metadata.modified().ok().map(DateTime::<Utc>::from) == node.mtime
+        }
+        Err(_) => false,
+    };
+    if !unchanged {
+        return Ok(false);
+    }
+    let digest = |path: &Path| {
+        sha256::hex_digest_file(path)
+            .map_err(|err| format!("Could not read {}: {}", path.display(), err))
+    };
+    if digest(&restored)? != digest(live)? {
+        return Err(
+            "Contents differ from the live file, which hasn't changed since the backup".to_string(),
+        );
+    }
+    Ok(true)
+}
+
+/// Prints the results of a test restore
+pub fn print_verification(verification: &Verification, output: OutputFormat) -> Result<()> {
+    if output == OutputFormat::Json {
+        return output::print_json(verification);
+    }
+
+    println!(
+        "Restored {} of {} files from snapshot {}, and compared {} to the live files",
+        verification.restored_files,
+        verification.total_files,
+        &verification.snapshot[..verification.snapshot.len().min(8)],
+        verification.compared_files
+    );
+    for discrepancy in verification.discrepancies.iter() {
+        println!("{}: {}", discrepancy.path, discrepancy.problem);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::SampleSize;
+    use crate::runner::MockResponse;
+    use crate::test::TestFixture;
+
+    #[test]
+    fn test_sample_size() {
+        assert_eq!("20".parse::<SampleSize>().unwrap().of(100), 20);
+        assert_eq!("20".parse::<SampleSize>().unwrap().of(5), 5);
+        assert_eq!("5%".parse::<SampleSize>().unwrap().of(100), 5);
+        assert_eq!("5%".parse::<SampleSize>().unwrap().of(3), 1);
+        assert_eq!("5%".parse::<SampleSize>().unwrap().of(0), 0);
+        assert!("0".parse::<SampleSize>().is_err());
+        assert!("150%".parse::<SampleSize>().is_err());
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut fixture = TestFixture::new();
+        fixture.profile_mut().host = Some("laptop".to_string());
+        let restore_directory = fixture.content_root().join("restores");
+        fixture.profile_mut().verify.restore_directory = Some(restore_directory.clone());
+        let (restic, runner) = fixture.mock_restic();
+
+        let live = fixture.content_root().join("notes.txt");
+        fs::write(&live, "hello").unwrap();
+        runner.respond(MockResponse::success(
+            r#"[{"id":"0123456789abcdef","time":"2020-09-01T10:00:00Z","hostname":"laptop"}]"#,
+        ));
+        runner.respond(MockResponse::success(&format!(
+            "{{\"struct_type\":\"snapshot\"}}\n\
+             {{\"struct_type\":\"node\",\"type\":\"dir\",\"path\":\"/home\"}}\n\
+             {{\"struct_type\":\"node\",\"type\":\"file\",\"path\":{:?},\"size\":5}}\n",
+            live.display().to_string()
+        )));
+        runner.respond(MockResponse::success(
+            "restic 0.17.3 compiled with go1.22.5 on linux/amd64",
+        ));
+
+        // The mock restore doesn't write anything, so the file is missing
+        let verification = restic.verify(None).unwrap();
+        assert_eq!(verification.total_files, 1);
+        assert_eq!(verification.restored_files, 1);
+        assert_eq!(verification.discrepancies.len(), 1);
+        let invocations = runner.invocations();
+        assert!(invocations[0].has_arg("--host"));
+        assert!(invocations[0].has_arg("laptop"));
+        let restore = &invocations[3];
+        assert!(restore.has_arg("restore"));
+        assert!(restore.has_arg("--include-file"));
+        assert!(!restore.has_arg(&live.display().to_string()));
+        // The sample was restored into a directory inside the configured one, which was removed afterwards
+        let target = &restore.args[restore
+            .args
+            .iter()
+            .position(|arg| arg == "--target")
+            .unwrap()
+            + 1];
+        assert!(target.starts_with(&restore_directory.display().to_string()));
+        assert_eq!(fs::read_dir(&restore_directory).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,147 @@
+//! Checking which version of Restic is installed
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use slog::debug;
+
+use crate::restic::Restic;
+
+/// A Restic release version, like `0.16.4`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ResticVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl ResticVersion {
+    pub const fn new(major: u32, minor: u32, patch: u32) -> ResticVersion {
+        ResticVersion {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    /// Parses the output of `restic version`, like `restic 0.16.4 compiled with go1.21.6 on linux/amd64`
+    pub fn from_version_output(output: &str) -> Result<ResticVersion> {
+        output
+            .split_whitespace()
+            .nth(1)
+            .filter(|_| output.starts_with("restic "))
+            .ok_or_else(|| anyhow!("Unexpected `restic version` output: {}", output.trim()))?
+            .parse()
+    }
+}
+
+impl FromStr for ResticVersion {
+    type Err = anyhow::Error;
+
+    /// Parses a version like `0.16.4` or `0.16`. Pre-release suffixes like `-dev` are ignored.
+    fn from_str(s: &str) -> Result<ResticVersion> {
+        let release = s.trim().split('-').next().unwrap_or_default();
+        let parts = release
+            .split('.')
+            .map(|part| part.parse::<u32>())
+            .collect::<Result<Vec<u32>, _>>()
+            .map_err(|_| anyhow!("Invalid Restic version `{}`", s))?;
+        match parts[..] {
+            [major, minor] => Ok(ResticVersion {
+                major,
+                minor,
+                patch: 0,
+            }),
+            [major, minor, patch] => Ok(ResticVersion {
+                major,
+                minor,
+                patch,
+            }),
+            _ => bail!(
+                "Invalid Restic version `{}`, expected a version like `0.16.4`",
+                s
+            ),
+        }
+    }
+}
+
+impl TryFrom<String> for ResticVersion {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<ResticVersion> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for ResticVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Extends the Restic wrapper with version checks
+impl<'a> Restic<'a> {
+    /// Version of the Restic binary this profile uses
+    pub fn version(&self) -> Result<ResticVersion> {
+        let mut cmd = self.bare_command();
+        cmd.arg("version");
+        let output = self
+            .execute_output(&mut cmd)
+            .context("Could not determine Restic version")?;
+        let version = ResticVersion::from_version_output(&output)?;
+        debug!(self.logger(), "Found Restic {}", version);
+        Ok(version)
+    }
+
+    /// Returns an error if Restic is older than `minimum`, the version needed for `feature`
+    pub fn require_version(&self, minimum: ResticVersion, feature: &str) -> Result<()> {
+        self.require_versions(&[(minimum, feature.to_string())])
+    }
+
+    /// Checks several version requirements, each a minimum version and the feature that needs it, while only running
+    /// `restic version` once. Does nothing if there are no requirements.
+    pub fn require_versions(&self, requirements: &[(ResticVersion, String)]) -> Result<()> {
+        if requirements.is_empty() {
+            return Ok(());
+        }
+        let version = self.version()?;
+        for (minimum, feature) in requirements {
+            if version < *minimum {
+                bail!(
+                    "{} requires Restic {} or newer, but {} is version {}",
+                    feature,
+                    minimum,
+                    self.restic_command(),
+                    version
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ResticVersion;
+
+    #[test]
+    fn test_parse_version() {
+        let version = ResticVersion::from_version_output(
+            "restic 0.16.4 compiled with go1.21.6 on linux/amd64\n",
+        )
+        .unwrap();
+        assert_eq!(version.to_string(), "0.16.4");
+        assert!(version < "0.17".parse().unwrap());
+        assert!(version > "0.9.6".parse().unwrap());
+        assert_eq!(
+            "0.18.0-dev".parse::<ResticVersion>().unwrap().to_string(),
+            "0.18.0"
+        );
+        assert!("latest".parse::<ResticVersion>().is_err());
+        assert!(ResticVersion::from_version_output("rclone v1.2.3").is_err());
+    }
+}
@@ -0,0 +1,625 @@
+//! Backing up a profile whenever its files change, for working directories that change too often for a fixed schedule to
+//! keep up with. Changes are picked up with inotify on Linux, and by periodically rescanning the files elsewhere.
+
+use std::env;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant};
+
+#[cfg(target_os = "linux")]
+use anyhow::Context;
+use anyhow::{bail, Result};
+use slog::{error, info, warn, Logger};
+
+use crate::lock;
+use crate::report;
+use crate::restic::Restic;
+use crate::state;
+
+/// How long files must go unchanged before backing up, unless `--settle` is passed
+pub const DEFAULT_SETTLE: Duration = Duration::from_secs(30);
+
+/// Minimum time between the start of one backup and the next, unless `--min-interval` is passed
+pub const DEFAULT_MIN_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Extends the Restic wrapper with backing up on changes
+impl<'a> Restic<'a> {
+    /// Watches the files the profile backs up, and backs them up once they've gone `settle` without changing. Backups start
+    /// at least `min_interval` apart, and files that keep changing are backed up anyway after `min_interval`. A failed
+    /// backup is logged, and watching continues. This only returns if watching fails.
+    pub fn watch(&self, settle: Duration, min_interval: Duration) -> Result<()> {
+        let paths = self.watched_paths()?;
+        if paths.is_empty() {
+            bail!("Profile does not include any files to watch");
+        }
+        if let Some(root) = paths.iter().find(|path| path.parent().is_none()) {
+            bail!(
+                "Refusing to watch {}, since changes all over the file system would keep triggering backups. Narrow the \
+                 profile's include patterns, or set its `base_directory`.",
+                root.display()
+            );
+        }
+        let filter = self.change_filter()?;
+        let mut watcher = ChangeWatcher::new(&paths, filter, self.logger())?;
+        info!(self.logger(), "Watching for changes"; "paths" => ?paths, "settle" => ?settle,
+            "min_interval" => ?min_interval);
+
+        let mut last_backup: Option<Instant> = None;
+        loop {
+            while !watcher.wait(None)? {}
+            let first_change = Instant::now();
+            while first_change.elapsed() < min_interval.max(settle) && watcher.wait(Some(settle))? {
+            }
+
+            if let Some(last_backup) = last_backup {
+                let next = last_backup + min_interval;
+                if Instant::now() < next {
+                    info!(
+                        self.logger(),
+                        "Changes settled, backing up in {:?}",
+                        next - Instant::now()
+                    );
+                    while Instant::now() < next {
+                        watcher.wait(Some(next - Instant::now()))?;
+                    }
+                }
+            }
+            last_backup = Some(Instant::now());
+
+            if let Some(reason) = self.backup_skip_reason()? {
+                info!(self.logger(), "Skipping backup: {}", reason);
+                continue;
+            }
+            if let Err(err) = self.require_repository() {
+                warn!(self.logger(), "Skipping backup: {:#}", err);
+                continue;
+            }
+            info!(self.logger(), "Files changed, backing up");
+            if let Err(err) = self.backup() {
+                error!(self.logger(), "Backup failed: {:#}", err);
+            }
+        }
+    }
+
+    /// Which changes shouldn't trigger a backup: anything the profile excludes, and the files that Rustic and Restic write
+    /// while backing up. Otherwise, watching a home directory would back it up again every time a backup updated Restic's
+    /// cache or Rustic's run history.
+    pub fn change_filter(&self) -> Result<ChangeFilter> {
+        let (excludes, insensitive) = self.exclude_patterns()?;
+        let config = self.config();
+        let mut ignored = vec![
+            state::state_file(config)?,
+            state::state_lock_file(config)?,
+            lock::lock_directory(),
+        ];
+        match config.cache_directory {
+            Some(ref cache_directory) => ignored.push(cache_directory.clone()),
+            None => ignored.extend(
+                env::var_os("RESTIC_CACHE_DIR")
+                    .map(PathBuf::from)
+                    .or_else(|| dirs::cache_dir().map(|cache_dir| cache_dir.join("restic"))),
+            ),
+        }
+        ignored.extend(config.metrics_directory.clone());
+        if let Some(ref report_file) = config.report_file {
+            ignored.push(report::report_path(
+                report_file,
+                self.profile_name(),
+                "backup",
+            )?);
+        }
+        let base_directory = &self.profile().base_directory;
+        Ok(ChangeFilter {
+            excludes,
+            insensitive: insensitive
+                .iter()
+                .map(|pattern| pattern.to_lowercase())
+                .collect(),
+            ignored: ignored
+                .into_iter()
+                .map(|path| base_directory.join(path))
+                .collect(),
+        })
+    }
+}
+
+/// Decides which changed files should trigger a backup
+#[derive(Debug, Default)]
+pub struct ChangeFilter {
+    /// The profile's exclude patterns
+    excludes: Vec<String>,
+
+    /// The profile's case-insensitive exclude patterns, in lowercase
+    insensitive: Vec<String>,
+
+    /// Files and directories that Rustic and Restic write to themselves
+    ignored: Vec<PathBuf>,
+}
+
+impl ChangeFilter {
+    /// Whether a change to `path` should be ignored. Besides the ignored paths themselves, this covers the temporary files
+    /// next to them that Rustic writes first and then renames into place.
+    pub fn ignores(&self, path: &Path) -> bool {
+        let is_temporary = path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with(".tmp"));
+        if self.ignored.iter().any(|ignored| {
+            path.starts_with(ignored) || (is_temporary && ignored.parent() == path.parent())
+        }) {
+            return true;
+        }
+
+        let path = path.to_string_lossy();
+        let lowercase = path.to_lowercase();
+        self.excludes
+            .iter()
+            .any(|pattern| exclude_matches(pattern, &path))
+            || self
+                .insensitive
+                .iter()
+                .any(|pattern| exclude_matches(pattern, &lowercase))
+    }
+}
+
+/// Whether Restic's exclude `pattern` matches `path` or a directory it's in. Like Restic, a pattern that doesn't start with
+/// `/` matches at any depth, `**` matches any number of directories, and each other part of the pattern matches a single
+/// path component, with `*`, `?`, and `[...]` wildcards.
+fn exclude_matches(pattern: &str, path: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('/').filter(|part| !part.is_empty()).collect();
+    let components: Vec<String> = Path::new(path)
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect();
+    let components: Vec<&str> = components.iter().map(String::as_str).collect();
+    if parts.is_empty() {
+        false
+    } else if pattern.starts_with('/') {
+        prefix_matches(&parts, &components)
+    } else {
+        (0..components.len()).any(|start| prefix_matches(&parts, &components[start..]))
+    }
+}
+
+/// Whether the pattern `parts` match the first of `components`, so that the path is the matched path or inside it
+fn prefix_matches(parts: &[&str], components: &[&str]) -> bool {
+    match parts.split_first() {
+        None => true,
+        Some((&"**", rest)) => {
+            (0..=components.len()).any(|skip| prefix_matches(rest, &components[skip..]))
+        }
+        Some((part, rest)) => match components.split_first() {
+            Some((component, components)) => {
+                let part: Vec<char> = part.chars().collect();
+                let component: Vec<char> = component.chars().collect();
+                component_matches(&part, &component) && prefix_matches(rest, components)
+            }
+            None => false,
+        },
+    }
+}
+
+/// Whether one path component matches a pattern with `*`, `?`, and `[...]` wildcards
+fn component_matches(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|skip| component_matches(rest, &name[skip..])),
+        Some(('?', rest)) => !name.is_empty() && component_matches(rest, &name[1..]),
+        Some(('[', rest)) => {
+            let end = match rest.iter().skip(1).position(|&c| c == ']') {
+                Some(end) => end + 1,
+                None => return false,
+            };
+            match name.split_first() {
+                Some((&c, name)) => {
+                    class_matches(&rest[..end], c) && component_matches(&rest[end + 1..], name)
+                }
+                None => false,
+            }
+        }
+        Some((&c, rest)) => name.first() == Some(&c) && component_matches(rest, &name[1..]),
+    }
+}
+
+/// Whether `c` is in a character class like `a-z0-9`, which is negated if it starts with `^` or `!`
+fn class_matches(class: &[char], c: char) -> bool {
+    let (negated, class) = match class.split_first() {
+        Some(('^', rest)) | Some(('!', rest)) => (true, rest),
+        _ => (false, class),
+    };
+    let mut matched = false;
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == '-' {
+            matched |= class[i] <= c && c <= class[i + 2];
+            i += 3;
+        } else {
+            matched |= class[i] == c;
+            i += 1;
+        }
+    }
+    matched != negated
+}
+
+/// Watches files and directory trees for changes, using inotify
+#[cfg(target_os = "linux")]
+pub struct ChangeWatcher {
+    fd: libc::c_int,
+    /// Path each inotify watch is for, to watch new subdirectories inside it
+    paths: std::collections::HashMap<libc::c_int, PathBuf>,
+    filter: ChangeFilter,
+    logger: Logger,
+    /// Whether the warning about running out of inotify watches was logged already
+    warned_limit: bool,
+}
+
+#[cfg(target_os = "linux")]
+impl ChangeWatcher {
+    /// Starts watching `paths`, including everything inside directories, except for what `filter` ignores
+    pub fn new(paths: &[PathBuf], filter: ChangeFilter, logger: &Logger) -> Result<ChangeWatcher> {
+        // Safe because inotify_init1 has no memory safety requirements
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error())
+                .context("Could not start watching for changes");
+        }
+        let mut watcher = ChangeWatcher {
+            fd,
+            paths: std::collections::HashMap::new(),
+            filter,
+            logger: logger.clone(),
+            warned_limit: false,
+        };
+        for path in paths {
+            watcher
+                .add_watch(path)
+                .with_context(|| format!("Could not watch {}", path.display()))?;
+            if path.is_dir() {
+                watcher.watch_subdirectories(path);
+            }
+        }
+        Ok(watcher)
+    }
+
+    /// Waits up to `timeout`, or forever if it's `None`, for something that isn't ignored to change. Returns whether
+    /// anything did.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> Result<bool> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let timeout = deadline.map_or(-1, |deadline| {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                remaining.as_millis().min(i32::MAX as u128) as i32
+            });
+            if self.poll(timeout)? {
+                return Ok(true);
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Waits up to `timeout` milliseconds, or forever if it's -1, for events. Returns whether any of them were for paths
+    /// that aren't ignored.
+    fn poll(&mut self, timeout: libc::c_int) -> Result<bool> {
+        let mut poll = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // Safe because poll only writes to the one pollfd it's given
+        let ready = unsafe { libc::poll(&mut poll, 1, timeout) };
+        if ready < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                return Ok(false);
+            }
+            return Err(err).context("Could not wait for changes");
+        }
+        if ready == 0 {
+            return Ok(false);
+        }
+
+        // Read every queued event, watching any new directories so changes inside them are seen too
+        let mut changed = false;
+        let mut new_directories = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            // Safe because read writes at most buf.len() bytes into buf
+            let len =
+                unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if len <= 0 {
+                break;
+            }
+            let mut offset = 0;
+            while offset < len as usize {
+                // Safe because the kernel only returns whole events, and read_unaligned doesn't need the buffer aligned
+                let event: libc::inotify_event =
+                    unsafe { std::ptr::read_unaligned(buf[offset..].as_ptr() as *const _) };
+                let name_start = offset + std::mem::size_of::<libc::inotify_event>();
+                let name = &buf[name_start..name_start + event.len as usize];
+                offset = name_start + event.len as usize;
+
+                let path = match self.paths.get(&event.wd) {
+                    Some(directory) => {
+                        let name = name.split(|b| *b == 0).next().unwrap_or_default();
+                        directory.join(bytes_to_path(name))
+                    }
+                    None => continue,
+                };
+                if self.filter.ignores(&path) {
+                    continue;
+                }
+                changed = true;
+                if event.mask & libc::IN_ISDIR != 0
+                    && event.mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0
+                {
+                    new_directories.push(path);
+                }
+            }
+        }
+        for directory in new_directories {
+            if self.try_add_watch(&directory) {
+                self.watch_subdirectories(&directory);
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Watches each directory below `directory`. Directories that can't be read, like ones the user doesn't have access
+    /// to, are skipped, since Restic can't back them up either.
+    fn watch_subdirectories(&mut self, directory: &Path) {
+        let entries = match std::fs::read_dir(directory) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let is_dir = entry.file_type().is_ok_and(|t| t.is_dir());
+            if is_dir && !self.filter.ignores(&path) && self.try_add_watch(&path) {
+                self.watch_subdirectories(&path);
+            }
+        }
+    }
+
+    /// Watches `path`, logging a warning if that fails. Returns whether it's watched.
+    fn try_add_watch(&mut self, path: &Path) -> bool {
+        let err = match self.add_watch(path) {
+            Ok(()) => return true,
+            Err(err) => err,
+        };
+        if err.kind() == std::io::ErrorKind::NotFound {
+            // The directory was removed again before it could be watched, which is fine
+        } else if err.raw_os_error() == Some(libc::ENOSPC) {
+            if !self.warned_limit {
+                self.warned_limit = true;
+                warn!(self.logger, "Ran out of inotify watches, so changes in some directories won't be noticed. \
+                    Raise the `fs.inotify.max_user_watches` sysctl to watch them."; "path" => %path.display());
+            }
+        } else {
+            warn!(self.logger, "Could not watch for changes: {}", err; "path" => %path.display());
+        }
+        false
+    }
+
+    fn add_watch(&mut self, path: &Path) -> std::io::Result<()> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+        let mask = libc::IN_CREATE
+            | libc::IN_DELETE
+            | libc::IN_MODIFY
+            | libc::IN_ATTRIB
+            | libc::IN_MOVED_FROM
+            | libc::IN_MOVED_TO;
+        // Safe because c_path is a valid C string
+        let wd = unsafe { libc::inotify_add_watch(self.fd, c_path.as_ptr(), mask) };
+        if wd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        self.paths.insert(wd, path.to_path_buf());
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for ChangeWatcher {
+    fn drop(&mut self) {
+        // Safe because the watcher owns its file descriptor
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn bytes_to_path(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+
+    PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+}
+
+/// How often the scanning watcher looks for changes
+#[cfg(not(target_os = "linux"))]
+const SCAN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Watches files and directory trees for changes, by rescanning them every few seconds and comparing modification times
+/// and sizes
+#[cfg(not(target_os = "linux"))]
+pub struct ChangeWatcher {
+    paths: Vec<PathBuf>,
+    filter: ChangeFilter,
+    fingerprint: u64,
+}
+
+#[cfg(not(target_os = "linux"))]
+impl ChangeWatcher {
+    /// Starts watching `paths`, including everything inside directories, except for what `filter` ignores
+    pub fn new(paths: &[PathBuf], filter: ChangeFilter, _logger: &Logger) -> Result<ChangeWatcher> {
+        let fingerprint = fingerprint(paths, &filter);
+        Ok(ChangeWatcher {
+            paths: paths.to_vec(),
+            filter,
+            fingerprint,
+        })
+    }
+
+    /// Waits up to `timeout`, or forever if it's `None`, for something to change. Returns whether anything did.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> Result<bool> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let delay = match deadline {
+                Some(deadline) => {
+                    SCAN_INTERVAL.min(deadline.saturating_duration_since(Instant::now()))
+                }
+                None => SCAN_INTERVAL,
+            };
+            std::thread::sleep(delay);
+            let fingerprint = fingerprint(&self.paths, &self.filter);
+            if fingerprint != self.fingerprint {
+                self.fingerprint = fingerprint;
+                return Ok(true);
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Ok(false);
+            }
+        }
+    }
+}
+
+/// Hashes the path, modification time, and size of every file and directory under `paths` that `filter` doesn't ignore
+#[cfg(not(target_os = "linux"))]
+fn fingerprint(paths: &[PathBuf], filter: &ChangeFilter) -> u64 {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    fn visit(path: &Path, filter: &ChangeFilter, hasher: &mut DefaultHasher) {
+        if filter.ignores(path) {
+            return;
+        }
+        let metadata = match std::fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(_) => return,
+        };
+        path.hash(hasher);
+        metadata.len().hash(hasher);
+        metadata.modified().ok().hash(hasher);
+        if metadata.is_dir() {
+            if let Ok(entries) = std::fs::read_dir(path) {
+                let mut children: Vec<PathBuf> =
+                    entries.flatten().map(|entry| entry.path()).collect();
+                children.sort();
+                for child in children {
+                    visit(&child, filter, hasher);
+                }
+            }
+        }
+    }
+
+    let mut hasher = DefaultHasher::new();
+    for path in paths {
+        visit(path, filter, &mut hasher);
+    }
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use slog::{o, Discard, Logger};
+    use tempfile::TempDir;
+
+    use super::{exclude_matches, ChangeFilter, ChangeWatcher};
+    use crate::test::TestFixture;
+
+    #[test]
+    fn test_change_watcher() {
+        let root = TempDir::new().unwrap();
+        let filter = ChangeFilter {
+            excludes: vec!["*.swp".to_string()],
+            ignored: vec![root.path().join("cache")],
+            ..ChangeFilter::default()
+        };
+        let logger = Logger::root(Discard, o!());
+        let mut watcher =
+            ChangeWatcher::new(&[root.path().to_path_buf()], filter, &logger).unwrap();
+        assert!(!watcher.wait(Some(Duration::from_millis(0))).unwrap());
+
+        fs::write(root.path().join("notes.txt"), "hello").unwrap();
+        assert!(watcher.wait(Some(Duration::from_secs(2))).unwrap());
+
+        // Changes inside directories created after watching started are seen too
+        fs::create_dir(root.path().join("drafts")).unwrap();
+        assert!(watcher.wait(Some(Duration::from_secs(2))).unwrap());
+        fs::write(root.path().join("drafts").join("chapter.txt"), "draft").unwrap();
+        assert!(watcher.wait(Some(Duration::from_secs(2))).unwrap());
+        assert!(!watcher.wait(Some(Duration::from_millis(0))).unwrap());
+
+        // Excluded and ignored files don't count as changes
+        fs::write(root.path().join("drafts").join(".chapter.txt.swp"), "swap").unwrap();
+        fs::create_dir(root.path().join("cache")).unwrap();
+        fs::write(root.path().join("cache").join("index"), "cached").unwrap();
+        assert!(!watcher.wait(Some(Duration::from_millis(200))).unwrap());
+    }
+
+    #[test]
+    fn test_exclude_matches() {
+        assert!(exclude_matches("*.swp", "/home/ben/.notes.swp"));
+        assert!(exclude_matches(
+            "node_modules",
+            "/home/ben/app/node_modules/left-pad/index.js"
+        ));
+        assert!(!exclude_matches(
+            "node_modules",
+            "/home/ben/app/node_modules_old"
+        ));
+        assert!(exclude_matches("/home/*/.cache", "/home/ben/.cache/restic"));
+        assert!(!exclude_matches("/home/*/.cache", "/srv/home/ben/.cache"));
+        assert!(exclude_matches(
+            "/home/**/target",
+            "/home/ben/src/rustic/target/debug"
+        ));
+        assert!(exclude_matches("/home/**/target", "/home/target"));
+        assert!(exclude_matches("build-[0-9]", "/src/build-7"));
+        assert!(!exclude_matches("build-[!0-9]", "/src/build-7"));
+        assert!(exclude_matches("file?.txt", "/src/file1.txt"));
+        assert!(!exclude_matches("file?.txt", "/src/file.txt"));
+    }
+
+    #[test]
+    fn test_change_filter() {
+        let mut fixture = TestFixture::new();
+        let content_root = fixture.content_root().to_path_buf();
+        let state_file = content_root.join("state/state.json");
+        let cache_directory = content_root.join("cache");
+        fixture.config_mut().state_file = Some(state_file.clone());
+        fixture.config_mut().cache_directory = Some(cache_directory.clone());
+        fixture.profile_mut().exclude.patterns = vec!["*.o".to_string()];
+        fixture.profile_mut().exclude.insensitive_patterns = vec!["*.LOG".to_string()];
+
+        let filter = fixture.restic().change_filter().unwrap();
+        assert!(filter.ignores(&state_file));
+        assert!(filter.ignores(&content_root.join("state/.tmpAbC123")));
+        assert!(filter.ignores(&content_root.join("state/state.json.lock")));
+        assert!(filter.ignores(&cache_directory.join("0123abcd/index")));
+        assert!(filter.ignores(&content_root.join("src/main.o")));
+        assert!(filter.ignores(&content_root.join("debug.log")));
+        assert!(!filter.ignores(&content_root.join("src/main.c")));
+        assert!(!filter.ignores(&content_root.join("state/notes.txt")));
+    }
+
+    #[test]
+    fn test_watch_root() {
+        let mut fixture = TestFixture::new();
+        fixture.profile_mut().base_directory = PathBuf::from("/");
+        fixture.profile_mut().include.patterns = vec!["/".to_string()];
+        let err = fixture
+            .restic()
+            .watch(Duration::from_secs(1), Duration::from_secs(1))
+            .unwrap_err();
+        assert!(err.to_string().contains("Refusing to watch /"), "{:#}", err);
+    }
+}
@@ -0,0 +1,121 @@
+//! Daily time windows like `09:00-18:00`, for settings that depend on the time of day
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use chrono::{Datelike, NaiveDateTime, NaiveTime, Weekday};
+use serde::Deserialize;
+
+/// A range of times of day. If the end is before the start, the window wraps around midnight, so `22:00-06:00` covers
+/// the night.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct TimeWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl TimeWindow {
+    /// Whether `time` falls in the window. The start is included and the end isn't.
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+
+    /// Whether `datetime` falls in the window on one of `days`, or on any day if `days` is empty. For windows that wrap
+    /// around midnight, the day is the one the window started on, so a Friday `22:00-06:00` window includes early Saturday
+    /// morning.
+    pub fn contains_on(&self, days: &[Weekday], datetime: NaiveDateTime) -> bool {
+        let time = datetime.time();
+        if !self.contains(time) {
+            return false;
+        }
+        let weekday = if self.start > self.end && time < self.end {
+            datetime.weekday().pred()
+        } else {
+            datetime.weekday()
+        };
+        days.is_empty() || days.contains(&weekday)
+    }
+}
+
+impl FromStr for TimeWindow {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<TimeWindow> {
+        let invalid = || {
+            anyhow!(
+                "Invalid time window `{}`, expected a range like `09:00-18:00`",
+                s
+            )
+        };
+        let mut parts = s.splitn(2, '-');
+        let mut time = || {
+            parts
+                .next()
+                .and_then(|part| NaiveTime::parse_from_str(part.trim(), "%H:%M").ok())
+                .ok_or_else(invalid)
+        };
+        Ok(TimeWindow {
+            start: time()?,
+            end: time()?,
+        })
+    }
+}
+
+impl TryFrom<String> for TimeWindow {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<TimeWindow> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{NaiveDate, NaiveTime, Weekday};
+
+    use super::TimeWindow;
+
+    #[test]
+    fn test_time_window() {
+        let at = |h, m| NaiveTime::from_hms(h, m, 0);
+
+        let work: TimeWindow = "09:00-18:00".parse().unwrap();
+        assert!(work.contains(at(9, 0)));
+        assert!(work.contains(at(17, 59)));
+        assert!(!work.contains(at(18, 0)));
+        assert!(!work.contains(at(3, 0)));
+
+        let night: TimeWindow = "22:00 - 06:00".parse().unwrap();
+        assert_eq!(night.to_string(), "22:00-06:00");
+        assert!(night.contains(at(23, 0)));
+        assert!(night.contains(at(5, 0)));
+        assert!(!night.contains(at(12, 0)));
+
+        // 2026-10-17 is a Saturday
+        let saturday_morning = NaiveDate::from_ymd(2026, 10, 17).and_hms(2, 0, 0);
+        assert!(night.contains_on(&[Weekday::Fri], saturday_morning));
+        assert!(!night.contains_on(&[Weekday::Sat], saturday_morning));
+        assert!(!work.contains_on(&[], saturday_morning));
+
+        assert!("9am-5pm".parse::<TimeWindow>().is_err());
+        assert!("09:00".parse::<TimeWindow>().is_err());
+    }
+}